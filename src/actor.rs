@@ -0,0 +1,173 @@
+//!Actors on top of MRMS channels.
+//!
+//!An actor owns its state and handles one message at a time, so it needs
+//!no locking. `spawn` gives it a Mailbox, the receiving end of a channel,
+//!and runs its loop on a ThreadPool worker, handing back an `Addr` that
+//!clones into as many senders as needed. The loop ends once every Addr is
+//!gone and the mailbox is drained, or after a message handler asks to
+//!stop. A spawned actor keeps its worker busy until it ends.
+
+
+use super::Async;
+use super::mrms::{channel,MRMSReceiver,MRMSSender};
+use super::pool::ThreadPool;
+use super::backoff::{backoff,Backoff};
+
+
+///What an actor wants after handling a message
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Flow {
+    ///Keep handling messages
+    Continue,
+    ///Stop the loop, queued messages are dropped
+    Stop
+}
+
+///State that handles messages of type `M` one at a time
+pub trait Actor<M>: Send + 'static {
+    ///Handle one message
+    fn handle(&mut self, msg: M) -> Flow;
+    ///Runs once on the worker before the first message
+    fn started(&mut self) { }
+    ///Runs once after the loop ends
+    fn stopped(&mut self) { }
+}
+
+///Receiving end of an actor's channel
+pub struct Mailbox<M: Send+'static> {
+    receiver: MRMSReceiver<M>
+}
+impl<M: Send+'static> Mailbox<M> {
+    ///Build a mailbox pre-sized for `capacity` messages and its first
+    ///address
+    pub fn new(capacity: usize) -> (Addr<M>,Mailbox<M>) {
+        let (sender, receiver) = channel(capacity);
+        (Addr { sender }, Mailbox { receiver })
+    }
+    ///Wait for the next message
+    ///
+    ///Returns None once every Addr is gone and nothing is queued
+    pub fn recv(&self) -> Option<M> {
+        let backoff = Backoff::new();
+        loop {
+            match self.receiver.recv() {
+                Async::Ok(Option::Some(msg)) => return Some(msg),
+                Async::Err(()) => return None,
+                Async::Ok(Option::None) |
+                Async::Block(()) => { }
+            };
+            backoff.snooze();
+        }
+    }
+    ///Feed messages to `actor` until it stops or every Addr is gone
+    pub fn run<A: Actor<M>>(self, actor: &mut A) {
+        actor.started();
+        while let Option::Some(msg) = self.recv() {
+            if actor.handle(msg) == Flow::Stop {
+                break;
+            }
+        }
+        actor.stopped();
+    }
+}
+
+///Handle for sending messages to an actor
+pub struct Addr<M: Send+'static> {
+    sender: MRMSSender<M>
+}
+impl<M: Send+'static> Clone for Addr<M> {
+    fn clone(&self) -> Addr<M> {
+        Addr {
+            sender: self.sender.clone()
+        }
+    }
+}
+impl<M: Send+'static> Addr<M> {
+    ///Send a message
+    ///
+    ///Has the same return values as `MRMSSender::send`
+    pub fn try_send(&self, msg: M) -> Async<(),M,M> {
+        self.sender.send(msg)
+    }
+    ///Send a message, retrying while the mailbox is contended
+    ///
+    ///Returns Err(M) if the actor has stopped
+    pub fn send(&self, msg: M) -> Result<(),M> {
+        let mut msg = msg;
+        let mut step = 0;
+        loop {
+            match self.sender.send(msg) {
+                Async::Ok(()) => return Ok(()),
+                Async::Block(x) => {
+                    msg = x;
+                    backoff(&mut step);
+                }
+                Async::Err(x) => return Err(x)
+            };
+        }
+    }
+    ///Returns true while the actor's mailbox is still open
+    pub fn is_alive(&self) -> bool {
+        !self.sender.poll_ready().is_err()
+    }
+}
+
+///Run `actor` on a worker of `pool`, returning its address
+pub fn spawn<M,A>(pool: &ThreadPool, actor: A) -> Addr<M>
+where
+    M: Send + 'static,
+    A: Actor<M>,
+{
+    let (addr, mailbox) = Mailbox::new(16);
+    let mut actor = actor;
+    pool.execute(move || mailbox.run(&mut actor));
+    addr
+}
+
+#[test]
+fn test_actor_counter() {
+    use std::thread;
+    use std::sync::mpsc;
+    struct Counter {
+        total: usize,
+        report: mpsc::Sender<usize>
+    }
+    enum Msg {
+        Add(usize),
+        Report,
+        Stop
+    }
+    impl Actor<Msg> for Counter {
+        fn handle(&mut self, msg: Msg) -> Flow {
+            match msg {
+                Msg::Add(x) => self.total += x,
+                Msg::Report => self.report.send(self.total).unwrap(),
+                Msg::Stop => return Flow::Stop
+            };
+            Flow::Continue
+        }
+        fn stopped(&mut self) {
+            self.report.send(usize::MAX).unwrap();
+        }
+    }
+    let pool = ThreadPool::new(2);
+    let (tx, rx) = mpsc::channel();
+    let addr = spawn(&pool, Counter { total: 0, report: tx });
+    let senders = (0..2).map(|_| {
+        let addr = addr.clone();
+        thread::spawn(move || {
+            for i in 0..100 {
+                addr.send(Msg::Add(i)).ok().unwrap();
+            }
+        })
+    }).collect::<Vec<_>>();
+    for s in senders {
+        s.join().unwrap();
+    }
+    addr.send(Msg::Report).ok().unwrap();
+    assert_eq!(rx.recv().unwrap(), 2 * 4950);
+    addr.send(Msg::Stop).ok().unwrap();
+    assert_eq!(rx.recv().unwrap(), usize::MAX);
+    pool.join();
+    assert!(!addr.is_alive());
+}
@@ -0,0 +1,248 @@
+//!Spin-then-park lock.
+//!
+//!Short critical sections are served by spinning like every other lock in
+//!the crate. Once a waiter has spun for its budget it registers itself and
+//!parks, and the releasing thread unparks it. The state word follows the
+//!usual futex layout: `0` unlocked, `1` locked, `2` locked with parked
+//!waiters.
+
+
+#[cfg(feature="lock-stats")]
+use super::lockstats::LockStats;
+use super::lockstats::{HoldTimer,LockCounters};
+use super::lockorder;
+use super::backoff::Backoff;
+use super::spinlock::SpinLock;
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::{Deref,DerefMut};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread::{self,Thread};
+const SEQ: Ordering = Ordering::SeqCst;
+
+const UNLOCKED: usize = 0;
+const LOCKED: usize = 1;
+const PARKED: usize = 2;
+
+///Polls made before a waiter parks when built with `new`
+pub const DEFAULT_SPINS: u32 = 100;
+
+///Lock that spins for a bounded time, then parks
+pub struct AdaptiveLock<T: ?Sized> {
+    state: AtomicUsize,
+    spins: u32,
+    waiters: SpinLock<VecDeque<Thread>>,
+    counters: LockCounters,
+    data: UnsafeCell<T>
+}
+unsafe impl<T: ?Sized+Send> Send for AdaptiveLock<T> { }
+unsafe impl<T: ?Sized+Send> Sync for AdaptiveLock<T> { }
+impl<T> AdaptiveLock<T> {
+    ///Build a new unlocked AdaptiveLock
+    #[inline(always)]
+    pub const fn new(data: T) -> AdaptiveLock<T> {
+        AdaptiveLock::with_spins(data, DEFAULT_SPINS)
+    }
+    ///Build a new unlocked AdaptiveLock whose waiters poll `spins` times
+    ///before parking
+    #[inline(always)]
+    pub const fn with_spins(data: T, spins: u32) -> AdaptiveLock<T> {
+        AdaptiveLock {
+            state: AtomicUsize::new(UNLOCKED),
+            spins,
+            waiters: SpinLock::new(VecDeque::new()),
+            counters: LockCounters::new(),
+            data: UnsafeCell::new(data)
+        }
+    }
+    ///Consume the lock, returning the data
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+impl<T: ?Sized> AdaptiveLock<T> {
+    ///Spin for the budget, then park until the lock is handed over
+    #[inline(always)]
+    pub fn lock<'a>(&'a self) -> AdaptiveGuard<'a,T> {
+        self.acquire();
+        self.guard()
+    }
+    #[inline(always)]
+    fn guard<'a>(&'a self) -> AdaptiveGuard<'a,T> {
+        AdaptiveGuard {
+            lock: self,
+            timer: self.counters.acquired()
+        }
+    }
+    ///Attempt the lock once
+    ///
+    ///Returns Err(()) if somebody else holds it
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_lock<'a>(&'a self) -> Result<AdaptiveGuard<'a,T>,()> {
+        match self.poll() {
+            Ok(()) => Ok(self.guard()),
+            Err(()) => {
+                self.counters.failed();
+                Err(())
+            }
+        }
+    }
+    ///Returns true if somebody holds the lock right now
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.state.load(SEQ) != UNLOCKED
+    }
+    ///Number of threads currently parked on the lock
+    #[inline(always)]
+    pub fn parked(&self) -> usize {
+        self.waiters.lock().len()
+    }
+    ///Contention statistics gathered so far, time spent parked is not
+    ///counted as spinning
+    #[cfg(feature="lock-stats")]
+    pub fn stats(&self) -> LockStats {
+        self.counters.snapshot()
+    }
+    ///Mutable access without locking
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        self.data.get_mut()
+    }
+}
+//kept off the `Lock` trait, a public release would let anybody unlock the
+//data out from under the guard
+impl<T: ?Sized> AdaptiveLock<T> {
+    fn poll(&self) -> Result<(),()> {
+        match self.state.compare_exchange(UNLOCKED, LOCKED, SEQ, SEQ) {
+            Ok(_) => {
+                lockorder::acquired(lockorder::id_of(self));
+                Ok(())
+            }
+            Err(_) => Err(())
+        }
+    }
+    fn release(&self) {
+        lockorder::released(lockorder::id_of(self));
+        if self.state.swap(UNLOCKED, SEQ) == PARKED {
+            let next = self.waiters.lock().pop_front();
+            if let Option::Some(waiter) = next {
+                waiter.unpark();
+            }
+        }
+    }
+    fn acquire(&self) {
+        lockorder::waiting(lockorder::id_of(self));
+        //on a single threaded target the first failed poll panics rather
+        //than parking for good
+        let backoff = Backoff::new();
+        for spins in 0..self.spins {
+            if self.poll().is_ok() {
+                self.counters.spun(spins as u64);
+                return;
+            }
+            self.counters.failed();
+            backoff.spin();
+        }
+        self.counters.spun(self.spins as u64);
+        loop {
+            {
+                //marking the word and queueing happen under the waiter lock,
+                //so a release either sees our mark after we queued or we see
+                //its unlock here
+                let mut waiters = self.waiters.lock();
+                if self.state.swap(PARKED, SEQ) == UNLOCKED {
+                    drop(waiters);
+                    lockorder::acquired(lockorder::id_of(self));
+                    return;
+                }
+                waiters.push_back(thread::current());
+            }
+            thread::park();
+            //stale entries from spurious wake ups only cost an extra unpark
+            if self.state.swap(PARKED, SEQ) == UNLOCKED {
+                lockorder::acquired(lockorder::id_of(self));
+                return;
+            }
+        }
+    }
+}
+impl<T: Default> Default for AdaptiveLock<T> {
+    fn default() -> AdaptiveLock<T> {
+        AdaptiveLock::new(T::default())
+    }
+}
+
+///Access to the data of an AdaptiveLock, wakes a parked waiter on drop
+pub struct AdaptiveGuard<'a,T: ?Sized+'a> {
+    lock: &'a AdaptiveLock<T>,
+    timer: HoldTimer
+}
+//sharing the guard shares `&T`, the auto impl would only ask for T: Send
+unsafe impl<'a,T: ?Sized+Sync> Sync for AdaptiveGuard<'a,T> { }
+impl<'a,T: ?Sized> AdaptiveGuard<'a,T> {
+    ///Release the lock, same as dropping the guard
+    #[inline(always)]
+    pub fn unlock(self) {
+        mem::drop(self);
+    }
+}
+impl<'a,T: ?Sized> Deref for AdaptiveGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> DerefMut for AdaptiveGuard<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> Drop for AdaptiveGuard<'a,T> {
+    fn drop(&mut self) {
+        self.lock.counters.released(&self.timer);
+        self.lock.release();
+    }
+}
+
+#[test]
+fn test_adaptive_lock_parks() {
+    use std::sync::Arc;
+    use std::time::Duration;
+    let lock = Arc::new(AdaptiveLock::with_spins(0usize, 1));
+    let held = lock.lock();
+    let waiter = {
+        let lock = lock.clone();
+        thread::spawn(move || {
+            *lock.lock() += 1;
+        })
+    };
+    while lock.parked() == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    held.unlock();
+    waiter.join().unwrap();
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test]
+fn test_adaptive_lock_contended() {
+    use std::sync::Arc;
+    let lock = Arc::new(AdaptiveLock::with_spins(0usize, 10));
+    let workers = (0..4).map(|_| {
+        let lock = lock.clone();
+        thread::spawn(move || {
+            for _ in 0..500 {
+                *lock.lock() += 1;
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.lock(), 2000);
+}
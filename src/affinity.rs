@@ -0,0 +1,155 @@
+//!CPU affinity for threads.
+//!
+//!Per core shards only stay per core if the threads using them stay on
+//!their cores. These helpers pin the calling thread to a set of cores and
+//!look up which cores belong to a NUMA node. On Linux they call
+//!`sched_setaffinity` and read `/sys/devices/system/node`. Other targets
+//!report `io::ErrorKind::Unsupported`.
+//!
+//!`ThreadPool::builder` takes an `Affinity` and applies it to each worker
+//!as the worker starts.
+
+
+use std::io;
+
+///Where the workers of a pool may run
+#[derive(Clone,Debug,PartialEq,Eq,Default)]
+pub enum Affinity {
+    ///Wherever the OS schedules them
+    #[default]
+    Unpinned,
+    ///Worker `i` is pinned to `cores[i % cores.len()]`
+    Cores(Vec<usize>),
+    ///Every worker may run on any core of this NUMA node
+    NumaNode(usize)
+}
+impl Affinity {
+    ///Cores worker `index` should be restricted to, None for no pinning
+    pub(crate) fn resolve(&self, index: usize) -> Option<Vec<usize>> {
+        match *self {
+            Affinity::Unpinned => None,
+            Affinity::Cores(ref cores) if cores.is_empty() => None,
+            Affinity::Cores(ref cores) => Some(vec![cores[index % cores.len()]]),
+            Affinity::NumaNode(node) => numa_node_cores(node).ok()
+        }
+    }
+}
+
+///Pin the calling thread to `core`
+pub fn pin_current(core: usize) -> io::Result<()> {
+    set_current(&[core])
+}
+
+///Restrict the calling thread to `cores`
+pub fn set_current(cores: &[usize]) -> io::Result<()> {
+    sys::set(cores)
+}
+
+///Cores the calling thread may run on
+pub fn current() -> io::Result<Vec<usize>> {
+    sys::get()
+}
+
+///Cores belonging to NUMA node `node`
+pub fn numa_node_cores(node: usize) -> io::Result<Vec<usize>> {
+    sys::node_cores(node)
+}
+
+///Parse a kernel cpu list like `0-3,8,10-11`
+fn parse_cpulist(list: &str) -> Option<Vec<usize>> {
+    let mut cores = Vec::new();
+    for part in list.trim().split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Option::Some((lo, hi)) => {
+                let (lo, hi) = (lo.parse::<usize>().ok()?, hi.parse::<usize>().ok()?);
+                cores.extend(lo..=hi);
+            }
+            Option::None => cores.push(part.parse().ok()?)
+        };
+    }
+    Some(cores)
+}
+
+#[cfg(target_os="linux")]
+mod sys {
+    use std::fs;
+    use std::io;
+
+    ///CPU_SETSIZE of glibc and musl
+    const MAX_CORES: usize = 1024;
+    const WORD_BITS: usize = usize::BITS as usize;
+    const WORDS: usize = MAX_CORES / WORD_BITS;
+
+    extern "C" {
+        fn sched_setaffinity(pid: i32, size: usize, mask: *const usize) -> i32;
+        fn sched_getaffinity(pid: i32, size: usize, mask: *mut usize) -> i32;
+    }
+
+    pub fn set(cores: &[usize]) -> io::Result<()> {
+        let mut mask = [0usize; WORDS];
+        for &core in cores {
+            if core >= MAX_CORES {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "core index out of range"));
+            }
+            mask[core / WORD_BITS] |= 1 << (core % WORD_BITS);
+        }
+        //pid 0 is the calling thread
+        if unsafe{ sched_setaffinity(0, WORDS * (WORD_BITS / 8), mask.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn get() -> io::Result<Vec<usize>> {
+        let mut mask = [0usize; WORDS];
+        if unsafe{ sched_getaffinity(0, WORDS * (WORD_BITS / 8), mask.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..MAX_CORES).filter(|c| mask[c / WORD_BITS] & (1 << (c % WORD_BITS)) != 0).collect())
+    }
+
+    pub fn node_cores(node: usize) -> io::Result<Vec<usize>> {
+        let list = fs::read_to_string(format!("/sys/devices/system/node/node{}/cpulist", node))?;
+        super::parse_cpulist(&list)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed cpulist"))
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+mod sys {
+    use std::io;
+
+    fn unsupported<T>() -> io::Result<T> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "thread affinity is not supported on this target"))
+    }
+    pub fn set(_: &[usize]) -> io::Result<()> {
+        unsupported()
+    }
+    pub fn get() -> io::Result<Vec<usize>> {
+        unsupported()
+    }
+    pub fn node_cores(_: usize) -> io::Result<Vec<usize>> {
+        unsupported()
+    }
+}
+
+#[test]
+fn test_parse_cpulist() {
+    assert_eq!(parse_cpulist("0-3,8,10-11\n"), Some(vec![0,1,2,3,8,10,11]));
+    assert_eq!(parse_cpulist(""), Some(vec![]));
+    assert_eq!(parse_cpulist("1-x"), None);
+    assert_eq!(Affinity::Cores(vec![4,6]).resolve(3), Some(vec![6]));
+    assert_eq!(Affinity::Unpinned.resolve(0), None);
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_pin_current() {
+    use std::thread;
+    let core = current().unwrap()[0];
+    thread::spawn(move || {
+        pin_current(core).unwrap();
+        assert_eq!(current().unwrap(), vec![core]);
+    }).join().unwrap();
+    assert!(pin_current(1 << 20).is_err());
+}
@@ -0,0 +1,317 @@
+//!Concurrent bump arena.
+//!
+//!Memory is handed out from large chunks by bumping an offset, nothing is
+//!given back one value at a time. Threads bump in the chunk cached on their
+//!own padded slot, so they rarely touch the same cache line. A full chunk is
+//!swapped for a new one, chunks freed by `reset` are kept and reused.
+//!
+//!Values allocated with `alloc` are never dropped. `ArenaBox` drops its
+//!value in place but the memory only comes back at the next `reset`, the
+//!box keeps the arena alive, so `reset` through `Arc::get_mut` can only
+//!run once every box is gone. `channel_in_arena` in `mrms` moves messages
+//!through a channel this way.
+
+
+use super::Async;
+use super::mrms::MRMSSender;
+use super::policy::Policy;
+use super::spinlock::SpinLock;
+use std::alloc::{self,Layout};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref,DerefMut};
+use std::ptr::{self,NonNull};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicPtr,AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+
+///Bytes in a chunk unless an allocation needs more
+pub const CHUNK_SIZE: usize = 64 * 1024;
+///Thread slots, threads past this many share slots
+const SLOTS: usize = 16;
+const CHUNK_ALIGN: usize = 16;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+thread_local!(static THREAD: usize = NEXT_THREAD.fetch_add(1, RELAXED));
+
+struct Chunk {
+    base: NonNull<u8>,
+    size: usize,
+    used: AtomicUsize
+}
+impl Chunk {
+    fn new(size: usize) -> Box<Chunk> {
+        let layout = Layout::from_size_align(size, CHUNK_ALIGN).unwrap();
+        let base = match NonNull::new(unsafe{ alloc::alloc(layout) }) {
+            Option::Some(base) => base,
+            Option::None => alloc::handle_alloc_error(layout)
+        };
+        Box::new(Chunk {
+            base,
+            size,
+            used: AtomicUsize::new(0)
+        })
+    }
+    ///Bump out room for `layout`, None if the chunk is too full
+    fn bump(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let base = self.base.as_ptr() as usize;
+        let mut offset = 0;
+        self.used.fetch_update(RELAXED, RELAXED, |used| {
+            let start = (base + used).checked_add(layout.align() - 1)? & !(layout.align() - 1);
+            offset = start - base;
+            let end = offset.checked_add(layout.size())?;
+            if end > self.size {
+                return None;
+            }
+            Some(end)
+        }).ok()?;
+        NonNull::new(unsafe{ self.base.as_ptr().add(offset) })
+    }
+}
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.size, CHUNK_ALIGN).unwrap();
+        unsafe{ alloc::dealloc(self.base.as_ptr(), layout) };
+    }
+}
+
+///Chunk a thread slot bumps in, padded to its own cache line
+#[repr(align(128))]
+struct Slot(AtomicPtr<Chunk>);
+
+//boxed so a slot's pointer stays put when the lists grow
+#[allow(clippy::vec_box)]
+struct Chunks {
+    ///every chunk handed to a slot since the last reset
+    used: Vec<Box<Chunk>>,
+    ///chunks freed by reset
+    spare: Vec<Box<Chunk>>
+}
+
+///Thread safe bump allocator, freed in bulk
+pub struct ConcurrentArena {
+    slots: Box<[Slot]>,
+    chunks: SpinLock<Chunks>
+}
+unsafe impl Send for ConcurrentArena { }
+unsafe impl Sync for ConcurrentArena { }
+impl ConcurrentArena {
+    ///Build an empty arena, chunks are allocated on first use
+    pub fn new() -> ConcurrentArena {
+        ConcurrentArena {
+            slots: (0..SLOTS).map(|_| Slot(AtomicPtr::new(ptr::null_mut()))).collect(),
+            chunks: SpinLock::new(Chunks {
+                used: Vec::new(),
+                spare: Vec::new()
+            })
+        }
+    }
+    ///Allocate room for `layout`, valid until the next `reset`
+    pub fn alloc_layout(&self, layout: Layout) -> NonNull<u8> {
+        if layout.size() == 0 {
+            //any aligned address will do
+            return unsafe{ NonNull::new_unchecked(layout.align() as *mut u8) };
+        }
+        let slot = &self.slots[THREAD.try_with(|t| *t).unwrap_or(0) % SLOTS].0;
+        let current = slot.load(ACQUIRE);
+        if !current.is_null() {
+            if let Option::Some(p) = unsafe{ (*current).bump(layout) } {
+                return p;
+            }
+        }
+        let mut chunks = self.chunks.lock();
+        //another thread on this slot may have swapped the chunk already
+        let current = slot.load(ACQUIRE);
+        if !current.is_null() {
+            if let Option::Some(p) = unsafe{ (*current).bump(layout) } {
+                return p;
+            }
+        }
+        let need = layout.size() + layout.align();
+        let chunk = match chunks.spare.iter().position(|c| c.size >= need) {
+            Option::Some(i) => chunks.spare.swap_remove(i),
+            Option::None => Chunk::new(need.max(CHUNK_SIZE))
+        };
+        let p = chunk.bump(layout).unwrap();
+        slot.store(&*chunk as *const Chunk as *mut Chunk, RELEASE);
+        chunks.used.push(chunk);
+        p
+    }
+    ///Move `value` into the arena, it is never dropped
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<T>(&self, value: T) -> &mut T {
+        let p = self.alloc_layout(Layout::new::<T>()).as_ptr() as *mut T;
+        unsafe {
+            ptr::write(p, value);
+            &mut *p
+        }
+    }
+    ///Copy `values` into the arena
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice<T: Copy>(&self, values: &[T]) -> &mut [T] {
+        let layout = Layout::array::<T>(values.len()).unwrap();
+        let p = self.alloc_layout(layout).as_ptr() as *mut T;
+        unsafe {
+            ptr::copy_nonoverlapping(values.as_ptr(), p, values.len());
+            ::std::slice::from_raw_parts_mut(p, values.len())
+        }
+    }
+    ///Free everything allocated at once, the chunks are kept for reuse
+    pub fn reset(&mut self) {
+        for slot in self.slots.iter_mut() {
+            *slot.0.get_mut() = ptr::null_mut();
+        }
+        let chunks = self.chunks.get_mut();
+        for chunk in chunks.used.drain(..) {
+            chunk.used.store(0, RELAXED);
+            chunks.spare.push(chunk);
+        }
+    }
+    ///Bytes handed out since the last reset, alignment padding included
+    pub fn allocated(&self) -> usize {
+        self.chunks.lock().used.iter().map(|c| c.used.load(RELAXED)).sum()
+    }
+    ///Bytes held in chunks, used or spare
+    pub fn capacity(&self) -> usize {
+        let chunks = self.chunks.lock();
+        chunks.used.iter().chain(chunks.spare.iter()).map(|c| c.size).sum()
+    }
+}
+impl Default for ConcurrentArena {
+    fn default() -> ConcurrentArena {
+        ConcurrentArena::new()
+    }
+}
+
+///Owns a value in an arena, dropping it in place
+///
+///The memory is reclaimed by the arena's next `reset`.
+pub struct ArenaBox<T> {
+    ptr: NonNull<T>,
+    _arena: Arc<ConcurrentArena>,
+    marker: PhantomData<T>
+}
+unsafe impl<T: Send> Send for ArenaBox<T> { }
+unsafe impl<T: Sync> Sync for ArenaBox<T> { }
+impl<T> ArenaBox<T> {
+    ///Move `value` into `arena`
+    pub fn new(arena: &Arc<ConcurrentArena>, value: T) -> ArenaBox<T> {
+        ArenaBox {
+            ptr: NonNull::from(arena.alloc(value)),
+            _arena: arena.clone(),
+            marker: PhantomData
+        }
+    }
+    ///Move the value back out
+    pub fn into_inner(this: ArenaBox<T>) -> T {
+        let value = unsafe{ ptr::read(this.ptr.as_ptr()) };
+        let mut this = mem::ManuallyDrop::new(this);
+        unsafe{ ptr::drop_in_place(&mut this._arena) };
+        value
+    }
+}
+impl<T> Deref for ArenaBox<T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe{ self.ptr.as_ref() }
+    }
+}
+impl<T> DerefMut for ArenaBox<T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ self.ptr.as_mut() }
+    }
+}
+impl<T> Drop for ArenaBox<T> {
+    fn drop(&mut self) {
+        unsafe{ ptr::drop_in_place(self.ptr.as_ptr()) };
+    }
+}
+
+///Sends values through a channel in arena boxes
+pub struct ArenaSender<T: Sized+'static, P: Policy> {
+    sender: MRMSSender<ArenaBox<T>,P>,
+    arena: Arc<ConcurrentArena>
+}
+impl<T: Sized+'static, P: Policy> Clone for ArenaSender<T,P> {
+    fn clone(&self) -> ArenaSender<T,P> {
+        ArenaSender {
+            sender: self.sender.clone(),
+            arena: self.arena.clone()
+        }
+    }
+}
+impl<T: Sized+'static, P: Policy> ArenaSender<T,P> {
+    pub(crate) fn new(sender: MRMSSender<ArenaBox<T>,P>, arena: Arc<ConcurrentArena>) -> ArenaSender<T,P> {
+        ArenaSender {
+            sender,
+            arena
+        }
+    }
+    ///Sends an Item, it is moved into the arena
+    ///
+    ///Has the same return values as `MRMSSender::send`
+    pub fn send(&self, data: T) -> Async<(),T,T> {
+        match self.sender.send(ArenaBox::new(&self.arena, data)) {
+            Async::Ok(()) => Async::Ok(()),
+            Async::Block(b) => Async::Block(ArenaBox::into_inner(b)),
+            Async::Err(b) => Async::Err(ArenaBox::into_inner(b))
+        }
+    }
+    ///The arena messages are allocated in
+    #[inline(always)]
+    pub fn arena(&self) -> &Arc<ConcurrentArena> {
+        &self.arena
+    }
+}
+
+#[test]
+fn test_arena_alloc_reset() {
+    use std::thread;
+    let arena = Arc::new(ConcurrentArena::new());
+    let workers = (0..4).map(|t| {
+        let arena = arena.clone();
+        thread::spawn(move || {
+            let values = (0..250u64).map(|i| arena.alloc(t * 1000 + i) as *mut u64 as usize).collect::<Vec<_>>();
+            for (i, p) in values.into_iter().enumerate() {
+                assert_eq!(unsafe{ *(p as *const u64) }, t * 1000 + i as u64);
+                assert_eq!(p % mem::align_of::<u64>(), 0);
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    let mut arena = Arc::try_unwrap(arena).ok().unwrap();
+    assert!(arena.allocated() >= 1000 * 8);
+    let big = arena.alloc_slice(&[7u8; CHUNK_SIZE * 2]);
+    assert_eq!(big.len(), CHUNK_SIZE * 2);
+    let capacity = arena.capacity();
+    arena.reset();
+    assert_eq!(arena.allocated(), 0);
+    arena.alloc(1u8);
+    //chunks are reused after a reset
+    assert_eq!(arena.capacity(), capacity);
+}
+
+#[test]
+fn test_arena_box() {
+    let mut arena = Arc::new(ConcurrentArena::new());
+    let dropped = Arc::new(AtomicUsize::new(0));
+    struct Count(Arc<AtomicUsize>);
+    impl Drop for Count {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, RELAXED);
+        }
+    }
+    let boxed = ArenaBox::new(&arena, Count(dropped.clone()));
+    assert!(Arc::get_mut(&mut arena).is_none());
+    drop(boxed);
+    assert_eq!(dropped.load(RELAXED), 1);
+    let s = ArenaBox::new(&arena, String::from("hi"));
+    assert_eq!(ArenaBox::into_inner(s), "hi");
+    Arc::get_mut(&mut arena).unwrap().reset();
+}
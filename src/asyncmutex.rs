@@ -0,0 +1,319 @@
+//!Mutex for async code.
+//!
+//!`lock` returns a future. When the mutex is free it completes on its
+//!first poll; otherwise it queues a waiter on an `IntrusiveQueue` and
+//!returns Pending until an unlock wakes it, so a task waiting for the lock
+//!gives its thread back to the executor instead of spinning.
+//!
+//!An unlock hands the lock straight to the oldest waiter, leaving it
+//!locked, so a newcomer cannot barge in between. If the queue is empty
+//!the lock is released and the queue looked at once more, a waiter that
+//!queued in that window is picked up by whoever locks next or by the
+//!unlocker itself.
+//!
+//!Dropping a lock future is safe at any point. A waiter that was still
+//!queued is marked cancelled and skipped, one that had already been handed
+//!the lock passes it on.
+
+
+use super::Async;
+use super::backoff::backoff;
+use super::intrusive::{IntrusiveQueue,Link,Linked};
+use super::spinlock::SpinLock;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::future::Future;
+use std::ops::{Deref,DerefMut};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{self,AtomicBool,AtomicUsize,Ordering};
+use std::task::{Context,Poll,Waker};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+const SEQ: Ordering = Ordering::SeqCst;
+
+//waiter states
+const WAITING: usize = 0;
+const GRANTED: usize = 1;
+const CANCELLED: usize = 2;
+
+///Task queued on an async primitive
+#[repr(C)]
+pub(crate) struct Waiter {
+    link: Link,
+    state: AtomicUsize,
+    waker: SpinLock<Option<Waker>>
+}
+unsafe impl Linked for Waiter {
+    fn link(&self) -> &Link {
+        &self.link
+    }
+}
+impl Waiter {
+    pub(crate) fn new(waker: &Waker) -> Arc<Waiter> {
+        Arc::new(Waiter {
+            link: Link::new(),
+            state: AtomicUsize::new(WAITING),
+            waker: SpinLock::new(Some(waker.clone()))
+        })
+    }
+    ///Hand the resource to this waiter and wake it, false if it was
+    ///cancelled
+    pub(crate) fn grant(&self) -> bool {
+        if self.state.compare_exchange(WAITING, GRANTED, ACQREL, ACQUIRE).is_err() {
+            return false;
+        }
+        if let Option::Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+        true
+    }
+    ///Give up waiting, false if the resource was already granted
+    pub(crate) fn cancel(&self) -> bool {
+        self.state.compare_exchange(WAITING, CANCELLED, ACQREL, ACQUIRE).is_ok()
+    }
+    ///Returns true once the resource has been granted, storing `waker` to
+    ///be woken otherwise
+    pub(crate) fn poll_granted(&self, waker: &Waker) -> bool {
+        if self.state.load(ACQUIRE) == GRANTED {
+            return true;
+        }
+        {
+            let mut slot = self.waker.lock();
+            match *slot {
+                Option::Some(ref w) if w.will_wake(waker) => { }
+                _ => *slot = Some(waker.clone())
+            };
+        }
+        //a grant between the first check and storing the waker took the
+        //old waker
+        self.state.load(ACQUIRE) == GRANTED
+    }
+}
+
+///Pop waiters until one accepts the grant, false if the queue ran dry
+///
+///The caller must be the only thread popping from `queue`
+pub(crate) fn grant_next(queue: &IntrusiveQueue<Waiter>) -> bool {
+    let mut step = 0;
+    loop {
+        match queue.pop() {
+            Async::Ok(Option::Some(w)) => if w.grant() {
+                return true;
+            },
+            Async::Ok(Option::None) => return false,
+            Async::Block(()) |
+            Async::Err(()) => { backoff(&mut step); }
+        };
+    }
+}
+
+///Mutex whose lock can be awaited
+pub struct AsyncMutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: IntrusiveQueue<Waiter>,
+    data: UnsafeCell<T>
+}
+unsafe impl<T: ?Sized+Send> Send for AsyncMutex<T> { }
+unsafe impl<T: ?Sized+Send> Sync for AsyncMutex<T> { }
+impl<T> AsyncMutex<T> {
+    ///Build an unlocked mutex
+    pub fn new(data: T) -> AsyncMutex<T> {
+        AsyncMutex {
+            locked: AtomicBool::new(false),
+            waiters: IntrusiveQueue::new(),
+            data: UnsafeCell::new(data)
+        }
+    }
+    ///Consume the mutex, returning the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+impl<T: ?Sized> AsyncMutex<T> {
+    ///Future that resolves to a guard once the lock is held
+    pub fn lock<'a>(&'a self) -> Lock<'a,T> {
+        Lock {
+            mutex: self,
+            waiter: None
+        }
+    }
+    ///Take the lock if it is free
+    pub fn try_lock<'a>(&'a self) -> Option<AsyncMutexGuard<'a,T>> {
+        if self.acquire() {
+            Some(AsyncMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+    ///Returns true if the lock is held at this instant
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(RELAXED)
+    }
+    ///Borrow the data, exclusive access means no lock is needed
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.data.get() }
+    }
+    #[inline(always)]
+    fn acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, ACQUIRE, RELAXED).is_ok()
+    }
+    ///Pass the lock to a waiter, or release it
+    fn unlock(&self) {
+        loop {
+            if grant_next(&self.waiters) {
+                return;
+            }
+            self.locked.store(false, RELEASE);
+            //pairs with the fence in Lock::poll, either the waiter sees the
+            //lock free or this sees the waiter queued
+            atomic::fence(SEQ);
+            if self.waiters.is_empty() || !self.acquire() {
+                return;
+            }
+        }
+    }
+}
+impl<T: Default> Default for AsyncMutex<T> {
+    fn default() -> AsyncMutex<T> {
+        AsyncMutex::new(T::default())
+    }
+}
+impl<T: ?Sized+fmt::Debug> fmt::Debug for AsyncMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Option::Some(guard) => f.debug_struct("AsyncMutex").field("data", &&*guard).finish(),
+            Option::None => f.debug_struct("AsyncMutex").field("data", &"<locked>").finish()
+        }
+    }
+}
+
+///Future returned by `AsyncMutex::lock`
+pub struct Lock<'a,T: ?Sized+'a> {
+    mutex: &'a AsyncMutex<T>,
+    waiter: Option<Arc<Waiter>>
+}
+impl<'a,T: ?Sized+'a> Future for Lock<'a,T> {
+    type Output = AsyncMutexGuard<'a,T>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<AsyncMutexGuard<'a,T>> {
+        let mutex = self.mutex;
+        match self.waiter {
+            Option::Some(ref w) => {
+                if !w.poll_granted(cx.waker()) {
+                    return Poll::Pending;
+                }
+            }
+            Option::None => {
+                if !mutex.acquire() {
+                    let w = Waiter::new(cx.waker());
+                    let _ = mutex.waiters.push(w.clone());
+                    atomic::fence(SEQ);
+                    if !mutex.acquire() {
+                        self.waiter = Some(w);
+                        return Poll::Pending;
+                    }
+                    //nobody can grant while we hold the lock
+                    w.cancel();
+                }
+            }
+        };
+        self.waiter = None;
+        Poll::Ready(AsyncMutexGuard { mutex })
+    }
+}
+impl<'a,T: ?Sized+'a> Drop for Lock<'a,T> {
+    fn drop(&mut self) {
+        if let Option::Some(w) = self.waiter.take() {
+            if !w.cancel() {
+                //granted but never polled, pass the lock on
+                self.mutex.unlock();
+            }
+        }
+    }
+}
+
+///Holds an AsyncMutex locked until dropped
+pub struct AsyncMutexGuard<'a,T: ?Sized+'a> {
+    mutex: &'a AsyncMutex<T>
+}
+unsafe impl<'a,T: ?Sized+Sync+'a> Sync for AsyncMutexGuard<'a,T> { }
+impl<'a,T: ?Sized+'a> Deref for AsyncMutexGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ &*self.mutex.data.get() }
+    }
+}
+impl<'a,T: ?Sized+'a> DerefMut for AsyncMutexGuard<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.mutex.data.get() }
+    }
+}
+impl<'a,T: ?Sized+'a> Drop for AsyncMutexGuard<'a,T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+pub(crate) struct CountWake(pub(crate) AtomicUsize);
+#[cfg(test)]
+impl ::std::task::Wake for CountWake {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, RELAXED);
+    }
+}
+
+#[test]
+fn test_async_mutex_handoff() {
+    let count = Arc::new(CountWake(AtomicUsize::new(0)));
+    let waker = Waker::from(count.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mutex = AsyncMutex::new(0);
+    let guard = super::executor::block_on(mutex.lock());
+    let mut second = Box::pin(mutex.lock());
+    let mut third = Box::pin(mutex.lock());
+    assert!(second.as_mut().poll(&mut cx).is_pending());
+    assert!(third.as_mut().poll(&mut cx).is_pending());
+    drop(guard);
+    //the lock went straight to the second future
+    assert_eq!(count.0.load(RELAXED), 1);
+    assert!(mutex.is_locked() && mutex.try_lock().is_none());
+    //dropping a granted future passes the lock to the third
+    drop(second);
+    assert_eq!(count.0.load(RELAXED), 2);
+    match third.as_mut().poll(&mut cx) {
+        Poll::Ready(mut g) => *g += 1,
+        Poll::Pending => panic!("third should hold the lock")
+    };
+    drop(third);
+    assert!(!mutex.is_locked());
+    //a cancelled waiter is skipped
+    let guard = mutex.try_lock().unwrap();
+    let mut fourth = Box::pin(mutex.lock());
+    assert!(fourth.as_mut().poll(&mut cx).is_pending());
+    drop(fourth);
+    drop(guard);
+    assert!(!mutex.is_locked());
+    assert_eq!(mutex.into_inner(), 1);
+}
+
+#[test]
+fn test_async_mutex_threads() {
+    use std::thread;
+    let mutex = Arc::new(AsyncMutex::new(0usize));
+    let workers = (0..4).map(|_| {
+        let mutex = mutex.clone();
+        thread::spawn(move || {
+            for _ in 0..250 {
+                let mut guard = super::executor::block_on(mutex.lock());
+                *guard += 1;
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(*mutex.try_lock().unwrap(), 1000);
+}
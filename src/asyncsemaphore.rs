@@ -0,0 +1,285 @@
+//!Counting semaphore for async code.
+//!
+//!Same handoff scheme as `AsyncMutex`: an `acquire` that finds no permit
+//!queues a waiter and returns Pending, and a released permit goes straight
+//!to the oldest live waiter instead of back to the count. Permits come
+//!borrowed, tied to the semaphore's lifetime, or owned, holding an Arc so
+//!they can move into spawned tasks.
+
+
+use super::asyncmutex::{grant_next,Waiter};
+use super::intrusive::IntrusiveQueue;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{self,AtomicUsize,Ordering};
+use std::task::{Context,Poll};
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Hands out up to `n` permits at once to async tasks
+pub struct AsyncSemaphore {
+    permits: AtomicUsize,
+    waiters: IntrusiveQueue<Waiter>
+}
+impl AsyncSemaphore {
+    ///Build a semaphore with `permits` available
+    pub fn new(permits: usize) -> AsyncSemaphore {
+        AsyncSemaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: IntrusiveQueue::new()
+        }
+    }
+    #[inline(always)]
+    fn take(&self) -> bool {
+        let mut current = self.permits.load(ACQUIRE);
+        loop {
+            if current == 0 {
+                return false;
+            }
+            match self.permits.compare_exchange_weak(current, current-1, ACQUIRE, ACQUIRE) {
+                Ok(_) => return true,
+                Err(x) => current = x
+            };
+        }
+    }
+    ///Return one permit, to a waiter if there is one
+    fn give(&self) {
+        loop {
+            if grant_next(&self.waiters) {
+                return;
+            }
+            self.permits.fetch_add(1, RELEASE);
+            //pairs with the fence in Acquiring::poll
+            atomic::fence(SEQ);
+            if self.waiters.is_empty() || !self.take() {
+                return;
+            }
+        }
+    }
+    ///Take a permit if one is available
+    ///
+    ///Returns Err(()) if every permit is out
+    #[allow(clippy::result_unit_err)]
+    pub fn try_acquire<'a>(&'a self) -> Result<AsyncPermit<'a>,()> {
+        if self.take() {
+            Ok(AsyncPermit { sem: self })
+        } else {
+            Err(())
+        }
+    }
+    ///Take an owned permit if one is available
+    ///
+    ///Returns Err(()) if every permit is out
+    #[allow(clippy::result_unit_err)]
+    pub fn try_acquire_owned(self: Arc<Self>) -> Result<OwnedPermit,()> {
+        if self.take() {
+            Ok(OwnedPermit { sem: self })
+        } else {
+            Err(())
+        }
+    }
+    ///Future that resolves to a permit once one is available
+    pub fn acquire<'a>(&'a self) -> Acquire<'a> {
+        Acquire {
+            sem: self,
+            state: Acquiring { waiter: None }
+        }
+    }
+    ///Future that resolves to an owned permit once one is available
+    pub fn acquire_owned(self: Arc<Self>) -> AcquireOwned {
+        AcquireOwned {
+            sem: self,
+            state: Acquiring { waiter: None }
+        }
+    }
+    ///Add `n` permits
+    ///
+    ///Used to return permits given up with `forget`, or to grow the
+    ///semaphore. Waiting tasks are served first.
+    pub fn release(&self, n: usize) {
+        for _ in 0..n {
+            self.give();
+        }
+    }
+    ///Number of permits available right now
+    pub fn available(&self) -> usize {
+        self.permits.load(ACQUIRE)
+    }
+}
+
+///Waiter bookkeeping shared by both acquire futures
+struct Acquiring {
+    waiter: Option<Arc<Waiter>>
+}
+impl Acquiring {
+    fn poll(&mut self, sem: &AsyncSemaphore, cx: &mut Context) -> Poll<()> {
+        match self.waiter {
+            Option::Some(ref w) => {
+                if !w.poll_granted(cx.waker()) {
+                    return Poll::Pending;
+                }
+            }
+            Option::None => {
+                if !sem.take() {
+                    let w = Waiter::new(cx.waker());
+                    let _ = sem.waiters.push(w.clone());
+                    atomic::fence(SEQ);
+                    if !sem.take() {
+                        self.waiter = Some(w);
+                        return Poll::Pending;
+                    }
+                    if !w.cancel() {
+                        //granted as well, keep one
+                        sem.give();
+                    }
+                }
+            }
+        };
+        self.waiter = None;
+        Poll::Ready(())
+    }
+    fn cancel(&mut self, sem: &AsyncSemaphore) {
+        if let Option::Some(w) = self.waiter.take() {
+            if !w.cancel() {
+                //granted but never polled, pass the permit on
+                sem.give();
+            }
+        }
+    }
+}
+
+///Future returned by `AsyncSemaphore::acquire`
+pub struct Acquire<'a> {
+    sem: &'a AsyncSemaphore,
+    state: Acquiring
+}
+impl<'a> Future for Acquire<'a> {
+    type Output = AsyncPermit<'a>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<AsyncPermit<'a>> {
+        let sem = self.sem;
+        self.state.poll(sem, cx).map(|()| AsyncPermit { sem })
+    }
+}
+impl<'a> Drop for Acquire<'a> {
+    fn drop(&mut self) {
+        self.state.cancel(self.sem);
+    }
+}
+
+///Future returned by `AsyncSemaphore::acquire_owned`
+pub struct AcquireOwned {
+    sem: Arc<AsyncSemaphore>,
+    state: Acquiring
+}
+impl Future for AcquireOwned {
+    type Output = OwnedPermit;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<OwnedPermit> {
+        let this = &mut *self;
+        let sem = &this.sem;
+        this.state.poll(sem, cx).map(|()| OwnedPermit { sem: sem.clone() })
+    }
+}
+impl Drop for AcquireOwned {
+    fn drop(&mut self) {
+        self.state.cancel(&self.sem);
+    }
+}
+
+///A permit borrowed from an AsyncSemaphore, returned on drop
+pub struct AsyncPermit<'a> {
+    sem: &'a AsyncSemaphore
+}
+impl<'a> AsyncPermit<'a> {
+    ///Keep the permit consumed without holding the guard, give it back
+    ///later with `AsyncSemaphore::release`
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+impl<'a> Drop for AsyncPermit<'a> {
+    fn drop(&mut self) {
+        self.sem.give();
+    }
+}
+
+///A permit that keeps its AsyncSemaphore alive, returned on drop
+pub struct OwnedPermit {
+    sem: Arc<AsyncSemaphore>
+}
+impl OwnedPermit {
+    ///The semaphore this permit came from
+    pub fn semaphore(&self) -> &Arc<AsyncSemaphore> {
+        &self.sem
+    }
+    ///Keep the permit consumed without holding the guard, give it back
+    ///later with `AsyncSemaphore::release`
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+impl Drop for OwnedPermit {
+    fn drop(&mut self) {
+        self.sem.give();
+    }
+}
+
+#[test]
+fn test_async_semaphore_handoff() {
+    use super::asyncmutex::CountWake;
+    use std::task::Waker;
+    let count = Arc::new(CountWake(AtomicUsize::new(0)));
+    let waker = Waker::from(count.clone());
+    let mut cx = Context::from_waker(&waker);
+    let sem = Arc::new(AsyncSemaphore::new(2));
+    let a = sem.try_acquire().unwrap();
+    let b = sem.clone().try_acquire_owned().unwrap();
+    assert!(sem.try_acquire().is_err());
+    let mut waiting = Box::pin(sem.clone().acquire_owned());
+    let mut cancelled = Box::pin(sem.acquire());
+    assert!(waiting.as_mut().poll(&mut cx).is_pending());
+    assert!(cancelled.as_mut().poll(&mut cx).is_pending());
+    drop(cancelled);
+    drop(a);
+    assert_eq!(count.0.load(Ordering::Relaxed), 1);
+    //the permit went to the waiter, not back to the count
+    assert_eq!(sem.available(), 0);
+    let c = match waiting.as_mut().poll(&mut cx) {
+        Poll::Ready(p) => p,
+        Poll::Pending => panic!("permit should have been handed over")
+    };
+    drop(waiting);
+    drop(b);
+    drop(c);
+    assert_eq!(sem.available(), 2);
+    sem.try_acquire().unwrap().forget();
+    assert_eq!(sem.available(), 1);
+    sem.release(1);
+    assert_eq!(sem.available(), 2);
+}
+
+#[test]
+fn test_async_semaphore_bounds() {
+    use std::thread;
+    let sem = Arc::new(AsyncSemaphore::new(2));
+    let inside = Arc::new(AtomicUsize::new(0));
+    let workers = (0..4).map(|_| {
+        let sem = sem.clone();
+        let inside = inside.clone();
+        thread::spawn(move || {
+            for _ in 0..100 {
+                let permit = super::executor::block_on(sem.clone().acquire_owned());
+                assert!(inside.fetch_add(1, SEQ) < 2);
+                thread::yield_now();
+                inside.fetch_sub(1, SEQ);
+                drop(permit);
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(sem.available(), 2);
+}
@@ -0,0 +1,250 @@
+//!Atomic cell for plain data.
+//!
+//!When `T` is 1, 2, 4 or 8 bytes wide every operation is a single native
+//!atomic on the cell's storage, which is aligned to 8 bytes for that
+//!reason. Larger types fall back to a spin lock kept next to the value.
+//!
+//!Native operations move the value around as an integer of the same
+//!width, which is only defined if every byte of `T` is initialized. The
+//!cell therefore only holds types marked `NoPadding`.
+
+
+use super::backoff::Backoff;
+use std::cell::UnsafeCell;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool,AtomicU8,AtomicU16,AtomicU32,Ordering};
+#[cfg(target_has_atomic="64")]
+use std::sync::atomic::AtomicU64;
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+///Types with no padding or otherwise uninitialized bytes
+///
+///Implemented for the primitive integers, floats, `bool`, `char`, raw
+///pointers and arrays of those.
+///
+///# Safety
+///
+///Every byte of every value of the type must be initialized. A struct
+///qualifies only if it is `#[repr(C)]` or `#[repr(transparent)]`, its
+///fields are all `NoPadding` and their layout leaves no gaps. Enums with
+///fields never qualify.
+pub unsafe trait NoPadding: Sized { }
+macro_rules! no_padding {
+    ($($t: ty),*) => { $(unsafe impl NoPadding for $t { })* }
+}
+no_padding!(u8,u16,u32,u64,u128,usize,i8,i16,i32,i64,i128,isize,f32,f64,bool,char);
+unsafe impl<T> NoPadding for *const T { }
+unsafe impl<T> NoPadding for *mut T { }
+unsafe impl<T: NoPadding, const N: usize> NoPadding for [T;N] { }
+
+///Copy the bytes of `value` into an integer of the same width
+#[inline(always)]
+unsafe fn to_bits<T,I>(value: &T) -> I {
+    let mut bits = mem::zeroed::<I>();
+    ptr::copy_nonoverlapping(value as *const T as *const u8, &mut bits as *mut I as *mut u8, mem::size_of::<T>());
+    bits
+}
+#[inline(always)]
+unsafe fn from_bits<T,I>(bits: I) -> T {
+    mem::transmute_copy(&bits)
+}
+
+///Run `$native` with `$a` bound to a native atomic over the cell's
+///storage, or `$fallback` if `T` has no native atomic of its width
+macro_rules! dispatch {
+    ($cell: expr, |$a: ident| $native: expr, $fallback: expr) => {
+        match AtomicCell::<T>::native_width() {
+            1 => { let $a = unsafe{ &*($cell.value.get() as *const AtomicU8) }; $native }
+            2 => { let $a = unsafe{ &*($cell.value.get() as *const AtomicU16) }; $native }
+            4 => { let $a = unsafe{ &*($cell.value.get() as *const AtomicU32) }; $native }
+            #[cfg(target_has_atomic="64")]
+            8 => { let $a = unsafe{ &*($cell.value.get() as *const AtomicU64) }; $native }
+            _ => $fallback
+        }
+    }
+}
+
+///Holds a `T` that can be read and replaced atomically
+#[repr(C, align(8))]
+pub struct AtomicCell<T> {
+    value: UnsafeCell<T>,
+    ///only used when there is no native atomic for T
+    lock: AtomicBool
+}
+unsafe impl<T: Send> Send for AtomicCell<T> { }
+unsafe impl<T: Send> Sync for AtomicCell<T> { }
+impl<T: NoPadding> AtomicCell<T> {
+    ///Build a new cell
+    #[inline(always)]
+    pub const fn new(value: T) -> AtomicCell<T> {
+        AtomicCell {
+            value: UnsafeCell::new(value),
+            lock: AtomicBool::new(false)
+        }
+    }
+    ///Width of the native atomic used for T, or 0 if T uses the lock
+    #[inline(always)]
+    const fn native_width() -> usize {
+        match mem::size_of::<T>() {
+            1 | 2 | 4 => mem::size_of::<T>(),
+            #[cfg(target_has_atomic="64")]
+            8 => 8,
+            _ => 0
+        }
+    }
+    ///Returns true if operations on this cell are native atomics
+    #[inline(always)]
+    pub const fn is_lock_free() -> bool {
+        AtomicCell::<T>::native_width() != 0
+    }
+    ///Run `f` with the value while holding the fallback lock
+    #[inline(always)]
+    fn locked<R,F: FnOnce(*mut T) -> R>(&self, f: F) -> R {
+        let backoff = Backoff::new();
+        while self.lock.compare_exchange_weak(false, true, ACQUIRE, RELAXED).is_err() {
+            backoff.snooze();
+        }
+        let r = f(self.value.get());
+        self.lock.store(false, RELEASE);
+        r
+    }
+    ///Replace the value, returning the old one
+    pub fn swap(&self, value: T) -> T {
+        dispatch!(self,
+            |a| unsafe {
+                let old = a.swap(to_bits(&value), ACQREL);
+                mem::forget(value);
+                from_bits(old)
+            },
+            self.locked(|p| unsafe{ ptr::replace(p, value) }))
+    }
+    ///Replace the value, dropping the old one
+    #[inline(always)]
+    pub fn store(&self, value: T) {
+        drop(self.swap(value));
+    }
+    ///Take the value, leaving the default behind
+    #[inline(always)]
+    pub fn take(&self) -> T
+    where
+        T: Default,
+    {
+        self.swap(T::default())
+    }
+    ///Mutable access without synchronization
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        self.value.get_mut()
+    }
+    ///Consume the cell, returning the value
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+impl<T: NoPadding+Copy> AtomicCell<T> {
+    ///Copy the value out
+    pub fn load(&self) -> T {
+        dispatch!(self,
+            |a| unsafe{ from_bits(a.load(ACQUIRE)) },
+            self.locked(|p| unsafe{ *p }))
+    }
+    ///Apply `f` until it returns None or the result is stored without
+    ///interference
+    ///
+    ///Returns Ok with the value `f` replaced, or Err with the value `f`
+    ///declined
+    pub fn fetch_update<F>(&self, mut f: F) -> Result<T,T>
+    where
+        F: FnMut(T) -> Option<T>,
+        T: Eq,
+    {
+        let mut current = self.load();
+        while let Option::Some(next) = f(current) {
+            match self.compare_exchange(current, next) {
+                Ok(x) => return Ok(x),
+                Err(x) => current = x
+            };
+        }
+        Err(current)
+    }
+}
+impl<T: NoPadding+Copy+Eq> AtomicCell<T> {
+    ///Store `new` if the cell holds `current`
+    ///
+    ///Returns Ok with the previous value on success, Err with the value
+    ///found otherwise
+    pub fn compare_exchange(&self, current: T, new: T) -> Result<T,T> {
+        dispatch!(self,
+            |a| unsafe {
+                let mut expected = to_bits(&current);
+                loop {
+                    match a.compare_exchange(expected, to_bits(&new), ACQREL, ACQUIRE) {
+                        Ok(x) => return Ok(from_bits(x)),
+                        Err(x) => {
+                            let found: T = from_bits(x);
+                            if found != current {
+                                return Err(found);
+                            }
+                            //equal value, different bits
+                            expected = x;
+                        }
+                    };
+                }
+            },
+            self.locked(|p| unsafe {
+                if *p == current {
+                    Ok(ptr::replace(p, new))
+                } else {
+                    Err(*p)
+                }
+            }))
+    }
+}
+impl<T: NoPadding+Default> Default for AtomicCell<T> {
+    fn default() -> AtomicCell<T> {
+        AtomicCell::new(T::default())
+    }
+}
+
+#[test]
+fn test_atomic_cell_native() {
+    assert!(AtomicCell::<[u32;2]>::is_lock_free());
+    let pair = AtomicCell::new([1u32,2]);
+    assert_eq!(pair.swap([3,4]), [1,2]);
+    assert_eq!(pair.compare_exchange([3,4], [5,6]), Ok([3,4]));
+    assert_eq!(pair.compare_exchange([3,4], [0,0]), Err([5,6]));
+    assert_eq!(pair.fetch_update(|[a,b]| Some([a + 1, b])), Ok([5,6]));
+    assert_eq!(pair.load(), [6,6]);
+    let state = AtomicCell::new('a');
+    state.store('b');
+    assert_eq!(state.load(), 'b');
+    assert_eq!(state.fetch_update(|_| None), Err('b'));
+}
+
+#[test]
+fn test_atomic_cell_locked() {
+    use std::sync::Arc;
+    use std::thread;
+    assert!(!AtomicCell::<[u64;4]>::is_lock_free());
+    let cell = Arc::new(AtomicCell::new([0u64;4]));
+    let workers = (0..4).map(|_| {
+        let cell = cell.clone();
+        thread::spawn(move || {
+            for _ in 0..250 {
+                cell.fetch_update(|x| Some([x[0] + 1, x[1] + 1, x[2] + 1, x[3] + 1])).unwrap();
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(cell.load(), [1000;4]);
+    assert_eq!(cell.take(), [1000;4]);
+    assert_eq!(Arc::try_unwrap(cell).ok().unwrap().into_inner(), [0;4]);
+}
@@ -0,0 +1,181 @@
+//!Atomic floating point cells.
+//!
+//!The value is stored as its bit pattern in an integer atomic. Loads,
+//!stores and swaps are plain integer operations. Arithmetic has no
+//!hardware support, so `fetch_add` and friends load, compute and retry a
+//!compare and swap until no other thread got in between.
+//!
+//!`compare_exchange` compares bit patterns, not float equality: `0.0` and
+//!`-0.0` differ, and a NaN matches the identical NaN.
+//!
+//!`AtomicF64` only exists on targets with 64 bit atomics.
+
+
+use core::fmt;
+use core::sync::atomic::{AtomicU32,Ordering};
+#[cfg(target_has_atomic="64")]
+use core::sync::atomic::AtomicU64;
+
+macro_rules! atomic_float {
+    ($name: ident, $float: ty, $atomic: ty) => {
+        ///Float that can be shared between threads
+        #[repr(transparent)]
+        pub struct $name {
+            bits: $atomic
+        }
+        impl $name {
+            ///Build a cell holding `value`
+            #[inline(always)]
+            pub const fn new(value: $float) -> $name {
+                $name {
+                    bits: <$atomic>::new(value.to_bits())
+                }
+            }
+            ///Read the value
+            #[inline(always)]
+            pub fn load(&self, order: Ordering) -> $float {
+                <$float>::from_bits(self.bits.load(order))
+            }
+            ///Replace the value
+            #[inline(always)]
+            pub fn store(&self, value: $float, order: Ordering) {
+                self.bits.store(value.to_bits(), order);
+            }
+            ///Replace the value, returning the old one
+            #[inline(always)]
+            pub fn swap(&self, value: $float, order: Ordering) -> $float {
+                <$float>::from_bits(self.bits.swap(value.to_bits(), order))
+            }
+            ///Store `new` if the value has the same bits as `current`
+            ///
+            ///Returns Ok with the previous value on success, Err with the
+            ///value found otherwise
+            #[inline(always)]
+            pub fn compare_exchange(&self, current: $float, new: $float, success: Ordering, failure: Ordering) -> Result<$float,$float> {
+                self.bits.compare_exchange(current.to_bits(), new.to_bits(), success, failure)
+                    .map(<$float>::from_bits)
+                    .map_err(<$float>::from_bits)
+            }
+            ///Like `compare_exchange` but may fail spuriously, for loops
+            #[inline(always)]
+            pub fn compare_exchange_weak(&self, current: $float, new: $float, success: Ordering, failure: Ordering) -> Result<$float,$float> {
+                self.bits.compare_exchange_weak(current.to_bits(), new.to_bits(), success, failure)
+                    .map(<$float>::from_bits)
+                    .map_err(<$float>::from_bits)
+            }
+            ///Apply `f` until it is stored without interference, or `f`
+            ///returns None
+            ///
+            ///Returns Ok with the previous value if `f` was applied, Err
+            ///with the current value if `f` returned None
+            pub fn fetch_update<F>(&self, set: Ordering, fetch: Ordering, mut f: F) -> Result<$float,$float>
+            where
+                F: FnMut($float) -> Option<$float>,
+            {
+                self.bits.fetch_update(set, fetch, |bits| {
+                    f(<$float>::from_bits(bits)).map(<$float>::to_bits)
+                })
+                .map(<$float>::from_bits)
+                .map_err(<$float>::from_bits)
+            }
+            #[inline(always)]
+            fn apply<F: Fn($float) -> $float>(&self, order: Ordering, f: F) -> $float {
+                //loads of the current value need no more than Relaxed, the
+                //successful exchange carries `order`
+                let mut current = self.bits.load(Ordering::Relaxed);
+                loop {
+                    let next = f(<$float>::from_bits(current)).to_bits();
+                    match self.bits.compare_exchange_weak(current, next, order, Ordering::Relaxed) {
+                        Ok(prev) => return <$float>::from_bits(prev),
+                        Err(x) => current = x
+                    };
+                }
+            }
+            ///Add `value`, returning the previous value
+            #[inline(always)]
+            pub fn fetch_add(&self, value: $float, order: Ordering) -> $float {
+                self.apply(order, |x| x + value)
+            }
+            ///Subtract `value`, returning the previous value
+            #[inline(always)]
+            pub fn fetch_sub(&self, value: $float, order: Ordering) -> $float {
+                self.apply(order, |x| x - value)
+            }
+            ///Keep the larger of the value and `value`, returning the
+            ///previous value. NaN is ignored like `max` does.
+            #[inline(always)]
+            pub fn fetch_max(&self, value: $float, order: Ordering) -> $float {
+                self.apply(order, |x| x.max(value))
+            }
+            ///Keep the smaller of the value and `value`, returning the
+            ///previous value. NaN is ignored like `min` does.
+            #[inline(always)]
+            pub fn fetch_min(&self, value: $float, order: Ordering) -> $float {
+                self.apply(order, |x| x.min(value))
+            }
+            ///Consume the cell, returning the value
+            #[inline(always)]
+            pub fn into_inner(self) -> $float {
+                <$float>::from_bits(self.bits.into_inner())
+            }
+        }
+        impl Default for $name {
+            fn default() -> $name {
+                $name::new(0.0)
+            }
+        }
+        impl From<$float> for $name {
+            fn from(value: $float) -> $name {
+                $name::new(value)
+            }
+        }
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Debug::fmt(&self.load(Ordering::Relaxed), f)
+            }
+        }
+    }
+}
+
+atomic_float!(AtomicF32, f32, AtomicU32);
+#[cfg(target_has_atomic="64")]
+atomic_float!(AtomicF64, f64, AtomicU64);
+
+#[cfg(target_has_atomic="64")]
+#[test]
+fn test_atomic_float_ops() {
+    const RELAXED: Ordering = Ordering::Relaxed;
+    let x = AtomicF64::new(1.5);
+    assert_eq!(x.fetch_add(2.0, RELAXED), 1.5);
+    assert_eq!(x.fetch_sub(0.5, RELAXED), 3.5);
+    assert_eq!(x.fetch_max(10.0, RELAXED), 3.0);
+    assert_eq!(x.fetch_min(-1.0, RELAXED), 10.0);
+    assert_eq!(x.swap(4.0, RELAXED), -1.0);
+    assert_eq!(x.compare_exchange(4.0, 5.0, RELAXED, RELAXED), Ok(4.0));
+    assert_eq!(x.compare_exchange(4.0, 6.0, RELAXED, RELAXED), Err(5.0));
+    //bit comparison tells the zeros apart
+    let z = AtomicF32::new(0.0);
+    assert!(z.compare_exchange(-0.0, 1.0, RELAXED, RELAXED).is_err());
+    assert_eq!(z.fetch_update(RELAXED, RELAXED, |v| Some(v + 2.0)), Ok(0.0));
+    assert_eq!(z.into_inner(), 2.0);
+}
+
+#[cfg(all(feature="std",target_has_atomic="64"))]
+#[test]
+fn test_atomic_float_threads() {
+    use std::sync::Arc;
+    use std::thread;
+    let total = Arc::new(AtomicF64::default());
+    let workers = (0..4).map(|_| {
+        let total = total.clone();
+        thread::spawn(move || {
+            for _ in 0..250 {
+                total.fetch_add(0.5, Ordering::Relaxed);
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(total.load(Ordering::Relaxed), 500.0);
+}
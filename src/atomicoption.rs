@@ -0,0 +1,175 @@
+//!Single slot handoff of boxed values.
+//!
+//!AtomicOption holds at most one `Box<T>` as a raw pointer in an
+//!AtomicPtr, null meaning empty. A value is only ever moved in or out of
+//!the slot whole, never borrowed while shared, so there is no reclamation
+//!problem: whoever takes the pointer out owns the box. A value displaced
+//!by `store` is dropped by the storing thread, and whatever is left in the
+//!slot is dropped with it.
+//!
+//!A producer that `swap`s in every new result while a consumer `take`s
+//!gives "latest result wins" without a channel.
+
+
+use std::ptr;
+use std::fmt;
+use std::sync::atomic::{AtomicPtr,Ordering};
+const ACQUIRE: Ordering = Ordering::Acquire;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+#[inline(always)]
+fn into_raw<T>(value: Option<Box<T>>) -> *mut T {
+    match value {
+        Option::Some(b) => Box::into_raw(b),
+        Option::None => ptr::null_mut()
+    }
+}
+#[inline(always)]
+unsafe fn from_raw<T>(ptr: *mut T) -> Option<Box<T>> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(Box::from_raw(ptr))
+    }
+}
+
+///Slot holding an optional `Box<T>` that can be replaced atomically
+pub struct AtomicOption<T> {
+    ptr: AtomicPtr<T>
+}
+unsafe impl<T: Send> Send for AtomicOption<T> { }
+unsafe impl<T: Send> Sync for AtomicOption<T> { }
+impl<T> AtomicOption<T> {
+    ///Build an empty slot
+    #[inline(always)]
+    pub const fn empty() -> AtomicOption<T> {
+        AtomicOption {
+            ptr: AtomicPtr::new(ptr::null_mut())
+        }
+    }
+    ///Build a slot holding `value`
+    #[inline(always)]
+    pub fn new(value: Option<Box<T>>) -> AtomicOption<T> {
+        AtomicOption {
+            ptr: AtomicPtr::new(into_raw(value))
+        }
+    }
+    ///Put `value` in the slot, returning what was there
+    #[inline(always)]
+    pub fn swap(&self, value: Option<Box<T>>) -> Option<Box<T>> {
+        unsafe{ from_raw(self.ptr.swap(into_raw(value), ACQREL)) }
+    }
+    ///Empty the slot, returning what was there
+    #[inline(always)]
+    pub fn take(&self) -> Option<Box<T>> {
+        //avoid dirtying the cache line when there is nothing to take
+        if self.ptr.load(ACQUIRE).is_null() {
+            return None;
+        }
+        self.swap(None)
+    }
+    ///Put `value` in the slot, dropping what was there
+    #[inline(always)]
+    pub fn store(&self, value: Option<Box<T>>) {
+        drop(self.swap(value));
+    }
+    ///Put `value` in the slot only if it is empty
+    ///
+    ///Returns `value` back if the slot was full
+    #[inline(always)]
+    pub fn store_if_none(&self, value: Box<T>) -> Result<(),Box<T>> {
+        let raw = Box::into_raw(value);
+        match self.ptr.compare_exchange(ptr::null_mut(), raw, ACQREL, ACQUIRE) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(unsafe{ Box::from_raw(raw) })
+        }
+    }
+    ///Returns true if the slot is empty at this instant
+    #[inline(always)]
+    pub fn is_none(&self) -> bool {
+        self.ptr.load(ACQUIRE).is_null()
+    }
+    ///Borrow the value, exclusive access means nothing can take it
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        unsafe{ self.ptr.get_mut().as_mut() }
+    }
+    ///Consume the slot, returning its value
+    #[inline(always)]
+    pub fn into_inner(mut self) -> Option<Box<T>> {
+        let ptr = *self.ptr.get_mut();
+        *self.ptr.get_mut() = ptr::null_mut();
+        unsafe{ from_raw(ptr) }
+    }
+}
+impl<T> Drop for AtomicOption<T> {
+    fn drop(&mut self) {
+        drop(unsafe{ from_raw(*self.ptr.get_mut()) });
+    }
+}
+impl<T> Default for AtomicOption<T> {
+    fn default() -> AtomicOption<T> {
+        AtomicOption::empty()
+    }
+}
+impl<T> From<Box<T>> for AtomicOption<T> {
+    fn from(value: Box<T>) -> AtomicOption<T> {
+        AtomicOption::new(Some(value))
+    }
+}
+impl<T> fmt::Debug for AtomicOption<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AtomicOption").field("is_none", &self.is_none()).finish()
+    }
+}
+
+#[test]
+fn test_atomic_option_drops() {
+    use std::sync::Arc;
+    let tracker = Arc::new(());
+    let slot = AtomicOption::empty();
+    assert!(slot.take().is_none());
+    assert!(slot.store_if_none(Box::new(tracker.clone())).is_ok());
+    let back = slot.store_if_none(Box::new(tracker.clone())).unwrap_err();
+    drop(back);
+    assert_eq!(Arc::strong_count(&tracker), 2);
+    //displaced value is dropped by store
+    slot.store(Some(Box::new(tracker.clone())));
+    assert_eq!(Arc::strong_count(&tracker), 2);
+    let old = slot.swap(None).unwrap();
+    assert!(slot.is_none());
+    drop(old);
+    slot.store(Some(Box::new(tracker.clone())));
+    drop(slot);
+    assert_eq!(Arc::strong_count(&tracker), 1);
+    let mut slot = AtomicOption::from(Box::new(5));
+    *slot.get_mut().unwrap() += 1;
+    assert_eq!(slot.into_inner(), Some(Box::new(6)));
+}
+
+#[test]
+fn test_atomic_option_latest_wins() {
+    use std::sync::Arc;
+    use std::thread;
+    let slot = Arc::new(AtomicOption::empty());
+    let producer = {
+        let slot = slot.clone();
+        thread::spawn(move || {
+            for i in 0..1000usize {
+                slot.store(Some(Box::new(i)));
+            }
+        })
+    };
+    let mut last = 0;
+    while !producer.is_finished() {
+        if let Option::Some(x) = slot.take() {
+            assert!(*x >= last);
+            last = *x;
+        }
+    }
+    producer.join().unwrap();
+    if let Option::Some(x) = slot.take() {
+        last = *x;
+    }
+    assert_eq!(last, 999);
+}
@@ -0,0 +1,178 @@
+//!Exponential backoff for polling loops.
+//!
+//!Waiting escalates in three tiers. First it busy waits with
+//!`hint::spin_loop`, doubling each round, which is cheapest when the other
+//!side is about to finish. Then it yields the thread so whoever it waits on
+//!gets to run. Past that it sleeps, doubling up to `MAX_SLEEP`, so a long
+//!wait stops burning a core. Every polling loop in the crate goes through
+//!here.
+//!
+//!Without the `std` feature there is no thread to yield or sleep, the
+//!later tiers keep spinning at the longest busy wait instead. Under loom
+//!every round is a single yield to the model's scheduler.
+//!
+//!On a single threaded target, wasm32 without the `atomics` target feature
+//!or any build with `--cfg single_threaded`, nothing else can run while
+//!the caller waits, so a wait that has to back off would spin forever.
+//!There the first round of waiting panics instead, and timed waits give up
+//!at once.
+
+
+use super::primitive;
+use core::cell::Cell;
+#[cfg(all(feature="std",not(loom)))]
+use std::thread;
+use core::time::Duration;
+
+///Rounds that busy wait, the last spins `2^SPIN_LIMIT` times
+pub const SPIN_LIMIT: u32 = 6;
+///Rounds after which waiting sleeps instead of yielding
+pub const YIELD_LIMIT: u32 = 10;
+///First sleep once yielding is done
+const MIN_SLEEP: Duration = Duration::from_micros(10);
+///Longest single sleep
+pub const MAX_SLEEP: Duration = Duration::from_millis(1);
+
+///True on targets where only one thread can ever run
+pub const SINGLE_THREADED: bool = cfg!(any(single_threaded, all(target_arch="wasm32", not(target_feature="atomics"))));
+
+///Called where a single threaded target would wait forever
+#[cold]
+#[inline(never)]
+pub(crate) fn would_block_forever() -> ! {
+    panic!("waiting on another thread, but this target only has one")
+}
+
+///One round of waiting between failed polls, returns how many spins it
+///took
+#[inline(always)]
+pub(crate) fn backoff(step: &mut u32) -> u64 {
+    if SINGLE_THREADED {
+        would_block_forever();
+    }
+    if cfg!(loom) {
+        primitive::spin_loop();
+        *step += 1;
+        1
+    } else if *step <= SPIN_LIMIT {
+        let spins = 1 << *step;
+        for _ in 0..spins {
+            primitive::spin_loop();
+        }
+        *step += 1;
+        spins
+    } else if *step <= YIELD_LIMIT {
+        yield_now();
+        *step += 1;
+        1
+    } else {
+        let shift = (*step - YIELD_LIMIT - 1).min(16);
+        sleep((MIN_SLEEP * (1 << shift)).min(MAX_SLEEP));
+        *step += 1;
+        1
+    }
+}
+
+#[cfg(all(feature="std",not(loom)))]
+#[inline(always)]
+fn yield_now() {
+    thread::yield_now();
+}
+#[cfg(all(feature="std",not(loom)))]
+#[inline(always)]
+fn sleep(time: Duration) {
+    thread::sleep(time);
+}
+#[cfg(any(not(feature="std"),loom))]
+#[inline(always)]
+fn yield_now() {
+    for _ in 0..1u32 << SPIN_LIMIT {
+        primitive::spin_loop();
+    }
+}
+#[cfg(any(not(feature="std"),loom))]
+#[inline(always)]
+fn sleep(_: Duration) {
+    yield_now();
+}
+
+///Escalating wait between polls of a condition
+///
+///Build one per wait, it counts rounds on its own.
+#[derive(Debug,Default)]
+pub struct Backoff {
+    step: Cell<u32>
+}
+impl Backoff {
+    ///Start a new wait
+    #[inline(always)]
+    pub const fn new() -> Backoff {
+        Backoff {
+            step: Cell::new(0)
+        }
+    }
+    ///Start over, after some progress was made
+    #[inline(always)]
+    pub fn reset(&self) {
+        self.step.set(0);
+    }
+    ///Busy wait, for loops that retry a lost compare and swap
+    ///
+    ///Never yields, the spin count stops doubling at `2^SPIN_LIMIT`.
+    #[inline(always)]
+    pub fn spin(&self) {
+        if SINGLE_THREADED {
+            would_block_forever();
+        }
+        let step = self.step.get().min(SPIN_LIMIT);
+        for _ in 0..1u32 << step {
+            primitive::spin_loop();
+        }
+        if self.step.get() <= SPIN_LIMIT {
+            self.step.set(self.step.get() + 1);
+        }
+    }
+    ///Wait for another thread to make progress, spinning, then yielding,
+    ///then sleeping
+    #[inline(always)]
+    pub fn snooze(&self) {
+        let mut step = self.step.get();
+        backoff(&mut step);
+        self.step.set(step);
+    }
+    ///Returns true once the wait has reached the sleeping tier, a caller
+    ///that can block on something better should do so now
+    #[inline(always)]
+    pub fn is_completed(&self) -> bool {
+        self.step.get() > YIELD_LIMIT
+    }
+}
+
+#[cfg(all(feature="std",not(single_threaded)))]
+#[test]
+fn test_backoff_tiers() {
+    let backoff = Backoff::new();
+    for _ in 0..20 {
+        backoff.spin();
+    }
+    //spinning alone never escalates past the busy wait
+    assert!(!backoff.is_completed());
+    while !backoff.is_completed() {
+        backoff.snooze();
+    }
+    assert_eq!(backoff.step.get(), YIELD_LIMIT + 1);
+    let start = ::std::time::Instant::now();
+    for _ in 0..4 {
+        backoff.snooze();
+    }
+    assert!(start.elapsed() >= MIN_SLEEP * 15);
+    backoff.reset();
+    assert!(!backoff.is_completed());
+}
+
+#[cfg(single_threaded)]
+#[test]
+#[should_panic(expected="only has one")]
+fn single_thread_backoff() {
+    Backoff::new().snooze();
+}
@@ -0,0 +1,91 @@
+//!Reusable spin barrier.
+//!
+//!Arrivals count up in `count`. The last thread to arrive resets the count
+//!and bumps `generation`, which is what everybody else is spinning on, so
+//!the barrier is ready for the next phase as soon as it opens.
+
+
+use super::backoff::backoff;
+use std::sync::atomic::{AtomicUsize,Ordering};
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Blocks groups of `n` threads until all of them arrive
+pub struct SpinBarrier {
+    n: usize,
+    count: AtomicUsize,
+    generation: AtomicUsize
+}
+impl SpinBarrier {
+    ///Build a barrier for `n` threads. A barrier for 0 threads behaves like
+    ///one for 1, every call to `wait` returns right away.
+    #[inline(always)]
+    pub const fn new(n: usize) -> SpinBarrier {
+        SpinBarrier {
+            n,
+            count: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0)
+        }
+    }
+    ///Spin until `n` threads have called `wait` in this phase
+    ///
+    ///Returns true for exactly one thread per phase, the last to arrive.
+    #[inline(always)]
+    pub fn wait(&self) -> bool {
+        let generation = self.generation.load(SEQ);
+        if self.count.fetch_add(1, SEQ) + 1 >= self.n {
+            self.count.store(0, SEQ);
+            self.generation.fetch_add(1, SEQ);
+            return true;
+        }
+        let mut step = 0;
+        while self.generation.load(SEQ) == generation {
+            backoff(&mut step);
+        }
+        false
+    }
+    ///Number of phases the barrier has completed
+    #[inline(always)]
+    pub fn generation(&self) -> usize {
+        self.generation.load(SEQ)
+    }
+    ///Number of threads the barrier waits for
+    #[inline(always)]
+    pub fn parties(&self) -> usize {
+        self.n
+    }
+}
+
+#[test]
+fn test_barrier_phases() {
+    use std::sync::Arc;
+    use std::thread;
+    let barrier = Arc::new(SpinBarrier::new(3));
+    let progress = Arc::new(AtomicUsize::new(0));
+    let workers = (0..3).map(|_| {
+        let barrier = barrier.clone();
+        let progress = progress.clone();
+        thread::spawn(move || {
+            let mut leaders = 0;
+            for phase in 0..5 {
+                progress.fetch_add(1, SEQ);
+                if barrier.wait() {
+                    leaders += 1;
+                }
+                //everybody finished the phase before anybody starts the next
+                assert!(progress.load(SEQ) >= (phase+1)*3);
+                barrier.wait();
+            }
+            leaders
+        })
+    }).collect::<Vec<_>>();
+    let leaders: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+    assert_eq!(leaders, 5);
+    assert_eq!(barrier.generation(), 10);
+}
+
+#[test]
+fn test_barrier_static() {
+    static BARRIER: SpinBarrier = SpinBarrier::new(1);
+    assert!(BARRIER.wait());
+    assert_eq!(BARRIER.generation(), 1);
+}
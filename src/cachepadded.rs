@@ -0,0 +1,70 @@
+//!Cache line padding.
+//!
+//!Two values that different threads write often should not sit on the
+//!same cache line, or every write by one thread evicts the line from the
+//!other's cache even though they never touch each other's value. Wrapping
+//!each in a CachePadded gives it a line of its own. 128 bytes covers the
+//!adjacent line prefetcher of modern x86 and the 128 byte lines of some
+//!ARM cores.
+
+
+use core::fmt;
+use core::ops::{Deref,DerefMut};
+
+///Aligns and pads `T` to 128 bytes so it shares no cache line
+#[repr(align(128))]
+#[derive(Clone,Copy,Default,PartialEq,Eq,Hash)]
+pub struct CachePadded<T> {
+    value: T
+}
+impl<T> CachePadded<T> {
+    ///Pad `value`
+    #[inline(always)]
+    pub const fn new(value: T) -> CachePadded<T> {
+        CachePadded { value }
+    }
+    ///Remove the padding
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+impl<T> DerefMut for CachePadded<T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+impl<T> From<T> for CachePadded<T> {
+    fn from(value: T) -> CachePadded<T> {
+        CachePadded::new(value)
+    }
+}
+impl<T: fmt::Debug> fmt::Debug for CachePadded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CachePadded").field("value", &self.value).finish()
+    }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_cache_padded() {
+    use std::mem;
+    use std::sync::atomic::{AtomicUsize,Ordering};
+    assert_eq!(mem::align_of::<CachePadded<u8>>(), 128);
+    assert_eq!(mem::size_of::<[CachePadded<AtomicUsize>; 2]>(), 256);
+    let pair = [CachePadded::new(AtomicUsize::new(1)), CachePadded::new(AtomicUsize::new(2))];
+    pair[0].fetch_add(1, Ordering::Relaxed);
+    let a = &*pair[0] as *const AtomicUsize as usize;
+    let b = &*pair[1] as *const AtomicUsize as usize;
+    assert!(b - a >= 128);
+    assert_eq!(pair[0].load(Ordering::Relaxed), 2);
+    assert_eq!(CachePadded::new(5).into_inner(), 5);
+}
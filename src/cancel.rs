@@ -0,0 +1,154 @@
+//!Cooperative cancellation.
+//!
+//!A CancellationToken is a flag that can only go from live to cancelled.
+//!Clones share the flag. `child` builds a token that is cancelled along
+//!with its parent but can also be cancelled on its own without touching
+//!the parent, so a shutdown can be scoped to one part of a program.
+//!
+//!The flag is a `CountDownLatch` of one, so threads can block on it. Long
+//!waits elsewhere in the crate take a token and return `Cancelled` once it
+//!fires: `MRMSReceiver::recv_cancellable`, `SpmcSender::send_cancellable`,
+//!`SpmcReceiver::recv_cancellable` and `SpinLock::lock_cancellable`.
+
+
+use super::latch::CountDownLatch;
+use super::spinlock::SpinLock;
+use std::fmt;
+use std::sync::{Arc,Weak};
+use std::time::Duration;
+
+///Outcome of a wait that was cut short by a CancellationToken
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash)]
+pub struct Cancelled;
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("operation cancelled")
+    }
+}
+
+struct Node {
+    latch: CountDownLatch,
+    children: SpinLock<Vec<Weak<Node>>>
+}
+impl Node {
+    fn new() -> Arc<Node> {
+        Arc::new(Node {
+            latch: CountDownLatch::new(1),
+            children: SpinLock::new(Vec::new())
+        })
+    }
+    fn cancel(&self) {
+        if self.latch.is_open() {
+            return;
+        }
+        self.latch.count_down();
+        //taken after opening the latch, a child registering meanwhile sees
+        //the latch open and cancels itself
+        let children = ::std::mem::take(&mut *self.children.lock());
+        for child in children {
+            if let Option::Some(child) = child.upgrade() {
+                child.cancel();
+            }
+        }
+    }
+}
+
+///Shared flag for asking work to stop
+#[derive(Clone)]
+pub struct CancellationToken {
+    node: Arc<Node>
+}
+impl CancellationToken {
+    ///Build a live token
+    pub fn new() -> CancellationToken {
+        CancellationToken {
+            node: Node::new()
+        }
+    }
+    ///Build a token that is cancelled when this one is
+    ///
+    ///A child of a cancelled token starts cancelled.
+    pub fn child(&self) -> CancellationToken {
+        let node = Node::new();
+        {
+            let mut children = self.node.children.lock();
+            if self.node.latch.is_open() {
+                node.latch.count_down();
+            } else {
+                children.retain(|c| c.strong_count() > 0);
+                children.push(Arc::downgrade(&node));
+            }
+        }
+        CancellationToken { node }
+    }
+    ///Cancel this token and every child, waking whoever waits on them
+    pub fn cancel(&self) {
+        self.node.cancel();
+    }
+    ///Returns true once the token has been cancelled
+    #[inline(always)]
+    pub fn is_cancelled(&self) -> bool {
+        self.node.latch.is_open()
+    }
+    ///Returns Err(Cancelled) once the token has been cancelled, for `?`
+    #[inline(always)]
+    pub fn check(&self) -> Result<(),Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+    ///Block until the token is cancelled
+    pub fn wait(&self) {
+        self.node.latch.wait();
+    }
+    ///Block until the token is cancelled or `timeout` passes
+    ///
+    ///Returns true if the token was cancelled in time
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.node.latch.wait_timeout(timeout)
+    }
+}
+impl Default for CancellationToken {
+    fn default() -> CancellationToken {
+        CancellationToken::new()
+    }
+}
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CancellationToken").field("cancelled", &self.is_cancelled()).finish()
+    }
+}
+
+#[test]
+fn test_cancel_tree() {
+    let root = CancellationToken::new();
+    let a = root.child();
+    let a1 = a.child();
+    let b = root.child();
+    a.cancel();
+    assert!(a.is_cancelled() && a1.is_cancelled());
+    assert!(!root.is_cancelled() && !b.is_cancelled());
+    assert!(root.check().is_ok());
+    //dropped children are pruned, only a, b and the last one remain
+    for _ in 0..10 {
+        drop(root.child());
+    }
+    assert_eq!(root.node.children.lock().len(), 3);
+    root.clone().cancel();
+    assert!(b.is_cancelled());
+    assert_eq!(root.check(), Err(Cancelled));
+    assert!(root.child().is_cancelled());
+}
+
+#[test]
+fn test_cancel_wait() {
+    use std::thread;
+    let token = CancellationToken::new();
+    assert!(!token.wait_timeout(Duration::from_millis(1)));
+    let child = token.child();
+    let waiter = thread::spawn(move || child.wait());
+    token.cancel();
+    waiter.join().unwrap();
+}
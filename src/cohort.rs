@@ -0,0 +1,211 @@
+//!Cohort lock for machines with several NUMA nodes.
+//!
+//!Each node has its own ticket lock, and one global ticket lock is shared
+//!by every node. A thread first takes its node's lock, then the global
+//!lock. When it releases and another thread on the same node is already
+//!queued, the global lock is handed to that thread directly instead of being
+//!released, so the data stays in that node's caches. After `max_handoffs`
+//!local handoffs the global lock is released anyway so other nodes get a
+//!turn.
+//!
+//!There is no portable way to ask which node a thread runs on. Threads are
+//!spread over nodes by an id until they call `set_current_node`, which
+//!is best done right after pinning the thread.
+
+
+use super::backoff::backoff;
+use std::cell::{Cell,UnsafeCell};
+use std::ops::{Deref,DerefMut};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const RELAXED: Ordering = Ordering::Relaxed;
+
+///Local handoffs made before the global lock is released when built with
+///`new`
+pub const DEFAULT_HANDOFFS: usize = 64;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+thread_local!(static NODE: Cell<Option<usize>> = const { Cell::new(None) });
+thread_local!(static THREAD: usize = NEXT_THREAD.fetch_add(1,RELAXED));
+
+///Record which node the calling thread runs on
+pub fn set_current_node(node: usize) {
+    NODE.with(|n| n.set(Some(node)));
+}
+///Node the calling thread is assumed to run on, out of `nodes`
+pub fn current_node(nodes: usize) -> usize {
+    let node = NODE.with(|n| n.get()).unwrap_or_else(|| THREAD.with(|t| *t));
+    node % nodes.max(1)
+}
+
+struct Ticket {
+    next: AtomicUsize,
+    serving: AtomicUsize
+}
+impl Ticket {
+    fn new() -> Ticket {
+        Ticket {
+            next: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0)
+        }
+    }
+    #[inline(always)]
+    fn lock(&self) {
+        let ticket = self.next.fetch_add(1,RELAXED);
+        let mut step = 0;
+        while self.serving.load(ACQUIRE) != ticket {
+            backoff(&mut step);
+        }
+    }
+    #[inline(always)]
+    fn release(&self) {
+        self.serving.fetch_add(1,RELEASE);
+    }
+    ///Returns true if anybody besides the holder is queued
+    #[inline(always)]
+    fn has_waiters(&self) -> bool {
+        self.next.load(RELAXED).wrapping_sub(self.serving.load(RELAXED)) > 1
+    }
+}
+
+///Per node state, padded so nodes never share a cache line
+#[repr(align(128))]
+struct Node {
+    local: Ticket,
+    ///true while the global lock was passed to this node's next holder
+    owns_global: AtomicBool,
+    handoffs: AtomicUsize
+}
+
+///Lock preferring to hand over to waiters on the same node
+pub struct CohortLock<T: ?Sized> {
+    global: Ticket,
+    max_handoffs: usize,
+    nodes: Box<[Node]>,
+    data: UnsafeCell<T>
+}
+unsafe impl<T: ?Sized+Send> Send for CohortLock<T> { }
+unsafe impl<T: ?Sized+Send> Sync for CohortLock<T> { }
+impl<T> CohortLock<T> {
+    ///Build a new unlocked CohortLock for `nodes` nodes
+    #[inline(always)]
+    pub fn new(data: T, nodes: usize) -> CohortLock<T> {
+        CohortLock::with_handoffs(data, nodes, DEFAULT_HANDOFFS)
+    }
+    ///Build a new unlocked CohortLock that keeps the lock on one node for
+    ///at most `max_handoffs` consecutive handoffs
+    pub fn with_handoffs(data: T, nodes: usize, max_handoffs: usize) -> CohortLock<T> {
+        CohortLock {
+            global: Ticket::new(),
+            max_handoffs,
+            nodes: (0..nodes.max(1)).map(|_| Node {
+                local: Ticket::new(),
+                owns_global: AtomicBool::new(false),
+                handoffs: AtomicUsize::new(0)
+            }).collect(),
+            data: UnsafeCell::new(data)
+        }
+    }
+    ///Consume the lock, returning the data
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+impl<T: ?Sized> CohortLock<T> {
+    ///Take the lock from the calling thread's node
+    #[inline(always)]
+    pub fn lock<'a>(&'a self) -> CohortGuard<'a,T> {
+        self.lock_on(current_node(self.nodes.len()))
+    }
+    ///Take the lock as a member of `node`
+    pub fn lock_on<'a>(&'a self, node: usize) -> CohortGuard<'a,T> {
+        let node = node % self.nodes.len();
+        let local = &self.nodes[node];
+        local.local.lock();
+        if !local.owns_global.load(RELAXED) {
+            self.global.lock();
+        }
+        CohortGuard { lock: self, node }
+    }
+    ///Number of nodes the lock was built for
+    #[inline(always)]
+    pub fn nodes(&self) -> usize {
+        self.nodes.len()
+    }
+    ///Mutable access without locking
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        self.data.get_mut()
+    }
+    fn release(&self, node: usize) {
+        let local = &self.nodes[node];
+        //owns_global and handoffs are only touched by the local holder
+        let handoffs = local.handoffs.load(RELAXED);
+        if local.local.has_waiters() && handoffs < self.max_handoffs {
+            local.handoffs.store(handoffs + 1, RELAXED);
+            local.owns_global.store(true, RELAXED);
+        } else {
+            local.handoffs.store(0, RELAXED);
+            local.owns_global.store(false, RELAXED);
+            self.global.release();
+        }
+        local.local.release();
+    }
+}
+
+///Access to the data of a CohortLock
+pub struct CohortGuard<'a,T: ?Sized+'a> {
+    lock: &'a CohortLock<T>,
+    node: usize
+}
+//sharing the guard shares `&T`, the auto impl would only ask for T: Send
+unsafe impl<'a,T: ?Sized+Sync> Sync for CohortGuard<'a,T> { }
+impl<'a,T: ?Sized> CohortGuard<'a,T> {
+    ///Node the lock was taken from
+    #[inline(always)]
+    pub fn node(&self) -> usize {
+        self.node
+    }
+}
+impl<'a,T: ?Sized> Deref for CohortGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> DerefMut for CohortGuard<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> Drop for CohortGuard<'a,T> {
+    fn drop(&mut self) {
+        self.lock.release(self.node);
+    }
+}
+
+#[test]
+fn test_cohort_lock() {
+    use std::sync::Arc;
+    use std::thread;
+    let lock = Arc::new(CohortLock::with_handoffs(0usize, 2, 4));
+    let workers = (0..4).map(|i| {
+        let lock = lock.clone();
+        thread::spawn(move || {
+            set_current_node(i % 2);
+            for _ in 0..250 {
+                let mut guard = lock.lock();
+                assert_eq!(guard.node(), i % 2);
+                *guard += 1;
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(*lock.lock_on(1), 1000);
+    assert_eq!(lock.nodes(), 2);
+}
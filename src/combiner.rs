@@ -0,0 +1,194 @@
+//!Flat combining.
+//!
+//!Instead of every thread taking the lock in turn, a thread publishes its
+//!operation in a slot and tries the lock once. Whoever gets it becomes the
+//!combiner and runs every published operation in one pass while the data
+//!is hot in its cache, the others only wait for their slot to be marked
+//!done. Threads pick a slot by a per thread id and fall back to taking the
+//!lock themselves when every slot is busy.
+//!
+//!A panicking operation is caught by the combiner and raised again on the
+//!thread that published it.
+
+
+use super::backoff::backoff;
+use std::any::Any;
+use std::cell::UnsafeCell;
+use std::mem;
+use std::panic::{self,AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+
+///Slots a combiner built with `new` has
+pub const DEFAULT_SLOTS: usize = 32;
+
+//slot states
+const FREE: usize = 0;
+const CLAIMED: usize = 1;
+const PUBLISHED: usize = 2;
+const DONE: usize = 3;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+thread_local!(static THREAD: usize = NEXT_THREAD.fetch_add(1, RELAXED));
+
+type Op<T> = *mut (dyn FnMut(&mut T) + 'static);
+
+///Publication record, padded so slots never share a cache line
+#[repr(align(128))]
+struct Slot<T> {
+    state: AtomicUsize,
+    op: UnsafeCell<Option<Op<T>>>,
+    panic: UnsafeCell<Option<Box<dyn Any + Send + 'static>>>
+}
+
+///Data whose operations are batched by whichever thread holds the lock
+pub struct FlatCombiner<T> {
+    lock: AtomicBool,
+    slots: Box<[Slot<T>]>,
+    data: UnsafeCell<T>
+}
+unsafe impl<T: Send> Send for FlatCombiner<T> { }
+unsafe impl<T: Send> Sync for FlatCombiner<T> { }
+impl<T> FlatCombiner<T> {
+    ///Build a combiner with `DEFAULT_SLOTS` slots
+    pub fn new(data: T) -> FlatCombiner<T> {
+        FlatCombiner::with_slots(data, DEFAULT_SLOTS)
+    }
+    ///Build a combiner with `slots` slots, at least one
+    pub fn with_slots(data: T, slots: usize) -> FlatCombiner<T> {
+        FlatCombiner {
+            lock: AtomicBool::new(false),
+            slots: (0..slots.max(1)).map(|_| Slot {
+                state: AtomicUsize::new(FREE),
+                op: UnsafeCell::new(None),
+                panic: UnsafeCell::new(None)
+            }).collect(),
+            data: UnsafeCell::new(data)
+        }
+    }
+    #[inline(always)]
+    fn try_lock(&self) -> bool {
+        !self.lock.load(RELAXED) &&
+            self.lock.compare_exchange(false, true, ACQUIRE, RELAXED).is_ok()
+    }
+    ///Run every published operation, the caller holds the lock
+    fn combine(&self) {
+        let data = unsafe{ &mut *self.data.get() };
+        for slot in self.slots.iter() {
+            if slot.state.load(ACQUIRE) != PUBLISHED {
+                continue;
+            }
+            unsafe {
+                let op = (*slot.op.get()).take().unwrap();
+                if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| (*op)(data))) {
+                    *slot.panic.get() = Some(e);
+                }
+            }
+            slot.state.store(DONE, RELEASE);
+        }
+    }
+    ///Claim a free slot, starting at the calling thread's own
+    fn claim(&self) -> Option<&Slot<T>> {
+        let start = THREAD.try_with(|t| *t).unwrap_or(0);
+        let n = self.slots.len();
+        (0..n).map(|i| &self.slots[(start + i) % n]).find(|slot| {
+            slot.state.load(RELAXED) == FREE &&
+                slot.state.compare_exchange(FREE, CLAIMED, ACQUIRE, RELAXED).is_ok()
+        })
+    }
+    ///Run `f` on the data, possibly on another thread that is combining
+    pub fn apply<R,F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R + Send,
+        R: Send,
+    {
+        let mut f = Some(f);
+        let mut result = None;
+        let mut run = |data: &mut T| result = Some((f.take().unwrap())(data));
+        let slot = match self.claim() {
+            Option::Some(slot) => slot,
+            Option::None => {
+                //every slot is busy, queue on the lock like a plain spinlock
+                let mut step = 0;
+                while !self.try_lock() {
+                    backoff(&mut step);
+                }
+                self.combine();
+                let data = unsafe{ &mut *self.data.get() };
+                let r = panic::catch_unwind(AssertUnwindSafe(|| run(data)));
+                self.lock.store(false, RELEASE);
+                if let Err(e) = r {
+                    panic::resume_unwind(e);
+                }
+                return result.unwrap();
+            }
+        };
+        {
+            let op: &mut (dyn FnMut(&mut T) + '_) = &mut run;
+            //`run` outlives the slot's use of it, we wait for DONE below
+            let op: Op<T> = unsafe{ mem::transmute(op as *mut (dyn FnMut(&mut T) + '_)) };
+            unsafe{ *slot.op.get() = Some(op) };
+            slot.state.store(PUBLISHED, RELEASE);
+            let mut step = 0;
+            while slot.state.load(ACQUIRE) != DONE {
+                if self.try_lock() {
+                    self.combine();
+                    self.lock.store(false, RELEASE);
+                } else {
+                    backoff(&mut step);
+                }
+            }
+        }
+        let panic = unsafe{ (*slot.panic.get()).take() };
+        slot.state.store(FREE, RELEASE);
+        if let Option::Some(e) = panic {
+            panic::resume_unwind(e);
+        }
+        result.unwrap()
+    }
+    ///Mutable access without combining
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        self.data.get_mut()
+    }
+    ///Consume the combiner, returning the data
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+impl<T: Default> Default for FlatCombiner<T> {
+    fn default() -> FlatCombiner<T> {
+        FlatCombiner::new(T::default())
+    }
+}
+
+#[test]
+fn test_flat_combiner() {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::thread;
+    //fewer slots than threads so the fallback path runs too
+    let queue = Arc::new(FlatCombiner::with_slots(VecDeque::new(), 2));
+    let workers = (0..4).map(|t| {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            let mut popped = 0;
+            for i in 0..250 {
+                queue.apply(|q| q.push_back(t * 250 + i));
+                if queue.apply(|q| q.pop_front()).is_some() {
+                    popped += 1;
+                }
+            }
+            popped
+        })
+    }).collect::<Vec<_>>();
+    let popped: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+    assert_eq!(popped + queue.apply(|q| q.len()), 1000);
+    let caught = panic::catch_unwind(AssertUnwindSafe(|| queue.apply(|_| panic!("bad op"))));
+    assert!(caught.is_err());
+    assert_eq!(queue.apply(|q| q.len()), 1000 - popped);
+}
@@ -0,0 +1,122 @@
+//!Condition variable for SpinLock.
+//!
+//!Waiters remember the notification generation while they still hold the
+//!lock, release it, and back off until the generation moves. Because the
+//!generation is read under the lock a notification sent after the data
+//!changed can never be missed. Like any condition variable waiters may wake
+//!without the condition holding, `wait_while` re-checks for you.
+
+
+use super::ordering::MemoryOrdering;
+use super::policy::Policy;
+use super::backoff::backoff;
+use super::spinlock::SpinGuard;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::{Duration,Instant};
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Lets threads holding a SpinLock wait for a change to the data
+pub struct SpinCondvar {
+    generation: AtomicUsize
+}
+impl SpinCondvar {
+    ///Build a new SpinCondvar
+    #[inline(always)]
+    pub const fn new() -> SpinCondvar {
+        SpinCondvar {
+            generation: AtomicUsize::new(0)
+        }
+    }
+    ///Release the lock, wait for a notification, then take the lock back
+    #[inline(always)]
+    pub fn wait<'a,T: ?Sized,O: MemoryOrdering,P: Policy>(&self, guard: SpinGuard<'a,T,O,P>) -> SpinGuard<'a,T,O,P> {
+        let lock = guard.source();
+        let generation = self.generation.load(SEQ);
+        drop(guard);
+        let mut step = 0;
+        while self.generation.load(SEQ) == generation {
+            backoff(&mut step);
+        }
+        lock.lock()
+    }
+    ///Wait until `condition` returns false, checking it under the lock
+    #[inline(always)]
+    pub fn wait_while<'a,T: ?Sized,O: MemoryOrdering,P: Policy,F>(&self, mut guard: SpinGuard<'a,T,O,P>, mut condition: F) -> SpinGuard<'a,T,O,P>
+    where
+        F: FnMut(&mut T) -> bool
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+    ///Like `wait_while`, giving up after `timeout`
+    ///
+    ///The returned bool is true if the timeout passed with the condition
+    ///still holding. A timeout too long to express as an Instant waits for
+    ///good.
+    pub fn wait_timeout_while<'a,T: ?Sized,O: MemoryOrdering,P: Policy,F>(&self, mut guard: SpinGuard<'a,T,O,P>, timeout: Duration, mut condition: F) -> (SpinGuard<'a,T,O,P>,bool)
+    where
+        F: FnMut(&mut T) -> bool
+    {
+        let deadline = Instant::now().checked_add(timeout);
+        while condition(&mut *guard) {
+            let lock = guard.source();
+            let generation = self.generation.load(SEQ);
+            drop(guard);
+            let mut step = 0;
+            while self.generation.load(SEQ) == generation {
+                if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                    let guard = lock.lock();
+                    return (guard, true);
+                }
+                backoff(&mut step);
+            }
+            guard = lock.lock();
+        }
+        (guard, false)
+    }
+    ///Wake a waiter
+    ///
+    ///Waiters are not queued, so this may wake more than one of them.
+    #[inline(always)]
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, SEQ);
+    }
+    ///Wake every waiter
+    #[inline(always)]
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, SEQ);
+    }
+}
+impl Default for SpinCondvar {
+    fn default() -> SpinCondvar {
+        SpinCondvar::new()
+    }
+}
+
+#[test]
+fn test_condvar_wait_while() {
+    use super::spinlock::SpinLock;
+    use std::sync::Arc;
+    use std::thread;
+    let pair = Arc::new((SpinLock::new(Vec::new()), SpinCondvar::new()));
+    let producer = {
+        let pair = pair.clone();
+        thread::spawn(move || {
+            for i in 0..3 {
+                pair.0.lock().push(i);
+                pair.1.notify_all();
+            }
+        })
+    };
+    let (lock, cond) = (&pair.0, &pair.1);
+    let items = cond.wait_while(lock.lock(), |items| items.len() < 3);
+    assert_eq!(*items, vec![0,1,2]);
+    drop(items);
+    producer.join().unwrap();
+    let (_, timed_out) = cond.wait_timeout_while(lock.lock(), Duration::from_millis(5), |_| true);
+    assert!(timed_out);
+    let (_, timed_out) = cond.wait_timeout_while(lock.lock(), Duration::MAX, |_| false);
+    assert!(!timed_out);
+}
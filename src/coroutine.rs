@@ -0,0 +1,368 @@
+//!Cooperative coroutines on a single thread.
+//!
+//!Coroutines are stackless: each one is a future, written by hand or as
+//!an `async` block in a crate on a newer edition. `spawn` puts one on the
+//!calling thread's run queue, a `VecDeque` in a thread local reached
+//!through `threadlocalkey::with_mut`, and `run` polls the queue round
+//!robin until every coroutine on the thread has finished. Nothing needs to
+//!be Send; coroutines never leave the thread that spawned them.
+//!
+//!A coroutine gives way to its siblings by returning Pending: from
+//!`yield_now`, or from `recv` and `send` while the channel has nothing to
+//!offer. A coroutine is only polled again once it was woken. When a whole
+//!round polls nothing but empty channels the scheduler backs off, and
+//!when nothing was woken at all it parks until a waker fires.
+//!
+//!A panic in a coroutine unwinds out of `run`.
+
+
+use super::Async;
+use super::backoff::Backoff;
+use super::mrms::{MRMSReceiver,MRMSSender};
+use super::policy::Policy;
+use super::threadlocalkey::with_mut;
+use std::cell::{Cell,RefCell};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::task::{Context,Poll,Wake,Waker};
+use std::thread::{self,Thread};
+use std::time::Duration;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+
+///Longest the scheduler parks when no coroutine is woken, covers a wake
+///that raced with it going to sleep
+const PARK_LIMIT: Duration = Duration::from_millis(1);
+
+thread_local!(static RUN_QUEUE: RefCell<VecDeque<Coroutine>> = const { RefCell::new(VecDeque::new()) });
+thread_local!(static RUNNING: Cell<bool> = const { Cell::new(false) });
+//set by a channel future that found nothing to do
+thread_local!(static IDLE_POLL: Cell<bool> = const { Cell::new(false) });
+
+///Marks a coroutine runnable and wakes the thread that runs it
+struct WakeFlag {
+    woken: AtomicBool,
+    thread: Thread
+}
+impl Wake for WakeFlag {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, RELEASE);
+        self.thread.unpark();
+    }
+}
+
+struct Coroutine {
+    future: Pin<Box<dyn Future<Output=()>>>,
+    flag: Arc<WakeFlag>,
+    waker: Waker
+}
+
+struct Output<T> {
+    value: Option<T>,
+    waker: Option<Waker>
+}
+
+///Wraps a spawned future to store its output for the handle
+struct Spawned<F: Future> {
+    future: Pin<Box<F>>,
+    output: Rc<RefCell<Output<F::Output>>>
+}
+impl<F: Future> Future for Spawned<F> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let value = match self.future.as_mut().poll(cx) {
+            Poll::Ready(x) => x,
+            Poll::Pending => return Poll::Pending
+        };
+        let waker = {
+            let mut output = self.output.borrow_mut();
+            output.value = Some(value);
+            output.waker.take()
+        };
+        if let Option::Some(waker) = waker {
+            waker.wake();
+        }
+        Poll::Ready(())
+    }
+}
+
+///Handle to a coroutine's output. It is itself a future, so a sibling
+///coroutine can wait on it.
+pub struct CoHandle<T> {
+    output: Rc<RefCell<Output<T>>>
+}
+impl<T> CoHandle<T> {
+    ///Returns true once the coroutine finished
+    pub fn is_finished(&self) -> bool {
+        self.output.borrow().value.is_some()
+    }
+    ///Take the output if the coroutine finished
+    pub fn take(&self) -> Option<T> {
+        self.output.borrow_mut().value.take()
+    }
+}
+impl<T> Future for CoHandle<T> {
+    type Output = T;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut output = self.output.borrow_mut();
+        match output.value.take() {
+            Option::Some(x) => Poll::Ready(x),
+            Option::None => {
+                output.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+///Put `future` on this thread's run queue
+///
+///It first runs during the next `run` on this thread, or the current one
+///if called from a coroutine.
+pub fn spawn<F>(future: F) -> CoHandle<F::Output>
+where
+    F: Future + 'static,
+{
+    let output = Rc::new(RefCell::new(Output { value: None, waker: None }));
+    let flag = Arc::new(WakeFlag {
+        woken: AtomicBool::new(true),
+        thread: thread::current()
+    });
+    let co = Coroutine {
+        future: Box::pin(Spawned {
+            future: Box::pin(future),
+            output: output.clone()
+        }),
+        waker: Waker::from(flag.clone()),
+        flag
+    };
+    with_mut(&RUN_QUEUE, |queue| queue.push_back(co));
+    CoHandle { output }
+}
+
+///Coroutines queued on this thread, finished ones are gone
+pub fn queued() -> usize {
+    with_mut(&RUN_QUEUE, |queue| queue.len())
+}
+
+///Run the coroutines on this thread's queue until all of them finished
+///
+///Panics if called from inside a coroutine.
+pub fn run() {
+    assert!(!RUNNING.with(|r| r.replace(true)), "coroutine::run called from inside a coroutine");
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            RUNNING.with(|r| r.set(false));
+        }
+    }
+    let _reset = Reset;
+    let backoff = Backoff::new();
+    //coroutines looked at since the last one that got anything done
+    let mut stalled = 0;
+    //whether any of those was woken at all
+    let mut woken = false;
+    loop {
+        let next = with_mut(&RUN_QUEUE, |queue| queue.pop_front());
+        let mut co = match next {
+            Option::Some(co) => co,
+            Option::None => return
+        };
+        if co.flag.woken.swap(false, ACQUIRE) {
+            IDLE_POLL.with(|i| i.set(false));
+            let mut cx = Context::from_waker(&co.waker);
+            let done = co.future.as_mut().poll(&mut cx).is_ready();
+            if IDLE_POLL.with(|i| i.get()) {
+                woken = true;
+                stalled += 1;
+            } else {
+                stalled = 0;
+                woken = false;
+                backoff.reset();
+            }
+            if done {
+                continue;
+            }
+        } else {
+            stalled += 1;
+        }
+        let len = with_mut(&RUN_QUEUE, |queue| {
+            queue.push_back(co);
+            queue.len()
+        });
+        if stalled >= len {
+            if woken {
+                backoff.snooze();
+            } else {
+                thread::park_timeout(PARK_LIMIT);
+            }
+            stalled = 0;
+            woken = false;
+        }
+    }
+}
+
+///Future that returns Pending once, letting sibling coroutines run
+pub struct YieldNow(bool);
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+///Let sibling coroutines run before continuing
+pub fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+///Future returned by `recv`
+pub struct RecvFuture<'a,T: Sized+'static,P: Policy+'a> {
+    receiver: &'a MRMSReceiver<T,P>
+}
+impl<'a,T: Sized+'static,P: Policy+'a> Future for RecvFuture<'a,T,P> {
+    type Output = Option<T>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        match self.receiver.recv() {
+            Async::Ok(Option::Some(x)) => Poll::Ready(Some(x)),
+            Async::Err(()) => Poll::Ready(None),
+            Async::Ok(Option::None) |
+            Async::Block(()) => {
+                //MRMS has no wakeups, poll again after the siblings ran
+                IDLE_POLL.with(|i| i.set(true));
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+///Receive from `receiver`, yielding to sibling coroutines while it is
+///empty
+///
+///Resolves to None once there is no sender nor messages to read
+pub fn recv<'a,T: Sized+'static,P: Policy+'a>(receiver: &'a MRMSReceiver<T,P>) -> RecvFuture<'a,T,P> {
+    RecvFuture { receiver }
+}
+
+///Future returned by `send`
+pub struct SendFuture<'a,T: Sized+'static,P: Policy+'a> {
+    sender: &'a MRMSSender<T,P>,
+    data: Option<T>
+}
+impl<'a,T: Sized+'static,P: Policy+'a> Unpin for SendFuture<'a,T,P> { }
+impl<'a,T: Sized+'static,P: Policy+'a> Future for SendFuture<'a,T,P> {
+    type Output = Result<(),T>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(),T>> {
+        let data = self.data.take().expect("send polled after completion");
+        match self.sender.send(data) {
+            Async::Ok(()) => Poll::Ready(Ok(())),
+            Async::Err(x) => Poll::Ready(Err(x)),
+            Async::Block(x) => {
+                self.data = Some(x);
+                IDLE_POLL.with(|i| i.set(true));
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+///Send on `sender`, yielding to sibling coroutines while the channel is
+///contended
+///
+///Resolves to Err with the item if there are no receivers
+pub fn send<'a,T: Sized+'static,P: Policy+'a>(sender: &'a MRMSSender<T,P>, data: T) -> SendFuture<'a,T,P> {
+    SendFuture { sender, data: Some(data) }
+}
+
+#[cfg(test)]
+struct Counter {
+    log: Rc<RefCell<Vec<(usize,usize)>>>,
+    id: usize,
+    step: usize,
+    pending: Option<YieldNow>
+}
+#[cfg(test)]
+impl Future for Counter {
+    type Output = usize;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<usize> {
+        loop {
+            if let Option::Some(ref mut y) = self.pending {
+                if Pin::new(y).poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+            if self.step == 3 {
+                return Poll::Ready(self.id);
+            }
+            let entry = (self.id, self.step);
+            self.log.borrow_mut().push(entry);
+            self.step += 1;
+            self.pending = Some(yield_now());
+        }
+    }
+}
+
+#[test]
+fn test_coroutine_interleaves() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let handles = (0..2).map(|id| spawn(Counter { log: log.clone(), id, step: 0, pending: None })).collect::<Vec<_>>();
+    assert_eq!(queued(), 2);
+    run();
+    assert_eq!(queued(), 0);
+    assert_eq!(handles[0].take(), Some(0));
+    assert_eq!(handles[1].take(), Some(1));
+    //each yield handed the thread to the other coroutine
+    assert_eq!(*log.borrow(), vec![(0,0),(1,0),(0,1),(1,1),(0,2),(1,2)]);
+}
+
+#[test]
+fn test_coroutine_channel() {
+    use super::mrms::channel;
+    struct Consumer {
+        receiver: MRMSReceiver<usize>,
+        total: usize
+    }
+    impl Future for Consumer {
+        type Output = usize;
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<usize> {
+            loop {
+                let next = {
+                    let mut recv = recv(&self.receiver);
+                    Pin::new(&mut recv).poll(cx)
+                };
+                match next {
+                    Poll::Ready(Option::Some(x)) => self.total += x,
+                    Poll::Ready(Option::None) => return Poll::Ready(self.total),
+                    Poll::Pending => return Poll::Pending
+                };
+            }
+        }
+    }
+    let (s, r) = channel(4);
+    let consumer = spawn(Consumer { receiver: r, total: 0 });
+    //a producer on another thread wakes nothing, the consumer polls
+    let producer = thread::spawn(move || {
+        for i in 0..100 {
+            while !s.send(i).is_ok() {
+                thread::yield_now();
+            }
+        }
+    });
+    run();
+    producer.join().unwrap();
+    assert_eq!(consumer.take(), Some(4950));
+}
@@ -0,0 +1,113 @@
+//!Sharded counter.
+//!
+//!Updates go to one of several counters, each on its own cache line,
+//!picked by a per thread id, so threads counting at the same time rarely
+//!contend. Reading sums every shard, which is slower and only exact while
+//!nobody is updating.
+
+
+use std::sync::atomic::{AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+thread_local!(static THREAD: usize = NEXT_THREAD.fetch_add(1, RELAXED));
+
+///Shards a counter built with `new` has
+pub const DEFAULT_SHARDS: usize = 16;
+
+///One shard, padded to its own cache line
+#[repr(align(128))]
+struct Shard(AtomicUsize);
+
+///Counter striped across padded shards, summed on read
+///
+///Arithmetic wraps like the atomics it is built on.
+pub struct ShardedCounter {
+    shards: Box<[Shard]>,
+    mask: usize
+}
+impl ShardedCounter {
+    ///Build a counter at zero with `DEFAULT_SHARDS` shards
+    pub fn new() -> ShardedCounter {
+        ShardedCounter::with_shards(DEFAULT_SHARDS)
+    }
+    ///Build a counter at zero with at least `shards` shards, rounded up to
+    ///a power of two
+    pub fn with_shards(shards: usize) -> ShardedCounter {
+        let shards = shards.max(1).next_power_of_two();
+        ShardedCounter {
+            shards: (0..shards).map(|_| Shard(AtomicUsize::new(0))).collect(),
+            mask: shards - 1
+        }
+    }
+    #[inline(always)]
+    fn shard(&self) -> &AtomicUsize {
+        &self.shards[THREAD.try_with(|t| *t).unwrap_or(0) & self.mask].0
+    }
+    ///Add one
+    #[inline(always)]
+    pub fn incr(&self) {
+        self.add(1);
+    }
+    ///Add `x`
+    #[inline(always)]
+    pub fn add(&self, x: usize) {
+        self.shard().fetch_add(x, RELAXED);
+    }
+    ///Subtract `x`
+    ///
+    ///The sum is only meaningful if it never goes below zero overall,
+    ///single shards may wrap.
+    #[inline(always)]
+    pub fn sub(&self, x: usize) {
+        self.shard().fetch_sub(x, RELAXED);
+    }
+    ///Sum of every shard
+    pub fn sum(&self) -> usize {
+        self.shards.iter().fold(0usize, |acc, s| acc.wrapping_add(s.0.load(RELAXED)))
+    }
+    ///Set every shard to zero, returning the sum they held
+    pub fn reset(&self) -> usize {
+        self.shards.iter().fold(0usize, |acc, s| acc.wrapping_add(s.0.swap(0, RELAXED)))
+    }
+    ///Number of shards
+    #[inline(always)]
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+    ///Consume the counter, returning its sum
+    pub fn into_inner(self) -> usize {
+        self.sum()
+    }
+}
+impl Default for ShardedCounter {
+    fn default() -> ShardedCounter {
+        ShardedCounter::new()
+    }
+}
+
+#[test]
+fn test_sharded_counter() {
+    use std::sync::Arc;
+    use std::thread;
+    let counter = Arc::new(ShardedCounter::with_shards(3));
+    assert_eq!(counter.shards(), 4);
+    let workers = (0..4).map(|_| {
+        let counter = counter.clone();
+        thread::spawn(move || {
+            for _ in 0..250 {
+                counter.incr();
+            }
+            counter.add(10);
+            counter.sub(5);
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    //a shard may have gone below zero on its own, the sum still adds up
+    counter.sub(20);
+    assert_eq!(counter.sum(), 1000);
+    assert_eq!(counter.reset(), 1000);
+    assert_eq!(Arc::try_unwrap(counter).ok().unwrap().into_inner(), 0);
+}
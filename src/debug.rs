@@ -0,0 +1,275 @@
+//!Registry of live channels, locks and pools, for finding out what a hung
+//!process is stuck on.
+//!
+//!Only built with the `debug-registry` feature. Channels made with
+//!`mrms::channel_named` and every `ThreadPool` register themselves under
+//!their name, locks are registered with `register_lock` or
+//!`register_static_lock`. `dump` reports the current state of everything
+//!still alive: queue depths and handle counts of channels, whether locks
+//!are held and how many threads wait on them, and the backlog of pools.
+//!
+//!Entries only hold weak references, a dropped object leaves the registry
+//!at the next `dump` or `register`. Waiters are counted on the contended
+//!path only, a lock taken without waiting never touches the registry and
+//!neither does waiting on a lock that was never registered.
+
+
+use super::policy::Policy;
+use super::ordering::MemoryOrdering;
+use super::spinlock::SpinLock;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc,Mutex,TryLockError};
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+///What a registered object looked like when `dump` was called
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum State {
+    ///A channel. `depth` is None if the channel was locked by someone
+    ///else, for broadcast channels it is the longest receiver queue.
+    Channel {
+        depth: Option<usize>,
+        senders: usize,
+        receivers: usize
+    },
+    ///A lock and the number of threads waiting to take it
+    Lock {
+        locked: bool,
+        waiters: usize
+    },
+    ///A thread pool, `pending` counts queued and running jobs
+    Pool {
+        workers: usize,
+        pending: usize,
+        panicked: usize
+    }
+}
+
+///One registered object
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Entry {
+    ///name it was registered under
+    pub name: String,
+    ///what it looked like during the dump
+    pub state: State
+}
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.state {
+            State::Channel { depth, senders, receivers } => {
+                write!(f, "channel {}: ", self.name)?;
+                match depth {
+                    Option::Some(depth) => write!(f, "{} queued", depth)?,
+                    Option::None => f.write_str("locked")?
+                };
+                write!(f, ", {} senders, {} receivers", senders, receivers)
+            }
+            State::Lock { locked, waiters } => {
+                write!(f, "lock {}: {}, {} waiting", self.name, if locked { "held" } else { "free" }, waiters)
+            }
+            State::Pool { workers, pending, panicked } => {
+                write!(f, "pool {}: {} workers, {} pending, {} panicked", self.name, workers, pending, panicked)
+            }
+        }
+    }
+}
+
+///Reads the state of a registered object, None once it is gone
+pub(crate) type Probe = Box<dyn Fn() -> Option<State> + Send>;
+
+struct Slot {
+    name: String,
+    ///id of the lock whose waiters are counted
+    lock: Option<usize>,
+    probe: Probe
+}
+
+struct Registry {
+    slots: Vec<Slot>,
+    waiters: BTreeMap<usize,usize>
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    slots: Vec::new(),
+    waiters: BTreeMap::new()
+});
+///Buckets of `REGISTERED`
+const BUCKETS: usize = 64;
+///Registered locks counted by a hash of their id, lets the wait hooks skip
+///the registry for locks that cannot be in it
+static REGISTERED: [AtomicUsize; BUCKETS] = [const { AtomicUsize::new(0) }; BUCKETS];
+///Held through a sweep, while the slots are out of the registry
+static SWEEP: Mutex<()> = Mutex::new(());
+
+fn registry() -> std::sync::MutexGuard<'static,Registry> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn bucket(id: usize) -> &'static AtomicUsize {
+    let hash = (id as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 58;
+    &REGISTERED[hash as usize % BUCKETS]
+}
+
+///Add an object, `lock` is set for locks
+pub(crate) fn register(name: &str, lock: Option<usize>, probe: Probe) {
+    //drop what died since the last sweep, unless one is running already,
+    //possibly on this thread if a probe registers something
+    match SWEEP.try_lock() {
+        Ok(_sweep) => { sweep(); }
+        Err(TryLockError::Poisoned(e)) => {
+            let _sweep = e.into_inner();
+            sweep();
+        }
+        Err(TryLockError::WouldBlock) => { }
+    };
+    let mut registry = registry();
+    if let Option::Some(id) = lock {
+        registry.waiters.entry(id).or_insert(0);
+        bucket(id).fetch_add(1, Ordering::Relaxed);
+    }
+    registry.slots.push(Slot {
+        name: name.to_string(),
+        lock,
+        probe
+    });
+}
+
+///Register a lock under `name` until it is dropped
+pub fn register_lock<T,O,P>(name: &str, lock: &Arc<SpinLock<T,O,P>>)
+where
+    T: ?Sized+Send+'static,
+    O: MemoryOrdering+'static,
+    P: Policy+'static,
+{
+    let weak = Arc::downgrade(lock);
+    register(name, Some(lock.raw_id()), Box::new(move || {
+        weak.upgrade().map(|lock| State::Lock {
+            locked: lock.is_locked(),
+            waiters: 0
+        })
+    }));
+}
+
+///Register a lock in a `static` under `name`
+pub fn register_static_lock<T,O,P>(name: &str, lock: &'static SpinLock<T,O,P>)
+where
+    T: ?Sized+Send,
+    O: MemoryOrdering,
+    P: Policy,
+{
+    register(name, Some(lock.raw_id()), Box::new(move || {
+        Some(State::Lock {
+            locked: lock.is_locked(),
+            waiters: 0
+        })
+    }));
+}
+
+///A thread failed to take lock `id` and started waiting
+pub(crate) fn waiting(id: usize) {
+    if bucket(id).load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    if let Option::Some(count) = registry().waiters.get_mut(&id) {
+        *count += 1;
+    }
+}
+
+///A thread waiting for lock `id` got it
+pub(crate) fn waited(id: usize) {
+    if bucket(id).load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    if let Option::Some(count) = registry().waiters.get_mut(&id) {
+        *count = count.saturating_sub(1);
+    }
+}
+
+///Run every probe and drop the slots whose object is gone, returns the
+///state of the rest in the order they were registered
+///
+///Must be called holding `SWEEP`.
+fn sweep() -> Vec<Entry> {
+    //probes run without the registry locked, the last reference to an
+    //object may be dropped in one and its destructor may do anything
+    let mut slots = mem::take(&mut registry().slots);
+    let states = slots.iter().map(|slot| (slot.probe)()).collect::<Vec<_>>();
+    let mut registry = registry();
+    let mut entries = Vec::with_capacity(slots.len());
+    let mut states = states.into_iter();
+    slots.retain(|slot| {
+        match states.next().unwrap() {
+            Option::Some(mut state) => {
+                if let (State::Lock { waiters: ref mut n, .. }, Option::Some(id)) = (&mut state, slot.lock) {
+                    *n = registry.waiters.get(&id).cloned().unwrap_or(0);
+                }
+                entries.push(Entry {
+                    name: slot.name.clone(),
+                    state
+                });
+                true
+            }
+            Option::None => {
+                if let Option::Some(id) = slot.lock {
+                    registry.waiters.remove(&id);
+                    bucket(id).fetch_sub(1, Ordering::Relaxed);
+                }
+                false
+            }
+        }
+    });
+    //keep whatever was registered meanwhile after the older entries
+    slots.append(&mut registry.slots);
+    registry.slots = slots;
+    entries
+}
+
+///Report everything registered that is still alive, in the order it was
+///registered
+pub fn dump() -> Vec<Entry> {
+    let _sweep = SWEEP.lock().unwrap_or_else(|e| e.into_inner());
+    sweep()
+}
+
+#[test]
+fn test_debug_dump() {
+    use super::mrms;
+    use super::pool::ThreadPool;
+    use std::thread;
+    use std::time::Duration;
+    let (s, r) = mrms::channel_named::<usize>("debug-test-jobs", 4);
+    assert!(s.send(1).is_ok());
+    assert!(s.send(2).is_ok());
+    let lock = Arc::new(SpinLock::new(0usize));
+    register_lock("debug-test-lock", &lock);
+    let guard = lock.lock();
+    let other = lock.clone();
+    let t = thread::spawn(move || {
+        *other.lock() += 1;
+    });
+    let find = |name: &str| dump().into_iter().find(|e| e.name == name);
+    //wait for the thread to queue up behind the guard
+    while find("debug-test-lock").map(|e| e.state) != Some(State::Lock { locked: true, waiters: 1 }) {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let chan = find("debug-test-jobs").unwrap();
+    assert_eq!(chan.state, State::Channel { depth: Some(2), senders: 1, receivers: 1 });
+    assert_eq!(chan.to_string(), "channel debug-test-jobs: 2 queued, 1 senders, 1 receivers");
+    drop(guard);
+    t.join().unwrap();
+    assert_eq!(find("debug-test-lock").unwrap().state, State::Lock { locked: false, waiters: 0 });
+    let pool = ThreadPool::builder().name("debug-test-pool").workers(2).build();
+    assert_eq!(find("debug-test-pool").unwrap().state, State::Pool { workers: 2, pending: 0, panicked: 0 });
+    //dropped objects leave the registry, registering sweeps them out too
+    drop((s, r, lock, pool));
+    let other = Arc::new(SpinLock::new(()));
+    register_lock("debug-test-other", &other);
+    {
+        let _sweep = SWEEP.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(registry().slots.iter().all(|slot| slot.name != "debug-test-jobs"));
+    }
+    assert!(find("debug-test-jobs").is_none());
+    assert!(find("debug-test-lock").is_none());
+    assert!(find("debug-test-pool").is_none());
+}
@@ -0,0 +1,294 @@
+//!Chase-Lev work stealing deque.
+//!
+//!The owning Worker pushes and pops at the bottom without contention,
+//!Stealers take from the top and only race each other, or the worker when
+//!a single item is left. The ring buffer doubles when it fills. Old
+//!buffers may still be read by a thief that loaded them before the swap,
+//!so they are retired rather than freed and only released with the deque.
+
+
+use super::Async;
+use super::spinlock::SpinLock;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{self,AtomicBool,AtomicIsize,AtomicPtr,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Slots a new deque starts with
+const MIN_CAPACITY: usize = 16;
+
+///Ring of slots indexed by the unbounded top and bottom counters. Slots
+///are never dropped by the buffer, ownership of the items is tracked by
+///top and bottom.
+struct Buffer<T> {
+    mask: usize,
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>
+}
+impl<T> Buffer<T> {
+    fn alloc(cap: usize) -> *mut Buffer<T> {
+        debug_assert!(cap.is_power_of_two());
+        Box::into_raw(Box::new(Buffer {
+            mask: cap - 1,
+            slots: (0..cap).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect()
+        }))
+    }
+    #[inline(always)]
+    fn cap(&self) -> usize {
+        self.mask + 1
+    }
+    #[inline(always)]
+    unsafe fn write(&self, index: isize, item: T) {
+        let slot = &self.slots[index as usize & self.mask];
+        ptr::write(slot.get(), MaybeUninit::new(item));
+    }
+    ///Copy a slot out, still untyped: a stealer's copy is only a `T` once
+    ///its claim on the slot succeeded
+    #[inline(always)]
+    unsafe fn read(&self, index: isize) -> MaybeUninit<T> {
+        let slot = &self.slots[index as usize & self.mask];
+        ptr::read(slot.get())
+    }
+}
+
+struct Inner<T> {
+    top: AtomicIsize,
+    bottom: AtomicIsize,
+    buffer: AtomicPtr<Buffer<T>>,
+    retired: SpinLock<Vec<*mut Buffer<T>>>,
+    worker_alive: AtomicBool
+}
+unsafe impl<T: Send> Send for Inner<T> { }
+unsafe impl<T: Send> Sync for Inner<T> { }
+impl<T> Inner<T> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        let b = self.bottom.load(ACQUIRE);
+        let t = self.top.load(ACQUIRE);
+        (b - t).max(0) as usize
+    }
+}
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let b = *self.bottom.get_mut();
+        let t = *self.top.get_mut();
+        let buffer = *self.buffer.get_mut();
+        unsafe {
+            for i in t..b {
+                drop((*buffer).read(i).assume_init());
+            }
+            drop(Box::from_raw(buffer));
+            for old in self.retired.get_mut().drain(..) {
+                drop(Box::from_raw(old));
+            }
+        }
+    }
+}
+
+///Owning end of a work stealing deque, pushes and pops at the bottom
+///
+///There is only ever one Worker, it can be sent to another thread but not
+///shared.
+pub struct Worker<T> {
+    inner: Arc<Inner<T>>,
+    marker: PhantomData<*const ()>
+}
+unsafe impl<T: Send> Send for Worker<T> { }
+impl<T> Worker<T> {
+    ///Build a new empty deque
+    pub fn new() -> Worker<T> {
+        Worker {
+            inner: Arc::new(Inner {
+                top: AtomicIsize::new(0),
+                bottom: AtomicIsize::new(0),
+                buffer: AtomicPtr::new(Buffer::alloc(MIN_CAPACITY)),
+                retired: SpinLock::new(Vec::new()),
+                worker_alive: AtomicBool::new(true)
+            }),
+            marker: PhantomData
+        }
+    }
+    ///Build a Stealer for this deque
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            inner: self.inner.clone()
+        }
+    }
+    ///Push an item onto the bottom
+    pub fn push(&self, item: T) {
+        let inner = &*self.inner;
+        let b = inner.bottom.load(RELAXED);
+        let t = inner.top.load(ACQUIRE);
+        let mut buffer = inner.buffer.load(RELAXED);
+        unsafe {
+            if (b - t) as usize >= (*buffer).cap() {
+                buffer = self.grow(buffer, t, b);
+            }
+            (*buffer).write(b, item);
+        }
+        atomic::fence(RELEASE);
+        inner.bottom.store(b + 1, RELAXED);
+    }
+    ///Move the live items into a buffer twice the size
+    #[cold]
+    unsafe fn grow(&self, old: *mut Buffer<T>, t: isize, b: isize) -> *mut Buffer<T> {
+        let new = Buffer::alloc((*old).cap() * 2);
+        for i in t..b {
+            (*new).write(i, (*old).read(i).assume_init());
+        }
+        self.inner.buffer.store(new, RELEASE);
+        self.inner.retired.lock().push(old);
+        new
+    }
+    ///Pop the most recently pushed item
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        let b = inner.bottom.load(RELAXED) - 1;
+        let buffer = inner.buffer.load(RELAXED);
+        inner.bottom.store(b, RELAXED);
+        atomic::fence(SEQ);
+        let t = inner.top.load(RELAXED);
+        if t > b {
+            inner.bottom.store(b + 1, RELAXED);
+            return None;
+        }
+        if t == b {
+            //last item, race the thieves for it
+            let won = inner.top.compare_exchange(t, t + 1, SEQ, RELAXED).is_ok();
+            inner.bottom.store(b + 1, RELAXED);
+            if !won {
+                return None;
+            }
+        }
+        Some(unsafe{ (*buffer).read(b).assume_init() })
+    }
+    ///Number of items in the deque
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+    ///Returns true if the deque is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+impl<T> Default for Worker<T> {
+    fn default() -> Worker<T> {
+        Worker::new()
+    }
+}
+impl<T> Drop for Worker<T> {
+    fn drop(&mut self) {
+        self.inner.worker_alive.store(false, RELEASE);
+    }
+}
+
+///Stealing end of a work stealing deque, takes the oldest item
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>
+}
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Stealer<T> {
+        Stealer {
+            inner: self.inner.clone()
+        }
+    }
+}
+unsafe impl<T: Send> Send for Stealer<T> { }
+unsafe impl<T: Send> Sync for Stealer<T> { }
+impl<T> Stealer<T> {
+    ///Take the oldest item
+    ///
+    ///Returns Async::Ok(Option<T>) with the item, or None if the deque was
+    ///empty
+    ///Returns Async::Block(()) if another thread took the item first, retry
+    ///Returns Async::Err(()) if the deque is empty and the Worker is gone
+    pub fn steal(&self) -> Async<Option<T>,(),()> {
+        let inner = &*self.inner;
+        let t = inner.top.load(ACQUIRE);
+        atomic::fence(SEQ);
+        let b = inner.bottom.load(ACQUIRE);
+        if t >= b {
+            if inner.worker_alive.load(ACQUIRE) {
+                return Async::Ok(None);
+            }
+            return Async::Err(());
+        }
+        let buffer = inner.buffer.load(ACQUIRE);
+        //read before claiming, once top moves the slot may be reused
+        let item = unsafe{ (*buffer).read(t) };
+        if inner.top.compare_exchange(t, t + 1, SEQ, RELAXED).is_err() {
+            //somebody else owns the slot, the copy may be stale
+            return Async::Block(());
+        }
+        Async::Ok(Some(unsafe{ item.assume_init() }))
+    }
+    ///Number of items in the deque
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+    ///Returns true if the deque is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+///Build a new work stealing deque
+pub fn deque<T>() -> (Worker<T>,Stealer<T>) {
+    let w = Worker::new();
+    let s = w.stealer();
+    (w,s)
+}
+
+#[test]
+fn test_deque_worker_lifo() {
+    let (w,s) = deque::<Box<usize>>();
+    for i in 0..100 {
+        w.push(Box::new(i));
+    }
+    assert_eq!(w.len(), 100);
+    assert!(w.pop().map(|x| *x) == Some(99));
+    assert!(s.steal().ok().and_then(|x| x.as_ref().map(|x| **x)) == Some(0));
+    //remaining boxes are dropped with the deque
+    drop(w);
+    assert!(s.steal().is_ok());
+}
+
+#[test]
+fn test_deque_stealing() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    let (w,s) = deque::<usize>();
+    let total = Arc::new(AtomicUsize::new(0));
+    let thieves = (0..3).map(|_| {
+        let s = s.clone();
+        let total = total.clone();
+        thread::spawn(move || {
+            loop {
+                match s.steal() {
+                    Async::Ok(Option::Some(x)) => { total.fetch_add(x, RELAXED); }
+                    Async::Ok(Option::None) |
+                    Async::Block(()) => thread::yield_now(),
+                    Async::Err(()) => return
+                };
+            }
+        })
+    }).collect::<Vec<_>>();
+    for i in 1..=1000 {
+        w.push(i);
+        if i % 3 == 0 {
+            if let Some(x) = w.pop() {
+                total.fetch_add(x, RELAXED);
+            }
+        }
+    }
+    drop(w);
+    for t in thieves {
+        t.join().unwrap();
+    }
+    assert_eq!(total.load(RELAXED), 500500);
+}
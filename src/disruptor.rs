@@ -0,0 +1,420 @@
+//!Disruptor style ring bus.
+//!
+//!Every slot is allocated up front and reused, nothing is allocated per
+//!event. Writers claim sequence numbers, fill the claimed slots in place
+//!and publish them. Each consumer tracks how far it got in its own padded
+//!sequence and may be placed after other consumers, in which case it never
+//!passes them. Writers never lap the slowest consumer.
+//!
+//!With a single writer the claim counter is plain and publishing is one
+//!store of the cursor. With several writers claims are a fetch_add and
+//!every slot records the sequence last published into it, consumers read
+//!up to the first slot not yet published.
+
+
+use super::Async;
+use super::backoff::backoff;
+use super::spinlock::SpinLock;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Index,IndexMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+///Picks how writers claim sequence numbers
+pub trait WriterMode {
+    ///True if several writers may claim at once
+    const MULTI: bool;
+}
+///Exactly one Writer, it cannot be cloned
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct Single;
+impl WriterMode for Single {
+    const MULTI: bool = false;
+}
+///Any number of Writers, cloned from the first
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct Multi;
+impl WriterMode for Multi {
+    const MULTI: bool = true;
+}
+
+///A counter on its own cache line
+#[repr(align(128))]
+struct Sequence(AtomicUsize);
+impl Sequence {
+    fn new(x: usize) -> Arc<Sequence> {
+        Arc::new(Sequence(AtomicUsize::new(x)))
+    }
+}
+
+struct Shared<T,W> {
+    slots: Box<[UnsafeCell<T>]>,
+    mask: usize,
+    ///number of sequences handed out to writers
+    claimed: Sequence,
+    ///number of sequences published, single writer only
+    cursor: Sequence,
+    ///per slot, one past the sequence last published into it, multi
+    ///writer only
+    published: Box<[AtomicUsize]>,
+    consumers: SpinLock<Vec<Arc<Sequence>>>,
+    writers: AtomicUsize,
+    mode: PhantomData<W>
+}
+unsafe impl<T: Send+Sync,W> Send for Shared<T,W> { }
+unsafe impl<T: Send+Sync,W> Sync for Shared<T,W> { }
+impl<T,W: WriterMode> Shared<T,W> {
+    ///Number of sequences readable from `from` on, without gaps
+    fn available(&self, from: usize) -> usize {
+        if !W::MULTI {
+            return self.cursor.0.load(ACQUIRE);
+        }
+        let claimed = self.claimed.0.load(ACQUIRE);
+        let mut seq = from;
+        while seq < claimed && self.published[seq & self.mask].load(ACQUIRE) == seq + 1 {
+            seq += 1;
+        }
+        seq
+    }
+    fn publish(&self, start: usize, end: usize) {
+        if W::MULTI {
+            for seq in start..end {
+                self.published[seq & self.mask].store(seq + 1, RELEASE);
+            }
+        } else {
+            self.cursor.0.store(end, RELEASE);
+        }
+    }
+}
+
+///Builds a ring bus, consumers are added before the first Writer exists
+pub struct Disruptor<T,W: WriterMode = Single> {
+    shared: Arc<Shared<T,W>>
+}
+impl<T,W: WriterMode> Disruptor<T,W> {
+    ///Build a ring of at least `capacity` slots, rounded up to a power of
+    ///two, filled by `factory`
+    pub fn new<F: FnMut() -> T>(capacity: usize, mut factory: F) -> Disruptor<T,W> {
+        let capacity = capacity.max(1).next_power_of_two();
+        Disruptor {
+            shared: Arc::new(Shared {
+                slots: (0..capacity).map(|_| UnsafeCell::new(factory())).collect(),
+                mask: capacity - 1,
+                claimed: Sequence(AtomicUsize::new(0)),
+                cursor: Sequence(AtomicUsize::new(0)),
+                published: (0..capacity).map(|_| AtomicUsize::new(0)).collect(),
+                consumers: SpinLock::new(Vec::new()),
+                writers: AtomicUsize::new(0),
+                mode: PhantomData
+            })
+        }
+    }
+    ///Add a consumer that sees every event after every consumer in `after`
+    ///has
+    pub fn consumer(&mut self, after: &[&Consumer<T,W>]) -> Consumer<T,W> {
+        let seq = Sequence::new(0);
+        self.shared.consumers.lock().push(seq.clone());
+        Consumer {
+            shared: self.shared.clone(),
+            seq,
+            after: after.iter().map(|c| c.seq.clone()).collect()
+        }
+    }
+    ///Finish building and return the first writer
+    pub fn writer(self) -> Writer<T,W> {
+        let gating = self.shared.consumers.lock().clone().into_boxed_slice();
+        self.shared.writers.fetch_add(1, RELAXED);
+        Writer {
+            shared: self.shared,
+            gating: Arc::from(gating),
+            cached_min: 0
+        }
+    }
+}
+
+///Claims and publishes slots of the ring
+pub struct Writer<T,W: WriterMode = Single> {
+    shared: Arc<Shared<T,W>>,
+    gating: Arc<[Arc<Sequence>]>,
+    ///lowest consumer sequence seen last time, saves rescanning
+    cached_min: usize
+}
+impl<T> Clone for Writer<T,Multi> {
+    fn clone(&self) -> Writer<T,Multi> {
+        self.shared.writers.fetch_add(1, RELAXED);
+        Writer {
+            shared: self.shared.clone(),
+            gating: self.gating.clone(),
+            cached_min: self.cached_min
+        }
+    }
+}
+impl<T,W: WriterMode> Writer<T,W> {
+    ///Capacity of the ring
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+    fn slowest(&mut self) -> usize {
+        let min = self.gating.iter().map(|s| s.0.load(ACQUIRE)).min().unwrap_or(usize::MAX);
+        self.cached_min = min;
+        min
+    }
+    ///Returns true if sequences up to `end` fit without lapping a consumer
+    #[inline(always)]
+    fn fits(&mut self, end: usize) -> bool {
+        let cap = self.capacity();
+        end <= self.cached_min.saturating_add(cap) || end <= self.slowest().saturating_add(cap)
+    }
+    ///Claim `n` consecutive slots, waiting for consumers to make room
+    ///
+    ///Panics if `n` is 0 or larger than the capacity
+    pub fn claim<'a>(&'a mut self, n: usize) -> Claim<'a,T,W> {
+        assert!(n > 0 && n <= self.capacity(), "claim of {} slots on a ring of {}", n, self.capacity());
+        let start = if W::MULTI {
+            self.shared.claimed.0.fetch_add(n, ACQREL)
+        } else {
+            self.shared.claimed.0.load(RELAXED)
+        };
+        let mut step = 0;
+        while !self.fits(start + n) {
+            backoff(&mut step);
+        }
+        if !W::MULTI {
+            self.shared.claimed.0.store(start + n, RELEASE);
+        }
+        Claim {
+            writer: self,
+            start,
+            n
+        }
+    }
+    ///Claim `n` slots if there is room for them right now
+    ///
+    ///Returns Async::Ok(Claim) with the slots
+    ///Returns Async::Block(()) if the ring is too full, retry later
+    ///Never returns Async::Err
+    pub fn try_claim<'a>(&'a mut self, n: usize) -> Async<Claim<'a,T,W>,(),()> {
+        assert!(n > 0 && n <= self.capacity(), "claim of {} slots on a ring of {}", n, self.capacity());
+        let start = if W::MULTI {
+            let mut start = self.shared.claimed.0.load(RELAXED);
+            loop {
+                if !self.fits(start + n) {
+                    return Async::Block(());
+                }
+                match self.shared.claimed.0.compare_exchange_weak(start, start + n, ACQREL, RELAXED) {
+                    Ok(_) => break start,
+                    Err(x) => start = x
+                };
+            }
+        } else {
+            let start = self.shared.claimed.0.load(RELAXED);
+            if !self.fits(start + n) {
+                return Async::Block(());
+            }
+            self.shared.claimed.0.store(start + n, RELEASE);
+            start
+        };
+        Async::Ok(Claim {
+            writer: self,
+            start,
+            n
+        })
+    }
+    ///Claim one slot, fill it with `f`, and publish it
+    pub fn publish_with<F: FnOnce(&mut T)>(&mut self, f: F) {
+        let mut claim = self.claim(1);
+        f(&mut claim[0]);
+    }
+}
+impl<T,W: WriterMode> Drop for Writer<T,W> {
+    fn drop(&mut self) {
+        self.shared.writers.fetch_sub(1, RELEASE);
+    }
+}
+
+///Slots claimed by a Writer, published when dropped
+pub struct Claim<'a,T: 'a,W: WriterMode + 'a> {
+    writer: &'a mut Writer<T,W>,
+    start: usize,
+    n: usize
+}
+impl<'a,T,W: WriterMode> Claim<'a,T,W> {
+    ///Sequence number of the first claimed slot
+    #[inline(always)]
+    pub fn sequence(&self) -> usize {
+        self.start
+    }
+    ///Number of slots claimed
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.n
+    }
+    ///Always false, a claim holds at least one slot
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+    ///Publish the slots, the same as dropping the claim
+    #[inline(always)]
+    pub fn publish(self) { }
+}
+impl<'a,T,W: WriterMode> Index<usize> for Claim<'a,T,W> {
+    type Output = T;
+    ///The `i`th claimed slot, slots may wrap around the end of the ring
+    fn index(&self, i: usize) -> &T {
+        assert!(i < self.n, "slot {} of a claim of {}", i, self.n);
+        let shared = &self.writer.shared;
+        unsafe{ &*shared.slots[(self.start + i) & shared.mask].get() }
+    }
+}
+impl<'a,T,W: WriterMode> IndexMut<usize> for Claim<'a,T,W> {
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        assert!(i < self.n, "slot {} of a claim of {}", i, self.n);
+        let shared = &self.writer.shared;
+        //consumers are all past this slot and no other writer claimed it
+        unsafe{ &mut *shared.slots[(self.start + i) & shared.mask].get() }
+    }
+}
+impl<'a,T,W: WriterMode> Drop for Claim<'a,T,W> {
+    fn drop(&mut self) {
+        self.writer.shared.publish(self.start, self.start + self.n);
+    }
+}
+
+///Reads events from the ring in order
+pub struct Consumer<T,W: WriterMode = Single> {
+    shared: Arc<Shared<T,W>>,
+    seq: Arc<Sequence>,
+    after: Box<[Arc<Sequence>]>
+}
+impl<T,W: WriterMode> Consumer<T,W> {
+    ///Run `f` on every event that is ready, in one batch
+    ///
+    ///Returns Async::Ok(n) with the number of events handled, at least one
+    ///Returns Async::Block(()) if nothing is ready yet
+    ///Returns Async::Err(()) once every Writer is gone and everything
+    ///published was handled
+    pub fn poll<F: FnMut(usize, &T)>(&mut self, mut f: F) -> Async<usize,(),()> {
+        let from = self.seq.0.load(RELAXED);
+        let mut upto = self.shared.available(from);
+        for dep in self.after.iter() {
+            upto = upto.min(dep.0.load(ACQUIRE));
+        }
+        if upto <= from {
+            //writers publish before they go, so check for them first
+            if self.shared.writers.load(ACQUIRE) == 0 && self.shared.available(from) <= from {
+                return Async::Err(());
+            }
+            return Async::Block(());
+        }
+        for seq in from..upto {
+            f(seq, unsafe{ &*self.shared.slots[seq & self.shared.mask].get() });
+        }
+        self.seq.0.store(upto, RELEASE);
+        Async::Ok(upto - from)
+    }
+    ///Like `poll` but waits until at least one event is ready
+    ///
+    ///Returns Err(()) once every Writer is gone and everything published
+    ///was handled
+    #[allow(clippy::result_unit_err)]
+    pub fn wait<F: FnMut(usize, &T)>(&mut self, mut f: F) -> Result<usize,()> {
+        let mut step = 0;
+        loop {
+            match self.poll(&mut f) {
+                Async::Ok(n) => return Ok(n),
+                Async::Block(()) => backoff(&mut step),
+                Async::Err(()) => return Err(())
+            };
+        }
+    }
+    ///Number of events this consumer handled
+    #[inline(always)]
+    pub fn sequence(&self) -> usize {
+        self.seq.0.load(RELAXED)
+    }
+}
+impl<T,W: WriterMode> Drop for Consumer<T,W> {
+    fn drop(&mut self) {
+        //stop gating writers and dependents on a consumer that is gone
+        self.seq.0.store(usize::MAX, RELEASE);
+    }
+}
+
+#[test]
+fn test_disruptor_single() {
+    use std::thread;
+    let mut ring = Disruptor::<u64,Single>::new(8, || 0);
+    let mut parse = ring.consumer(&[]);
+    let mut journal = ring.consumer(&[]);
+    let mut apply = ring.consumer(&[&parse, &journal]);
+    let mut writer = ring.writer();
+    let producer = thread::spawn(move || {
+        for i in 0..250u64 {
+            if i % 5 == 0 {
+                let mut batch = writer.claim(5);
+                for j in 0..5 {
+                    batch[j] = i + j as u64;
+                }
+            }
+        }
+    });
+    let parse = thread::spawn(move || {
+        let mut sum = 0;
+        while parse.wait(|_, x| sum += *x).is_ok() { }
+        sum
+    });
+    let journal = thread::spawn(move || {
+        let mut next = 0;
+        while journal.wait(|seq, x| {
+            assert_eq!(seq as u64, *x);
+            assert_eq!(next, *x);
+            next += 1;
+        }).is_ok() { }
+        next
+    });
+    let mut seen = 0;
+    while apply.wait(|_, _| seen += 1).is_ok() { }
+    producer.join().unwrap();
+    assert_eq!(parse.join().unwrap(), 249 * 250 / 2);
+    assert_eq!(journal.join().unwrap(), 250);
+    assert_eq!(seen, 250);
+}
+
+#[test]
+fn test_disruptor_multi() {
+    use std::thread;
+    let mut ring = Disruptor::<usize,Multi>::new(4, || 0);
+    let mut reader = ring.consumer(&[]);
+    let writer = ring.writer();
+    let writers = (0..3).map(|t| {
+        let mut writer = writer.clone();
+        thread::spawn(move || {
+            for i in 0..100 {
+                writer.publish_with(|slot| *slot = t * 100 + i);
+            }
+        })
+    }).collect::<Vec<_>>();
+    drop(writer);
+    let mut got = Vec::new();
+    while reader.wait(|_, x| got.push(*x)).is_ok() { }
+    for w in writers {
+        w.join().unwrap();
+    }
+    got.sort();
+    assert_eq!(got, (0..300).collect::<Vec<_>>());
+    //a full ring turns writers away until the consumer moves on
+    let mut ring = Disruptor::<u8,Single>::new(2, || 0);
+    let mut reader = ring.consumer(&[]);
+    let mut writer = ring.writer();
+    writer.claim(2).publish();
+    assert!(writer.try_claim(1).is_blocked());
+    assert!(reader.poll(|_, _| ()) == Async::Ok(2));
+    assert!(writer.try_claim(1).is_ok());
+}
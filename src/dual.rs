@@ -0,0 +1,107 @@
+//!Dual lane channels.
+//!
+//!A dual lane channel carries a normal data lane alongside a small control
+//!lane. Receivers always drain the control lane first, so out of band
+//!commands never queue up behind bulk data.
+
+use super::Async;
+use super::mrms::{self,MRMSSender,MRMSReceiver};
+
+///A message taken from one of the two lanes
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Lane<C,D> {
+    Control(C),
+    Data(D)
+}
+
+///Sends on either lane
+pub struct DualSender<C: Sized+'static, D: Sized+'static> {
+    control: MRMSSender<C>,
+    data: MRMSSender<D>
+}
+impl<C: Sized+'static, D: Sized+'static> Clone for DualSender<C,D> {
+    fn clone(&self) -> DualSender<C,D> {
+        DualSender {
+            control: self.control.clone(),
+            data: self.data.clone()
+        }
+    }
+}
+impl<C: Sized+'static, D: Sized+'static> DualSender<C,D> {
+    ///Send on the data lane
+    ///
+    ///Has the same return values as `MRMSSender::send`
+    pub fn send(&self, data: D) -> Async<(),D,D> {
+        self.data.send(data)
+    }
+    ///Send on the control lane
+    ///
+    ///Has the same return values as `MRMSSender::send`
+    pub fn send_control(&self, cmd: C) -> Async<(),C,C> {
+        self.control.send(cmd)
+    }
+}
+
+///Receives from both lanes, control first
+pub struct DualReceiver<C: Sized+'static, D: Sized+'static> {
+    control: MRMSReceiver<C>,
+    data: MRMSReceiver<D>
+}
+impl<C: Sized+'static, D: Sized+'static> Clone for DualReceiver<C,D> {
+    fn clone(&self) -> DualReceiver<C,D> {
+        DualReceiver {
+            control: self.control.clone(),
+            data: self.data.clone()
+        }
+    }
+}
+impl<C: Sized+'static, D: Sized+'static> DualReceiver<C,D> {
+    ///Receive items
+    ///
+    ///Returns Async::Ok(Option<Lane>) an item may have returned, control
+    ///messages are always handed out before data
+    ///Returns Async::Block(()) either lane is blocked
+    ///Returns Async::Err(()) if there is no sender nor messages to read
+    pub fn recv(&self) -> Async<Option<Lane<C,D>>,(),()> {
+        let control_closed = match self.control.recv() {
+            Async::Ok(Option::Some(cmd)) => return Async::Ok(Some(Lane::Control(cmd))),
+            //data must not overtake a control message we could not see
+            Async::Block(()) => return Async::Block(()),
+            Async::Ok(Option::None) => false,
+            Async::Err(()) => true
+        };
+        match self.data.recv() {
+            Async::Ok(x) => Async::Ok(x.map(Lane::Data)),
+            Async::Block(()) => Async::Block(()),
+            Async::Err(()) if control_closed => Async::Err(()),
+            Async::Err(()) => Async::Ok(None)
+        }
+    }
+}
+
+///Build a new dual lane channel
+///
+///Accepts sized arguments to pre-size each lane
+pub fn channel<C: Sized+'static, D: Sized+'static>(control_size: usize, data_size: usize) -> (DualSender<C,D>,DualReceiver<C,D>) {
+    let (cs,cr) = mrms::channel(control_size);
+    let (ds,dr) = mrms::channel(data_size);
+    (DualSender { control: cs, data: ds }, DualReceiver { control: cr, data: dr })
+}
+
+#[test]
+fn test_dual_control_first() {
+    let (s,r) = channel::<&'static str,usize>(2, 16);
+    for x in 0..10 {
+        assert!(s.send(x).is_ok());
+    }
+    assert!(s.send_control("pause").is_ok());
+    assert!(r.recv() == Async::Ok(Some(Lane::Control("pause"))));
+    assert!(r.recv() == Async::Ok(Some(Lane::Data(0))));
+    drop(s);
+    let mut rest = 0;
+    while let Async::Ok(Option::Some(_)) = r.recv() {
+        rest += 1;
+    }
+    assert_eq!(rest, 9);
+    assert!(r.recv().is_err());
+}
@@ -0,0 +1,263 @@
+//!Epoch based memory reclamation.
+//!
+//!A thread `pin`s itself before it reads shared pointers and keeps the
+//!Guard for as long as it uses what it read. Memory unlinked from a shared
+//!structure is handed to `Guard::defer` instead of being freed. The global
+//!epoch only moves forward once every pinned thread has seen the current
+//!one, so anything deferred in epoch `e` can be freed once the global
+//!epoch reached `e + 2`, no pinned thread can still hold a pointer to it.
+//!
+//!Deferred work is gathered in a per thread bag and moved to a global list
+//!when the bag fills, the Guard is flushed, or the thread exits. `collect`
+//!tries to advance the epoch and runs whatever became safe. Pinning calls
+//!it every so often on its own.
+
+
+use super::spinlock::SpinLock;
+use std::cell::{Cell,RefCell};
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{self,AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Deferred functions a thread holds before moving them to the global list
+const BAG_SIZE: usize = 32;
+///Pins between two automatic collections, a power of two
+const COLLECT_EVERY: usize = 128;
+///Set in a participant's state while it is pinned
+const PINNED: usize = 1;
+
+type Deferred = Box<dyn FnOnce() + Send + 'static>;
+
+///Pinned epoch of one thread, `epoch << 1 | PINNED` or 0
+struct Participant {
+    state: AtomicUsize
+}
+
+struct Global {
+    epoch: AtomicUsize,
+    participants: SpinLock<Vec<Arc<Participant>>>,
+    garbage: SpinLock<Vec<(usize,Deferred)>>
+}
+static GLOBAL: Global = Global {
+    epoch: AtomicUsize::new(0),
+    participants: SpinLock::new(Vec::new()),
+    garbage: SpinLock::new(Vec::new())
+};
+impl Global {
+    ///Move the epoch forward if every pinned thread has seen the current
+    ///one, returns the epoch after the attempt
+    fn try_advance(&self) -> usize {
+        let epoch = self.epoch.load(RELAXED);
+        atomic::fence(SEQ);
+        for p in self.participants.lock().iter() {
+            let state = p.state.load(RELAXED);
+            if state & PINNED != 0 && state >> 1 != epoch {
+                return epoch;
+            }
+        }
+        atomic::fence(ACQUIRE);
+        match self.epoch.compare_exchange(epoch, epoch + 1, RELEASE, RELAXED) {
+            Ok(_) => epoch + 1,
+            Err(x) => x
+        }
+    }
+    fn collect(&self) {
+        let epoch = self.try_advance();
+        let ready = {
+            let mut garbage = self.garbage.lock();
+            let (ready,keep): (Vec<_>,Vec<_>) = mem::take(&mut *garbage)
+                .into_iter()
+                .partition(|&(e,_)| e + 2 <= epoch);
+            *garbage = keep;
+            ready
+        };
+        //run outside of the lock, a destructor may defer more work
+        for (_,f) in ready {
+            f();
+        }
+    }
+}
+
+///Per thread registration
+struct Local {
+    participant: Arc<Participant>,
+    depth: Cell<usize>,
+    pins: Cell<usize>,
+    bag: RefCell<Vec<(usize,Deferred)>>
+}
+impl Local {
+    fn register() -> Rc<Local> {
+        let participant = Arc::new(Participant {
+            state: AtomicUsize::new(0)
+        });
+        GLOBAL.participants.lock().push(participant.clone());
+        Rc::new(Local {
+            participant,
+            depth: Cell::new(0),
+            pins: Cell::new(0),
+            bag: RefCell::new(Vec::new())
+        })
+    }
+    fn flush(&self) {
+        let bag = mem::take(&mut *self.bag.borrow_mut());
+        if !bag.is_empty() {
+            GLOBAL.garbage.lock().extend(bag);
+        }
+    }
+}
+impl Drop for Local {
+    fn drop(&mut self) {
+        self.flush();
+        let p = &self.participant;
+        GLOBAL.participants.lock().retain(|x| !Arc::ptr_eq(x, p));
+    }
+}
+
+thread_local!(static LOCAL: Rc<Local> = Local::register());
+
+///Keeps the calling thread pinned to an epoch while it is alive
+///
+///Guards nest, the thread stays pinned until the last one is dropped.
+pub struct Guard {
+    local: Rc<Local>,
+    ///guards belong to the thread that pinned
+    marker: PhantomData<*const ()>
+}
+impl Guard {
+    ///Run `f` once no thread can still be using memory it could read
+    ///before this call
+    pub fn defer<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let epoch = GLOBAL.epoch.load(SEQ);
+        let full = {
+            let mut bag = self.local.bag.borrow_mut();
+            bag.push((epoch, Box::new(f)));
+            bag.len() >= BAG_SIZE
+        };
+        if full {
+            self.local.flush();
+        }
+    }
+    ///Like `defer` without the Send and 'static bounds
+    ///
+    ///# Safety
+    ///
+    ///`f` may run on any thread at any later point, whatever it captures
+    ///has to stay valid and be safe to use from there.
+    pub unsafe fn defer_unchecked<'a,F>(&self, f: F)
+    where
+        F: FnOnce() + 'a,
+    {
+        let f: Box<dyn FnOnce() + 'a> = Box::new(f);
+        let f: Deferred = mem::transmute(f);
+        self.defer(f);
+    }
+    ///Free a boxed pointer once no thread can still be reading it
+    ///
+    ///# Safety
+    ///
+    ///`ptr` must come from `Box::into_raw`, be unreachable for threads that
+    ///pin after this call, and not be freed anywhere else.
+    pub unsafe fn defer_destroy<T>(&self, ptr: *mut T) {
+        self.defer_unchecked(move || drop(Box::from_raw(ptr)));
+    }
+    ///Move this thread's deferred work to the global list and collect
+    pub fn flush(&self) {
+        self.local.flush();
+        GLOBAL.collect();
+    }
+    ///Epoch the thread is pinned to
+    pub fn epoch(&self) -> usize {
+        self.local.participant.state.load(RELAXED) >> 1
+    }
+}
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let depth = self.local.depth.get() - 1;
+        self.local.depth.set(depth);
+        if depth == 0 {
+            self.local.participant.state.store(0, RELEASE);
+        }
+    }
+}
+
+///Pin the calling thread to the current epoch
+pub fn pin() -> Guard {
+    //a thread that already lost its thread local still gets a
+    //registration of its own, released with the guard
+    let local = LOCAL.try_with(|l| l.clone()).unwrap_or_else(|_| Local::register());
+    let depth = local.depth.get();
+    local.depth.set(depth + 1);
+    let guard = Guard {
+        local,
+        marker: PhantomData
+    };
+    if depth == 0 {
+        let local = &guard.local;
+        let epoch = GLOBAL.epoch.load(RELAXED);
+        local.participant.state.store(epoch << 1 | PINNED, RELAXED);
+        atomic::fence(SEQ);
+        let pins = local.pins.get().wrapping_add(1);
+        local.pins.set(pins);
+        if pins & (COLLECT_EVERY - 1) == 0 {
+            GLOBAL.collect();
+        }
+    }
+    guard
+}
+
+///Returns true if the calling thread is pinned
+pub fn is_pinned() -> bool {
+    LOCAL.try_with(|l| l.depth.get() > 0).unwrap_or(false)
+}
+
+///Try to advance the epoch and run deferred work that became safe
+pub fn collect() {
+    GLOBAL.collect();
+}
+
+#[test]
+fn test_epoch_defer() {
+    use std::thread;
+    let ran = Arc::new(AtomicUsize::new(0));
+    {
+        let guard = pin();
+        let inner = pin();
+        assert!(is_pinned());
+        for _ in 0..10 {
+            let ran = ran.clone();
+            guard.defer(move || { ran.fetch_add(1, RELAXED); });
+        }
+        let node = Box::into_raw(Box::new(5usize));
+        unsafe{ inner.defer_destroy(node) };
+        drop(inner);
+        assert!(is_pinned());
+        //still pinned, nothing may run yet
+        guard.flush();
+        guard.flush();
+        assert_eq!(ran.load(RELAXED), 0);
+    }
+    assert!(!is_pinned());
+    //deferred work from a thread that exited is not lost
+    let r = ran.clone();
+    thread::spawn(move || {
+        pin().defer(move || { r.fetch_add(1, RELAXED); });
+    }).join().unwrap();
+    //other tests may keep a thread pinned for a moment
+    for _ in 0..10000 {
+        if ran.load(RELAXED) == 11 {
+            break;
+        }
+        collect();
+        thread::yield_now();
+    }
+    assert_eq!(ran.load(RELAXED), 11);
+}
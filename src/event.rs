@@ -0,0 +1,179 @@
+//!Event notification with permit semantics.
+//!
+//!`notify_one` wakes the longest waiting thread, or if nobody is waiting
+//!leaves a single permit behind that the next `wait` consumes without
+//!blocking, so a notify that races ahead of its wait is not lost. Permits
+//!do not stack. `notify_all` wakes everybody waiting at that moment and
+//!leaves no permit.
+
+
+use super::spinlock::SpinLock;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread::{self,Thread};
+use std::time::{Duration,Instant};
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+
+struct Waiter {
+    thread: Thread,
+    notified: AtomicBool
+}
+impl Waiter {
+    fn wake(&self) {
+        self.notified.store(true, RELEASE);
+        self.thread.unpark();
+    }
+}
+
+struct State {
+    permit: bool,
+    waiters: VecDeque<Arc<Waiter>>
+}
+
+///Wakes waiting threads, remembering one notification nobody waited for
+pub struct Event {
+    state: SpinLock<State>
+}
+impl Event {
+    ///Build an event without a permit
+    #[inline(always)]
+    pub const fn new() -> Event {
+        Event {
+            state: SpinLock::new(State {
+                permit: false,
+                waiters: VecDeque::new()
+            })
+        }
+    }
+    ///Wake one waiter, or store a permit if nobody is waiting
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Option::Some(w) => w.wake(),
+            Option::None => state.permit = true
+        };
+    }
+    ///Wake every thread currently waiting
+    pub fn notify_all(&self) {
+        let waiters = ::std::mem::take(&mut self.state.lock().waiters);
+        for w in waiters {
+            w.wake();
+        }
+    }
+    ///Consume the permit if there is one, without blocking
+    pub fn try_wait(&self) -> bool {
+        ::std::mem::replace(&mut self.state.lock().permit, false)
+    }
+    ///Consume the permit or queue up as a waiter
+    fn enqueue(&self) -> Option<Arc<Waiter>> {
+        let mut state = self.state.lock();
+        if state.permit {
+            state.permit = false;
+            return None;
+        }
+        let waiter = Arc::new(Waiter {
+            thread: thread::current(),
+            notified: AtomicBool::new(false)
+        });
+        state.waiters.push_back(waiter.clone());
+        Some(waiter)
+    }
+    ///Block until notified, returns right away if a permit is stored
+    pub fn wait(&self) {
+        if let Option::Some(waiter) = self.enqueue() {
+            while !waiter.notified.load(ACQUIRE) {
+                thread::park();
+            }
+        }
+    }
+    ///Block until notified or `timeout` passes
+    ///
+    ///Returns true if the thread was notified. A timeout too long to express
+    ///as an Instant waits for good.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let waiter = match self.enqueue() {
+            Option::Some(w) => w,
+            Option::None => return true
+        };
+        loop {
+            if waiter.notified.load(ACQUIRE) {
+                return true;
+            }
+            match deadline {
+                Option::None => thread::park(),
+                Option::Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            };
+        }
+        let mut state = self.state.lock();
+        match state.waiters.iter().position(|w| Arc::ptr_eq(w, &waiter)) {
+            Option::Some(pos) => {
+                state.waiters.remove(pos);
+                false
+            }
+            //notified between the deadline and taking the lock
+            Option::None => true
+        }
+    }
+    ///Number of threads waiting
+    pub fn waiters(&self) -> usize {
+        self.state.lock().waiters.len()
+    }
+}
+impl Default for Event {
+    fn default() -> Event {
+        Event::new()
+    }
+}
+
+#[test]
+fn test_event_permit() {
+    let event = Event::new();
+    //notify before wait is remembered once
+    event.notify_one();
+    event.notify_one();
+    event.wait();
+    assert!(!event.try_wait());
+    assert!(!event.wait_timeout(Duration::from_millis(5)));
+    assert_eq!(event.waiters(), 0);
+    event.notify_one();
+    assert!(event.wait_timeout(Duration::MAX));
+    //notify_all leaves no permit
+    event.notify_all();
+    assert!(!event.try_wait());
+}
+
+#[test]
+fn test_event_wakes() {
+    use std::sync::atomic::AtomicUsize;
+    let event = Arc::new(Event::new());
+    let woken = Arc::new(AtomicUsize::new(0));
+    let waiters = (0..3).map(|_| {
+        let event = event.clone();
+        let woken = woken.clone();
+        thread::spawn(move || {
+            event.wait();
+            woken.fetch_add(1, RELEASE);
+        })
+    }).collect::<Vec<_>>();
+    while event.waiters() < 3 {
+        thread::yield_now();
+    }
+    event.notify_one();
+    while woken.load(ACQUIRE) < 1 {
+        thread::yield_now();
+    }
+    event.notify_all();
+    for w in waiters {
+        w.join().unwrap();
+    }
+    assert_eq!(woken.load(ACQUIRE), 3);
+}
@@ -0,0 +1,241 @@
+//!Topic filtered broadcast over MRMS channels.
+//!
+//!Every subscriber gets its own channel and a filter, either one topic or
+//!a predicate over topics. `publish` sends a clone of the message down
+//!each channel whose filter matches. Subscribers hold only a weak
+//!reference to the bus and remove their entry when dropped, so publishers
+//!never see them again. Once every handle to the bus is gone the channels
+//!lose their senders and subscribers see the end.
+
+
+use super::Async;
+use super::mrms::{channel,MRMSReceiver,MRMSSender};
+use super::rwlock::RwSpinLock;
+use super::backoff::{backoff,Backoff};
+use std::sync::{Arc,Weak};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::Duration;
+const RELAXED: Ordering = Ordering::Relaxed;
+
+///Messages a subscriber channel is pre-sized for
+pub const DEFAULT_CAPACITY: usize = 16;
+
+enum Filter<K> {
+    Topic(K),
+    Predicate(Box<dyn Fn(&K) -> bool + Send + Sync>)
+}
+impl<K: PartialEq> Filter<K> {
+    #[inline(always)]
+    fn matches(&self, topic: &K) -> bool {
+        match *self {
+            Filter::Topic(ref k) => k == topic,
+            Filter::Predicate(ref f) => f(topic)
+        }
+    }
+}
+
+struct Entry<K,T: Send+'static> {
+    id: usize,
+    filter: Filter<K>,
+    sender: MRMSSender<T>
+}
+
+struct Inner<K,T: Send+'static> {
+    subscribers: RwSpinLock<Vec<Entry<K,T>>>,
+    next_id: AtomicUsize,
+    capacity: usize
+}
+impl<K,T: Send+'static> Inner<K,T> {
+    fn remove(&self, id: usize) {
+        self.subscribers.write().retain(|e| e.id != id);
+    }
+}
+
+///Handle for publishing to and subscribing on a bus, clones share it
+pub struct EventBus<K,T: Send+'static> {
+    inner: Arc<Inner<K,T>>
+}
+impl<K,T: Send+'static> Clone for EventBus<K,T> {
+    fn clone(&self) -> EventBus<K,T> {
+        EventBus {
+            inner: self.inner.clone()
+        }
+    }
+}
+impl<K: PartialEq,T: Clone+Send+'static> EventBus<K,T> {
+    ///Build a bus with no subscribers
+    pub fn new() -> EventBus<K,T> {
+        EventBus::with_capacity(DEFAULT_CAPACITY)
+    }
+    ///Build a bus whose subscriber channels are pre-sized for `capacity`
+    ///messages
+    pub fn with_capacity(capacity: usize) -> EventBus<K,T> {
+        EventBus {
+            inner: Arc::new(Inner {
+                subscribers: RwSpinLock::new(Vec::new()),
+                next_id: AtomicUsize::new(0),
+                capacity
+            })
+        }
+    }
+    fn register(&self, filter: Filter<K>) -> Subscriber<K,T> {
+        let (sender, receiver) = channel(self.inner.capacity);
+        let id = self.inner.next_id.fetch_add(1, RELAXED);
+        self.inner.subscribers.write().push(Entry { id, filter, sender });
+        Subscriber {
+            receiver,
+            bus: Arc::downgrade(&self.inner),
+            id
+        }
+    }
+    ///Receive every message published on `topic`
+    pub fn subscribe(&self, topic: K) -> Subscriber<K,T> {
+        self.register(Filter::Topic(topic))
+    }
+    ///Receive every message whose topic `f` returns true for
+    pub fn subscribe_with<F>(&self, f: F) -> Subscriber<K,T>
+    where
+        F: Fn(&K) -> bool + Send + Sync + 'static,
+    {
+        self.register(Filter::Predicate(Box::new(f)))
+    }
+    ///Send a clone of `msg` to every subscriber matching `topic`
+    ///
+    ///Returns how many subscribers it was delivered to
+    pub fn publish(&self, topic: &K, msg: T) -> usize {
+        let mut delivered = 0;
+        let mut dead = false;
+        {
+            let subscribers = self.inner.subscribers.read();
+            for e in subscribers.iter().filter(|e| e.filter.matches(topic)) {
+                let mut item = msg.clone();
+                let mut step = 0;
+                loop {
+                    match e.sender.send(item) {
+                        Async::Ok(()) => {
+                            delivered += 1;
+                            break;
+                        }
+                        Async::Block(x) => {
+                            item = x;
+                            backoff(&mut step);
+                        }
+                        Async::Err(_) => {
+                            dead = true;
+                            break;
+                        }
+                    };
+                }
+            }
+        }
+        if dead {
+            //a subscriber's receiver went away before its drop removed it
+            self.inner.subscribers.write().retain(|e| !e.sender.poll_ready().is_err());
+        }
+        delivered
+    }
+    ///Subscribers registered right now
+    pub fn subscribers(&self) -> usize {
+        self.inner.subscribers.read().len()
+    }
+}
+impl<K: PartialEq,T: Clone+Send+'static> Default for EventBus<K,T> {
+    fn default() -> EventBus<K,T> {
+        EventBus::new()
+    }
+}
+
+///Receiving end of one subscription, unsubscribes when dropped
+pub struct Subscriber<K,T: Send+'static> {
+    receiver: MRMSReceiver<T>,
+    bus: Weak<Inner<K,T>>,
+    id: usize
+}
+impl<K,T: Send+'static> Subscriber<K,T> {
+    ///Take the next message if one is queued
+    ///
+    ///Returns Ok(None) if nothing is queued, Block if the channel is
+    ///contended, Err once the bus is gone and everything is drained
+    pub fn try_recv(&self) -> Async<Option<T>,(),()> {
+        self.receiver.recv()
+    }
+    ///Wait for the next message
+    ///
+    ///Returns None once the bus is gone and everything is drained
+    pub fn recv(&self) -> Option<T> {
+        let backoff = Backoff::new();
+        loop {
+            match self.receiver.recv() {
+                Async::Ok(Option::Some(msg)) => return Some(msg),
+                Async::Err(()) => return None,
+                Async::Ok(Option::None) |
+                Async::Block(()) => { }
+            };
+            backoff.snooze();
+        }
+    }
+    ///Wait up to `timeout` for the next message
+    ///
+    ///Has the same return values as `MRMSReceiver::recv_timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Async<T,(),()> {
+        self.receiver.recv_timeout(timeout)
+    }
+}
+impl<K,T: Send+'static> Drop for Subscriber<K,T> {
+    fn drop(&mut self) {
+        if let Option::Some(bus) = self.bus.upgrade() {
+            bus.remove(self.id);
+        }
+    }
+}
+
+#[test]
+fn test_eventbus_topics() {
+    let bus = EventBus::new();
+    let a = bus.subscribe("a");
+    let b = bus.subscribe("b");
+    let all = bus.subscribe_with(|_: &&str| true);
+    assert_eq!(bus.publish(&"a", 1), 2);
+    assert_eq!(bus.publish(&"b", 2), 2);
+    assert_eq!(bus.publish(&"c", 3), 1);
+    assert_eq!(a.recv(), Some(1));
+    assert!(matches!(a.try_recv(), Async::Ok(Option::None)));
+    assert_eq!(b.recv(), Some(2));
+    assert_eq!((all.recv(), all.recv(), all.recv()), (Some(1), Some(2), Some(3)));
+    //dropped subscribers leave the bus
+    drop(b);
+    assert_eq!(bus.subscribers(), 2);
+    assert_eq!(bus.publish(&"b", 4), 1);
+    //dropping the bus ends every subscription
+    drop(bus);
+    assert_eq!(all.recv(), Some(4));
+    assert_eq!(all.recv(), None);
+    assert!(a.try_recv().is_err());
+}
+
+#[test]
+fn test_eventbus_threads() {
+    use std::thread;
+    let bus = EventBus::new();
+    let subs = (0..2).map(|_| bus.subscribe(0u8)).collect::<Vec<_>>();
+    let publishers = (0..2).map(|_| {
+        let bus = bus.clone();
+        thread::spawn(move || {
+            for i in 0..250usize {
+                bus.publish(&0, i);
+                bus.publish(&1, i);
+            }
+        })
+    }).collect::<Vec<_>>();
+    for p in publishers {
+        p.join().unwrap();
+    }
+    drop(bus);
+    for s in subs {
+        let mut total = 0;
+        while let Option::Some(x) = s.recv() {
+            total += x;
+        }
+        assert_eq!(total, 2 * 31125);
+    }
+}
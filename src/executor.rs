@@ -0,0 +1,413 @@
+//!Minimal futures executor.
+//!
+//!`block_on` drives one future on the calling thread, parking it between
+//!wakeups. `Executor` runs spawned futures on a fixed set of workers. A
+//!woken task is sent down an MRMS channel that every worker receives from,
+//!each task carries a small state word so it is queued at most once and a
+//!wakeup that arrives while it is being polled is not lost.
+//!
+//!On a single threaded target an `Executor` starts no workers. Woken tasks
+//!wait in the queue and are run by `Executor::block_on` and
+//!`JoinHandle::join` whenever the future they drive is pending, and a wait
+//!that nothing could ever wake panics instead of parking forever.
+
+
+use super::Async;
+use super::mrms::{self,MRMSSender,MRMSReceiver};
+use super::backoff::{backoff,would_block_forever,SINGLE_THREADED};
+use super::spinlock::SpinLock;
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self,AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc,Weak};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use std::task::{Context,Poll,Wake,Waker};
+use std::thread::{self,JoinHandle as ThreadHandle,Thread};
+use std::time::Duration;
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+///Empty polls an idle worker makes before parking
+const IDLE_POLLS: u32 = 8;
+///Longest an idle worker parks before checking the queue again
+const PARK_LIMIT: Duration = Duration::from_millis(1);
+
+//task states
+const IDLE: usize = 0;
+const SCHEDULED: usize = 1;
+const RUNNING: usize = 2;
+const NOTIFIED: usize = 3;
+const DONE: usize = 4;
+
+///Wakes the thread blocked in `block_on`
+struct Unpark {
+    thread: Thread,
+    woken: AtomicBool
+}
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, RELEASE);
+        self.thread.unpark();
+    }
+}
+
+///Run `future` to completion on the calling thread
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    drive(None, future)
+}
+
+///Run `future` to completion, on a single threaded target running the
+///tasks queued on `shared` while it is pending
+fn drive<F: Future>(shared: Option<&Shared>, future: F) -> F::Output {
+    let unpark = Arc::new(Unpark {
+        thread: thread::current(),
+        woken: AtomicBool::new(false)
+    });
+    let waker = Waker::from(unpark.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(x) = future.as_mut().poll(&mut cx) {
+            return x;
+        }
+        if SINGLE_THREADED {
+            let ran = shared.map(|s| s.run_queued()).unwrap_or(false);
+            if !unpark.woken.swap(false, ACQUIRE) && !ran {
+                would_block_forever();
+            }
+            continue;
+        }
+        while !unpark.woken.swap(false, ACQUIRE) {
+            thread::park();
+        }
+    }
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output=()> + Send + 'static>>;
+
+///State shared by the executor handle and its workers
+struct Shared {
+    sender: MRMSSender<Arc<Task>>,
+    ///the queue, kept here when there are no workers to receive from it
+    local: Option<MRMSReceiver<Arc<Task>>>,
+    threads: SpinLock<Vec<Thread>>,
+    next: AtomicUsize,
+    shutdown: AtomicBool
+}
+impl Shared {
+    fn schedule(&self, task: Arc<Task>) {
+        let mut task = task;
+        let mut step = 0;
+        loop {
+            match self.sender.send(task) {
+                Async::Ok(()) => break,
+                Async::Block(x) => {
+                    task = x;
+                    backoff(&mut step);
+                }
+                //every worker is gone, nothing would run it
+                Async::Err(_) => return
+            };
+        }
+        let threads = self.threads.lock();
+        if !threads.is_empty() {
+            threads[self.next.fetch_add(1, RELAXED) % threads.len()].unpark();
+        }
+    }
+    ///Run every task queued for the missing workers, returns true if there
+    ///were any
+    fn run_queued(&self) -> bool {
+        let mut ran = false;
+        if let Option::Some(ref local) = self.local {
+            while let Async::Ok(Option::Some(task)) = local.recv() {
+                task.run(self);
+                ran = true;
+            }
+        }
+        ran
+    }
+}
+
+///A spawned future. Wakers hold the task, the task only holds a weak
+///reference back to the executor so queued tasks never keep it alive.
+struct Task {
+    future: SpinLock<Option<BoxFuture>>,
+    state: AtomicUsize,
+    shared: Weak<Shared>
+}
+impl Task {
+    fn run(self: Arc<Self>, shared: &Shared) {
+        self.state.store(RUNNING, RELAXED);
+        let waker = Waker::from(self.clone());
+        let mut cx = Context::from_waker(&waker);
+        let ready = {
+            let mut slot = self.future.lock();
+            //Spawned catches panics of the user's future
+            let ready = match *slot {
+                Option::Some(ref mut future) => future.as_mut().poll(&mut cx).is_ready(),
+                Option::None => true
+            };
+            if ready {
+                *slot = None;
+            }
+            ready
+        };
+        if ready {
+            self.state.store(DONE, RELEASE);
+        } else if self.state.compare_exchange(RUNNING, IDLE, ACQREL, ACQUIRE).is_err() {
+            //woken while polling
+            self.state.store(SCHEDULED, RELAXED);
+            shared.schedule(self);
+        }
+    }
+}
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut state = self.state.load(ACQUIRE);
+        loop {
+            let next = match state {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                _ => return
+            };
+            match self.state.compare_exchange_weak(state, next, ACQREL, ACQUIRE) {
+                Ok(_) => break,
+                Err(x) => state = x
+            };
+        }
+        if state == IDLE {
+            if let Option::Some(shared) = self.shared.upgrade() {
+                shared.schedule(self.clone());
+            }
+        }
+    }
+}
+
+///Result of a spawned future, filled in by the worker that finished it
+struct Output<T> {
+    value: Option<thread::Result<T>>,
+    waker: Option<Waker>
+}
+
+///Wraps a spawned future to store its output for the JoinHandle
+struct Spawned<F: Future> {
+    future: Pin<Box<F>>,
+    output: Arc<SpinLock<Output<F::Output>>>
+}
+impl<F: Future> Future for Spawned<F> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        let value = match panic::catch_unwind(AssertUnwindSafe(|| this.future.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => return Poll::Pending,
+            Ok(Poll::Ready(x)) => Ok(x),
+            Err(e) => Err(e)
+        };
+        let waker = {
+            let mut output = this.output.lock();
+            output.value = Some(value);
+            output.waker.take()
+        };
+        if let Option::Some(waker) = waker {
+            waker.wake();
+        }
+        Poll::Ready(())
+    }
+}
+
+///Handle to the output of a spawned future. It is itself a future, or
+///can be waited on with `join`.
+pub struct JoinHandle<T> {
+    output: Arc<SpinLock<Output<T>>>,
+    shared: Weak<Shared>
+}
+impl<T> JoinHandle<T> {
+    ///Block until the future finishes
+    ///
+    ///Returns Err with the panic payload if the future panicked
+    pub fn join(self) -> Result<T,Box<dyn Any + Send + 'static>> {
+        let shared = if SINGLE_THREADED { self.shared.upgrade() } else { None };
+        drive(shared.as_deref(), self)
+    }
+    ///Returns true once the future finished
+    pub fn is_finished(&self) -> bool {
+        self.output.lock().value.is_some()
+    }
+}
+impl<T> Future for JoinHandle<T> {
+    type Output = thread::Result<T>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<thread::Result<T>> {
+        let mut output = self.output.lock();
+        match output.value.take() {
+            Option::Some(x) => Poll::Ready(x),
+            Option::None => {
+                output.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+///Runs spawned futures on a fixed number of worker threads
+///
+///Dropping the executor stops the workers, futures that have not finished
+///are dropped with it.
+pub struct Executor {
+    shared: Arc<Shared>,
+    workers: Vec<ThreadHandle<()>>
+}
+impl Executor {
+    ///Start an executor with `n` worker threads, at least one
+    pub fn new(n: usize) -> Executor {
+        let (sender,receiver) = mrms::channel::<Arc<Task>>(64);
+        let n = if SINGLE_THREADED { 0 } else { n.max(1) };
+        let shared = Arc::new(Shared {
+            sender,
+            local: if n == 0 { Some(receiver.clone()) } else { None },
+            threads: SpinLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false)
+        });
+        let workers = (0..n).map(|i| {
+            let receiver = receiver.clone();
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("executor-worker-{}", i))
+                .spawn(move || work(receiver, shared))
+                .expect("failed to spawn executor worker")
+        }).collect::<Vec<_>>();
+        *shared.threads.lock() = workers.iter().map(|w| w.thread().clone()).collect();
+        Executor { shared, workers }
+    }
+    ///Run `future` on one of the workers
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let output = Arc::new(SpinLock::new(Output {
+            value: None,
+            waker: None
+        }));
+        let task = Arc::new(Task {
+            future: SpinLock::new(Some(Box::pin(Spawned {
+                future: Box::pin(future),
+                output: output.clone()
+            }))),
+            state: AtomicUsize::new(SCHEDULED),
+            shared: Arc::downgrade(&self.shared)
+        });
+        self.shared.schedule(task);
+        JoinHandle {
+            output,
+            shared: Arc::downgrade(&self.shared)
+        }
+    }
+    ///Run `future` on the calling thread while the workers keep going
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        drive(Some(&self.shared), future)
+    }
+    ///Number of worker threads, zero on a single threaded target
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+}
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, RELEASE);
+        for t in self.shared.threads.lock().iter() {
+            t.unpark();
+        }
+        for w in self.workers.drain(..) {
+            let _ = w.join();
+        }
+    }
+}
+
+///Worker loop, polls woken tasks until the executor is dropped
+fn work(receiver: MRMSReceiver<Arc<Task>>, shared: Arc<Shared>) {
+    let mut idle = 0;
+    let mut step = 0;
+    while !shared.shutdown.load(ACQUIRE) {
+        match receiver.recv() {
+            Async::Ok(Option::Some(task)) => {
+                idle = 0;
+                step = 0;
+                task.run(&shared);
+            }
+            Async::Ok(Option::None) |
+            Async::Block(()) => {
+                idle += 1;
+                if idle < IDLE_POLLS {
+                    backoff(&mut step);
+                } else {
+                    thread::park_timeout(PARK_LIMIT);
+                }
+            }
+            Async::Err(()) => return
+        };
+    }
+}
+
+#[cfg(test)]
+struct Yield(usize);
+#[cfg(test)]
+impl Future for Yield {
+    type Output = usize;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<usize> {
+        if self.0 == 0 {
+            return Poll::Ready(7);
+        }
+        self.0 -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test]
+fn test_block_on() {
+    assert_eq!(block_on(Yield(5)), 7);
+}
+
+#[test]
+fn test_executor_spawn() {
+    let exec = Executor::new(2);
+    let handles = (0..20).map(|i| exec.spawn(Yield(i))).collect::<Vec<_>>();
+    for h in handles {
+        assert_eq!(h.join().unwrap(), 7);
+    }
+    struct Fail;
+    impl Future for Fail {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+            panic!("task failed")
+        }
+    }
+    //the worker survives a panicking future
+    assert!(exec.block_on(exec.spawn(Fail)).is_err());
+    assert_eq!(exec.spawn(Yield(1)).join().unwrap(), 7);
+}
+
+#[cfg(single_threaded)]
+#[test]
+fn single_thread_executor() {
+    let exec = Executor::new(4);
+    assert_eq!(exec.workers(), 0);
+    let handles = (0..4).map(|i| exec.spawn(Yield(i))).collect::<Vec<_>>();
+    //queued tasks run while the thread waits on one of them
+    assert_eq!(exec.block_on(exec.spawn(Yield(2))).unwrap(), 7);
+    for h in handles {
+        assert!(h.is_finished());
+        assert_eq!(h.join().unwrap(), 7);
+    }
+    assert_eq!(exec.spawn(Yield(3)).join().unwrap(), 7);
+}
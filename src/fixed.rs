@@ -0,0 +1,318 @@
+//!Fixed capacity channels.
+//!
+//!Storage is an inline ring buffer of `N` slots, so once the channel has
+//!been built sending and receiving never touch the allocator. A full
+//!channel hands the item back in the Block arm instead of growing.
+
+use super::Async;
+use super::spinlock::{LoanLock,Lock};
+use super::floater::Floater;
+use super::primitive::{UnsafeCell,AtomicUsize};
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::Ordering;
+
+const SEQ: Ordering = Ordering::SeqCst;
+
+struct Ring<T: Sized, const N: usize> {
+    head: usize,
+    len: usize,
+    slots: [MaybeUninit<T>; N]
+}
+impl<T: Sized, const N: usize> Ring<T,N> {
+    #[inline(always)]
+    fn append(&mut self, data: T) -> Result<(),T> {
+        if self.len == N {
+            return Err(data);
+        }
+        let tail = (self.head + self.len) % N;
+        self.slots[tail] = MaybeUninit::new(data);
+        self.len += 1;
+        Ok(())
+    }
+    #[inline(always)]
+    fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let x = unsafe{ ptr::read(self.slots[self.head].as_ptr()) };
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(x)
+    }
+}
+impl<T: Sized, const N: usize> Drop for Ring<T,N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() { }
+    }
+}
+
+struct FixedCore<T: Sized, const N: usize> {
+    send: AtomicUsize,
+    recv: AtomicUsize,
+    lock: AtomicUsize,
+    ring: UnsafeCell<Ring<T,N>>
+}
+impl<T: Sized, const N: usize> FixedCore<T,N> {
+    loom_const_fn! {
+        fn new() -> FixedCore<T,N> {
+            FixedCore {
+                send: AtomicUsize::new(1),
+                recv: AtomicUsize::new(1),
+                lock: AtomicUsize::new(0),
+                ring: UnsafeCell::new(Ring {
+                    head: 0,
+                    len: 0,
+                    slots: [const { MaybeUninit::uninit() }; N]
+                })
+            }
+        }
+    }
+    ///Work on the ring, the lock must be held
+    #[inline(always)]
+    fn with_ring<R,F: FnOnce(&mut Ring<T,N>) -> R>(&self, f: F) -> R {
+        self.ring.with_mut(|ring| f(unsafe{ &mut *ring }))
+    }
+}
+unsafe impl<T: Sized, const N: usize> Sync for FixedCore<T,N> { }
+impl<T: Sized, const N: usize> LoanLock for FixedCore<T,N> {
+    type Word = AtomicUsize;
+    #[allow(clippy::needless_lifetimes)]
+    fn loan<'a>(&'a self) -> &'a AtomicUsize {
+        &self.lock
+    }
+}
+
+///Shared reference to the core behind a handle
+#[inline(always)]
+#[allow(clippy::needless_lifetimes)]
+fn core<'a,T: Sized, const N: usize>(data: &'a Floater<FixedCore<T,N>>) -> &'a FixedCore<T,N> {
+    unsafe{ data.get() }
+}
+
+///Send Item
+pub struct FixedSender<T: Sized+'static, const N: usize> {
+    data: Floater<FixedCore<T,N>>,
+    marker: PhantomData<&'static T>
+}
+impl<T: Sized+'static, const N: usize> Clone for FixedSender<T,N> {
+    fn clone(&self) -> FixedSender<T,N> {
+        core(&self.data).send.fetch_add(1,SEQ);
+        FixedSender {
+            data: self.data.clone(),
+            marker: PhantomData
+        }
+    }
+}
+impl<T: Sized+'static, const N: usize> Drop for FixedSender<T,N> {
+    fn drop(&mut self) {
+        core(&self.data).send.fetch_sub(1,SEQ);
+    }
+}
+unsafe impl<T: Sized+Send+'static, const N: usize> Sync for FixedSender<T,N> { }
+unsafe impl<T: Sized+Send+'static, const N: usize> Send for FixedSender<T,N> { }
+impl<T: Sized+'static, const N: usize> FixedSender<T,N> {
+    ///Sends and Item
+    ///
+    ///Returns Async::Ok(()) if everything happened okay
+    ///Returns Async::Block(T) if the send was blocked or the channel is full
+    ///Returns Async::Err(T) if there is no receiver to get your message
+    pub fn send(&self, data: T) -> Async<(),T,T> {
+        let ptr = core(&self.data);
+        if ptr.poll().is_err() {
+            return Async::Block(data);
+        }
+        if ptr.recv.load(SEQ) == 0 {
+            ptr.release();
+            return Async::Err(data);
+        }
+        let x = ptr.with_ring(|ring| ring.append(data));
+        ptr.release();
+        match x {
+            Ok(()) => Async::Ok(()),
+            Err(data) => Async::Block(data)
+        }
+    }
+}
+
+///Receiver
+pub struct FixedReceiver<T: Sized+'static, const N: usize> {
+    data: Floater<FixedCore<T,N>>,
+    marker: PhantomData<&'static T>
+}
+impl<T: Sized+'static, const N: usize> Clone for FixedReceiver<T,N> {
+    fn clone(&self) -> FixedReceiver<T,N> {
+        core(&self.data).recv.fetch_add(1,SEQ);
+        FixedReceiver {
+            data: self.data.clone(),
+            marker: PhantomData
+        }
+    }
+}
+impl<T: Sized+'static, const N: usize> Drop for FixedReceiver<T,N> {
+    fn drop(&mut self) {
+        core(&self.data).recv.fetch_sub(1,SEQ);
+    }
+}
+unsafe impl<T: Sized+Send+'static, const N: usize> Sync for FixedReceiver<T,N> { }
+unsafe impl<T: Sized+Send+'static, const N: usize> Send for FixedReceiver<T,N> { }
+impl<T: Sized+'static, const N: usize> FixedReceiver<T,N> {
+    ///Receive items
+    ///
+    ///Returns Async::Ok(Option<T>) an item may have returned
+    ///Returns Async::Block(()) the channel is blocked
+    ///Returns Async::Err(()) if there is no sender nor messages to read
+    pub fn recv(&self) -> Async<Option<T>,(),()> {
+        let ptr = core(&self.data);
+        if ptr.poll().is_err() {
+            return Async::Block(());
+        }
+        let x = ptr.with_ring(|ring| {
+            if ring.len == 0 && ptr.send.load(SEQ) == 0 {
+                return Err(());
+            }
+            Ok(ring.pop())
+        });
+        ptr.release();
+        match x {
+            Ok(x) => Async::Ok(x),
+            Err(()) => Async::Err(())
+        }
+    }
+}
+
+///Build a new fixed capacity channel holding at most `N` items
+pub fn static_channel<T: Sized, const N: usize>() -> (FixedSender<T,N>,FixedReceiver<T,N>) {
+    assert!(N > 0, "a fixed channel needs at least one slot");
+    let x = Floater::new(FixedCore::new());
+    let s = FixedSender {
+        data: x.clone(),
+        marker: PhantomData
+    };
+    let r = FixedReceiver {
+        data: x,
+        marker: PhantomData
+    };
+    (s,r)
+}
+
+///Fixed capacity channel that can be built at compile time and placed in
+///a `static`
+///
+///There are no handles, everybody sends and receives through a shared
+///reference, so the channel never closes and never returns Async::Err.
+pub struct StaticChannel<T: Sized, const N: usize> {
+    core: FixedCore<T,N>
+}
+unsafe impl<T: Sized+Send, const N: usize> Sync for StaticChannel<T,N> { }
+unsafe impl<T: Sized+Send, const N: usize> Send for StaticChannel<T,N> { }
+impl<T: Sized, const N: usize> StaticChannel<T,N> {
+    loom_const_fn! {
+        ///Build an empty channel holding at most `N` items
+        pub fn new() -> StaticChannel<T,N> {
+            assert!(N > 0, "a fixed channel needs at least one slot");
+            StaticChannel {
+                core: FixedCore::new()
+            }
+        }
+    }
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    fn core<'a>(&'a self) -> &'a FixedCore<T,N> {
+        &self.core
+    }
+    ///Sends and Item
+    ///
+    ///Returns Async::Ok(()) if everything happened okay
+    ///Returns Async::Block(T) if the send was blocked or the channel is full
+    pub fn send(&self, data: T) -> Async<(),T,()> {
+        let ptr = self.core();
+        if ptr.poll().is_err() {
+            return Async::Block(data);
+        }
+        let x = ptr.with_ring(|ring| ring.append(data));
+        ptr.release();
+        match x {
+            Ok(()) => Async::Ok(()),
+            Err(data) => Async::Block(data)
+        }
+    }
+    ///Receive items
+    ///
+    ///Returns Async::Ok(Option<T>) an item may have returned
+    ///Returns Async::Block(()) the channel is blocked
+    pub fn recv(&self) -> Async<Option<T>,(),()> {
+        let ptr = self.core();
+        if ptr.poll().is_err() {
+            return Async::Block(());
+        }
+        let x = ptr.with_ring(|ring| ring.pop());
+        ptr.release();
+        Async::Ok(x)
+    }
+}
+impl<T: Sized, const N: usize> Default for StaticChannel<T,N> {
+    fn default() -> StaticChannel<T,N> {
+        StaticChannel::new()
+    }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_fixed_channel_capacity() {
+    use std::sync::Arc;
+    let (s,r) = static_channel::<Arc<usize>,2>();
+    let tracker = Arc::new(0usize);
+    assert!(s.send(tracker.clone()).is_ok());
+    assert!(s.send(tracker.clone()).is_ok());
+    //full, the item comes back
+    assert!(s.send(tracker.clone()).is_blocked());
+    assert!(r.recv().ok().map(|x| x.is_some()).unwrap_or(false));
+    assert!(s.send(tracker.clone()).is_ok());
+    assert_eq!(Arc::strong_count(&tracker), 3);
+    //queued items are dropped with the channel
+    drop(s);
+    drop(r);
+    assert_eq!(Arc::strong_count(&tracker), 1);
+}
+
+#[cfg(not(loom))]
+#[test]
+fn test_static_channel() {
+    static CHANNEL: StaticChannel<usize,2> = StaticChannel::new();
+    assert!(CHANNEL.send(1).is_ok());
+    assert!(CHANNEL.send(2).is_ok());
+    assert!(CHANNEL.send(3).is_blocked());
+    assert!(CHANNEL.recv().ok() == Some(&Some(1)));
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_fixed_channel() {
+    use loom::thread;
+    loom::model(|| {
+        let (s, r) = static_channel::<usize,1>();
+        let t = thread::spawn(move || {
+            while s.send(7).is_blocked() {
+                thread::yield_now();
+            }
+        });
+        //racing the sender, the lock may be held or the ring still empty
+        let early = match r.recv() {
+            Async::Ok(x) => x,
+            Async::Block(()) => None,
+            Async::Err(()) => panic!("sender left without sending")
+        };
+        t.join().unwrap();
+        let got = match early {
+            Option::Some(x) => x,
+            Option::None => match r.recv() {
+                Async::Ok(Option::Some(x)) => x,
+                _ => panic!("item lost")
+            }
+        };
+        assert_eq!(got, 7);
+        assert!(r.recv() == Async::Err(()));
+    });
+}
@@ -1,55 +1,848 @@
 
-use std::cell::RefCell;
-use std::sync::Arc;
+use super::backoff::Backoff;
+use super::primitive::{UnsafeCell,AtomicUsize};
+use super::spinlock::{LoanLock,Lock};
+use core::mem;
+use core::ops::{Deref,DerefMut};
+use core::panic::Location;
+use core::pin::Pin;
+#[cfg(feature="debug-aliasing")]
+use core::ptr;
+use alloc::sync::{Arc,Weak};
+use core::sync::atomic::Ordering;
+#[cfg(feature="debug-aliasing")]
+use core::sync::atomic::AtomicPtr;
 
+const SEQ: Ordering = Ordering::SeqCst;
 
-///Floater is an abstraction around Arc<RefCell<T>>. It exists to modularize
-///the code involved when you want to have aliased access to a memory safe
-///location.
+//borrow flag value while a FloaterMut is alive, any other non-zero value
+//is the number of live FloaterRef guards
+const WRITER: usize = usize::MAX;
+
+///Records where the current mutable access to a Floater came from. Without
+///the `debug-aliasing` feature this is zero sized and every method is a
+///no-op.
+#[cfg(feature="debug-aliasing")]
+struct AliasTracker {
+    active: AtomicUsize,
+    location: AtomicPtr<Location<'static>>
+}
+#[cfg(feature="debug-aliasing")]
+impl AliasTracker {
+    fn new() -> AliasTracker {
+        AliasTracker {
+            active: AtomicUsize::new(0),
+            location: AtomicPtr::new(ptr::null_mut())
+        }
+    }
+    #[inline(always)]
+    fn enter(&self, at: &'static Location<'static>) {
+        if self.active.fetch_add(1,SEQ) != 0 {
+            self.active.fetch_sub(1,SEQ);
+            let other = self.location.load(SEQ);
+            let other = unsafe{ other.as_ref() }
+                .map(|l| l.to_string())
+                .unwrap_or_else(|| "<unknown>".to_string());
+            panic!("mutable access to a Floater at {} overlaps the access taken at {}", at, other);
+        }
+        self.location.store(at as *const Location<'static> as *mut Location<'static>, SEQ);
+    }
+    #[inline(always)]
+    fn exit(&self) {
+        self.location.store(ptr::null_mut(), SEQ);
+        self.active.fetch_sub(1,SEQ);
+    }
+}
+#[cfg(not(feature="debug-aliasing"))]
+struct AliasTracker;
+#[cfg(not(feature="debug-aliasing"))]
+impl AliasTracker {
+    fn new() -> AliasTracker {
+        AliasTracker
+    }
+    #[inline(always)]
+    fn enter(&self, _at: &'static Location<'static>) { }
+    #[inline(always)]
+    fn exit(&self) { }
+}
+
+#[repr(C)]
+struct Inner<T: ?Sized+Sync> {
+    borrows: AtomicUsize,
+    alias: AliasTracker,
+    cell: UnsafeCell<T>
+}
+//access to the cell is coordinated by the borrow flag, or by the caller of
+//the unsafe accessors
+unsafe impl<T: ?Sized+Sync+Send> Sync for Inner<T> { }
+
+///Floater is an abstraction around Arc<UnsafeCell<T>>. It exists to
+///modularize the code involved when you want to have aliased access to a
+///memory safe location.
 ///
-///The floater interfaces do not do any locking. They do not trigger the
-///RefCell to track borrows. All internal methods will be inlined.
-pub struct Floater<T: Sync> {
-    data: Arc<RefCell<T>>
+///The raw `get`/`get_mut` interfaces do not do any locking or borrow
+///tracking, and are unsafe for it. The
+///`borrow`/`borrow_mut` guards track access with an atomic borrow flag and
+///release it on drop. All internal methods will be inlined.
+pub struct Floater<T: ?Sized+Sync> {
+    data: Arc<Inner<T>>
 }
 impl<T: Sync> Floater<T> {
-    ///Build a new Floater. This simply creates the Arc<RefCell< >> wrappers.
+    ///Build a new Floater. This simply creates the Arc<UnsafeCell< >>
+    ///wrappers.
     #[inline(always)]
     pub fn new(data: T) -> Floater<T> {
         Floater {
-            data: Arc::new(RefCell::new(data))
+            data: Arc::new(Inner {
+                borrows: AtomicUsize::new(0),
+                alias: AliasTracker::new(),
+                cell: UnsafeCell::new(data)
+            })
+        }
+    }
+    ///Build a new Floater whose value holds a weak reference to itself
+    ///
+    ///The closure is handed a WeakFloater that will point at the finished
+    ///Floater. Upgrading it inside the closure returns None.
+    #[inline(always)]
+    pub fn new_cyclic<F>(init: F) -> Floater<T>
+    where
+        F: FnOnce(&WeakFloater<T>) -> T
+    {
+        Floater {
+            data: Arc::new_cyclic(|weak| {
+                let weak = WeakFloater {
+                    data: weak.clone()
+                };
+                Inner {
+                    borrows: AtomicUsize::new(0),
+                    alias: AliasTracker::new(),
+                    cell: UnsafeCell::new(init(&weak))
+                }
+            })
         }
     }
+    ///Swap the shared value with `other`
+    ///
+    ///Goes through `borrow_mut`, so it waits for outstanding guards.
+    #[inline(always)]
+    pub fn swap(&self, other: &mut T) {
+        mem::swap(&mut *self.borrow_mut(), other);
+    }
+    ///Replace the shared value, returning the old one
+    ///
+    ///Goes through `borrow_mut`, so it waits for outstanding guards.
+    #[inline(always)]
+    pub fn replace(&self, data: T) -> T {
+        mem::replace(&mut *self.borrow_mut(), data)
+    }
+    ///Take the shared value, leaving the default in its place
+    ///
+    ///Goes through `borrow_mut`, so it waits for outstanding guards.
+    #[inline(always)]
+    pub fn take(&self) -> T
+    where
+        T: Default
+    {
+        mem::take(&mut *self.borrow_mut())
+    }
+    ///Build a new Floater whose value will never move again
+    ///
+    ///The value lives in the shared allocation until the last handle is
+    ///dropped, and PinnedFloater offers no way to move it out, so the
+    ///guards it hands out are pinned.
+    #[inline(always)]
+    pub fn pin(data: T) -> PinnedFloater<T> {
+        PinnedFloater {
+            data: Floater::new(data)
+        }
+    }
+    ///Clone on write access
+    ///
+    ///If other Floaters or WeakFloaters share the data, it is cloned (under
+    ///a tracked shared borrow) and this Floater is re-pointed at the copy.
+    ///Either way the returned ref is unique, so no locking is needed.
+    #[inline(always)]
+    pub fn make_mut(&mut self) -> &mut T
+    where
+        T: Clone
+    {
+        if Arc::get_mut(&mut self.data).is_none() {
+            let copy = self.borrow().clone();
+            *self = Floater::new(copy);
+        }
+        Arc::get_mut(&mut self.data).expect("Floater was just made unique").cell.get_mut()
+    }
+    ///Take the data back out, succeeds only if this is the last Floater
+    ///pointing at it
+    ///
+    ///Returns Err(self) if other Floaters are still alive
+    #[inline(always)]
+    pub fn try_unwrap(self) -> Result<T,Floater<T>> {
+        match Arc::try_unwrap(self.data) {
+            Ok(inner) => Ok(inner.cell.into_inner()),
+            Err(data) => Err(Floater { data })
+        }
+    }
+    ///Take the data back out when this is known to be the only owner
+    ///
+    ///This will panic if other Floaters are still alive.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        match self.try_unwrap() {
+            Ok(data) => data,
+            Err(_) => panic!("Floater::into_inner called on a shared Floater")
+        }
+    }
+}
+impl<T: ?Sized+Sync> Floater<T> {
     ///Get a mutable ref.
     ///
-    ///This will panic if the pointer is invalid.
     ///There is no locking done at this interface, that is expected to be
-    ///handled by T. The method used internally is unsafe, so there can be
-    ///mutible mutable borrows existing at once. As such the interface is
-    ///unsafe
+    ///handled by T. The cell is not tracked, so there can be mutible
+    ///mutable borrows existing at once.
+    ///
+    ///# Safety
+    ///
+    ///The caller is responsible for making sure the returned ref never
+    ///overlaps another ref to the same data.
     #[inline(always)]
-    pub fn get_mut<'a>(&'a self) -> &'a mut T {
-        unsafe{
-            self.data.as_ptr().as_mut().expect("Null Pointer error!")
-        }
+    #[allow(clippy::mut_from_ref)]
+    #[allow(clippy::needless_lifetimes)]
+    pub unsafe fn get_mut<'a>(&'a self) -> &'a mut T {
+        &mut *self.data.cell.get()
     }
 
     ///Get a un-mutable ref
     ///
-    ///This will panic if the pointer is invalid. There is no locking or
-    ///tracking done internally. 
+    ///There is no locking or tracking done internally.
+    ///
+    ///# Safety
+    ///
+    ///The caller must make sure no mutable ref is alive at the same time.
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub unsafe fn get<'a>(&'a self) -> &'a T {
+        &*self.data.cell.get()
+    }
+
+    ///Attempt a tracked shared borrow
+    ///
+    ///Returns Err(()) if a FloaterMut is currently alive
     #[inline(always)]
-    pub fn get<'a>(&'a self) -> &'a T {
-        unsafe{
-            self.data.as_ptr().as_mut().expect("Null Pointer error!")
-    
+    #[allow(clippy::result_unit_err)]
+    pub fn try_borrow<'a>(&'a self) -> Result<FloaterRef<'a,T>,()> {
+        let mut current = self.data.borrows.load(SEQ);
+        loop {
+            if current >= WRITER-1 {
+                return Err(());
+            }
+            match self.data.borrows.compare_exchange(current, current+1, SEQ, SEQ) {
+                Ok(_) => return Ok(FloaterRef { floater: self }),
+                Err(x) => current = x
+            };
+        }
+    }
+    ///Attempt a tracked mutable borrow
+    ///
+    ///Returns Err(()) if any other guard is currently alive
+    #[inline(always)]
+    #[track_caller]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_borrow_mut<'a>(&'a self) -> Result<FloaterMut<'a,T>,()> {
+        match self.data.borrows.compare_exchange(0, WRITER, SEQ, SEQ) {
+            Ok(_) => {
+                self.data.alias.enter(Location::caller());
+                Ok(FloaterMut { floater: self })
+            }
+            Err(_) => Err(())
+        }
+    }
+    ///Tracked shared borrow, spins while a FloaterMut is alive
+    #[inline(always)]
+    pub fn borrow<'a>(&'a self) -> FloaterRef<'a,T> {
+        let backoff = Backoff::new();
+        loop {
+            if let Ok(guard) = self.try_borrow() {
+                return guard;
+            }
+            backoff.snooze();
+        }
+    }
+    ///Tracked mutable borrow, spins while any other guard is alive
+    #[inline(always)]
+    #[track_caller]
+    pub fn borrow_mut<'a>(&'a self) -> FloaterMut<'a,T> {
+        let backoff = Backoff::new();
+        loop {
+            if let Ok(guard) = self.try_borrow_mut() {
+                return guard;
+            }
+            backoff.snooze();
+        }
+    }
+    ///Access guarded by T's own lock
+    ///
+    ///Blocks in `Lock::lock` and calls `Lock::release` when the guard drops.
+    ///This does not touch the borrow flag, T's lock is the only thing
+    ///coordinating access.
+    #[inline(always)]
+    #[track_caller]
+    pub fn lock<'a>(&'a self) -> FloaterLock<'a,T>
+    where
+        T: Lock
+    {
+        Lock::lock(unsafe{ self.get() });
+        self.data.alias.enter(Location::caller());
+        FloaterLock { floater: self }
+    }
+    ///Access guarded by T's own lock, without spinning
+    ///
+    ///Returns Err(()) if `Lock::poll` failed
+    #[inline(always)]
+    #[track_caller]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_lock<'a>(&'a self) -> Result<FloaterLock<'a,T>,()>
+    where
+        T: Lock
+    {
+        unsafe{ self.get() }.poll()?;
+        self.data.alias.enter(Location::caller());
+        Ok(FloaterLock { floater: self })
+    }
+    ///Scoped mutable access with no coordination at all
+    ///
+    ///Behaves like `get_mut`, but the access ends when the guard drops.
+    ///With the `debug-aliasing` feature, overlapping scoped, locked, or
+    ///borrowed mutable accesses panic and report both call sites.
+    ///
+    ///# Safety
+    ///
+    ///The caller must make sure no other ref is alive at the same time,
+    ///typically through some external lock.
+    #[inline(always)]
+    #[track_caller]
+    pub unsafe fn access_mut<'a>(&'a self) -> FloaterAccess<'a,T> {
+        self.data.alias.enter(Location::caller());
+        FloaterAccess { floater: self }
+    }
+    ///Build a weak reference that does not keep the data alive
+    #[inline(always)]
+    pub fn downgrade(&self) -> WeakFloater<T> {
+        WeakFloater {
+            data: Arc::downgrade(&self.data)
+        }
+    }
+    ///Returns true if both Floaters point at the same data
+    #[inline(always)]
+    pub fn ptr_eq(&self, other: &Floater<T>) -> bool {
+        Arc::ptr_eq(&self.data, &other.data)
+    }
+    ///Number of Floaters pointing at this data
+    #[inline(always)]
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.data)
+    }
+    ///Number of WeakFloaters pointing at this data
+    #[inline(always)]
+    pub fn weak_count(&self) -> usize {
+        Arc::weak_count(&self.data)
+    }
+    ///Convert into a Floater over an unsized view of the same value,
+    ///such as a trait object or a slice
+    ///
+    ///The cast is usually a plain coercion, e.g. `|x| x as &dyn Handler`.
+    ///This will panic if the cast does not return a view of the whole
+    ///value, at the same address with the same size and alignment.
+    pub fn unsize<U: ?Sized+Sync>(self, cast: fn(&T) -> &U) -> Floater<U> {
+        let cell: *const T = self.data.cell.get();
+        let view: *const U = cast(unsafe{ &*cell });
+        assert!(view as *const u8 == cell as *const u8, "unsize cast moved the pointer");
+        unsafe {
+            assert_eq!(mem::size_of_val(&*view), mem::size_of_val(&*cell), "unsize cast changed the size");
+            assert_eq!(mem::align_of_val(&*view), mem::align_of_val(&*cell), "unsize cast changed the alignment");
+        }
+        //Inner is repr(C), so with matching alignment the cell sits at the
+        //same offset for both T and U
+        let raw = Arc::into_raw(self.data);
+        let offset = cell as *const u8 as usize - raw as *const u8 as usize;
+        let inner = view.wrapping_byte_sub(offset) as *const Inner<U>;
+        Floater {
+            data: unsafe{ Arc::from_raw(inner) }
         }
     }
 }
-impl<T: Sync> Clone for Floater<T> {
+impl<T: ?Sized+Sync> Clone for Floater<T> {
     fn clone(&self) -> Floater<T> {
         Floater {
             data: self.data.clone()
         }
     }
 }
+
+///WeakFloater is the Weak counterpart of a Floater. It does not keep the
+///data alive, so it can be used to break reference cycles between
+///Floaters.
+pub struct WeakFloater<T: ?Sized+Sync> {
+    data: Weak<Inner<T>>
+}
+impl<T: Sync> WeakFloater<T> {
+    ///Build a WeakFloater that never upgrades
+    #[inline(always)]
+    pub fn new() -> WeakFloater<T> {
+        WeakFloater {
+            data: Weak::new()
+        }
+    }
+}
+impl<T: ?Sized+Sync> WeakFloater<T> {
+    ///Get a Floater back, returns None if the data was already dropped
+    #[inline(always)]
+    pub fn upgrade(&self) -> Option<Floater<T>> {
+        self.data.upgrade().map(|data| Floater { data })
+    }
+}
+impl<T: Sync> Default for WeakFloater<T> {
+    fn default() -> WeakFloater<T> {
+        WeakFloater::new()
+    }
+}
+impl<T: ?Sized+Sync> Clone for WeakFloater<T> {
+    fn clone(&self) -> WeakFloater<T> {
+        WeakFloater {
+            data: self.data.clone()
+        }
+    }
+}
+
+///Shared borrow of a Floater, released on drop
+pub struct FloaterRef<'a,T: ?Sized+Sync+'a> {
+    floater: &'a Floater<T>
+}
+impl<'a,T: ?Sized+Sync> Deref for FloaterRef<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.floater.data.cell.with(|data| unsafe{ &*data })
+    }
+}
+impl<'a,T: ?Sized+Sync> Drop for FloaterRef<'a,T> {
+    fn drop(&mut self) {
+        self.floater.data.borrows.fetch_sub(1,SEQ);
+    }
+}
+
+///Mutable borrow of a Floater, released on drop
+pub struct FloaterMut<'a,T: ?Sized+Sync+'a> {
+    floater: &'a Floater<T>
+}
+impl<'a,T: ?Sized+Sync> Deref for FloaterMut<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.floater.data.cell.with(|data| unsafe{ &*data })
+    }
+}
+impl<'a,T: ?Sized+Sync> DerefMut for FloaterMut<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.floater.data.cell.with_mut(|data| unsafe{ &mut *data })
+    }
+}
+impl<'a,T: ?Sized+Sync> Drop for FloaterMut<'a,T> {
+    fn drop(&mut self) {
+        self.floater.data.alias.exit();
+        self.floater.data.borrows.store(0,SEQ);
+    }
+}
+
+///Access to a Floater holding T's own lock, released on drop
+///
+///Only hands out `&T`: the lock word is part of T and other threads keep
+///polling it, so a `&mut T` would alias their reads. T's data has to sit
+///in a cell of its own, as `LockedFloater` does.
+pub struct FloaterLock<'a,T: ?Sized+Sync+Lock+'a> {
+    floater: &'a Floater<T>
+}
+impl<'a,T: ?Sized+Sync+Lock> Deref for FloaterLock<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ self.floater.get() }
+    }
+}
+impl<'a,T: ?Sized+Sync+Lock> Drop for FloaterLock<'a,T> {
+    fn drop(&mut self) {
+        self.floater.data.alias.exit();
+        unsafe{ self.floater.get() }.release();
+    }
+}
+
+///Scoped mutable access to a Floater, see `Floater::access_mut`
+pub struct FloaterAccess<'a,T: ?Sized+Sync+'a> {
+    floater: &'a Floater<T>
+}
+impl<'a,T: ?Sized+Sync> Deref for FloaterAccess<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ self.floater.get() }
+    }
+}
+impl<'a,T: ?Sized+Sync> DerefMut for FloaterAccess<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ self.floater.get_mut() }
+    }
+}
+impl<'a,T: ?Sized+Sync> Drop for FloaterAccess<'a,T> {
+    fn drop(&mut self) {
+        self.floater.data.alias.exit();
+    }
+}
+
+///A Floater whose value is pinned, see `Floater::pin`
+///
+///Only the operations that leave the value in place are exposed. The
+///tracked guards come back wrapped in Pin, so `Pin::as_mut`, `Pin::set`
+///and `map_unchecked_mut` can be used to project into fields.
+pub struct PinnedFloater<T: ?Sized+Sync> {
+    data: Floater<T>
+}
+impl<T: ?Sized+Sync> PinnedFloater<T> {
+    ///Pinned tracked shared borrow, spins while a mutable guard is alive
+    #[inline(always)]
+    pub fn borrow<'a>(&'a self) -> Pin<FloaterRef<'a,T>> {
+        unsafe{ Pin::new_unchecked(self.data.borrow()) }
+    }
+    ///Pinned tracked mutable borrow, spins while any other guard is alive
+    #[inline(always)]
+    #[track_caller]
+    pub fn borrow_mut<'a>(&'a self) -> Pin<FloaterMut<'a,T>> {
+        unsafe{ Pin::new_unchecked(self.data.borrow_mut()) }
+    }
+    ///Pinned tracked shared borrow, fails if a mutable guard is alive
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_borrow<'a>(&'a self) -> Result<Pin<FloaterRef<'a,T>>,()> {
+        self.data.try_borrow().map(|g| unsafe{ Pin::new_unchecked(g) })
+    }
+    ///Pinned tracked mutable borrow, fails if any other guard is alive
+    #[inline(always)]
+    #[track_caller]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_borrow_mut<'a>(&'a self) -> Result<Pin<FloaterMut<'a,T>>,()> {
+        self.data.try_borrow_mut().map(|g| unsafe{ Pin::new_unchecked(g) })
+    }
+    ///Run `lambda` with pinned mutable access to a projection of the value
+    ///
+    ///A convenience around `borrow_mut` for the common case of reaching a
+    ///single pinned field.
+    ///
+    ///# Safety
+    ///
+    ///Same contract as `Pin::map_unchecked_mut`: `project` must return a
+    ///field of the value and must not move anything out of it.
+    #[inline(always)]
+    #[track_caller]
+    pub unsafe fn with_projection<U,P,F,R>(&self, project: P, lambda: F) -> R
+    where
+        U: ?Sized,
+        P: FnOnce(&mut T) -> &mut U,
+        F: FnOnce(Pin<&mut U>) -> R
+    {
+        let mut guard = self.borrow_mut();
+        lambda(guard.as_mut().map_unchecked_mut(project))
+    }
+    ///Returns true if both point at the same data
+    #[inline(always)]
+    pub fn ptr_eq(&self, other: &PinnedFloater<T>) -> bool {
+        self.data.ptr_eq(&other.data)
+    }
+}
+impl<T: Sync+Unpin> PinnedFloater<T> {
+    ///Unpin types may move freely, so they can go back to a plain Floater
+    #[inline(always)]
+    pub fn into_floater(self) -> Floater<T> {
+        self.data
+    }
+}
+impl<T: ?Sized+Sync> Clone for PinnedFloater<T> {
+    fn clone(&self) -> PinnedFloater<T> {
+        PinnedFloater {
+            data: self.data.clone()
+        }
+    }
+}
+
+///The value stored by a LockedFloater, its lock word sits next to the data
+///
+///Only ever reached through `&Locked`, other threads poll the lock word
+///while the holder writes the data, so `&mut` is only taken of `data`.
+struct Locked<T: Sync> {
+    lock: AtomicUsize,
+    data: core::cell::UnsafeCell<T>
+}
+//`data` is only touched under the lock. Moving the floater to another
+//thread still needs `Locked<T>: Send`, which asks for T: Send.
+unsafe impl<T: Sync> Sync for Locked<T> { }
+impl<T: Sync> LoanLock for Locked<T> {
+    type Word = AtomicUsize;
+    #[allow(clippy::needless_lifetimes)]
+    fn loan<'a>(&'a self) -> &'a AtomicUsize {
+        &self.lock
+    }
+}
+
+///LockedFloater is a Floater fused with a spin lock. Access is only handed
+///out through guards, which release the lock when they drop, so there is
+///no `release()` to forget.
+pub struct LockedFloater<T: Sync> {
+    data: Floater<Locked<T>>
+}
+impl<T: Sync> LockedFloater<T> {
+    ///Build a new, unlocked LockedFloater
+    #[inline(always)]
+    pub fn new(data: T) -> LockedFloater<T> {
+        LockedFloater {
+            data: Floater::new(Locked {
+                lock: AtomicUsize::new(0),
+                data: core::cell::UnsafeCell::new(data)
+            })
+        }
+    }
+    ///Spin until the lock is held
+    #[inline(always)]
+    #[track_caller]
+    pub fn lock<'a>(&'a self) -> LockedGuard<'a,T> {
+        LockedGuard {
+            guard: self.data.lock()
+        }
+    }
+    ///Attempt the lock once
+    ///
+    ///Returns Err(()) if somebody else holds it
+    #[inline(always)]
+    #[track_caller]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_lock<'a>(&'a self) -> Result<LockedGuard<'a,T>,()> {
+        self.data.try_lock().map(|guard| LockedGuard { guard })
+    }
+}
+impl<T: Sync> Clone for LockedFloater<T> {
+    fn clone(&self) -> LockedFloater<T> {
+        LockedFloater {
+            data: self.data.clone()
+        }
+    }
+}
+
+///Access to the data of a LockedFloater, unlocks on drop
+pub struct LockedGuard<'a,T: Sync+'a> {
+    guard: FloaterLock<'a,Locked<T>>
+}
+impl<'a,T: Sync> Deref for LockedGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ &*self.guard.data.get() }
+    }
+}
+impl<'a,T: Sync> DerefMut for LockedGuard<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        //through the shared `Locked`, never `&mut` over the lock word
+        unsafe{ &mut *self.guard.data.get() }
+    }
+}
+
+#[test]
+fn test_floater_guards() {
+    let f = Floater::new(5usize);
+    {
+        let a = f.borrow();
+        let b = f.borrow();
+        assert_eq!(*a + *b, 10);
+        assert!(f.try_borrow_mut().is_err());
+    }
+    {
+        let mut m = f.borrow_mut();
+        *m += 1;
+        assert!(f.try_borrow().is_err());
+    }
+    assert_eq!(*f.borrow(), 6);
+}
+
+#[test]
+fn test_floater_weak() {
+    let f = Floater::new(1usize);
+    let w = f.downgrade();
+    assert_eq!(*w.upgrade().expect("still alive").borrow(), 1);
+    drop(f);
+    assert!(w.upgrade().is_none());
+    assert!(WeakFloater::<usize>::new().upgrade().is_none());
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_floater_unwrap() {
+    let f = Floater::new(vec![1usize]);
+    let g = f.clone();
+    let f = match f.try_unwrap() {
+        Ok(_) => panic!("g still shares the data"),
+        Err(f) => f
+    };
+    drop(g);
+    assert_eq!(f.into_inner(), vec![1]);
+}
+
+#[test]
+fn test_floater_new_cyclic() {
+    struct Node {
+        me: WeakFloater<Node>,
+        value: usize
+    }
+    let f = Floater::new_cyclic(|me| {
+        assert!(me.upgrade().is_none());
+        Node {
+            me: me.clone(),
+            value: 7
+        }
+    });
+    let again = f.borrow().me.upgrade().expect("points at itself");
+    assert_eq!(again.borrow().value, 7);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_floater_replace() {
+    let f = Floater::new(vec![1usize]);
+    assert_eq!(f.replace(vec![2]), vec![1]);
+    let mut other = vec![3];
+    f.swap(&mut other);
+    assert_eq!(other, vec![2]);
+    assert_eq!(f.take(), vec![3]);
+    assert!(f.borrow().is_empty());
+}
+
+#[test]
+fn test_floater_unsized() {
+    trait Handler: Sync {
+        fn handle(&self) -> usize;
+    }
+    struct Doubler(usize);
+    impl Handler for Doubler {
+        fn handle(&self) -> usize {
+            self.0 * 2
+        }
+    }
+    let f: Floater<dyn Handler> = Floater::new(Doubler(4)).unsize(|x| x as &dyn Handler);
+    let g = f.clone();
+    assert_eq!(g.borrow().handle(), 8);
+    let s: Floater<[u64]> = Floater::new([1u64,2,3]).unsize(|x| &x[..]);
+    assert_eq!(s.borrow().iter().sum::<u64>(), 6);
+}
+
+#[cfg(feature="debug-aliasing")]
+#[test]
+#[should_panic(expected="overlaps the access taken at")]
+fn test_floater_debug_aliasing() {
+    //a lock that never excludes anybody
+    struct Broken;
+    impl Lock for Broken {
+        fn poll(&self) -> Result<(),()> {
+            Ok(())
+        }
+        fn release(&self) { }
+    }
+    let f = Floater::new(Broken);
+    let _a = f.lock();
+    let _b = f.lock();
+}
+
+#[test]
+fn test_floater_counts() {
+    let f = Floater::new(0usize);
+    let g = f.clone();
+    let h = Floater::new(0usize);
+    let w = f.downgrade();
+    assert!(f.ptr_eq(&g));
+    assert!(!f.ptr_eq(&h));
+    assert_eq!(f.strong_count(), 2);
+    assert_eq!(f.weak_count(), 1);
+    drop(w);
+    drop(g);
+    assert_eq!(f.strong_count(), 1);
+    assert_eq!(f.weak_count(), 0);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_locked_floater() {
+    use std::thread;
+    let f = LockedFloater::new(0usize);
+    {
+        let _held = f.lock();
+        assert!(f.try_lock().is_err());
+    }
+    let workers = (0..4).map(|_| {
+        let f = f.clone();
+        thread::spawn(move || {
+            for _ in 0..1000 {
+                *f.lock() += 1;
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(*f.try_lock().unwrap(), 4000);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_floater_make_mut() {
+    let mut config = Floater::new(vec![1usize]);
+    let reader = config.clone();
+    config.make_mut().push(2);
+    //the reader keeps the old snapshot
+    assert_eq!(*reader.borrow(), vec![1]);
+    assert_eq!(*config.borrow(), vec![1,2]);
+    assert!(!config.ptr_eq(&reader));
+    //unique now, so no further copies are made
+    let before = config.clone();
+    drop(before);
+    let ptr = config.make_mut() as *const Vec<usize>;
+    assert_eq!(ptr, config.make_mut() as *const Vec<usize>);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_floater_pin() {
+    use std::marker::PhantomPinned;
+    struct Node {
+        value: usize,
+        _pinned: PhantomPinned
+    }
+    let f = Floater::pin(Node { value: 1, _pinned: PhantomPinned });
+    let addr = {
+        let guard = f.borrow();
+        &*guard as *const Node
+    };
+    let g = f.clone();
+    unsafe {
+        g.with_projection(|n| &mut n.value, |v| *v.get_mut() += 1);
+    }
+    let guard = g.borrow();
+    assert_eq!(guard.value, 2);
+    assert_eq!(addr, &*guard as *const Node);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_floater_borrows() {
+    use loom::thread;
+    loom::model(|| {
+        let f = Floater::new(0usize);
+        let other = f.clone();
+        let t = thread::spawn(move || {
+            *other.borrow_mut() += 1;
+        });
+        let seen = *f.borrow();
+        assert!(seen <= 1);
+        t.join().unwrap();
+        assert_eq!(*f.borrow(), 1);
+    });
+}
@@ -0,0 +1,242 @@
+//!Sharded concurrent hash map.
+//!
+//!Keys are spread over a power of two number of shards by the upper bits
+//!of their hash, each shard is a `HashMap` behind its own SpinLock so
+//!threads working on different keys rarely meet. Lookups hand back a
+//!guard into the shard, the shard stays locked while it is held.
+
+
+use super::spinlock::{SpinLock,SpinGuard,MappedSpinGuard};
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher,Hash};
+
+///Shards a map built with `new` starts with
+pub const DEFAULT_SHARDS: usize = 16;
+
+///Guard to a value in the map, its shard is locked until it is dropped
+pub type ValueGuard<'a,K,V,S=RandomState> = MappedSpinGuard<'a,HashMap<K,V,S>,V>;
+
+///One shard, padded so neighbouring locks never share a cache line
+#[repr(align(128))]
+struct Shard<K,V,S>(SpinLock<HashMap<K,V,S>>);
+
+///Hash map split over independently locked shards
+pub struct ConcurrentHashMap<K,V,S=RandomState> {
+    shards: Box<[Shard<K,V,S>]>,
+    mask: usize,
+    hasher: S
+}
+impl<K: Hash+Eq,V> ConcurrentHashMap<K,V,RandomState> {
+    ///Build an empty map with `DEFAULT_SHARDS` shards
+    pub fn new() -> Self {
+        ConcurrentHashMap::with_shards(DEFAULT_SHARDS)
+    }
+    ///Build an empty map with at least `shards` shards, rounded up to a
+    ///power of two
+    pub fn with_shards(shards: usize) -> Self {
+        ConcurrentHashMap::with_shards_and_hasher(shards, RandomState::new())
+    }
+}
+impl<K: Hash+Eq,V,S: BuildHasher+Clone> ConcurrentHashMap<K,V,S> {
+    ///Build an empty map using `hasher` for shard selection and every
+    ///shard
+    pub fn with_shards_and_hasher(shards: usize, hasher: S) -> Self {
+        let shards = shards.max(1).next_power_of_two();
+        ConcurrentHashMap {
+            shards: (0..shards).map(|_| {
+                Shard(SpinLock::new(HashMap::with_hasher(hasher.clone())))
+            }).collect(),
+            mask: shards - 1,
+            hasher
+        }
+    }
+    #[inline(always)]
+    fn shard<Q: Hash+?Sized>(&self, key: &Q) -> &SpinLock<HashMap<K,V,S>> {
+        //the low bits pick the bucket inside the shard, use the high ones
+        let hash = self.hasher.hash_one(key);
+        &self.shards[(hash >> 32) as usize & self.mask].0
+    }
+    ///Lock the shard holding `key` and return a guard to its value
+    pub fn get<'a,Q>(&'a self, key: &Q) -> Option<ValueGuard<'a,K,V,S>>
+    where
+        K: Borrow<Q>,
+        Q: Hash+Eq+?Sized,
+    {
+        let guard = self.shard(key).lock();
+        if !guard.contains_key(key) {
+            return None;
+        }
+        Some(SpinGuard::map(guard, |map| map.get_mut(key).unwrap()))
+    }
+    ///Clone the value stored for `key`
+    pub fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash+Eq+?Sized,
+        V: Clone,
+    {
+        self.shard(key).lock().get(key).cloned()
+    }
+    ///Returns true if the map holds `key`
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash+Eq+?Sized,
+    {
+        self.shard(key).lock().contains_key(key)
+    }
+    ///Insert a value, returning the one it replaced
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).lock().insert(key, value)
+    }
+    ///Remove a key, returning its value
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash+Eq+?Sized,
+    {
+        self.shard(key).lock().remove(key)
+    }
+    ///Lock the shard holding `key` for in place manipulation
+    pub fn entry<'a>(&'a self, key: K) -> Entry<'a,K,V,S> {
+        Entry {
+            guard: self.shard(&key).lock(),
+            key
+        }
+    }
+    ///Keep only the entries `f` returns true for, one shard at a time
+    pub fn retain<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        for shard in self.shards.iter() {
+            shard.0.lock().retain(|k,v| f(k,v));
+        }
+    }
+    ///Visit every entry, one shard at a time. The view is not a snapshot,
+    ///shards visited earlier may change while later ones are visited.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        for shard in self.shards.iter() {
+            for (k,v) in shard.0.lock().iter() {
+                f(k,v);
+            }
+        }
+    }
+    ///Remove every entry
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.0.lock().clear();
+        }
+    }
+    ///Number of entries, summed shard by shard
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.0.lock().len()).sum()
+    }
+    ///Returns true if no shard holds an entry
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.0.lock().is_empty())
+    }
+    ///Number of shards
+    #[inline(always)]
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+}
+impl<K: Hash+Eq,V> Default for ConcurrentHashMap<K,V,RandomState> {
+    fn default() -> Self {
+        ConcurrentHashMap::new()
+    }
+}
+
+///A key and its locked shard, see `ConcurrentHashMap::entry`
+pub struct Entry<'a,K: 'a,V: 'a,S: 'a> {
+    guard: SpinGuard<'a,HashMap<K,V,S>>,
+    key: K
+}
+impl<'a,K: Hash+Eq,V,S: BuildHasher> Entry<'a,K,V,S> {
+    ///Returns true if the key is present
+    pub fn is_occupied(&self) -> bool {
+        self.guard.contains_key(&self.key)
+    }
+    ///Modify the value in place if the key is present
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Option::Some(v) = self.guard.get_mut(&self.key) {
+            f(v);
+        }
+        self
+    }
+    ///Insert `default` if the key is absent, returns a guard to the value
+    pub fn or_insert(self, default: V) -> ValueGuard<'a,K,V,S> {
+        self.or_insert_with(|| default)
+    }
+    ///Insert the result of `f` if the key is absent, returns a guard to
+    ///the value
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> ValueGuard<'a,K,V,S> {
+        let key = self.key;
+        SpinGuard::map(self.guard, |map| map.entry(key).or_insert_with(f))
+    }
+    ///Insert `V::default()` if the key is absent, returns a guard to the
+    ///value
+    pub fn or_default(self) -> ValueGuard<'a,K,V,S>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+    ///Remove the key, returning its value
+    pub fn remove(mut self) -> Option<V> {
+        self.guard.remove(&self.key)
+    }
+}
+
+#[test]
+fn test_hashmap_basic() {
+    let map = ConcurrentHashMap::with_shards(3);
+    assert_eq!(map.shards(), 4);
+    for i in 0..100 {
+        assert!(map.insert(i, i * 2).is_none());
+    }
+    assert_eq!(map.len(), 100);
+    assert_eq!(map.insert(7, 0), Some(14));
+    assert_eq!(map.get_cloned(&7), Some(0));
+    *map.get(&8).unwrap() += 1;
+    assert_eq!(*map.get(&8).unwrap(), 17);
+    assert!(map.get(&100).is_none());
+    assert_eq!(map.remove(&9), Some(18));
+    *map.entry(9).or_insert(5) += 1;
+    assert_eq!(map.get_cloned(&9), Some(6));
+    map.entry(9).and_modify(|v| *v = 0);
+    assert_eq!(map.get_cloned(&9), Some(0));
+    map.retain(|k,_| k % 2 == 0);
+    assert_eq!(map.len(), 50);
+    let mut sum = 0;
+    map.for_each(|k,_| sum += *k);
+    assert_eq!(sum, 2450);
+    map.clear();
+    assert!(map.is_empty());
+}
+
+#[test]
+fn test_hashmap_threads() {
+    use std::sync::Arc;
+    use std::thread;
+    let map = Arc::new(ConcurrentHashMap::<usize,usize>::new());
+    let workers = (0..4).map(|_| {
+        let map = map.clone();
+        thread::spawn(move || {
+            for i in 0..250 {
+                *map.entry(i % 50).or_default() += 1;
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(map.len(), 50);
+    map.for_each(|_,v| assert_eq!(*v, 20));
+}
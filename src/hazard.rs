@@ -0,0 +1,264 @@
+//!Hazard pointers.
+//!
+//!Before a thread dereferences a shared pointer it publishes it in a hazard
+//!slot and checks the source still holds it. Unlinked pointers are
+//!`retire`d to a per thread list, once the list passes a threshold the
+//!thread scans every published hazard and frees what nobody protects.
+//!Unlike epochs a stalled thread only holds back the few pointers it has
+//!published, so the amount of unreclaimed memory stays bounded.
+//!
+//!Slots live in a global list that only grows, a slot released by one
+//!thread is reused by the next one that asks for a slot.
+
+
+use super::spinlock::SpinLock;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicBool,AtomicPtr,AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Retired pointers a thread holds before it scans, at the least
+const RETIRE_THRESHOLD: usize = 64;
+
+///One published hazard
+struct Slot {
+    hazard: AtomicPtr<()>,
+    active: AtomicBool,
+    next: *mut Slot
+}
+
+static SLOTS: AtomicPtr<Slot> = AtomicPtr::new(ptr::null_mut());
+static SLOT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+///Take an unused slot, or add one to the list
+fn acquire_slot() -> &'static Slot {
+    let mut cursor = SLOTS.load(ACQUIRE);
+    while !cursor.is_null() {
+        let slot = unsafe{ &*cursor };
+        if !slot.active.load(RELAXED) &&
+           slot.active.compare_exchange(false, true, ACQUIRE, RELAXED).is_ok() {
+            return slot;
+        }
+        cursor = slot.next;
+    }
+    let slot = Box::into_raw(Box::new(Slot {
+        hazard: AtomicPtr::new(ptr::null_mut()),
+        active: AtomicBool::new(true),
+        next: ptr::null_mut()
+    }));
+    let mut head = SLOTS.load(RELAXED);
+    loop {
+        unsafe{ (*slot).next = head };
+        match SLOTS.compare_exchange_weak(head, slot, RELEASE, RELAXED) {
+            Ok(_) => break,
+            Err(x) => head = x
+        };
+    }
+    SLOT_COUNT.fetch_add(1, RELAXED);
+    unsafe{ &*slot }
+}
+
+///A pointer waiting to be freed with the function that frees it
+struct RetiredPtr {
+    ptr: *mut (),
+    free: unsafe fn(*mut ())
+}
+unsafe impl Send for RetiredPtr { }
+
+unsafe fn free_box<T>(ptr: *mut ()) {
+    drop(Box::from_raw(ptr as *mut T));
+}
+
+///Pointers left behind by threads that exited, picked up by the next scan
+static ORPHANS: SpinLock<Vec<RetiredPtr>> = SpinLock::new(Vec::new());
+
+struct Retired(Vec<RetiredPtr>);
+impl Drop for Retired {
+    fn drop(&mut self) {
+        free(unprotected(&mut self.0));
+        if !self.0.is_empty() {
+            ORPHANS.lock().append(&mut self.0);
+        }
+    }
+}
+thread_local!(static RETIRED: RefCell<Retired> = const { RefCell::new(Retired(Vec::new())) });
+
+///Take every pointer out of `list` that no slot protects
+///
+///Freeing is left to the caller, to do once the list is no longer
+///borrowed: a destructor may retire pointers of its own.
+fn unprotected(list: &mut Vec<RetiredPtr>) -> Vec<RetiredPtr> {
+    list.append(&mut ORPHANS.lock());
+    let mut hazards = Vec::new();
+    let mut cursor = SLOTS.load(ACQUIRE);
+    while !cursor.is_null() {
+        let slot = unsafe{ &*cursor };
+        let p = slot.hazard.load(SEQ);
+        if !p.is_null() {
+            hazards.push(p);
+        }
+        cursor = slot.next;
+    }
+    hazards.sort_unstable();
+    let (keep, free) = list.drain(..).partition(|r| hazards.binary_search(&r.ptr).is_ok());
+    *list = keep;
+    free
+}
+
+fn free(list: Vec<RetiredPtr>) {
+    for r in list {
+        unsafe{ (r.free)(r.ptr) };
+    }
+}
+
+///Retire a boxed pointer, it is freed once no hazard slot protects it
+///
+///# Safety
+///
+///`ptr` must come from `Box::into_raw`, already be unreachable from the
+///shared structure, and not be retired or freed anywhere else.
+pub unsafe fn retire<T>(ptr: *mut T) {
+    let r = RetiredPtr {
+        ptr: ptr as *mut (),
+        free: free_box::<T>
+    };
+    let threshold = RETIRE_THRESHOLD.max(2 * SLOT_COUNT.load(RELAXED));
+    let pushed = RETIRED.try_with(|retired| {
+        let unused = {
+            let mut retired = retired.borrow_mut();
+            retired.0.push(r);
+            if retired.0.len() < threshold {
+                return;
+            }
+            unprotected(&mut retired.0)
+        };
+        free(unused);
+    });
+    if pushed.is_err() {
+        //thread local is gone, leave it for another thread
+        ORPHANS.lock().push(RetiredPtr {
+            ptr: ptr as *mut (),
+            free: free_box::<T>
+        });
+    }
+}
+
+///Free the calling thread's retired pointers that are no longer protected
+pub fn scan() {
+    let unused = RETIRED.try_with(|retired| unprotected(&mut retired.borrow_mut().0));
+    if let Ok(unused) = unused {
+        free(unused);
+    }
+}
+
+///Number of retired pointers the calling thread still holds
+pub fn retired() -> usize {
+    RETIRED.try_with(|retired| retired.borrow().0.len()).unwrap_or(0)
+}
+
+///A hazard slot owned by one thread
+///
+///Holds at most one protected pointer at a time. The slot goes back to the
+///global list when the HazardPointer is dropped.
+pub struct HazardPointer {
+    slot: &'static Slot,
+    marker: PhantomData<*const ()>
+}
+impl HazardPointer {
+    ///Take a hazard slot
+    pub fn new() -> HazardPointer {
+        HazardPointer {
+            slot: acquire_slot(),
+            marker: PhantomData
+        }
+    }
+    ///Load `src` and protect the result from being freed until `reset` or
+    ///the next `protect`
+    pub fn protect<T>(&self, src: &AtomicPtr<T>) -> *mut T {
+        let mut p = src.load(RELAXED);
+        loop {
+            self.slot.hazard.store(p as *mut (), SEQ);
+            let q = src.load(ACQUIRE);
+            if p == q {
+                return p;
+            }
+            p = q;
+        }
+    }
+    ///Protect a pointer the caller knows is still live
+    pub fn set<T>(&self, ptr: *mut T) {
+        self.slot.hazard.store(ptr as *mut (), SEQ);
+    }
+    ///Stop protecting the current pointer
+    pub fn reset(&self) {
+        self.slot.hazard.store(ptr::null_mut(), RELEASE);
+    }
+}
+impl Default for HazardPointer {
+    fn default() -> HazardPointer {
+        HazardPointer::new()
+    }
+}
+impl Drop for HazardPointer {
+    fn drop(&mut self) {
+        self.reset();
+        self.slot.active.store(false, RELEASE);
+    }
+}
+
+#[test]
+fn test_hazard_protects() {
+    use std::sync::Arc;
+    struct Node(Arc<AtomicUsize>);
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, RELAXED);
+        }
+    }
+    let freed = Arc::new(AtomicUsize::new(0));
+    let shared = AtomicPtr::new(Box::into_raw(Box::new(Node(freed.clone()))));
+    let hp = HazardPointer::new();
+    let p = hp.protect(&shared);
+    //unlink and retire while protected
+    shared.store(ptr::null_mut(), SEQ);
+    unsafe{ retire(p) };
+    scan();
+    assert_eq!(freed.load(RELAXED), 0);
+    assert_eq!(retired(), 1);
+    hp.reset();
+    scan();
+    assert_eq!(freed.load(RELAXED), 1);
+    assert_eq!(retired(), 0);
+    //slots are reused
+    drop(hp);
+    let count = SLOT_COUNT.load(RELAXED);
+    let again = HazardPointer::new();
+    assert!(SLOT_COUNT.load(RELAXED) <= count + 1);
+    drop(again);
+}
+
+#[test]
+fn test_retire_from_drop() {
+    static FREED: AtomicUsize = AtomicUsize::new(0);
+    //frees retire their child, as a node owning the next one would
+    struct Node(*mut Node);
+    impl Drop for Node {
+        fn drop(&mut self) {
+            if !self.0.is_null() {
+                unsafe{ retire(self.0) };
+            }
+            FREED.fetch_add(1, RELAXED);
+        }
+    }
+    let child = Box::into_raw(Box::new(Node(ptr::null_mut())));
+    let parent = Box::into_raw(Box::new(Node(child)));
+    unsafe{ retire(parent) };
+    scan();
+    scan();
+    assert_eq!(FREED.load(RELAXED), 2);
+    assert_eq!(retired(), 0);
+}
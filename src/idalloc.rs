@@ -0,0 +1,253 @@
+//!Lock-free ID allocator over atomic bitmaps.
+//!
+//!Every ID is one bit in an array of `AtomicU64` words, set while the ID
+//!is handed out. Allocating finds a word with a clear bit and sets it with
+//!a compare and swap, freeing clears it with `fetch_and`. Threads start
+//!their search at different words so they rarely fight over one.
+//!
+//!A growable allocator adds segments of twice the previous size when every
+//!bit is taken. Segments are never moved or freed while the allocator
+//!lives, so IDs stay put.
+
+
+use std::ptr;
+use std::sync::atomic::{AtomicPtr,AtomicU64,AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+///Segments a growable allocator adds at most
+const MAX_SEGMENTS: usize = 32;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+thread_local!(static THREAD: usize = NEXT_THREAD.fetch_add(1, RELAXED));
+
+struct Segment {
+    base: u64,
+    words: Box<[AtomicU64]>
+}
+impl Segment {
+    ///Segment of `words` words whose bits past `bits` are never handed out
+    fn new(base: u64, words: usize, bits: u64) -> Box<Segment> {
+        Box::new(Segment {
+            base,
+            words: (0..words as u64).map(|w| {
+                let used = bits.saturating_sub(w * 64).min(64);
+                AtomicU64::new(if used == 64 { 0 } else { !0 << used })
+            }).collect()
+        })
+    }
+    fn allocate(&self, start: usize) -> Option<u64> {
+        let n = self.words.len();
+        for i in 0..n {
+            let w = (start + i) % n;
+            let word = &self.words[w];
+            let mut bits = word.load(RELAXED);
+            while bits != !0 {
+                let bit = (!bits).trailing_zeros() as u64;
+                match word.compare_exchange_weak(bits, bits | (1 << bit), ACQUIRE, RELAXED) {
+                    Ok(_) => return Some(self.base + w as u64 * 64 + bit),
+                    Err(x) => bits = x
+                };
+            }
+        }
+        None
+    }
+}
+
+///Hands out small integer IDs, each at most once until freed
+pub struct IdAllocator {
+    segments: [AtomicPtr<Segment>; MAX_SEGMENTS],
+    ///words in the first segment
+    first: usize,
+    ///number of IDs a fixed allocator has, None if growable
+    limit: Option<u64>,
+    allocated: AtomicUsize
+}
+unsafe impl Send for IdAllocator { }
+unsafe impl Sync for IdAllocator { }
+impl IdAllocator {
+    ///Build an allocator of the IDs `0..capacity`
+    pub fn new(capacity: u32) -> IdAllocator {
+        let bits = capacity as u64;
+        let alloc = IdAllocator::build((bits as usize).div_ceil(64).max(1), Some(bits));
+        alloc.segments[0].store(Box::into_raw(Segment::new(0, alloc.first, bits)), RELEASE);
+        alloc
+    }
+    ///Build an allocator that starts with room for `initial` IDs and grows
+    ///when they are all taken
+    pub fn growable(initial: u32) -> IdAllocator {
+        let words = (initial as usize).div_ceil(64).max(1);
+        let alloc = IdAllocator::build(words, None);
+        alloc.segments[0].store(Box::into_raw(Segment::new(0, words, words as u64 * 64)), RELEASE);
+        alloc
+    }
+    fn build(first: usize, limit: Option<u64>) -> IdAllocator {
+        IdAllocator {
+            segments: Default::default(),
+            first,
+            limit,
+            allocated: AtomicUsize::new(0)
+        }
+    }
+    ///First ID and word count of segment `k`
+    fn layout(&self, k: usize) -> (u64,usize) {
+        let words = self.first << k;
+        ((words as u64 - self.first as u64) * 64, words)
+    }
+    ///Install segment `k`, or use the one another thread installed first
+    fn grow(&self, k: usize) -> *mut Segment {
+        let (base, words) = self.layout(k);
+        if base + words as u64 * 64 > u32::MAX as u64 + 1 {
+            return ptr::null_mut();
+        }
+        let fresh = Box::into_raw(Segment::new(base, words, words as u64 * 64));
+        match self.segments[k].compare_exchange(ptr::null_mut(), fresh, ACQREL, ACQUIRE) {
+            Ok(_) => fresh,
+            Err(other) => {
+                unsafe{ drop(Box::from_raw(fresh)) };
+                other
+            }
+        }
+    }
+    ///Take a free ID, searching from a word picked by the calling thread
+    ///
+    ///Returns None if a fixed allocator is full, or a growable one ran out
+    ///of 32 bit IDs
+    pub fn allocate(&self) -> Option<u32> {
+        let thread = THREAD.try_with(|t| *t).unwrap_or(0);
+        for k in 0..MAX_SEGMENTS {
+            let mut seg = self.segments[k].load(ACQUIRE);
+            if seg.is_null() {
+                if self.limit.is_some() {
+                    return None;
+                }
+                seg = self.grow(k);
+                if seg.is_null() {
+                    return None;
+                }
+            }
+            let seg = unsafe{ &*seg };
+            if let Option::Some(id) = seg.allocate(thread.wrapping_mul(7)) {
+                self.allocated.fetch_add(1, RELAXED);
+                return Some(id as u32);
+            }
+        }
+        None
+    }
+    ///Give `id` back
+    ///
+    ///Returns false if it was not handed out
+    pub fn free(&self, id: u32) -> bool {
+        let id = id as u64;
+        for k in 0..MAX_SEGMENTS {
+            let seg = self.segments[k].load(ACQUIRE);
+            if seg.is_null() {
+                return false;
+            }
+            let seg = unsafe{ &*seg };
+            let end = seg.base + seg.words.len() as u64 * 64;
+            if id >= end {
+                continue;
+            }
+            if self.limit.map(|limit| id >= limit).unwrap_or(false) {
+                return false;
+            }
+            let offset = id - seg.base;
+            let mask = 1u64 << (offset % 64);
+            let was = seg.words[(offset / 64) as usize].fetch_and(!mask, ACQREL);
+            if was & mask == 0 {
+                return false;
+            }
+            self.allocated.fetch_sub(1, RELAXED);
+            return true;
+        }
+        false
+    }
+    ///Returns true if `id` is handed out
+    pub fn is_allocated(&self, id: u32) -> bool {
+        let id = id as u64;
+        for k in 0..MAX_SEGMENTS {
+            let seg = self.segments[k].load(ACQUIRE);
+            if seg.is_null() || self.limit.map(|limit| id >= limit).unwrap_or(false) {
+                return false;
+            }
+            let seg = unsafe{ &*seg };
+            let offset = id.wrapping_sub(seg.base);
+            if offset < seg.words.len() as u64 * 64 {
+                return seg.words[(offset / 64) as usize].load(RELAXED) & (1 << (offset % 64)) != 0;
+            }
+        }
+        false
+    }
+    ///Number of IDs handed out
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(RELAXED)
+    }
+    ///Number of IDs the allocator can hand out without growing
+    pub fn capacity(&self) -> usize {
+        if let Option::Some(limit) = self.limit {
+            return limit as usize;
+        }
+        self.segments.iter()
+            .map(|s| s.load(ACQUIRE))
+            .take_while(|s| !s.is_null())
+            .map(|s| unsafe{ (&*s).words.len() * 64 })
+            .sum()
+    }
+}
+impl Drop for IdAllocator {
+    fn drop(&mut self) {
+        for seg in self.segments.iter_mut() {
+            let seg = *seg.get_mut();
+            if !seg.is_null() {
+                unsafe{ drop(Box::from_raw(seg)) };
+            }
+        }
+    }
+}
+
+#[test]
+fn test_id_allocator_fixed() {
+    let ids = IdAllocator::new(70);
+    let mut taken = (0..70).map(|_| ids.allocate().unwrap()).collect::<Vec<_>>();
+    assert!(ids.allocate().is_none());
+    taken.sort();
+    assert_eq!(taken, (0..70).collect::<Vec<_>>());
+    assert!(ids.free(65));
+    assert!(!ids.free(65));
+    assert!(!ids.free(100));
+    assert!(!ids.is_allocated(65));
+    assert_eq!(ids.allocate(), Some(65));
+    assert_eq!(ids.allocated(), 70);
+    assert_eq!(ids.capacity(), 70);
+}
+
+#[test]
+fn test_id_allocator_growable() {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+    let ids = Arc::new(IdAllocator::growable(64));
+    let workers = (0..4).map(|_| {
+        let ids = ids.clone();
+        thread::spawn(move || {
+            let mine = (0..250).map(|_| ids.allocate().unwrap()).collect::<Vec<_>>();
+            for id in mine.iter().step_by(2) {
+                assert!(ids.free(*id));
+            }
+            mine.into_iter().skip(1).step_by(2).collect::<Vec<_>>()
+        })
+    }).collect::<Vec<_>>();
+    let mut kept = HashSet::new();
+    for w in workers {
+        for id in w.join().unwrap() {
+            //no ID was handed out twice
+            assert!(kept.insert(id));
+            assert!(ids.is_allocated(id));
+        }
+    }
+    assert_eq!(ids.allocated(), 500);
+    assert!(ids.capacity() >= 500);
+}
@@ -0,0 +1,170 @@
+//!Hooks for the `tracing` and `debug-registry` features.
+//!
+//!With the feature, channels give every message a correlation id when it
+//!is sent, and emit an event when it is queued and another when it is
+//!handed out, both carrying the id and the queue depth, so a subscriber
+//!can follow a message and see how long it sat in the queue. Spin locks
+//!emit an event per acquisition with the number of failed polls, and pool
+//!workers run each task inside a span. Without the feature every hook is
+//!a no-op and MsgTrace is zero sized.
+//!
+//!Events are emitted with the target of the module they describe, such as
+//!`lib_concurrent::mrms`, at TRACE level, except for contention which is
+//!reported at DEBUG.
+//!
+//!Lock waits are also passed to the debug registry, which counts the
+//!threads waiting on each registered lock.
+
+
+#[cfg(feature="tracing")]
+use std::sync::atomic::{AtomicU64,Ordering};
+#[cfg(feature="tracing")]
+use std::time::Instant;
+
+#[cfg(feature="tracing")]
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+///Correlation id of a message and when it was sent
+#[cfg(feature="tracing")]
+#[derive(Copy,Clone)]
+pub(crate) struct MsgTrace {
+    id: u64,
+    sent: Instant
+}
+#[cfg(feature="tracing")]
+impl MsgTrace {
+    #[inline(always)]
+    pub(crate) fn new() -> MsgTrace {
+        MsgTrace {
+            //ids only need to be unique
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            sent: Instant::now()
+        }
+    }
+    ///The message was queued, leaving `depth` messages in the queue
+    #[inline(always)]
+    pub(crate) fn sent(&self, depth: usize) {
+        tracing::trace!(target: "lib_concurrent::mrms", msg = self.id, depth, "message sent");
+    }
+    ///The message was handed to a receiver, leaving `depth` behind it
+    #[inline(always)]
+    pub(crate) fn received(&self, depth: usize) {
+        let queued_us = self.sent.elapsed().as_micros() as u64;
+        tracing::trace!(target: "lib_concurrent::mrms", msg = self.id, depth, queued_us, "message received");
+    }
+}
+
+///A channel call found the channel locked by someone else
+#[cfg(feature="tracing")]
+#[inline(always)]
+pub(crate) fn channel_contended(op: &'static str) {
+    tracing::debug!(target: "lib_concurrent::mrms", op, "channel contended");
+}
+
+///A lock was taken after `failed_polls` polls found it held
+#[cfg(feature="tracing")]
+#[inline(always)]
+pub(crate) fn lock_acquired(lock: usize, failed_polls: u64) {
+    if failed_polls == 0 {
+        tracing::trace!(target: "lib_concurrent::spinlock", lock, "lock acquired");
+    } else {
+        tracing::debug!(target: "lib_concurrent::spinlock", lock, failed_polls, "lock contended");
+    }
+}
+
+///Run a pool task inside a span
+#[cfg(feature="tracing")]
+#[inline(always)]
+pub(crate) fn task<R,F: FnOnce() -> R>(worker: usize, f: F) -> R {
+    tracing::trace_span!(target: "lib_concurrent::pool", "task", worker).in_scope(f)
+}
+
+#[cfg(not(feature="tracing"))]
+#[derive(Copy,Clone)]
+pub(crate) struct MsgTrace;
+#[cfg(not(feature="tracing"))]
+impl MsgTrace {
+    #[inline(always)]
+    pub(crate) fn new() -> MsgTrace {
+        MsgTrace
+    }
+    #[inline(always)]
+    pub(crate) fn sent(&self, _depth: usize) { }
+    #[inline(always)]
+    pub(crate) fn received(&self, _depth: usize) { }
+}
+#[cfg(not(feature="tracing"))]
+#[inline(always)]
+pub(crate) fn channel_contended(_op: &'static str) { }
+#[cfg(not(feature="tracing"))]
+#[inline(always)]
+pub(crate) fn lock_acquired(_lock: usize, _failed_polls: u64) { }
+#[cfg(not(feature="tracing"))]
+#[inline(always)]
+pub(crate) fn task<R,F: FnOnce() -> R>(_worker: usize, f: F) -> R {
+    f()
+}
+
+///A thread found `lock` held and is going to wait for it
+#[inline(always)]
+pub(crate) fn lock_waiting(_lock: usize) {
+    #[cfg(all(feature="debug-registry",not(loom)))]
+    super::debug::waiting(_lock);
+}
+
+///A thread that waited for `lock` took it
+#[inline(always)]
+pub(crate) fn lock_waited(_lock: usize) {
+    #[cfg(all(feature="debug-registry",not(loom)))]
+    super::debug::waited(_lock);
+}
+
+#[cfg(feature="tracing")]
+#[test]
+fn test_tracing_events() {
+    use super::mrms::channel;
+    use std::fmt;
+    use std::sync::{Arc,Mutex};
+    use tracing::{Event,Metadata,Subscriber};
+    use tracing::field::{Field,Visit};
+    use tracing::span::{Attributes,Id,Record};
+    type Recorded = Vec<(String,Vec<(String,String)>)>;
+    //records the message and fields of every event
+    struct Collect(Arc<Mutex<Recorded>>);
+    struct Fields(Vec<(String,String)>);
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+    impl Subscriber for Collect {
+        fn enabled(&self, _: &Metadata) -> bool { true }
+        fn new_span(&self, _: &Attributes) -> Id { Id::from_u64(1) }
+        fn record(&self, _: &Id, _: &Record) { }
+        fn record_follows_from(&self, _: &Id, _: &Id) { }
+        fn event(&self, event: &Event) {
+            let mut fields = Fields(Vec::new());
+            event.record(&mut fields);
+            let message = fields.0.iter().find(|f| f.0 == "message").map(|f| f.1.clone()).unwrap_or_default();
+            self.0.lock().unwrap().push((message, fields.0));
+        }
+        fn enter(&self, _: &Id) { }
+        fn exit(&self, _: &Id) { }
+    }
+    let events = Arc::new(Mutex::new(Vec::new()));
+    tracing::subscriber::with_default(Collect(events.clone()), || {
+        let (s, r) = channel(4);
+        assert!(s.send(1usize).is_ok());
+        assert!(s.send(2usize).is_ok());
+        assert!(r.recv() == super::Async::Ok(Some(1)));
+    });
+    let events = events.lock().unwrap();
+    let field = |i: usize, name: &str| events[i].1.iter().find(|f| f.0 == name).map(|f| f.1.clone());
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].0, "message sent");
+    assert_eq!(field(1, "depth"), Some("2".to_string()));
+    //the receive carries the id the first send was given
+    assert_eq!(events[2].0, "message received");
+    assert_eq!(field(2, "msg"), field(0, "msg"));
+    assert_eq!(field(2, "depth"), Some("1".to_string()));
+}
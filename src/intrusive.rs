@@ -0,0 +1,258 @@
+//!Intrusive multi producer single consumer queue.
+//!
+//!Vyukov's algorithm. Items carry their own `Link` so queueing allocates
+//!nothing: a push is one swap of the head plus one store to the previous
+//!node, a pop follows `next` pointers from the tail. A stub link owned by
+//!the queue stands in whenever the queue would otherwise be empty.
+//!
+//!Items go in as `Arc<T>` and come out as the same Arc, the queue holds
+//!the reference in between. Each Link records whether it is queued, so
+//!pushing an item that is already waiting is refused instead of
+//!corrupting the list; a waker list gets deduplication for free.
+//!
+//!A push that has swapped the head but not yet linked the previous node
+//!leaves the list briefly cut. The consumer sees that as `Async::Block`
+//!and should retry.
+
+
+use super::Async;
+use super::cachepadded::CachePadded;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,AtomicPtr,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+///Link embedded in every item that can be queued
+pub struct Link {
+    next: AtomicPtr<Link>,
+    queued: AtomicBool
+}
+impl Link {
+    ///Build an unqueued link
+    #[inline(always)]
+    pub const fn new() -> Link {
+        Link {
+            next: AtomicPtr::new(ptr::null_mut()),
+            queued: AtomicBool::new(false)
+        }
+    }
+    ///Returns true while the item sits in a queue
+    #[inline(always)]
+    pub fn is_queued(&self) -> bool {
+        self.queued.load(ACQUIRE)
+    }
+}
+impl Default for Link {
+    fn default() -> Link {
+        Link::new()
+    }
+}
+
+///Item type that embeds a `Link`
+///
+///# Safety
+///
+///`Self` must be `#[repr(C)]` with the Link as its first field, and
+///`link` must return that field. The queue converts between link and item
+///pointers by casting.
+pub unsafe trait Linked {
+    ///The embedded link
+    fn link(&self) -> &Link;
+}
+
+///Allocation free MPSC queue of `Arc<T>`
+pub struct IntrusiveQueue<T: Linked> {
+    head: CachePadded<AtomicPtr<Link>>,
+    tail: CachePadded<UnsafeCell<*mut Link>>,
+    ///held while a thread pops
+    consumer: AtomicBool,
+    stub: Box<Link>,
+    _marker: PhantomData<Arc<T>>
+}
+unsafe impl<T: Linked+Send+Sync> Send for IntrusiveQueue<T> { }
+unsafe impl<T: Linked+Send+Sync> Sync for IntrusiveQueue<T> { }
+impl<T: Linked> IntrusiveQueue<T> {
+    ///Build an empty queue
+    pub fn new() -> IntrusiveQueue<T> {
+        let stub = Box::new(Link::new());
+        let ptr = &*stub as *const Link as *mut Link;
+        IntrusiveQueue {
+            head: CachePadded::new(AtomicPtr::new(ptr)),
+            tail: CachePadded::new(UnsafeCell::new(ptr)),
+            consumer: AtomicBool::new(false),
+            stub,
+            _marker: PhantomData
+        }
+    }
+    #[inline(always)]
+    fn stub(&self) -> *mut Link {
+        &*self.stub as *const Link as *mut Link
+    }
+    #[inline(always)]
+    fn push_link(&self, link: *mut Link) {
+        unsafe{ (*link).next.store(ptr::null_mut(), RELAXED) };
+        let prev = self.head.swap(link, ACQREL);
+        unsafe{ (*prev).next.store(link, RELEASE) };
+    }
+    ///Queue `item`
+    ///
+    ///Returns the item back if it is already queued somewhere
+    pub fn push(&self, item: Arc<T>) -> Result<(),Arc<T>> {
+        debug_assert!(ptr::eq(item.link() as *const Link as *const u8, Arc::as_ptr(&item) as *const u8));
+        if item.link().queued.swap(true, ACQREL) {
+            return Err(item);
+        }
+        self.push_link(Arc::into_raw(item) as *mut Link);
+        Ok(())
+    }
+    ///Take the oldest item
+    ///
+    ///Returns Ok(None) if the queue is empty, Block if another thread is
+    ///popping or a push is half done. Never returns Async::Err.
+    pub fn pop(&self) -> Async<Option<Arc<T>>,(),()> {
+        if self.consumer.swap(true, ACQUIRE) {
+            return Async::Block(());
+        }
+        let out = unsafe{ self.pop_unchecked() };
+        self.consumer.store(false, RELEASE);
+        out
+    }
+    ///Vyukov's pop, the caller must be the only consumer
+    unsafe fn pop_unchecked(&self) -> Async<Option<Arc<T>>,(),()> {
+        let stub = self.stub();
+        let tail_cell = self.tail.get();
+        let mut tail = *tail_cell;
+        let mut next = (*tail).next.load(ACQUIRE);
+        if tail == stub {
+            if next.is_null() {
+                return if self.head.load(ACQUIRE) == stub {
+                    Async::Ok(None)
+                } else {
+                    Async::Block(())
+                };
+            }
+            *tail_cell = next;
+            tail = next;
+            next = (*next).next.load(ACQUIRE);
+        }
+        if !next.is_null() {
+            *tail_cell = next;
+            return Async::Ok(Some(self.release(tail)));
+        }
+        if tail != self.head.load(ACQUIRE) {
+            return Async::Block(());
+        }
+        //tail is the last item, put the stub behind it so it can leave
+        self.push_link(stub);
+        next = (*tail).next.load(ACQUIRE);
+        if !next.is_null() {
+            *tail_cell = next;
+            return Async::Ok(Some(self.release(tail)));
+        }
+        Async::Block(())
+    }
+    ///Hand an unlinked item back to its owner
+    #[inline(always)]
+    unsafe fn release(&self, link: *mut Link) -> Arc<T> {
+        (*link).queued.store(false, RELEASE);
+        Arc::from_raw(link as *const T)
+    }
+    ///Returns true if nothing is queued at this instant
+    pub fn is_empty(&self) -> bool {
+        let stub = self.stub();
+        self.head.load(ACQUIRE) == stub && unsafe{ (*stub).next.load(ACQUIRE).is_null() }
+    }
+}
+impl<T: Linked> Default for IntrusiveQueue<T> {
+    fn default() -> IntrusiveQueue<T> {
+        IntrusiveQueue::new()
+    }
+}
+impl<T: Linked> Drop for IntrusiveQueue<T> {
+    fn drop(&mut self) {
+        //no pushes can be in flight, every Block is a transient that a
+        //retry resolves
+        loop {
+            match unsafe{ self.pop_unchecked() } {
+                Async::Ok(Option::None) => break,
+                Async::Ok(Option::Some(item)) => drop(item),
+                Async::Block(()) |
+                Async::Err(()) => { }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+#[repr(C)]
+struct TestItem {
+    link: Link,
+    value: usize
+}
+#[cfg(test)]
+unsafe impl Linked for TestItem {
+    fn link(&self) -> &Link {
+        &self.link
+    }
+}
+
+#[test]
+fn test_intrusive_order() {
+    let q = IntrusiveQueue::new();
+    assert!(matches!(q.pop(), Async::Ok(Option::None)));
+    let items = (0..5).map(|value| Arc::new(TestItem { link: Link::new(), value })).collect::<Vec<_>>();
+    for item in &items {
+        q.push(item.clone()).ok().unwrap();
+    }
+    //already queued
+    assert!(q.push(items[2].clone()).is_err());
+    assert!(items[2].link.is_queued());
+    for i in 0..5 {
+        match q.pop() {
+            Async::Ok(Option::Some(item)) => assert_eq!(item.value, i),
+            _ => panic!("expected item {}", i)
+        };
+    }
+    assert!(q.is_empty());
+    assert!(!items[2].link.is_queued());
+    //requeue after popping, then drop the queue with items inside
+    q.push(items[0].clone()).ok().unwrap();
+    q.push(items[1].clone()).ok().unwrap();
+    drop(q);
+    assert_eq!(Arc::strong_count(&items[0]), 1);
+    assert_eq!(Arc::strong_count(&items[1]), 1);
+}
+
+#[test]
+fn test_intrusive_producers() {
+    use std::thread;
+    let q = Arc::new(IntrusiveQueue::new());
+    let producers = (0..4).map(|t| {
+        let q = q.clone();
+        thread::spawn(move || {
+            for i in 0..250 {
+                q.push(Arc::new(TestItem { link: Link::new(), value: t * 1000 + i })).ok().unwrap();
+            }
+        })
+    }).collect::<Vec<_>>();
+    let mut last = [None; 4];
+    let mut seen = 0;
+    while seen < 1000 {
+        if let Async::Ok(Option::Some(item)) = q.pop() {
+            let (t, i) = (item.value / 1000, item.value % 1000);
+            //each producer's items come out in order
+            assert!(last[t].is_none_or(|l| l < i));
+            last[t] = Some(i);
+            seen += 1;
+        }
+    }
+    for p in producers {
+        p.join().unwrap();
+    }
+    assert!(matches!(q.pop(), Async::Ok(Option::None)));
+}
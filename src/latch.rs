@@ -0,0 +1,139 @@
+//!Count down latch.
+//!
+//!Counters and waiters are separate threads: any thread may count down,
+//!any number of threads may wait for the count to reach zero. Waiters
+//!spin briefly, then register themselves and park. The thread that takes
+//!the count to zero unparks everybody registered. A waiter checks the
+//!count again after registering, so it cannot miss that wakeup.
+
+
+use super::backoff::{Backoff,SINGLE_THREADED};
+use super::spinlock::SpinLock;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread::{self,Thread};
+use std::time::{Duration,Instant};
+const ACQUIRE: Ordering = Ordering::Acquire;
+const ACQREL: Ordering = Ordering::AcqRel;
+const RELAXED: Ordering = Ordering::Relaxed;
+
+///Polls a waiter makes before it parks
+const SPINS: usize = 64;
+
+///Opens once it was counted down `n` times
+pub struct CountDownLatch {
+    count: AtomicUsize,
+    waiters: SpinLock<Vec<Thread>>
+}
+impl CountDownLatch {
+    ///Build a latch that opens after `n` calls to `count_down`. A latch for
+    ///0 starts open.
+    #[inline(always)]
+    pub const fn new(n: usize) -> CountDownLatch {
+        CountDownLatch {
+            count: AtomicUsize::new(n),
+            waiters: SpinLock::new(Vec::new())
+        }
+    }
+    ///Lower the count by one, opening the latch when it reaches zero.
+    ///Counting down an open latch does nothing.
+    pub fn count_down(&self) {
+        let prev = self.count.fetch_update(ACQREL, ACQUIRE, |x| x.checked_sub(1));
+        if prev == Ok(1) {
+            for t in self.waiters.lock().drain(..) {
+                t.unpark();
+            }
+        }
+    }
+    ///Number of `count_down` calls still needed
+    #[inline(always)]
+    pub fn count(&self) -> usize {
+        self.count.load(RELAXED)
+    }
+    ///Returns true once the count reached zero
+    #[inline(always)]
+    pub fn is_open(&self) -> bool {
+        self.count.load(ACQUIRE) == 0
+    }
+    ///Spin for a little while, returns true if the latch opened
+    fn spin(&self) -> bool {
+        let backoff = Backoff::new();
+        for _ in 0..SPINS {
+            if self.is_open() {
+                return true;
+            }
+            backoff.spin();
+        }
+        false
+    }
+    fn register(&self) {
+        self.waiters.lock().push(thread::current());
+    }
+    ///Block until the latch is open
+    pub fn wait(&self) {
+        if self.spin() {
+            return;
+        }
+        self.register();
+        while !self.is_open() {
+            thread::park();
+        }
+    }
+    ///Block until the latch is open or `timeout` passes
+    ///
+    ///Returns true if the latch opened in time. A timeout too long to
+    ///express as an Instant waits for good.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        if SINGLE_THREADED {
+            //nobody else can count down while we wait
+            return self.is_open();
+        }
+        let deadline = Instant::now().checked_add(timeout);
+        if self.spin() {
+            return true;
+        }
+        self.register();
+        loop {
+            if self.is_open() {
+                return true;
+            }
+            match deadline {
+                Option::None => thread::park(),
+                Option::Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        //the stale entry only costs an extra unpark later
+                        return false;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            };
+        }
+    }
+}
+
+#[test]
+fn test_latch() {
+    use std::sync::Arc;
+    let latch = Arc::new(CountDownLatch::new(3));
+    let waiters = (0..2).map(|_| {
+        let latch = latch.clone();
+        thread::spawn(move || {
+            latch.wait();
+            assert_eq!(latch.count(), 0);
+        })
+    }).collect::<Vec<_>>();
+    assert!(!latch.wait_timeout(Duration::from_millis(5)));
+    for _ in 0..3 {
+        let latch = latch.clone();
+        thread::spawn(move || latch.count_down()).join().unwrap();
+    }
+    for w in waiters {
+        w.join().unwrap();
+    }
+    assert!(latch.is_open());
+    assert!(latch.wait_timeout(Duration::MAX));
+    latch.count_down();
+    assert_eq!(latch.count(), 0);
+    static READY: CountDownLatch = CountDownLatch::new(0);
+    READY.wait();
+}
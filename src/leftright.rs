@@ -0,0 +1,165 @@
+//!Left-right concurrency control.
+//!
+//!Two copies of the data are kept. Readers announce themselves on one of
+//!two read indicators, look up which copy is current and read it, never
+//!waiting on anything. The single writer changes the copy readers are not
+//!on, points new readers at it, waits for the readers still on the old
+//!copy to leave by flipping the indicators, then applies the same change
+//!to the old copy. Every change is applied twice, so it has to give the
+//!same result on both copies.
+//!
+//!A reader that holds its guard for a long time stalls the writer, never
+//!other readers.
+
+
+use super::backoff::backoff;
+use super::spinlock::SpinLock;
+use std::cell::UnsafeCell;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize,Ordering};
+const RELEASE: Ordering = Ordering::Release;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Readers on one side of the version flip, padded to its own cache line
+#[repr(align(128))]
+struct Indicator(AtomicUsize);
+
+///Keeps two copies of a `T`, readers never wait for the writer
+pub struct LeftRight<T> {
+    sides: [UnsafeCell<T>; 2],
+    ///copy new readers use
+    current: AtomicUsize,
+    ///indicator new readers register on
+    version: AtomicUsize,
+    readers: [Indicator; 2],
+    writer: SpinLock<()>
+}
+unsafe impl<T: Send> Send for LeftRight<T> { }
+unsafe impl<T: Send+Sync> Sync for LeftRight<T> { }
+impl<T> LeftRight<T> {
+    ///Build from two equal copies
+    pub fn from_copies(left: T, right: T) -> LeftRight<T> {
+        LeftRight {
+            sides: [UnsafeCell::new(left), UnsafeCell::new(right)],
+            current: AtomicUsize::new(0),
+            version: AtomicUsize::new(0),
+            readers: [Indicator(AtomicUsize::new(0)), Indicator(AtomicUsize::new(0))],
+            writer: SpinLock::new(())
+        }
+    }
+    ///Build from one value and its clone
+    pub fn new(data: T) -> LeftRight<T>
+    where
+        T: Clone,
+    {
+        let copy = data.clone();
+        LeftRight::from_copies(data, copy)
+    }
+    ///Read the current copy, wait free
+    pub fn read<'a>(&'a self) -> ReadGuard<'a,T> {
+        let version = self.version.load(SEQ);
+        let indicator = &self.readers[version].0;
+        indicator.fetch_add(1, SEQ);
+        let side = self.current.load(SEQ);
+        ReadGuard {
+            data: unsafe{ &*self.sides[side].get() },
+            indicator
+        }
+    }
+    fn drain(&self, version: usize) {
+        let mut step = 0;
+        while self.readers[version].0.load(SEQ) != 0 {
+            backoff(&mut step);
+        }
+    }
+    ///Apply `f` to both copies, one at a time, while no reader is on the
+    ///copy being changed
+    ///
+    ///Writers take turns. `f` must leave both copies equal.
+    pub fn write<F>(&self, mut f: F)
+    where
+        F: FnMut(&mut T),
+    {
+        let _writer = self.writer.lock();
+        let old = self.current.load(ACQUIRE);
+        let next = old ^ 1;
+        //no reader is on `next`, the last write waited them all out
+        f(unsafe{ &mut *self.sides[next].get() });
+        self.current.store(next, SEQ);
+        //readers that loaded `old` registered on either indicator, flip and
+        //wait out both
+        let version = self.version.load(ACQUIRE);
+        self.drain(version ^ 1);
+        self.version.store(version ^ 1, SEQ);
+        self.drain(version);
+        f(unsafe{ &mut *self.sides[old].get() });
+    }
+    ///Consume the wrapper, returning both copies
+    pub fn into_copies(self) -> (T,T) {
+        let [left, right] = self.sides;
+        (left.into_inner(), right.into_inner())
+    }
+}
+impl<T: Clone+Default> Default for LeftRight<T> {
+    fn default() -> LeftRight<T> {
+        LeftRight::new(T::default())
+    }
+}
+
+///Read access to the current copy of a LeftRight
+pub struct ReadGuard<'a,T: 'a> {
+    data: &'a T,
+    indicator: &'a AtomicUsize
+}
+impl<'a,T> Deref for ReadGuard<'a,T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+impl<'a,T> Drop for ReadGuard<'a,T> {
+    fn drop(&mut self) {
+        self.indicator.fetch_sub(1, RELEASE);
+    }
+}
+
+#[test]
+fn test_left_right() {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+    let map = Arc::new(LeftRight::new(HashMap::new()));
+    let writer = {
+        let map = map.clone();
+        thread::spawn(move || {
+            for i in 0..250usize {
+                map.write(|m| { m.insert(i, i * 2); });
+            }
+        })
+    };
+    let readers = (0..2).map(|_| {
+        let map = map.clone();
+        thread::spawn(move || {
+            let mut seen = 0;
+            for _ in 0..250 {
+                let m = map.read();
+                //every entry a reader sees is complete
+                assert!(m.len() >= seen);
+                seen = m.len();
+                for (k,v) in m.iter() {
+                    assert_eq!(*v, k * 2);
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+    writer.join().unwrap();
+    for r in readers {
+        r.join().unwrap();
+    }
+    assert_eq!(map.read().len(), 250);
+    let map = Arc::try_unwrap(map).ok().unwrap();
+    let (left, right) = map.into_copies();
+    assert!(left == right);
+}
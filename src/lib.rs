@@ -1,7 +1,168 @@
+#![cfg_attr(not(feature="std"), no_std)]
+
+//!Concurrency primitives.
+//!
+//!The `std` feature is on by default. Without it the crate is `no_std` and
+//!only needs `alloc`: `Async`, the spinlocks and their `Lock` traits,
+//!`Floater`, the fixed-capacity channel, `backoff`, `cachepadded` and
+//!`atomicfloat` remain. Waits then never yield or sleep, locks are never
+//!poisoned, and timed or cancellable acquisition is gone.
+//!
+//!Building with `RUSTFLAGS="--cfg loom"` swaps the atomics and cells of
+//!the spinlocks, `Floater` and the fixed-capacity channel for loom's, and
+//!leaves out the rest of the crate. The `loom_` tests then explore every
+//!interleaving of the lock and channel protocols:
+//!
+//!```text
+//!RUSTFLAGS="--cfg loom" cargo test --release loom_
+//!```
+//!
+//!On wasm32 without the `atomics` target feature only one thread ever
+//!runs. Waits that need another thread to make progress panic there
+//!rather than spin forever, timed waits give up at once, `ThreadPool`
+//!runs jobs on the spot and `Executor` runs its tasks from `block_on`.
+//!`RUSTFLAGS="--cfg single_threaded"` selects the same behaviour on any
+//!target, the `single_thread_` tests cover it.
+
+#[cfg(feature="std")]
+extern crate core;
+extern crate alloc;
+#[cfg(feature="serde")]
+extern crate serde;
+#[cfg(feature="lock_api")]
+extern crate lock_api;
+#[cfg(feature="tracing")]
+extern crate tracing;
+#[cfg(loom)]
+extern crate loom;
+
+#[macro_use]
+mod primitive;
+#[cfg_attr(any(not(feature="std"),loom),allow(dead_code))]
+mod instrument;
+
+#[cfg(all(feature="std",not(loom)))]
 pub mod mrms;
+#[cfg(all(feature="std",not(loom)))]
 pub mod threadlocalkey;
 pub mod floater;
+pub mod ordering;
+pub mod policy;
 pub mod spinlock;
+pub mod poison;
+pub mod lockstats;
+pub mod lockorder;
+#[cfg(all(feature="std",not(loom)))]
+pub mod rwlock;
+pub mod mcs;
+#[cfg(all(feature="std",not(loom)))]
+pub mod cohort;
+#[cfg(all(feature="std",not(loom)))]
+pub mod reentrant;
+#[cfg(all(feature="std",not(loom)))]
+pub mod seqlock;
+#[cfg(all(feature="std",not(loom)))]
+pub mod adaptive;
+#[cfg(all(feature="std",not(loom)))]
+pub mod once;
+#[cfg(all(feature="std",not(loom)))]
+pub mod barrier;
+#[cfg(all(feature="std",not(loom)))]
+pub mod semaphore;
+#[cfg(all(feature="std",not(loom)))]
+pub mod condvar;
+#[cfg(all(feature="std",not(loom)))]
+pub mod rpc;
+#[cfg(all(feature="std",not(loom)))]
+pub mod dual;
+pub mod fixed;
+#[cfg(all(feature="std",not(loom)))]
+pub mod deque;
+#[cfg(all(feature="std",not(loom)))]
+pub mod pool;
+#[cfg(all(feature="std",not(loom)))]
+pub mod executor;
+#[cfg(all(feature="std",not(loom)))]
+pub mod scope;
+#[cfg(all(feature="std",not(loom)))]
+pub mod epoch;
+#[cfg(all(feature="std",not(loom)))]
+pub mod hazard;
+#[cfg(all(feature="std",not(loom)))]
+pub mod stack;
+#[cfg(all(feature="std",not(loom)))]
+pub mod queue;
+#[cfg(all(feature="std",not(loom)))]
+pub mod hashmap;
+#[cfg(all(feature="std",not(loom)))]
+pub mod skiplist;
+#[cfg(all(feature="std",not(loom)))]
+pub mod atomiccell;
+#[cfg(all(feature="std",not(loom)))]
+pub mod waitgroup;
+#[cfg(all(feature="std",not(loom)))]
+pub mod latch;
+#[cfg(all(feature="std",not(loom)))]
+pub mod event;
+#[cfg(all(feature="std",not(loom)))]
+pub mod rcu;
+#[cfg(all(feature="std",not(loom)))]
+pub mod leftright;
+#[cfg(all(feature="std",not(loom)))]
+pub mod combiner;
+#[cfg(all(feature="std",not(loom)))]
+pub mod disruptor;
+#[cfg(all(feature="std",not(loom)))]
+pub mod arena;
+#[cfg(all(feature="std",not(loom)))]
+pub mod counter;
+#[cfg(all(feature="std",not(loom)))]
+pub mod lru;
+#[cfg(all(feature="std",not(loom)))]
+pub mod ratelimit;
+#[cfg(all(feature="std",not(loom)))]
+pub mod actor;
+#[cfg(all(feature="std",not(loom)))]
+pub mod pipeline;
+#[cfg(all(feature="std",not(loom)))]
+pub mod timer;
+#[cfg(all(feature="std",not(loom)))]
+pub mod idalloc;
+#[cfg(all(feature="std",not(loom)))]
+pub mod syncpool;
+#[cfg(all(feature="std",not(loom)))]
+pub mod park;
+pub mod backoff;
+pub mod cachepadded;
+pub mod atomicfloat;
+#[cfg(all(feature="std",not(loom)))]
+pub mod atomicoption;
+#[cfg(all(feature="std",not(loom)))]
+pub mod intrusive;
+#[cfg(all(feature="std",not(loom)))]
+pub mod eventbus;
+#[cfg(all(feature="std",not(loom)))]
+pub mod spmc;
+#[cfg(all(feature="std",not(loom)))]
+pub mod asyncmutex;
+#[cfg(all(feature="std",not(loom)))]
+pub mod asyncsemaphore;
+#[cfg(all(feature="std",not(loom)))]
+pub mod cancel;
+#[cfg(all(feature="std",not(loom)))]
+pub mod taskscope;
+#[cfg(all(feature="std",not(loom)))]
+pub mod shutdown;
+#[cfg(all(feature="std",not(loom)))]
+pub mod affinity;
+#[cfg(all(feature="std",not(loom)))]
+pub mod coroutine;
+#[cfg(all(feature="std",not(loom)))]
+pub mod mpsc;
+#[cfg(all(feature="debug-registry",not(loom)))]
+pub mod debug;
+#[cfg(all(feature="testing",not(loom)))]
+pub mod testing;
 
 ///Async Enum
 ///
@@ -12,6 +173,7 @@ pub enum Async<T,B,E> {
     Block(B),
     Err(E)
 }
+#[allow(clippy::match_like_matches_macro,clippy::match_ref_pats,clippy::needless_borrowed_reference,clippy::needless_lifetimes)]
 impl<T,B,E> Async<T,B,E> {
     ///returns true if the value is Ok
     #[inline(always)]
@@ -71,6 +233,7 @@ impl<T,B,E> Async<T,B,E> {
         }
     }
 }
+#[allow(clippy::match_ref_pats,clippy::needless_borrowed_reference)]
 impl<T:PartialEq,B:PartialEq,E:PartialEq> PartialEq for Async<T,B,E> {
     fn eq(&self,other: &Async<T,B,E>) ->bool {
         match self {
@@ -0,0 +1,179 @@
+//!Lock order checking for debug builds.
+//!
+//!With the `debug-locks` feature every acquisition through `Lock` is
+//!recorded in a per-thread list of held locks, and each lock held at that
+//!moment adds an edge to a global "acquired before" graph. Acquiring a lock
+//!that can already reach one of the held locks through that graph means two
+//!code paths disagree on the order, which is a potential deadlock, so the
+//!acquisition panics with the cycle.
+//!
+//!Locks are identified by address. Once a lock that took part in nesting is
+//!freed its address may be reused by an unrelated lock, call `forget`
+//!before dropping such a lock to keep stale edges out of the graph. Without
+//!the feature every function here is a no-op.
+
+
+#[cfg(feature="debug-locks")]
+use core::cell::RefCell;
+#[cfg(feature="debug-locks")]
+use std::collections::{HashMap,HashSet,VecDeque};
+#[cfg(feature="debug-locks")]
+use std::collections::hash_map::Entry;
+#[cfg(feature="debug-locks")]
+use std::sync::Mutex;
+#[cfg(feature="debug-locks")]
+use std::thread;
+
+#[cfg(feature="debug-locks")]
+thread_local!(static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) });
+
+#[cfg(feature="debug-locks")]
+static GRAPH: Mutex<Option<HashMap<usize,HashSet<usize>>>> = Mutex::new(None);
+
+///Identify a lock by its address
+#[inline(always)]
+pub(crate) fn id_of<T: ?Sized>(lock: &T) -> usize {
+    lock as *const T as *const u8 as usize
+}
+
+///Path from `from` to `to` through the graph, if there is one
+#[cfg(feature="debug-locks")]
+fn path(graph: &HashMap<usize,HashSet<usize>>, from: usize, to: usize) -> Option<Vec<usize>> {
+    let mut parent = HashMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(from);
+    parent.insert(from, from);
+    while let Option::Some(node) = queue.pop_front() {
+        if node == to {
+            let mut path = vec![to];
+            let mut at = to;
+            while at != from {
+                at = parent[&at];
+                path.push(at);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if let Option::Some(edges) = graph.get(&node) {
+            for &next in edges {
+                if let Entry::Vacant(slot) = parent.entry(next) {
+                    slot.insert(node);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+    None
+}
+
+///Panic if taking `id` now would contradict an order seen before
+#[cfg(feature="debug-locks")]
+fn verify(id: usize, record: bool) {
+    if thread::panicking() {
+        return;
+    }
+    //locks taken from thread local destructors after HELD is gone are
+    //not tracked
+    let held = match HELD.try_with(|held| held.borrow().clone()) {
+        Ok(held) => held,
+        Err(_) => return
+    };
+    let report = {
+        let mut guard = GRAPH.lock().unwrap_or_else(|e| e.into_inner());
+        let graph = guard.get_or_insert_with(HashMap::new);
+        let mut report = None;
+        for &h in held.iter().filter(|&&h| h != id) {
+            if let Option::Some(cycle) = path(graph, id, h) {
+                report = Some((h, cycle));
+                break;
+            }
+            if record {
+                graph.entry(h).or_default().insert(id);
+            }
+        }
+        report
+    };
+    if let Option::Some((h, cycle)) = report {
+        let cycle = cycle.iter()
+            .map(|x| format!("{:#x}", x))
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        panic!("lock order inversion: acquiring {:#x} while holding {:#x}, but it was previously taken the other way round ({} -> {:#x})", id, h, cycle, id);
+    }
+}
+
+///Check the order before blocking on `id`, so an inversion is reported
+///instead of spinning forever
+#[cfg(feature="debug-locks")]
+pub(crate) fn waiting(id: usize) {
+    verify(id, false);
+}
+///Record that the calling thread now holds `id`
+#[cfg(feature="debug-locks")]
+pub(crate) fn acquired(id: usize) {
+    verify(id, true);
+    let _ = HELD.try_with(|held| held.borrow_mut().push(id));
+}
+///Record that `id` was released. Locks released from a thread other than
+///the one holding them are ignored.
+#[cfg(feature="debug-locks")]
+pub(crate) fn released(id: usize) {
+    let _ = HELD.try_with(|held| {
+        let mut held = held.borrow_mut();
+        if let Option::Some(pos) = held.iter().rposition(|&x| x == id) {
+            held.remove(pos);
+        }
+    });
+}
+///Drop every ordering recorded for `lock`
+#[cfg(feature="debug-locks")]
+pub fn forget<T: ?Sized>(lock: &T) {
+    let id = id_of(lock);
+    let mut guard = GRAPH.lock().unwrap_or_else(|e| e.into_inner());
+    if let Option::Some(graph) = guard.as_mut() {
+        graph.remove(&id);
+        for edges in graph.values_mut() {
+            edges.remove(&id);
+        }
+    }
+}
+///Number of locks the calling thread holds through `Lock`
+#[cfg(feature="debug-locks")]
+pub fn held_count() -> usize {
+    HELD.with(|held| held.borrow().len())
+}
+
+#[cfg(not(feature="debug-locks"))]
+#[inline(always)]
+pub(crate) fn waiting(_id: usize) { }
+#[cfg(not(feature="debug-locks"))]
+#[inline(always)]
+pub(crate) fn acquired(_id: usize) { }
+#[cfg(not(feature="debug-locks"))]
+#[inline(always)]
+pub(crate) fn released(_id: usize) { }
+///Drop every ordering recorded for `lock`
+#[cfg(not(feature="debug-locks"))]
+#[inline(always)]
+pub fn forget<T: ?Sized>(_lock: &T) { }
+
+#[cfg(feature="debug-locks")]
+#[test]
+fn test_lock_order_inversion() {
+    use super::spinlock::{Lock,RawSpinLock};
+    use std::panic;
+    let a = RawSpinLock::new();
+    let b = RawSpinLock::new();
+    a.lock();
+    b.lock();
+    assert_eq!(held_count(), 2);
+    b.release();
+    a.release();
+    b.lock();
+    let inverted = panic::catch_unwind(panic::AssertUnwindSafe(|| a.lock()));
+    assert!(inverted.is_err());
+    b.release();
+    assert_eq!(held_count(), 0);
+    forget(&a);
+    forget(&b);
+}
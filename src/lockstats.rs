@@ -0,0 +1,101 @@
+//!Contention counters for the concrete lock types.
+//!
+//!With the `lock-stats` feature every lock keeps a LockCounters block and
+//!its guards time how long they were held. Without the feature the block
+//!and the timer are zero sized and every method is a no-op.
+
+
+#[cfg(feature="lock-stats")]
+use core::sync::atomic::{AtomicU64,Ordering};
+#[cfg(feature="lock-stats")]
+use std::time::{Duration,Instant};
+//the counters order nothing, they are only ever summed up
+#[cfg(feature="lock-stats")]
+const REX: Ordering = Ordering::Relaxed;
+
+///Snapshot of the statistics a lock has gathered over its lifetime
+///
+///Only available with the `lock-stats` feature.
+#[cfg(feature="lock-stats")]
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct LockStats {
+    ///times the lock was taken
+    pub acquisitions: u64,
+    ///polls that found the lock held
+    pub failed_polls: u64,
+    ///busy wait iterations spent waiting for the lock
+    pub spins: u64,
+    ///longest time a guard was alive
+    pub longest_hold: Duration
+}
+
+///Internal counters backing LockStats
+#[cfg(feature="lock-stats")]
+#[derive(Default)]
+pub(crate) struct LockCounters {
+    acquisitions: AtomicU64,
+    failed_polls: AtomicU64,
+    spins: AtomicU64,
+    longest_hold: AtomicU64
+}
+#[cfg(feature="lock-stats")]
+impl LockCounters {
+    pub(crate) const fn new() -> LockCounters {
+        LockCounters {
+            acquisitions: AtomicU64::new(0),
+            failed_polls: AtomicU64::new(0),
+            spins: AtomicU64::new(0),
+            longest_hold: AtomicU64::new(0)
+        }
+    }
+    #[inline(always)]
+    pub(crate) fn acquired(&self) -> HoldTimer {
+        self.acquisitions.fetch_add(1,REX);
+        HoldTimer(Instant::now())
+    }
+    #[inline(always)]
+    pub(crate) fn failed(&self) {
+        self.failed_polls.fetch_add(1,REX);
+    }
+    #[inline(always)]
+    pub(crate) fn spun(&self, spins: u64) {
+        self.spins.fetch_add(spins,REX);
+    }
+    #[inline(always)]
+    pub(crate) fn released(&self, timer: &HoldTimer) {
+        let held = timer.0.elapsed().as_nanos() as u64;
+        self.longest_hold.fetch_max(held,REX);
+    }
+    pub(crate) fn snapshot(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(REX),
+            failed_polls: self.failed_polls.load(REX),
+            spins: self.spins.load(REX),
+            longest_hold: Duration::from_nanos(self.longest_hold.load(REX))
+        }
+    }
+}
+///When a guard was handed out
+#[cfg(feature="lock-stats")]
+pub(crate) struct HoldTimer(Instant);
+
+#[cfg(not(feature="lock-stats"))]
+pub(crate) struct LockCounters;
+#[cfg(not(feature="lock-stats"))]
+impl LockCounters {
+    pub(crate) const fn new() -> LockCounters {
+        LockCounters
+    }
+    #[inline(always)]
+    pub(crate) fn acquired(&self) -> HoldTimer {
+        HoldTimer
+    }
+    #[inline(always)]
+    pub(crate) fn failed(&self) { }
+    #[inline(always)]
+    pub(crate) fn spun(&self, _spins: u64) { }
+    #[inline(always)]
+    pub(crate) fn released(&self, _timer: &HoldTimer) { }
+}
+#[cfg(not(feature="lock-stats"))]
+pub(crate) struct HoldTimer;
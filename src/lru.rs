@@ -0,0 +1,251 @@
+//!Sharded bounded cache with CLOCK eviction.
+//!
+//!Keys are spread over shards like `ConcurrentHashMap`, each shard behind
+//!its own RwSpinLock. A hit only takes the read lock and sets the entry's
+//!referenced bit, so readers never wait on each other. Inserting into a
+//!full shard sweeps the clock hand over its entries, clearing referenced
+//!bits, and evicts the first entry found unreferenced. That approximates
+//!least recently used with a bounded amount of work per eviction, and only
+//!the one shard is write locked meanwhile.
+
+
+use super::rwlock::RwSpinLock;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher,Hash};
+use std::sync::atomic::{AtomicBool,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+
+///Shards a cache built with `new` has at most
+pub const DEFAULT_SHARDS: usize = 16;
+
+struct Entry<K,V> {
+    key: K,
+    value: V,
+    referenced: AtomicBool
+}
+
+struct Clock<K,V,S> {
+    index: HashMap<K,usize,S>,
+    entries: Vec<Entry<K,V>>,
+    hand: usize,
+    capacity: usize
+}
+impl<K: Hash+Eq+Clone,V,S: BuildHasher> Clock<K,V,S> {
+    ///Find the entry to replace, clearing referenced bits on the way
+    fn victim(&mut self) -> usize {
+        loop {
+            if self.hand >= self.entries.len() {
+                self.hand = 0;
+            }
+            let i = self.hand;
+            self.hand += 1;
+            if !self.entries[i].referenced.swap(false, RELAXED) {
+                return i;
+            }
+        }
+    }
+    fn insert(&mut self, key: K, value: V) -> Option<(K,V)> {
+        if let Option::Some(&i) = self.index.get(&key) {
+            let old = ::std::mem::replace(&mut self.entries[i].value, value);
+            self.entries[i].referenced.store(true, RELAXED);
+            return Some((key, old));
+        }
+        let entry = Entry {
+            key: key.clone(),
+            value,
+            referenced: AtomicBool::new(false)
+        };
+        if self.entries.len() < self.capacity {
+            self.index.insert(key, self.entries.len());
+            self.entries.push(entry);
+            return None;
+        }
+        let i = self.victim();
+        let old = ::std::mem::replace(&mut self.entries[i], entry);
+        self.index.remove(&old.key);
+        self.index.insert(key, i);
+        Some((old.key, old.value))
+    }
+    fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash+Eq+?Sized,
+    {
+        let i = self.index.remove(key)?;
+        let old = self.entries.swap_remove(i);
+        if i < self.entries.len() {
+            *self.index.get_mut::<K>(&self.entries[i].key).unwrap() = i;
+        }
+        Some(old.value)
+    }
+}
+
+///One shard, padded so neighbouring locks never share a cache line
+#[repr(align(128))]
+struct Shard<K,V,S>(RwSpinLock<Clock<K,V,S>>);
+
+///Bounded cache split over independently locked shards
+///
+///Capacity is split evenly over the shards, so a shard may evict while
+///others still have room.
+pub struct ConcurrentLru<K,V,S=RandomState> {
+    shards: Box<[Shard<K,V,S>]>,
+    mask: usize,
+    hasher: S
+}
+impl<K: Hash+Eq+Clone,V> ConcurrentLru<K,V,RandomState> {
+    ///Build an empty cache holding about `capacity` entries
+    pub fn new(capacity: usize) -> Self {
+        ConcurrentLru::with_shards(capacity, DEFAULT_SHARDS)
+    }
+    ///Build an empty cache holding about `capacity` entries in at most
+    ///`shards` shards, rounded down to a power of two
+    pub fn with_shards(capacity: usize, shards: usize) -> Self {
+        ConcurrentLru::with_shards_and_hasher(capacity, shards, RandomState::new())
+    }
+}
+impl<K: Hash+Eq+Clone,V,S: BuildHasher+Clone> ConcurrentLru<K,V,S> {
+    ///Build an empty cache using `hasher` for shard selection and every
+    ///shard
+    pub fn with_shards_and_hasher(capacity: usize, shards: usize, hasher: S) -> Self {
+        let capacity = capacity.max(1);
+        //never more shards than entries, every shard holds at least one
+        let shards = shards.clamp(1, capacity);
+        let shards = 1usize << (usize::BITS - 1 - shards.leading_zeros());
+        let per_shard = capacity.div_ceil(shards);
+        ConcurrentLru {
+            shards: (0..shards).map(|_| Shard(RwSpinLock::new(Clock {
+                index: HashMap::with_capacity_and_hasher(per_shard, hasher.clone()),
+                entries: Vec::with_capacity(per_shard),
+                hand: 0,
+                capacity: per_shard
+            }))).collect(),
+            mask: shards - 1,
+            hasher
+        }
+    }
+    #[inline(always)]
+    fn shard<Q: Hash+?Sized>(&self, key: &Q) -> &RwSpinLock<Clock<K,V,S>> {
+        //the low bits pick the bucket inside the shard, use the high ones
+        let hash = self.hasher.hash_one(key);
+        &self.shards[(hash >> 32) as usize & self.mask].0
+    }
+    ///Clone the value cached for `key`, marking it recently used
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash+Eq+?Sized,
+        V: Clone,
+    {
+        self.get_with(key, |v| v.clone())
+    }
+    ///Run `f` on the value cached for `key` under the shard's read lock,
+    ///marking it recently used
+    pub fn get_with<Q,R,F>(&self, key: &Q, f: F) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash+Eq+?Sized,
+        F: FnOnce(&V) -> R,
+    {
+        let clock = self.shard(key).read();
+        let entry = &clock.entries[*clock.index.get(key)?];
+        if !entry.referenced.load(RELAXED) {
+            entry.referenced.store(true, RELAXED);
+        }
+        Some(f(&entry.value))
+    }
+    ///Returns true if `key` is cached, without marking it used
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash+Eq+?Sized,
+    {
+        self.shard(key).read().index.contains_key(key)
+    }
+    ///Cache a value
+    ///
+    ///Returns the entry it pushed out, either the old value for `key` or
+    ///an evicted entry
+    pub fn insert(&self, key: K, value: V) -> Option<(K,V)> {
+        self.shard(&key).write().insert(key, value)
+    }
+    ///Drop `key` from the cache, returning its value
+    pub fn invalidate<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash+Eq+?Sized,
+    {
+        self.shard(key).write().remove(key)
+    }
+    ///Drop every entry, one shard at a time
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut clock = shard.0.write();
+            clock.index.clear();
+            clock.entries.clear();
+            clock.hand = 0;
+        }
+    }
+    ///Number of entries, shards are counted one at a time
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.0.read().entries.len()).sum()
+    }
+    ///Returns true if no shard holds an entry
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.0.read().entries.is_empty())
+    }
+    ///Entries the cache holds at most
+    pub fn capacity(&self) -> usize {
+        self.shards.iter().map(|s| s.0.read().capacity).sum()
+    }
+    ///Number of shards
+    #[inline(always)]
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+}
+
+#[test]
+fn test_lru_clock() {
+    let cache = ConcurrentLru::with_shards(3, 1);
+    assert_eq!(cache.capacity(), 3);
+    assert!(cache.insert("a", 1).is_none());
+    assert!(cache.insert("b", 2).is_none());
+    assert!(cache.insert("c", 3).is_none());
+    //replacing a value hands the old one back
+    assert_eq!(cache.insert("c", 30), Some(("c", 3)));
+    //a and c were used since, b is evicted
+    assert_eq!(cache.get("a"), Some(1));
+    assert_eq!(cache.insert("d", 4), Some(("b", 2)));
+    assert!(!cache.contains_key("b"));
+    assert_eq!(cache.invalidate("a"), Some(1));
+    assert_eq!(cache.get_with("d", |v| v * 10), Some(40));
+    assert_eq!(cache.len(), 2);
+    cache.clear();
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_lru_threads() {
+    use std::sync::Arc;
+    use std::thread;
+    let cache = Arc::new(ConcurrentLru::with_shards(64, 4));
+    let workers = (0..4).map(|t| {
+        let cache = cache.clone();
+        thread::spawn(move || {
+            for i in 0..250usize {
+                let key = (t * 250 + i) % 100;
+                match cache.get(&key) {
+                    Option::Some(v) => assert_eq!(v, key * 2),
+                    Option::None => { cache.insert(key, key * 2); }
+                };
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert!(cache.len() <= cache.capacity());
+}
@@ -0,0 +1,351 @@
+//!MCS queue lock.
+//!
+//!Waiters link themselves into a queue and each spins on a flag in its own
+//!node, so a contended hand off touches one cache line belonging to the
+//!next waiter rather than a word every core is hammering.
+//!
+//!With the `std` feature queue nodes are recycled through a small per
+//!thread cache, so taking the lock does not allocate once a thread has
+//!warmed up. Without it every acquisition boxes a fresh node.
+
+
+use super::poison::{Poison,PoisonError};
+#[cfg(feature="lock-stats")]
+use super::lockstats::LockStats;
+use super::lockstats::{HoldTimer,LockCounters};
+use super::lockorder;
+use super::backoff::backoff;
+use super::spinlock::Lock;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ops::{Deref,DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicBool,AtomicPtr,Ordering};
+const SEQ: Ordering = Ordering::SeqCst;
+
+struct Node {
+    locked: AtomicBool,
+    next: AtomicPtr<Node>
+}
+///Nodes a thread keeps around for its next acquisitions
+#[cfg(feature="std")]
+const NODE_CACHE: usize = 8;
+
+//boxes are handed out and taken back as they are
+#[cfg(feature="std")]
+#[allow(clippy::vec_box)]
+struct NodeCache(Vec<Box<Node>>);
+#[cfg(feature="std")]
+thread_local!(static NODES: core::cell::RefCell<NodeCache> = const { core::cell::RefCell::new(NodeCache(Vec::new())) });
+
+impl Node {
+    ///A node ready to join a queue, recycled if this thread has one
+    fn alloc() -> *mut Node {
+        #[cfg(feature="std")]
+        {
+            let cached = NODES.try_with(|n| n.borrow_mut().0.pop()).ok().flatten();
+            if let Option::Some(node) = cached {
+                node.locked.store(true, SEQ);
+                node.next.store(ptr::null_mut(), SEQ);
+                return Box::into_raw(node);
+            }
+        }
+        Box::into_raw(Box::new(Node {
+            locked: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut())
+        }))
+    }
+    ///Give back a node nobody else can reach any more
+    unsafe fn free(node: *mut Node) {
+        let node = Box::from_raw(node);
+        #[cfg(feature="std")]
+        {
+            let mut node = Some(node);
+            let _ = NODES.try_with(|n| {
+                let mut cache = n.borrow_mut();
+                if cache.0.len() < NODE_CACHE {
+                    cache.0.push(node.take().unwrap());
+                }
+            });
+            drop(node);
+        }
+        #[cfg(not(feature="std"))]
+        drop(node);
+    }
+}
+
+///Queue lock without data, the raw lock of the `Queued` policy
+///
+///`tail` is the last waiter in the queue, `owner` is the node of whoever
+///holds the lock. Keeping the owner in the lock lets it be released
+///without carrying the node.
+pub struct RawMcsLock {
+    tail: AtomicPtr<Node>,
+    owner: AtomicPtr<Node>
+}
+unsafe impl Send for RawMcsLock { }
+unsafe impl Sync for RawMcsLock { }
+impl RawMcsLock {
+    ///Build a new unlocked RawMcsLock
+    #[inline(always)]
+    pub const fn new() -> RawMcsLock {
+        RawMcsLock {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            owner: AtomicPtr::new(ptr::null_mut())
+        }
+    }
+    ///Returns true if somebody holds or waits on the lock
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(SEQ).is_null()
+    }
+    ///Join the queue and spin on our own node until the lock is handed
+    ///over, returns the spins it took if somebody was ahead of us
+    fn acquire(&self) -> Option<u64> {
+        lockorder::waiting(lockorder::id_of(self));
+        let node = Node::alloc();
+        let prev = self.tail.swap(node, SEQ);
+        let mut waited = None;
+        if !prev.is_null() {
+            let mut spins = 0;
+            let mut step = 0;
+            unsafe {
+                (*prev).next.store(node, SEQ);
+                while (*node).locked.load(SEQ) {
+                    spins += backoff(&mut step);
+                }
+            }
+            waited = Some(spins);
+        }
+        self.owner.store(node, SEQ);
+        lockorder::acquired(lockorder::id_of(self));
+        waited
+    }
+}
+impl Default for RawMcsLock {
+    fn default() -> RawMcsLock {
+        RawMcsLock::new()
+    }
+}
+impl Lock for RawMcsLock {
+    fn poll(&self) -> Result<(),()> {
+        if !self.tail.load(SEQ).is_null() {
+            return Err(());
+        }
+        let node = Node::alloc();
+        match self.tail.compare_exchange(ptr::null_mut(), node, SEQ, SEQ) {
+            Ok(_) => {
+                self.owner.store(node, SEQ);
+                lockorder::acquired(lockorder::id_of(self));
+                Ok(())
+            }
+            Err(_) => {
+                unsafe{ Node::free(node) };
+                Err(())
+            }
+        }
+    }
+    ///Hand the lock to the next waiter. Releasing a lock nobody holds does
+    ///nothing.
+    fn release(&self) {
+        let node = self.owner.swap(ptr::null_mut(), SEQ);
+        if node.is_null() {
+            return;
+        }
+        lockorder::released(lockorder::id_of(self));
+        unsafe {
+            let mut next = (*node).next.load(SEQ);
+            if next.is_null() {
+                if self.tail.compare_exchange(node, ptr::null_mut(), SEQ, SEQ).is_ok() {
+                    Node::free(node);
+                    return;
+                }
+                //a waiter swapped the tail but has not linked itself yet
+                let mut step = 0;
+                loop {
+                    next = (*node).next.load(SEQ);
+                    if !next.is_null() {
+                        break;
+                    }
+                    backoff(&mut step);
+                }
+            }
+            (*next).locked.store(false, SEQ);
+            Node::free(node);
+        }
+    }
+    ///Queue up rather than polling the tail
+    fn lock(&self) {
+        self.acquire();
+    }
+}
+
+///Queue lock owning its data
+///
+///Only a guard releases it, so unlike the raw lock it has no `Lock` impl
+///that would let safe code unlock it behind a guard's back.
+pub struct McsLock<T: ?Sized> {
+    raw: RawMcsLock,
+    poison: Poison,
+    counters: LockCounters,
+    data: UnsafeCell<T>
+}
+unsafe impl<T: ?Sized+Send> Send for McsLock<T> { }
+unsafe impl<T: ?Sized+Send> Sync for McsLock<T> { }
+impl<T> McsLock<T> {
+    ///Build a new unlocked McsLock
+    #[inline(always)]
+    pub const fn new(data: T) -> McsLock<T> {
+        McsLock {
+            raw: RawMcsLock::new(),
+            poison: Poison::new(false),
+            counters: LockCounters::new(),
+            data: UnsafeCell::new(data)
+        }
+    }
+    ///Build a new unlocked McsLock that is poisoned when a thread panics
+    ///while holding it
+    #[inline(always)]
+    pub const fn with_poisoning(data: T) -> McsLock<T> {
+        McsLock {
+            raw: RawMcsLock::new(),
+            poison: Poison::new(true),
+            counters: LockCounters::new(),
+            data: UnsafeCell::new(data)
+        }
+    }
+    ///Consume the lock, returning the data
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+impl<T: ?Sized> McsLock<T> {
+    ///Join the queue and spin on our own node until the lock is handed over
+    #[inline(always)]
+    pub fn lock<'a>(&'a self) -> McsGuard<'a,T> {
+        if let Option::Some(spins) = self.raw.acquire() {
+            self.counters.failed();
+            self.counters.spun(spins);
+        }
+        self.guard()
+    }
+    #[inline(always)]
+    fn guard<'a>(&'a self) -> McsGuard<'a,T> {
+        McsGuard {
+            lock: self,
+            armed: self.poison.arm(),
+            timer: self.counters.acquired()
+        }
+    }
+    ///Join the queue, reporting poisoning
+    ///
+    ///Returns Err(PoisonError) holding the guard if a thread panicked
+    ///while holding the lock
+    #[inline(always)]
+    pub fn lock_checked<'a>(&'a self) -> Result<McsGuard<'a,T>,PoisonError<McsGuard<'a,T>>> {
+        let guard = self.lock();
+        self.poison.check(guard)
+    }
+    ///Returns true if a thread panicked while holding the lock
+    #[inline(always)]
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+    ///Mark the data as consistent again
+    #[inline(always)]
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+    ///Take the lock only if the queue is empty
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_lock<'a>(&'a self) -> Result<McsGuard<'a,T>,()> {
+        match self.raw.poll() {
+            Ok(()) => Ok(self.guard()),
+            Err(()) => {
+                self.counters.failed();
+                Err(())
+            }
+        }
+    }
+    ///Contention statistics gathered so far
+    #[cfg(feature="lock-stats")]
+    pub fn stats(&self) -> LockStats {
+        self.counters.snapshot()
+    }
+    ///Returns true if somebody holds or waits on the lock
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+    ///Mutable access without locking
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        self.data.get_mut()
+    }
+}
+impl<T: Default> Default for McsLock<T> {
+    fn default() -> McsLock<T> {
+        McsLock::new(T::default())
+    }
+}
+
+///Access to the data of an McsLock, hands the lock to the next waiter on drop
+pub struct McsGuard<'a,T: ?Sized+'a> {
+    lock: &'a McsLock<T>,
+    armed: bool,
+    timer: HoldTimer
+}
+//sharing the guard shares `&T`, the auto impl would only ask for T: Send
+unsafe impl<'a,T: ?Sized+Sync> Sync for McsGuard<'a,T> { }
+impl<'a,T: ?Sized> Deref for McsGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> DerefMut for McsGuard<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> Drop for McsGuard<'a,T> {
+    fn drop(&mut self) {
+        self.lock.counters.released(&self.timer);
+        self.lock.poison.disarm(self.armed);
+        self.lock.raw.release();
+    }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_mcs_lock() {
+    use std::sync::Arc;
+    use std::thread;
+    let lock = Arc::new(McsLock::new(0usize));
+    {
+        let _held = lock.lock();
+        assert!(lock.try_lock().is_err());
+    }
+    //the raw lock ignores a release nobody holds
+    let raw = RawMcsLock::new();
+    raw.release();
+    assert!(raw.poll().is_ok());
+    raw.release();
+    assert!(!raw.is_locked());
+    let workers = (0..4).map(|_| {
+        let lock = lock.clone();
+        thread::spawn(move || {
+            for _ in 0..250 {
+                *lock.lock() += 1;
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.lock(), 1000);
+}
@@ -0,0 +1,212 @@
+//!Drop-in replacement for `std::sync::mpsc`.
+//!
+//!`Sender` and `Receiver` mirror the std handles over an MRMS channel, so
+//!switching is a matter of changing the import. Sends and receives wait out
+//!contention on the channel instead of returning Async::Block, and report
+//!a hung up peer with the std error types, which are re-exported here.
+//!
+//!Like std's `channel` the channel is unbounded. Unlike std, waiting is
+//!done by backing off rather than parking the thread.
+
+
+use super::Async;
+use super::backoff::Backoff;
+use super::mrms::{self,MRMSReceiver,MRMSSender};
+use std::fmt;
+use std::time::{Duration,Instant};
+pub use std::sync::mpsc::{RecvError,RecvTimeoutError,SendError,TryRecvError};
+
+///Slots the channel's queue starts out with
+const INITIAL_CAPACITY: usize = 16;
+
+///Build an unbounded channel
+pub fn channel<T>() -> (Sender<T>,Receiver<T>) {
+    let (tx, rx) = mrms::channel(INITIAL_CAPACITY);
+    (Sender { inner: tx }, Receiver { inner: rx })
+}
+
+///Sending half of a channel, clones send into the same channel
+pub struct Sender<T: 'static> {
+    inner: MRMSSender<T>
+}
+impl<T: 'static> Sender<T> {
+    ///Send `t`, waiting out contention on the channel
+    ///
+    ///Returns Err(SendError(t)) if the receiver has been dropped
+    pub fn send(&self, mut t: T) -> Result<(),SendError<T>> {
+        let backoff = Backoff::new();
+        loop {
+            match self.inner.send(t) {
+                Async::Ok(()) => return Ok(()),
+                Async::Err(x) => return Err(SendError(x)),
+                Async::Block(x) => t = x
+            };
+            backoff.snooze();
+        }
+    }
+}
+impl<T: 'static> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        Sender {
+            inner: self.inner.clone()
+        }
+    }
+}
+impl<T: 'static> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Sender { .. }")
+    }
+}
+
+///Receiving half of a channel
+pub struct Receiver<T: 'static> {
+    inner: MRMSReceiver<T>
+}
+impl<T: 'static> Receiver<T> {
+    ///Take a message if one is queued, without waiting for one
+    ///
+    ///Contention on the channel is waited out, so Empty means the queue
+    ///really was empty.
+    pub fn try_recv(&self) -> Result<T,TryRecvError> {
+        let backoff = Backoff::new();
+        loop {
+            match self.inner.recv() {
+                Async::Ok(Option::Some(x)) => return Ok(x),
+                Async::Ok(Option::None) => return Err(TryRecvError::Empty),
+                Async::Err(()) => return Err(TryRecvError::Disconnected),
+                Async::Block(()) => backoff.spin()
+            };
+        }
+    }
+    ///Wait for a message
+    ///
+    ///Returns Err(RecvError) once every sender is gone and the queue is
+    ///empty
+    pub fn recv(&self) -> Result<T,RecvError> {
+        let backoff = Backoff::new();
+        loop {
+            match self.inner.recv() {
+                Async::Ok(Option::Some(x)) => return Ok(x),
+                Async::Err(()) => return Err(RecvError),
+                Async::Ok(Option::None) |
+                Async::Block(()) => backoff.snooze()
+            };
+        }
+    }
+    ///Wait for a message for at most `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T,RecvTimeoutError> {
+        timed_out(self.inner.recv_timeout(timeout))
+    }
+    ///Wait for a message until `deadline`
+    pub fn recv_deadline(&self, deadline: Instant) -> Result<T,RecvTimeoutError> {
+        timed_out(self.inner.recv_deadline(deadline))
+    }
+    ///Iterate over messages, waiting for each, until every sender is gone
+    pub fn iter<'a>(&'a self) -> Iter<'a,T> {
+        Iter { rx: self }
+    }
+    ///Iterate over the messages queued right now
+    pub fn try_iter<'a>(&'a self) -> TryIter<'a,T> {
+        TryIter { rx: self }
+    }
+}
+impl<T: 'static> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Receiver { .. }")
+    }
+}
+
+fn timed_out<T>(x: Async<T,(),()>) -> Result<T,RecvTimeoutError> {
+    match x {
+        Async::Ok(x) => Ok(x),
+        Async::Block(()) => Err(RecvTimeoutError::Timeout),
+        Async::Err(()) => Err(RecvTimeoutError::Disconnected)
+    }
+}
+
+///Blocking iterator returned by `Receiver::iter`
+pub struct Iter<'a,T: 'static> {
+    rx: &'a Receiver<T>
+}
+impl<'a,T: 'static> Iterator for Iter<'a,T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+///Non-blocking iterator returned by `Receiver::try_iter`
+pub struct TryIter<'a,T: 'static> {
+    rx: &'a Receiver<T>
+}
+impl<'a,T: 'static> Iterator for TryIter<'a,T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
+
+///Blocking iterator that owns its receiver
+pub struct IntoIter<T: 'static> {
+    rx: Receiver<T>
+}
+impl<T: 'static> Iterator for IntoIter<T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+impl<T: 'static> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { rx: self }
+    }
+}
+impl<'a,T: 'static> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a,T>;
+    fn into_iter(self) -> Iter<'a,T> {
+        self.iter()
+    }
+}
+
+#[test]
+fn test_mpsc_shim() {
+    use std::thread;
+    let (tx, rx) = channel();
+    let senders = (0..4).map(|i| {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for j in 0..250 {
+                tx.send(i * 250 + j).unwrap();
+            }
+        })
+    }).collect::<Vec<_>>();
+    drop(tx);
+    let mut seen = rx.iter().collect::<Vec<usize>>();
+    for s in senders {
+        s.join().unwrap();
+    }
+    seen.sort();
+    assert_eq!(seen, (0..1000).collect::<Vec<_>>());
+    assert_eq!(rx.recv(), Err(RecvError));
+}
+
+#[test]
+fn test_mpsc_errors() {
+    let (tx, rx) = channel::<usize>();
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(rx.recv_timeout(Duration::from_millis(1)), Err(RecvTimeoutError::Timeout));
+    tx.send(1).unwrap();
+    tx.send(2).unwrap();
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1,2]);
+    tx.send(3).unwrap();
+    drop(tx);
+    //queued messages are still handed out after the senders hung up
+    assert_eq!(rx.recv_timeout(Duration::from_millis(1)), Ok(3));
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    let (tx, rx) = channel();
+    drop(rx);
+    assert_eq!(tx.send(4), Err(SendError(4)));
+}
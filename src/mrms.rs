@@ -1,142 +1,909 @@
 
 use super::Async;
-use super::spinlock::{LoanLock,Lock};
+use super::backoff::{Backoff,SINGLE_THREADED};
+use super::cachepadded::CachePadded;
+use super::spinlock::Lock;
+use super::ordering::AcquireRelease;
+use super::policy::{Policy,Unfair};
 use super::floater::Floater;
+use super::arena::{ArenaBox,ArenaSender,ConcurrentArena};
+use super::ratelimit::RateLimiter;
+use super::cancel::{Cancelled,CancellationToken};
+use super::instrument::{self,MsgTrace};
+#[cfg(feature="debug-registry")]
+use super::debug;
+use std::cell::UnsafeCell;
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicUsize,Ordering};
+use std::cmp;
+use std::time::{Duration,Instant};
+#[cfg(feature="serde")]
+use serde::{Serialize,Deserialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64,AtomicUsize,Ordering};
 
-const REX: Ordering = Ordering::SeqCst;
+//statistics and ids order nothing
+const RELAXED: Ordering = Ordering::Relaxed;
+//handle counts are read to decide whether the other side hung up
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+///Polls an unfair core makes before a send or recv reports Async::Block,
+///so a brief blip of contention isn't passed on to the caller
+const ENTER_ATTEMPTS: usize = 4;
 
+///Snapshot of the statistics a channel has gathered over its lifetime
+///
+///Only available with the `stats` feature.
+#[cfg(feature="stats")]
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct ChannelStats {
+    ///messages successfully sent
+    pub sent: usize,
+    ///messages handed to a receiver
+    pub received: usize,
+    ///sends rejected because there were no receivers
+    pub failed_sends: usize,
+    ///send or recv attempts that returned Block
+    pub blocked: usize,
+    ///largest queue depth observed
+    pub high_water: usize
+}
+
+///Internal counters backing ChannelStats. Without the `stats` feature this
+///is zero sized and every method is a no-op.
+#[cfg(feature="stats")]
+#[derive(Default)]
+struct StatsBlock {
+    sent: AtomicUsize,
+    received: AtomicUsize,
+    failed_sends: AtomicUsize,
+    blocked: AtomicUsize,
+    high_water: AtomicUsize
+}
+#[cfg(feature="stats")]
+impl StatsBlock {
+    fn new() -> StatsBlock {
+        StatsBlock::default()
+    }
+    #[inline(always)]
+    fn sent(&self, depth: usize) {
+        self.sent.fetch_add(1,RELAXED);
+        self.high_water.fetch_max(depth,RELAXED);
+    }
+    #[inline(always)]
+    fn received(&self) {
+        self.received.fetch_add(1,RELAXED);
+    }
+    #[inline(always)]
+    fn failed_send(&self) {
+        self.failed_sends.fetch_add(1,RELAXED);
+    }
+    #[inline(always)]
+    fn blocked(&self) {
+        self.blocked.fetch_add(1,RELAXED);
+    }
+    fn snapshot(&self) -> ChannelStats {
+        ChannelStats {
+            sent: self.sent.load(RELAXED),
+            received: self.received.load(RELAXED),
+            failed_sends: self.failed_sends.load(RELAXED),
+            blocked: self.blocked.load(RELAXED),
+            high_water: self.high_water.load(RELAXED)
+        }
+    }
+}
+#[cfg(not(feature="stats"))]
+struct StatsBlock;
+#[cfg(not(feature="stats"))]
+impl StatsBlock {
+    fn new() -> StatsBlock {
+        StatsBlock
+    }
+    #[inline(always)]
+    fn sent(&self, _depth: usize) { }
+    #[inline(always)]
+    fn received(&self) { }
+    #[inline(always)]
+    fn failed_send(&self) { }
+    #[inline(always)]
+    fn blocked(&self) { }
+}
+
+///How messages are delivered when a receiver has been cloned
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Dispatch {
+    ///Receivers compete, each message goes to exactly one of them
+    Compete,
+    ///Every receiver gets its own copy of each message sent after it was
+    ///created
+    Broadcast
+}
 
-struct ChannelCore<T: Sized> {
-    send: AtomicUsize,
-    recv: AtomicUsize,
-    lock: AtomicUsize,
-    data: VecDeque<T>
+///Where a message came from, recorded by sequenced channels
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Meta {
+    ///identifies the MRMSSender handle that sent the message
+    pub sender: usize,
+    ///position of the message among everything that sender sent, starting
+    ///at zero
+    pub seq: u64
 }
-impl<T: Sized> ChannelCore<T> {
-    fn new(size: usize) -> ChannelCore<T> {
+
+///A queued message along with the bookkeeping the channel keeps for it
+struct Envelope<T: Sized> {
+    msg: T,
+    expires: Option<Instant>,
+    stamp: Option<Meta>,
+    trace: MsgTrace
+}
+impl<T: Sized> Envelope<T> {
+    #[inline(always)]
+    fn new(msg: T) -> Envelope<T> {
+        Envelope {
+            msg,
+            expires: None,
+            stamp: None,
+            trace: MsgTrace::new()
+        }
+    }
+    #[inline(always)]
+    fn is_expired(&self, now: &mut Option<Instant>) -> bool {
+        match self.expires {
+            Option::None => false,
+            Option::Some(deadline) => *now.get_or_insert_with(Instant::now) >= deadline
+        }
+    }
+}
+
+///Callback handed messages whose time to live ran out before delivery
+pub type DeadLetter<T> = Arc<dyn Fn(T) + Send + Sync>;
+
+//the handle counts and the lock word are hit from every thread, each gets
+//a cache line so they do not false share with each other or the queues
+struct ChannelCore<T: Sized, P: Policy = Unfair> {
+    send: CachePadded<AtomicUsize>,
+    recv: CachePadded<AtomicUsize>,
+    lock: CachePadded<P::Raw<AcquireRelease>>,
+    stats: StatsBlock,
+    dispatch: Dispatch,
+    cloner: Option<fn(&T) -> T>,
+    sequenced: bool,
+    next_sender: AtomicUsize,
+    queues: UnsafeCell<Queues<T>>
+}
+///The part of the core guarded by its lock
+struct Queues<T: Sized> {
+    dead_letter: Option<DeadLetter<T>>,
+    next_lane: usize,
+    lanes: Vec<(usize,VecDeque<Envelope<T>>)>,
+    data: VecDeque<Envelope<T>>
+}
+impl<T: Sized, P: Policy> ChannelCore<T,P> {
+    fn new(size: usize, dispatch: Dispatch, cloner: Option<fn(&T) -> T>) -> ChannelCore<T,P> {
         ChannelCore {
-            send: AtomicUsize::new(1),
-            recv: AtomicUsize::new(1),
-            lock: AtomicUsize::new(0),
-            data: VecDeque::<T>::with_capacity(size)
+            send: CachePadded::new(AtomicUsize::new(1)),
+            recv: CachePadded::new(AtomicUsize::new(1)),
+            lock: CachePadded::new(P::raw()),
+            stats: StatsBlock::new(),
+            dispatch,
+            cloner,
+            sequenced: false,
+            next_sender: AtomicUsize::new(0),
+            queues: UnsafeCell::new(Queues {
+                dead_letter: None,
+                next_lane: 0,
+                lanes: Vec::new(),
+                data: VecDeque::<Envelope<T>>::with_capacity(size)
+            })
+        }
+    }
+    ///The queues behind the lock
+    ///
+    ///# Safety
+    ///
+    ///The caller must hold the lock, or otherwise be the only one with
+    ///access to the core, for as long as the reference lives.
+    #[inline(always)]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn queues(&self) -> &mut Queues<T> {
+        &mut *self.queues.get()
+    }
+    ///Acquire the core. Unfair cores give up after a few polls, fair ones
+    ///wait their turn in the queue.
+    #[inline(always)]
+    fn enter(&self) -> Result<(),()> {
+        if P::FAIR {
+            self.lock.lock();
+            Ok(())
+        } else {
+            self.lock.poll_n(ENTER_ATTEMPTS)
         }
     }
     #[inline(always)]
+    fn leave(&self) {
+        self.lock.release();
+    }
+    ///Block until the core is acquired
+    #[inline(always)]
+    fn enter_spin(&self) {
+        self.lock.lock();
+    }
+    #[inline(always)]
     fn send_count(&self) -> usize {
-        self.send.load(REX)
+        self.send.load(ACQUIRE)
     }
     #[inline(always)]
     fn recv_count(&self) -> usize {
-        self.recv.load(REX)
+        self.recv.load(ACQUIRE)
     }
+    ///Queue an item, returning the resulting queue depth. Must be called
+    ///under the lock.
     #[inline(always)]
-    fn append(&mut self, data: T) {
-        self.data.push_back(data);
+    unsafe fn append(&self, data: Envelope<T>) -> usize {
+        let queues = self.queues();
+        match self.dispatch {
+            Dispatch::Compete => {
+                queues.data.push_back(data);
+                queues.data.len()
+            }
+            Dispatch::Broadcast => {
+                let cloner = self.cloner.expect("broadcast channels always have a cloner");
+                let mut depth = 0;
+                let last = queues.lanes.len().saturating_sub(1);
+                for &mut (_,ref mut queue) in queues.lanes[..last].iter_mut() {
+                    queue.push_back(Envelope {
+                        msg: cloner(&data.msg),
+                        expires: data.expires,
+                        stamp: data.stamp,
+                        trace: data.trace
+                    });
+                    depth = cmp::max(depth, queue.len());
+                }
+                if let Option::Some(&mut (_,ref mut queue)) = queues.lanes.last_mut() {
+                    queue.push_back(data);
+                    depth = cmp::max(depth, queue.len());
+                }
+                depth
+            }
+        }
     }
+    ///A receiver's queue. Must be called under the lock.
     #[inline(always)]
-    fn pop(&mut self) -> Option<T> {
-        self.data.pop_front()
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn queue(&self, lane: usize) -> Option<&mut VecDeque<Envelope<T>>> {
+        let queues = self.queues();
+        match self.dispatch {
+            Dispatch::Compete => Some(&mut queues.data),
+            Dispatch::Broadcast => queues.lanes.iter_mut()
+                .find(|&&mut (id,_)| id == lane)
+                .map(|&mut (_,ref mut queue)| queue)
+        }
+    }
+    #[inline(always)]
+    unsafe fn queue_len(&self, lane: usize) -> usize {
+        self.queue(lane).map(|q| q.len()).unwrap_or(0)
+    }
+    #[inline(always)]
+    unsafe fn pop(&self, lane: usize) -> Option<Envelope<T>> {
+        self.queue(lane).and_then(|q| q.pop_front())
+    }
+    ///Report to the debug registry. The queues are only read if the lock
+    ///can be had right away, a dump must not hang on a stuck channel.
+    #[cfg(feature="debug-registry")]
+    fn debug_state(&self) -> debug::State {
+        let depth = match self.lock.poll_n(ENTER_ATTEMPTS) {
+            Ok(()) => {
+                let queues = unsafe{ self.queues() };
+                let depth = match self.dispatch {
+                    Dispatch::Compete => queues.data.len(),
+                    Dispatch::Broadcast => queues.lanes.iter().map(|l| l.1.len()).max().unwrap_or(0)
+                };
+                self.leave();
+                Some(depth)
+            }
+            Err(()) => None
+        };
+        debug::State::Channel {
+            depth,
+            senders: self.send_count(),
+            receivers: self.recv_count()
+        }
+    }
+    ///Give a new receiver its own queue. Must be called under the lock.
+    unsafe fn open_lane(&self) -> usize {
+        let queues = self.queues();
+        let id = queues.next_lane;
+        queues.next_lane += 1;
+        queues.lanes.push((id,VecDeque::new()));
+        id
+    }
+    ///Discard a departing receiver's queue. Must be called under the lock.
+    unsafe fn close_lane(&self, lane: usize) {
+        self.queues().lanes.retain(|&(id,_)| id != lane);
     }
 }
-unsafe impl<T:Sized> Sync for ChannelCore<T> { }
-impl<T: Sized> LoanLock for ChannelCore<T> {
-    fn loan<'a>(&'a self) -> &'a AtomicUsize {
-        &self.lock
+unsafe impl<T: Sized, P: Policy> Sync for ChannelCore<T,P> { }
+
+///Increments a handle count, but only if the count has not already reached
+///zero. Once a side of the channel has fully closed it stays closed.
+#[inline(always)]
+fn revive(count: &AtomicUsize) -> bool {
+    let mut current = count.load(RELAXED);
+    loop {
+        if current == 0 {
+            return false;
+        }
+        match count.compare_exchange(current, current+1, ACQUIRE, RELAXED) {
+            Ok(_) => return true,
+            Err(x) => current = x
+        };
     }
 }
 
+///Shared reference to the core behind a handle
+#[inline(always)]
+#[allow(clippy::needless_lifetimes)]
+fn core<'a,T: Sized,P: Policy>(data: &'a Floater<ChannelCore<T,P>>) -> &'a ChannelCore<T,P> {
+    unsafe{ data.get() }
+}
+
 use std::marker::PhantomData;
-const SEQ: Ordering = Ordering::SeqCst;
 
 ///Send Item
-pub struct MRMSSender<T: Sized+'static> {
-    data: Floater<ChannelCore<T>>,
+pub struct MRMSSender<T: Sized+'static, P: Policy = Unfair> {
+    data: Floater<ChannelCore<T,P>>,
+    id: usize,
+    seq: AtomicU64,
     marker: PhantomData<&'static T>
 }
-impl<T: Sized+'static> Clone for MRMSSender<T> {
-    fn clone(&self) -> MRMSSender<T> {
-        self.data.get().send.fetch_add(1,SEQ);
-        MRMSSender {
-            data: self.data.clone(),
-            marker: PhantomData
-        }
+impl<T: Sized+'static, P: Policy> Clone for MRMSSender<T,P> {
+    fn clone(&self) -> MRMSSender<T,P> {
+        core(&self.data).send.fetch_add(1,RELAXED);
+        MRMSSender::attach(&self.data)
     }
 }
-impl<T:Sized+'static> Drop for MRMSSender<T> {
+impl<T: Sized+'static, P: Policy> Drop for MRMSSender<T,P> {
     fn drop(&mut self) {
-        self.data.get().send.fetch_sub(1,SEQ);
+        core(&self.data).send.fetch_sub(1,RELEASE);
         let _ = self;
     }
 }
-unsafe impl<T:Sized+'static> Sync for MRMSSender<T> { }
-unsafe impl<T:Sized+'static> Send for MRMSSender<T> { }
-impl<T:Sized+'static> MRMSSender<T> {
+unsafe impl<T: Sized+'static, P: Policy> Sync for MRMSSender<T,P> { }
+unsafe impl<T: Sized+'static, P: Policy> Send for MRMSSender<T,P> { }
+impl<T: Sized+'static, P: Policy> MRMSSender<T,P> {
+    ///Build a handle for a sender that has already been counted
+    fn attach(data: &Floater<ChannelCore<T,P>>) -> MRMSSender<T,P> {
+        MRMSSender {
+            data: data.clone(),
+            id: core(data).next_sender.fetch_add(1,RELAXED),
+            seq: AtomicU64::new(0),
+            marker: PhantomData
+        }
+    }
+    ///Identifies this handle in the Meta of sequenced channels
+    pub fn id(&self) -> usize {
+        self.id
+    }
     ///Sends and Item
     ///
     ///Returns Async::Ok(()) if everything happened okay
     ///Returns Async::Block(T) if the send was blocked
     ///Returns Async::Err(T) if there is no receiver to get your message
     pub fn send(&self,data: T) -> Async<(),T,T> {
-        let mut ptr = self.data.get_mut();
-        //failed to lock
-        if ptr.poll().is_err() {
+        self.push(Envelope::new(data))
+    }
+    ///Sends an Item that expires after `ttl`
+    ///
+    ///If no receiver picks the item up before it expires it is skipped by
+    ///recv, and handed to the dead letter callback if one is set.
+    ///Has the same return values as `send`
+    pub fn send_with_ttl(&self, data: T, ttl: Duration) -> Async<(),T,T> {
+        let mut env = Envelope::new(data);
+        //a ttl too long to express as an Instant never expires
+        env.expires = Instant::now().checked_add(ttl);
+        self.push(env)
+    }
+    ///Sends an Item if `limiter` has a token for it
+    ///
+    ///The token is spent even if the channel then refuses the item.
+    ///Returns Async::Block(T) if the limiter or the channel is blocked,
+    ///otherwise has the same return values as `send`
+    pub fn send_limited(&self, data: T, limiter: &RateLimiter) -> Async<(),T,T> {
+        if !limiter.try_acquire(1).is_ok() {
             return Async::Block(data);
         }
+        self.send(data)
+    }
+    ///Check whether a send would currently go through, without building
+    ///the message first
+    ///
+    ///Returns Async::Ok(()) if the channel is open and uncontended
+    ///Returns Async::Block(()) if a send would have been blocked
+    ///Returns Async::Err(()) if there is no receiver to get a message
+    pub fn poll_ready(&self) -> Async<(),(),()> {
+        let ptr = core(&self.data);
+        if ptr.enter().is_err() {
+            return Async::Block(());
+        }
+        let open = ptr.recv_count() != 0;
+        ptr.leave();
+        if open {
+            Async::Ok(())
+        } else {
+            Async::Err(())
+        }
+    }
+    fn push(&self, env: Envelope<T>) -> Async<(),T,T> {
+        let mut env = env;
+        let ptr = core(&self.data);
+        //failed to lock
+        if ptr.enter().is_err() {
+            ptr.stats.blocked();
+            instrument::channel_contended("send");
+            return Async::Block(env.msg);
+        }
         //is there somebody to receive the result?
         if ptr.recv_count() == 0 {
-            ptr.release();
-            return Async::Err(data);
+            ptr.stats.failed_send();
+            ptr.leave();
+            return Async::Err(env.msg);
+        }
+        //stamped under the lock so sequence order matches queue order
+        if ptr.sequenced {
+            env.stamp = Some(Meta {
+                sender: self.id,
+                seq: self.seq.fetch_add(1,RELAXED)
+            });
         }
-        ptr.append(data);
-        ptr.release();
+        let trace = env.trace;
+        let depth = unsafe{ ptr.append(env) };
+        ptr.stats.sent(depth);
+        ptr.leave();
+        trace.sent(depth);
         Async::Ok(())
     }
+    ///Statistics gathered by the channel so far
+    #[cfg(feature="stats")]
+    pub fn stats(&self) -> ChannelStats {
+        core(&self.data).stats.snapshot()
+    }
+    ///Sends every item of an iterator, retrying whenever the channel is
+    ///blocked
+    ///
+    ///Returns Ok(n) with the number of items sent once the iterator is
+    ///exhausted. If the receivers all drop part way through, returns the
+    ///count accepted so far along with the rejected item and the rest of
+    ///the iterator.
+    pub fn send_iter<I>(&self, iter: I) -> Result<usize,SendIterError<T,I::IntoIter>>
+    where
+        I: IntoIterator<Item=T>
+    {
+        let mut iter = iter.into_iter();
+        let mut accepted = 0usize;
+        let backoff = Backoff::new();
+        while let Option::Some(item) = iter.next() {
+            let mut item = item;
+            loop {
+                match self.send(item) {
+                    Async::Ok(()) => break,
+                    Async::Block(x) => {
+                        item = x;
+                        backoff.snooze();
+                    }
+                    Async::Err(x) => return Err(SendIterError {
+                        accepted,
+                        rejected: x,
+                        rest: iter
+                    })
+                };
+            }
+            accepted += 1;
+            backoff.reset();
+        }
+        Ok(accepted)
+    }
+    ///Build a weak handle to this channel
+    ///
+    ///The weak handle does not count as a live sender, so it does not keep
+    ///receivers from observing the channel closing.
+    pub fn downgrade(&self) -> WeakSender<T,P> {
+        WeakSender {
+            data: self.data.clone(),
+            marker: PhantomData
+        }
+    }
 }
 
-///Receiver
-pub struct MRMSReceiver<T: Sized+'static> {
-    data: Floater<ChannelCore<T>>,
+///Returned by `send_iter` when the channel closes mid-way
+pub struct SendIterError<T,I> {
+    ///items sent before the channel closed
+    pub accepted: usize,
+    ///the item that could not be delivered
+    pub rejected: T,
+    ///the items that were never attempted
+    pub rest: I
+}
+
+///Weak Send Item
+///
+///Does not contribute to the send count. Must be upgraded to an MRMSSender
+///before it can be used.
+pub struct WeakSender<T: Sized+'static, P: Policy = Unfair> {
+    data: Floater<ChannelCore<T,P>>,
     marker: PhantomData<&'static T>
 }
-impl<T: Sized+'static> Clone for MRMSReceiver<T> {
-    fn clone(&self) -> MRMSReceiver<T> {
-        self.data.get().recv.fetch_add(1,SEQ);
-        MRMSReceiver {
+impl<T: Sized+'static, P: Policy> Clone for WeakSender<T,P> {
+    fn clone(&self) -> WeakSender<T,P> {
+        WeakSender {
             data: self.data.clone(),
             marker: PhantomData
         }
     }
 }
-impl<T:Sized+'static> Drop for MRMSReceiver<T> {
+unsafe impl<T: Sized+'static, P: Policy> Sync for WeakSender<T,P> { }
+unsafe impl<T: Sized+'static, P: Policy> Send for WeakSender<T,P> { }
+impl<T: Sized+'static, P: Policy> WeakSender<T,P> {
+    ///Attempt to upgrade to a full sender
+    ///
+    ///Returns None if every MRMSSender has already been dropped
+    pub fn upgrade(&self) -> Option<MRMSSender<T,P>> {
+        if revive(&core(&self.data).send) {
+            Some(MRMSSender::attach(&self.data))
+        } else {
+            None
+        }
+    }
+}
+
+///Receiver
+pub struct MRMSReceiver<T: Sized+'static, P: Policy = Unfair> {
+    data: Floater<ChannelCore<T,P>>,
+    lane: usize,
+    marker: PhantomData<&'static T>
+}
+impl<T: Sized+'static, P: Policy> Clone for MRMSReceiver<T,P> {
+    fn clone(&self) -> MRMSReceiver<T,P> {
+        core(&self.data).recv.fetch_add(1,RELAXED);
+        MRMSReceiver::attach(&self.data)
+    }
+}
+impl<T: Sized+'static, P: Policy> Drop for MRMSReceiver<T,P> {
     fn drop(&mut self) {
-        self.data.get().recv.fetch_sub(1,SEQ);
-        let _ = self;
+        let ptr = core(&self.data);
+        if ptr.dispatch == Dispatch::Broadcast {
+            //senders read the count under the lock, so they see the count
+            //and the lanes change together
+            ptr.enter_spin();
+            ptr.recv.fetch_sub(1,RELEASE);
+            unsafe{ ptr.close_lane(self.lane) };
+            ptr.leave();
+        } else {
+            ptr.recv.fetch_sub(1,RELEASE);
+        }
     }
 }
-unsafe impl<T:Sized+'static> Sync for MRMSReceiver<T> { }
-unsafe impl<T:Sized+'static> Send for MRMSReceiver<T> { }
-impl<T:Sized+'static> MRMSReceiver<T> {
+unsafe impl<T: Sized+'static, P: Policy> Sync for MRMSReceiver<T,P> { }
+unsafe impl<T: Sized+'static, P: Policy> Send for MRMSReceiver<T,P> { }
+impl<T: Sized+'static, P: Policy> MRMSReceiver<T,P> {
+    ///Build a handle for a receiver that has already been counted, giving
+    ///it its own queue if the channel broadcasts
+    fn attach(data: &Floater<ChannelCore<T,P>>) -> MRMSReceiver<T,P> {
+        let ptr = core(data);
+        let lane = match ptr.dispatch {
+            Dispatch::Compete => 0,
+            Dispatch::Broadcast => {
+                ptr.enter_spin();
+                let lane = unsafe{ ptr.open_lane() };
+                ptr.leave();
+                lane
+            }
+        };
+        MRMSReceiver {
+            data: data.clone(),
+            lane,
+            marker: PhantomData
+        }
+    }
     ///Receive items
     ///
     ///Returns Async::Ok(Option<T>) an item may have returned
     ///Returns Async::Block(()) the channel is blocked
     ///Returns Async::Err(()) if there is no sender nor messages to read
     pub fn recv(&self) -> Async<Option<T>,(),()> {
-        let mut ptr = self.data.get_mut();
+        match self.pull() {
+            Async::Ok(x) => Async::Ok(x.map(|env| env.msg)),
+            Async::Block(()) => Async::Block(()),
+            Async::Err(()) => Async::Err(())
+        }
+    }
+    ///Receive a batch of items
+    ///
+    ///Waits until at least `min` items have been gathered, then returns up
+    ///to `max` of them. `min` is clamped to `max`.
+    ///
+    ///Returns Async::Ok(Vec<T>) once enough items were gathered, or with
+    ///whatever was left if every sender has gone away
+    ///Returns Async::Block(Vec<T>) with the items gathered so far if the
+    ///timeout fired first
+    ///Returns Async::Err(()) if there is no sender nor messages to read
+    pub fn recv_many(&self, min: usize, max: usize, timeout: Duration) -> Async<Vec<T>,Vec<T>,()> {
+        //None for a timeout too long to express as an Instant, waits for good
+        let deadline = Instant::now().checked_add(timeout);
+        let min = cmp::min(min, max);
+        let mut out = Vec::with_capacity(max);
+        let backoff = Backoff::new();
+        loop {
+            let want = max - out.len();
+            match self.pull_batch(want, |env| out.push(env.msg)) {
+                Async::Ok(_) |
+                Async::Block(()) => { }
+                Async::Err(()) if out.is_empty() => return Async::Err(()),
+                Async::Err(()) => return Async::Ok(out)
+            };
+            if out.len() >= min {
+                return Async::Ok(out);
+            }
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                return Async::Block(out);
+            }
+            backoff.snooze();
+        }
+    }
+    ///Receive items along with where they came from
+    ///
+    ///The Meta is only present on channels built with `channel_sequenced`.
+    ///Has the same return values as `recv`
+    pub fn recv_with_meta(&self) -> Async<Option<(T,Option<Meta>)>,(),()> {
+        match self.pull() {
+            Async::Ok(x) => Async::Ok(x.map(|env| (env.msg,env.stamp))),
+            Async::Block(()) => Async::Block(()),
+            Async::Err(()) => Async::Err(())
+        }
+    }
+    fn pull(&self) -> Async<Option<Envelope<T>>,(),()> {
+        let mut x = None;
+        match self.pull_batch(1, |env| x = Some(env)) {
+            Async::Ok(_) => Async::Ok(x),
+            Async::Block(()) => Async::Block(()),
+            Async::Err(()) => Async::Err(())
+        }
+    }
+    ///Hand up to `max` live messages to `sink` under a single lock,
+    ///returning how many were taken
+    fn pull_batch<F>(&self, max: usize, mut sink: F) -> Async<usize,(),()>
+    where
+        F: FnMut(Envelope<T>)
+    {
+        let ptr = core(&self.data);
         //failed to lock
-        if ptr.poll().is_err() {
+        if ptr.enter().is_err() {
+            ptr.stats.blocked();
+            instrument::channel_contended("recv");
             return Async::Block(());
         }
         //is there somebody to receive the result?
-        if ptr.data.len() == 0 && ptr.send_count() == 0 {
-            ptr.release();
+        if unsafe{ ptr.queue_len(self.lane) } == 0 && ptr.send_count() == 0 {
+            ptr.leave();
             return Async::Err(());
         }
-        let x = ptr.pop();
-        ptr.release();
-        Async::Ok(x)
+        //skip over anything that outlived its ttl
+        let mut now = None;
+        let mut expired = Vec::new();
+        let mut taken = 0;
+        while taken < max {
+            let env = match unsafe{ ptr.pop(self.lane) } {
+                Option::None => break,
+                Option::Some(env) => env
+            };
+            if env.is_expired(&mut now) {
+                expired.push(env.msg);
+            } else {
+                ptr.stats.received();
+                let trace = env.trace;
+                sink(env);
+                trace.received(unsafe{ ptr.queue_len(self.lane) });
+                taken += 1;
+            }
+        }
+        let dead_letter = if expired.is_empty() {
+            None
+        } else {
+            unsafe{ ptr.queues().dead_letter.clone() }
+        };
+        ptr.leave();
+        //run the callback outside of the lock
+        if let Option::Some(callback) = dead_letter {
+            for msg in expired {
+                callback(msg);
+            }
+        }
+        Async::Ok(taken)
+    }
+    ///Set the callback that receives expired messages
+    ///
+    ///Without a callback expired messages are silently dropped. The
+    ///callback runs on the receiving thread after the channel is unlocked.
+    ///Returns Async::Block(F) if the channel was blocked
+    pub fn set_dead_letter<F>(&self, callback: F) -> Async<(),F,()>
+    where
+        F: Fn(T) + Send + Sync + 'static
+    {
+        let ptr = core(&self.data);
+        if ptr.enter().is_err() {
+            return Async::Block(callback);
+        }
+        unsafe{ ptr.queues().dead_letter = Some(Arc::new(callback)) };
+        ptr.leave();
+        Async::Ok(())
+    }
+    ///Receive an item, retrying until one arrives or the deadline passes
+    ///
+    ///Returns Async::Ok(T) when an item was received
+    ///Returns Async::Block(()) if the deadline passed first
+    ///Returns Async::Err(()) if there is no sender nor messages to read
+    pub fn recv_deadline(&self, deadline: Instant) -> Async<T,(),()> {
+        self.recv_until(Some(deadline))
+    }
+    ///Retry `recv` until an item arrives or `deadline`, if there is one,
+    ///passes
+    fn recv_until(&self, deadline: Option<Instant>) -> Async<T,(),()> {
+        let backoff = Backoff::new();
+        loop {
+            match self.recv() {
+                Async::Ok(Option::Some(x)) => return Async::Ok(x),
+                Async::Err(()) => return Async::Err(()),
+                Async::Ok(Option::None) |
+                Async::Block(()) => { }
+            };
+            //with one thread nothing arrives while we wait
+            if SINGLE_THREADED || deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                return Async::Block(());
+            }
+            backoff.snooze();
+        }
+    }
+    ///Receive an item, retrying for at most `timeout`. A timeout too long
+    ///to express as an Instant waits for good.
+    ///
+    ///Has the same return values as `recv_deadline`
+    pub fn recv_timeout(&self, timeout: Duration) -> Async<T,(),()> {
+        if SINGLE_THREADED {
+            //single threaded wasm has no clock to read
+            return match self.recv() {
+                Async::Ok(Option::Some(x)) => Async::Ok(x),
+                Async::Ok(Option::None) |
+                Async::Block(()) => Async::Block(()),
+                Async::Err(()) => Async::Err(())
+            };
+        }
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+    ///Receive an item, retrying until one arrives or `token` is cancelled
+    ///
+    ///Returns Async::Ok(T) when an item was received
+    ///Returns Async::Block(Cancelled) if the token was cancelled first
+    ///Returns Async::Err(()) if there is no sender nor messages to read
+    pub fn recv_cancellable(&self, token: &CancellationToken) -> Async<T,Cancelled,()> {
+        let backoff = Backoff::new();
+        loop {
+            match self.recv() {
+                Async::Ok(Option::Some(x)) => return Async::Ok(x),
+                Async::Err(()) => return Async::Err(()),
+                Async::Ok(Option::None) |
+                Async::Block(()) => { }
+            };
+            if token.is_cancelled() {
+                return Async::Block(Cancelled);
+            }
+            backoff.snooze();
+        }
+    }
+    ///Copy the messages currently queued for this receiver
+    ///
+    ///The queue is left untouched. Messages that have already expired are
+    ///left out, the rest keep their remaining time to live.
+    ///Returns Async::Block(()) if the channel was blocked
+    #[cfg(feature="serde")]
+    pub fn snapshot(&self) -> Async<ChannelSnapshot<T>,(),()>
+    where
+        T: Clone
+    {
+        let ptr = core(&self.data);
+        if ptr.enter().is_err() {
+            return Async::Block(());
+        }
+        let now = Instant::now();
+        let mut messages = Vec::new();
+        if let Option::Some(queue) = unsafe{ ptr.queue(self.lane) } {
+            for env in queue.iter() {
+                let ttl = match env.expires {
+                    Option::None => None,
+                    Option::Some(deadline) if deadline > now => Some(deadline - now),
+                    Option::Some(_) => continue
+                };
+                messages.push((env.msg.clone(), ttl));
+            }
+        }
+        ptr.leave();
+        Async::Ok(ChannelSnapshot {
+            messages
+        })
+    }
+    ///Statistics gathered by the channel so far
+    #[cfg(feature="stats")]
+    pub fn stats(&self) -> ChannelStats {
+        core(&self.data).stats.snapshot()
+    }
+    ///Build a weak handle to this channel
+    ///
+    ///The weak handle does not count as a live receiver, so it does not keep
+    ///senders from observing the channel closing.
+    pub fn downgrade(&self) -> WeakReceiver<T,P> {
+        WeakReceiver {
+            data: self.data.clone(),
+            marker: PhantomData
+        }
+    }
+}
+
+///Consuming iterator over a receiver
+///
+///Yields messages until every sender has been dropped and the queue is
+///empty, backing off while the channel is empty or contended.
+pub struct IntoIter<T: Sized+'static, P: Policy = Unfair> {
+    rx: MRMSReceiver<T,P>
+}
+impl<T: Sized+'static, P: Policy> Iterator for IntoIter<T,P> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        let backoff = Backoff::new();
+        loop {
+            match self.rx.recv() {
+                Async::Ok(Option::Some(x)) => return Some(x),
+                Async::Err(()) => return None,
+                Async::Ok(Option::None) |
+                Async::Block(()) => backoff.snooze()
+            };
+        }
+    }
+}
+impl<T: Sized+'static, P: Policy> IntoIterator for MRMSReceiver<T,P> {
+    type Item = T;
+    type IntoIter = IntoIter<T,P>;
+    fn into_iter(self) -> IntoIter<T,P> {
+        IntoIter {
+            rx: self
+        }
+    }
+}
+
+///Weak Receiver
+///
+///Does not contribute to the recv count. Must be upgraded to an
+///MRMSReceiver before it can be used.
+pub struct WeakReceiver<T: Sized+'static, P: Policy = Unfair> {
+    data: Floater<ChannelCore<T,P>>,
+    marker: PhantomData<&'static T>
+}
+impl<T: Sized+'static, P: Policy> Clone for WeakReceiver<T,P> {
+    fn clone(&self) -> WeakReceiver<T,P> {
+        WeakReceiver {
+            data: self.data.clone(),
+            marker: PhantomData
+        }
+    }
+}
+unsafe impl<T: Sized+'static, P: Policy> Sync for WeakReceiver<T,P> { }
+unsafe impl<T: Sized+'static, P: Policy> Send for WeakReceiver<T,P> { }
+impl<T: Sized+'static, P: Policy> WeakReceiver<T,P> {
+    ///Attempt to upgrade to a full receiver
+    ///
+    ///Returns None if every MRMSReceiver has already been dropped
+    pub fn upgrade(&self) -> Option<MRMSReceiver<T,P>> {
+        if revive(&core(&self.data).recv) {
+            Some(MRMSReceiver::attach(&self.data))
+        } else {
+            None
+        }
     }
 }
 
@@ -144,15 +911,98 @@ impl<T:Sized+'static> MRMSReceiver<T> {
 ///
 ///Accepts a sized argument to pre-size it
 pub fn channel<T: Sized>(size: usize) -> (MRMSSender<T>,MRMSReceiver<T>) {
-   let x = Floater::new(ChannelCore::new(size));
-   let s = MRMSSender {
-       data: x.clone(),
-       marker: PhantomData
-    };
-   let r = MRMSReceiver {
-       data: x.clone(),
-       marker: PhantomData
-    };
+    channel_with_policy(size)
+}
+
+///Build a new MRMS Channel and register it with the debug registry
+///under `name`
+///
+///Only available with the `debug-registry` feature.
+#[cfg(feature="debug-registry")]
+pub fn channel_named<T: Sized+Send>(name: &str, size: usize) -> (MRMSSender<T>,MRMSReceiver<T>) {
+    let (tx, rx) = channel(size);
+    let weak = tx.data.downgrade();
+    debug::register(name, None, Box::new(move || {
+        weak.upgrade().map(|data| core(&data).debug_state())
+    }));
+    (tx, rx)
+}
+
+///Build a new MRMS Channel with an explicit acquisition policy, see the
+///`policy` module
+///
+///On unfair channels a call that stays contended for a few polls returns
+///Async::Block and whoever retries first wins. On fair channels calls spin
+///until their turn comes up, so they never return Async::Block.
+///Accepts a sized argument to pre-size it
+pub fn channel_with_policy<T: Sized, P: Policy>(size: usize) -> (MRMSSender<T,P>,MRMSReceiver<T,P>) {
+    build(ChannelCore::new(size, Dispatch::Compete, None))
+}
+
+///Build a new MRMS Channel whose messages are allocated in `arena`
+///
+///Messages arrive as ArenaBoxes, their memory is reclaimed when the arena
+///is reset. Accepts a sized argument to pre-size it
+pub fn channel_in_arena<T: Sized, P: Policy>(size: usize, arena: Arc<ConcurrentArena>) -> (ArenaSender<T,P>,MRMSReceiver<ArenaBox<T>,P>) {
+    let (tx, rx) = channel_with_policy(size);
+    (ArenaSender::new(tx, arena), rx)
+}
+
+///Build a new MRMS Channel that stamps every message with a Meta
+///
+///Each sender handle numbers its messages from zero, so receivers using
+///`recv_with_meta` can check per-producer ordering and detect drops.
+///Accepts a sized argument to pre-size it
+pub fn channel_sequenced<T: Sized>(size: usize) -> (MRMSSender<T>,MRMSReceiver<T>) {
+    let mut core = ChannelCore::new(size, Dispatch::Compete, None);
+    core.sequenced = true;
+    build(core)
+}
+
+///Build a new MRMS Channel with explicit delivery semantics
+///
+///Accepts a sized argument to pre-size it. Broadcast channels clone each
+///message once per live receiver.
+pub fn channel_with_dispatch<T: Sized+Clone>(size: usize, dispatch: Dispatch) -> (MRMSSender<T>,MRMSReceiver<T>) {
+    build(ChannelCore::new(size, dispatch, Some(<T as Clone>::clone)))
+}
+
+///Queued messages captured by `MRMSReceiver::snapshot`
+///
+///Only available with the `serde` feature. Serialize it with any serde
+///format and hand it to `channel_from_snapshot` to restore the queue.
+#[cfg(feature="serde")]
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct ChannelSnapshot<T> {
+    ///each message with its remaining time to live
+    pub messages: Vec<(T,Option<Duration>)>
+}
+
+///Build a new MRMS Channel pre-loaded with the contents of a snapshot
+///
+///Accepts a sized argument to pre-size it
+#[cfg(feature="serde")]
+pub fn channel_from_snapshot<T: Sized>(size: usize, snapshot: ChannelSnapshot<T>) -> (MRMSSender<T>,MRMSReceiver<T>) {
+    let now = Instant::now();
+    let core = ChannelCore::new(size, Dispatch::Compete, None);
+    for (msg,ttl) in snapshot.messages {
+        //nothing else can see the core yet
+        let env = Envelope {
+            msg,
+            //a ttl past the end of Instant never expires, as with send_with_ttl
+            expires: ttl.and_then(|ttl| now.checked_add(ttl)),
+            stamp: None,
+            trace: MsgTrace::new()
+        };
+        unsafe{ core.append(env) };
+    }
+    build(core)
+}
+
+fn build<T: Sized,P: Policy>(core: ChannelCore<T,P>) -> (MRMSSender<T,P>,MRMSReceiver<T,P>) {
+   let x = Floater::new(core);
+   let s = MRMSSender::attach(&x);
+   let r = MRMSReceiver::attach(&x);
    (s,r)
 }
 
@@ -198,3 +1048,274 @@ fn test_mrms_channel() {
         assert_eq!( output[9], 9usize);
     });
 }
+
+#[test]
+fn test_mrms_weak_handles() {
+    let (s,r) = channel::<usize>(4);
+    let weak_s = s.downgrade();
+    let weak_r = r.downgrade();
+    {
+        let s2 = weak_s.upgrade().expect("sender should be live");
+        assert!(s2.send(1).is_ok());
+    }
+    drop(s);
+    assert!(r.recv() == Async::Ok(Some(1)));
+    //the weak sender must not hold the channel open
+    assert!(r.recv().is_err());
+    assert!(weak_s.upgrade().is_none());
+    assert!(weak_r.upgrade().is_some());
+    drop(r);
+    assert!(weak_r.upgrade().is_none());
+}
+
+#[cfg(feature="stats")]
+#[test]
+fn test_mrms_stats() {
+    let (s,r) = channel::<usize>(4);
+    for x in 0..3 {
+        assert!(s.send(x).is_ok());
+    }
+    assert!(r.recv() == Async::Ok(Some(0)));
+    let stats = r.stats();
+    assert_eq!(stats.sent, 3);
+    assert_eq!(stats.received, 1);
+    assert_eq!(stats.high_water, 3);
+    drop(r);
+    assert!(s.send(9).is_err());
+    assert_eq!(s.stats().failed_sends, 1);
+}
+
+#[test]
+fn test_mrms_fifo_fairness() {
+    use std::thread;
+    use super::policy::Ticket;
+    let (s,r) = channel_with_policy::<usize,Ticket>(64);
+    let workers = (0..4).map(|id| {
+        let s = s.clone();
+        thread::spawn(move || {
+            for x in 0..100 {
+                //fifo channels never hand back Block
+                assert!(s.send(id*100+x).is_ok());
+            }
+        })
+    }).collect::<Vec<_>>();
+    drop(s);
+    for w in workers {
+        w.join().unwrap();
+    }
+    let mut output = Vec::new();
+    loop {
+        match r.recv() {
+            Async::Ok(Some(z)) => output.push(z),
+            Async::Ok(None) => continue,
+            Async::Block(()) => panic!("fifo recv blocked"),
+            Async::Err(()) => break
+        };
+    }
+    output.sort();
+    assert_eq!(output, (0..400).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_mrms_recv_deadline() {
+    let (s,r) = channel::<usize>(4);
+    let deadline = Instant::now() + Duration::from_millis(10);
+    assert!(r.recv_deadline(deadline).is_blocked());
+    assert!(Instant::now() >= deadline);
+    assert!(s.send(5).is_ok());
+    assert!(r.recv_timeout(Duration::from_millis(10)) == Async::Ok(5));
+    assert!(s.send(6).is_ok());
+    assert!(r.recv_timeout(Duration::MAX) == Async::Ok(6));
+    drop(s);
+    assert!(r.recv_timeout(Duration::from_millis(10)).is_err());
+}
+
+#[test]
+fn test_mrms_recv_cancellable() {
+    use std::thread;
+    let (s,r) = channel::<usize>(4);
+    let token = CancellationToken::new();
+    assert!(s.send(1).is_ok());
+    assert!(r.recv_cancellable(&token) == Async::Ok(1));
+    let canceller = {
+        let token = token.clone();
+        thread::spawn(move || token.cancel())
+    };
+    assert!(r.recv_cancellable(&token) == Async::Block(Cancelled));
+    canceller.join().unwrap();
+    drop(s);
+    assert!(r.recv_cancellable(&token).is_err());
+}
+
+#[test]
+fn test_mrms_into_iter() {
+    use std::thread;
+    let (s,r) = channel::<usize>(4);
+    let producer = thread::spawn(move || {
+        for x in 0..10 {
+            let mut y = x;
+            while let Async::Block(z) = s.send(y) {
+                y = z;
+            }
+        }
+    });
+    let output = r.into_iter().map(|x| x*2).collect::<Vec<_>>();
+    producer.join().unwrap();
+    assert_eq!(output, (0..10).map(|x| x*2).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_mrms_send_iter() {
+    let (s,r) = channel::<usize>(4);
+    match s.send_iter(0..3) {
+        Ok(n) => assert_eq!(n, 3),
+        Err(_) => panic!("channel is open")
+    };
+    drop(r);
+    match s.send_iter(10..15) {
+        Ok(_) => panic!("channel is closed"),
+        Err(e) => {
+            assert_eq!(e.accepted, 0);
+            assert_eq!(e.rejected, 10);
+            assert_eq!(e.rest.collect::<Vec<_>>(), vec![11,12,13,14]);
+        }
+    };
+}
+
+#[test]
+fn test_mrms_ttl() {
+    use std::thread;
+    use std::sync::Mutex;
+    let (s,r) = channel::<usize>(4);
+    let dead = Arc::new(Mutex::new(Vec::new()));
+    let sink = dead.clone();
+    assert!(r.set_dead_letter(move |x| sink.lock().unwrap().push(x)).is_ok());
+    assert!(s.send_with_ttl(1, Duration::from_millis(0)).is_ok());
+    assert!(s.send_with_ttl(2, Duration::from_secs(60)).is_ok());
+    assert!(s.send_with_ttl(3, Duration::MAX).is_ok());
+    thread::sleep(Duration::from_millis(1));
+    assert!(r.recv() == Async::Ok(Some(2)));
+    assert!(r.recv() == Async::Ok(Some(3)));
+    assert_eq!(*dead.lock().unwrap(), vec![1]);
+}
+
+#[test]
+fn test_mrms_poll_ready() {
+    let (s,r) = channel::<usize>(4);
+    assert!(s.poll_ready().is_ok());
+    //hold the lock the same way a concurrent send would
+    assert!(core(&s.data).enter().is_ok());
+    assert!(s.poll_ready().is_blocked());
+    core(&s.data).leave();
+    drop(r);
+    assert!(s.poll_ready().is_err());
+}
+
+#[test]
+fn test_mrms_broadcast() {
+    let (s,r1) = channel_with_dispatch::<usize>(4, Dispatch::Broadcast);
+    let r2 = r1.clone();
+    assert!(s.send(1).is_ok());
+    let r3 = r1.clone();
+    assert!(s.send(2).is_ok());
+    assert!(r1.recv() == Async::Ok(Some(1)));
+    assert!(r1.recv() == Async::Ok(Some(2)));
+    assert!(r2.recv() == Async::Ok(Some(1)));
+    assert!(r2.recv() == Async::Ok(Some(2)));
+    //r3 only sees what was sent after it joined
+    assert!(r3.recv() == Async::Ok(Some(2)));
+    assert!(r3.recv() == Async::Ok(None));
+    drop(r1);
+    drop(r2);
+    assert!(s.send(3).is_ok());
+    assert!(r3.recv() == Async::Ok(Some(3)));
+}
+
+#[cfg(feature="serde")]
+#[test]
+fn test_mrms_snapshot() {
+    use serde::de::DeserializeOwned;
+    fn is_serde<S: Serialize+DeserializeOwned>(_: &S) { }
+    let (s,r) = channel::<usize>(4);
+    assert!(s.send(1).is_ok());
+    assert!(s.send_with_ttl(2, Duration::from_secs(60)).is_ok());
+    let snap = match r.snapshot() {
+        Async::Ok(snap) => snap,
+        _ => panic!("snapshot failed")
+    };
+    is_serde(&snap);
+    assert_eq!(snap.messages.len(), 2);
+    //the original queue is untouched
+    assert!(r.recv() == Async::Ok(Some(1)));
+    let (_s2,r2) = channel_from_snapshot(4, snap);
+    assert!(r2.recv() == Async::Ok(Some(1)));
+    assert!(r2.recv() == Async::Ok(Some(2)));
+}
+
+#[test]
+fn test_mrms_sequenced() {
+    let (s1,r) = channel_sequenced::<usize>(8);
+    let s2 = s1.clone();
+    assert!(s1.send(10).is_ok());
+    assert!(s2.send(20).is_ok());
+    assert!(s1.send(11).is_ok());
+    let meta = |x: Async<Option<(usize,Option<Meta>)>,(),()>| match x {
+        Async::Ok(Option::Some((_,Option::Some(m)))) => m,
+        _ => panic!("missing meta")
+    };
+    assert_eq!(meta(r.recv_with_meta()), Meta { sender: s1.id(), seq: 0 });
+    assert_eq!(meta(r.recv_with_meta()), Meta { sender: s2.id(), seq: 0 });
+    assert_eq!(meta(r.recv_with_meta()), Meta { sender: s1.id(), seq: 1 });
+    //unsequenced channels carry no meta
+    let (s,r) = channel::<usize>(1);
+    assert!(s.send(1).is_ok());
+    assert!(r.recv_with_meta() == Async::Ok(Some((1,None))));
+}
+
+#[test]
+fn test_mrms_recv_many() {
+    let (s,r) = channel::<usize>(8);
+    assert!(s.send_iter(0..3).is_ok());
+    //not enough yet, the timeout hands back what was gathered
+    match r.recv_many(4, 8, Duration::from_millis(5)) {
+        Async::Block(x) => assert_eq!(x, vec![0,1,2]),
+        _ => panic!("expected a timeout")
+    };
+    assert!(s.send_iter(3..10).is_ok());
+    match r.recv_many(2, 4, Duration::from_millis(5)) {
+        Async::Ok(x) => assert_eq!(x, vec![3,4,5,6]),
+        _ => panic!("expected a full batch")
+    };
+    drop(s);
+    match r.recv_many(8, 8, Duration::MAX) {
+        Async::Ok(x) => assert_eq!(x, vec![7,8,9]),
+        _ => panic!("expected the remainder")
+    };
+    assert!(r.recv_many(1, 1, Duration::from_millis(1)).is_err());
+}
+
+#[test]
+fn test_mrms_in_arena() {
+    use super::policy::Unfair;
+    let mut arena = Arc::new(ConcurrentArena::new());
+    let (s,r) = channel_in_arena::<String,Unfair>(4, arena.clone());
+    assert!(s.send(String::from("tick")).is_ok());
+    match r.recv() {
+        Async::Ok(Some(msg)) => assert_eq!(*msg, "tick"),
+        _ => panic!("expected a message")
+    };
+    assert!(s.arena().allocated() > 0);
+    drop((s,r));
+    Arc::get_mut(&mut arena).unwrap().reset();
+    assert_eq!(arena.allocated(), 0);
+}
+
+#[test]
+fn test_mrms_send_limited() {
+    let limiter = RateLimiter::with_interval(2, Duration::from_secs(60));
+    let (s,r) = channel::<usize>(4);
+    assert!(s.send_limited(1, &limiter).is_ok());
+    assert!(s.send_limited(2, &limiter).is_ok());
+    assert!(s.send_limited(3, &limiter) == Async::Block(3));
+    assert!(r.recv() == Async::Ok(Some(1)));
+}
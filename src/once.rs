@@ -0,0 +1,351 @@
+//!One time initialization built on a spin wait.
+//!
+//!The fast path is a single load of the state word, callers only race on
+//!the compare and swap while the value has not been published yet.
+//!
+//!`OnceCell` and `Lazy` cover what the once_cell crate is usually pulled in
+//!for, both can be built in a `static`.
+
+
+use super::backoff::backoff;
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize,Ordering};
+const SEQ: Ordering = Ordering::SeqCst;
+
+const INCOMPLETE: usize = 0;
+const RUNNING: usize = 1;
+const COMPLETE: usize = 2;
+
+///Puts the state back to INCOMPLETE if the initializer unwinds, so the
+///next caller gets to try again instead of spinning forever
+struct Reset<'a> {
+    state: &'a AtomicUsize,
+    to: usize
+}
+impl<'a> Drop for Reset<'a> {
+    fn drop(&mut self) {
+        self.state.store(self.to, SEQ);
+    }
+}
+
+///Run a closure exactly once
+pub struct SpinOnce {
+    state: AtomicUsize
+}
+impl SpinOnce {
+    ///Build a SpinOnce that has not run yet
+    #[inline(always)]
+    pub const fn new() -> SpinOnce {
+        SpinOnce {
+            state: AtomicUsize::new(INCOMPLETE)
+        }
+    }
+    ///Run `lambda` if no call has completed yet
+    ///
+    ///Concurrent callers spin until the running call finishes. If `lambda`
+    ///panics the SpinOnce is left unrun and a later call tries again.
+    #[inline(always)]
+    pub fn call_once<F>(&self, lambda: F)
+    where
+        F: FnOnce()
+    {
+        if self.state.load(SEQ) == COMPLETE {
+            return;
+        }
+        self.call_slow(lambda);
+    }
+    #[cold]
+    fn call_slow<F>(&self, lambda: F)
+    where
+        F: FnOnce()
+    {
+        let _ = self.try_call_slow(|| {
+            lambda();
+            Ok::<(),()>(())
+        });
+    }
+    ///Run `lambda` if no call has completed yet, a call that returns Err
+    ///does not count as completed
+    ///
+    ///Returns the error from `lambda`, or Ok(()) if this or an earlier call
+    ///completed.
+    #[inline(always)]
+    pub fn try_call_once<E,F>(&self, lambda: F) -> Result<(),E>
+    where
+        F: FnOnce() -> Result<(),E>
+    {
+        if self.state.load(SEQ) == COMPLETE {
+            return Ok(());
+        }
+        self.try_call_slow(lambda)
+    }
+    #[cold]
+    fn try_call_slow<E,F>(&self, lambda: F) -> Result<(),E>
+    where
+        F: FnOnce() -> Result<(),E>
+    {
+        let mut step = 0;
+        loop {
+            match self.state.compare_exchange(INCOMPLETE, RUNNING, SEQ, SEQ) {
+                Ok(_) => {
+                    let mut reset = Reset { state: &self.state, to: INCOMPLETE };
+                    let r = lambda();
+                    if r.is_ok() {
+                        reset.to = COMPLETE;
+                    }
+                    return r;
+                }
+                Err(COMPLETE) => return Ok(()),
+                Err(_) => { backoff(&mut step); }
+            };
+        }
+    }
+    ///Returns true once a call has finished
+    #[inline(always)]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(SEQ) == COMPLETE
+    }
+}
+impl Default for SpinOnce {
+    fn default() -> SpinOnce {
+        SpinOnce::new()
+    }
+}
+
+///A cell written at most once
+pub struct SpinOnceLock<T> {
+    once: SpinOnce,
+    value: UnsafeCell<MaybeUninit<T>>
+}
+unsafe impl<T: Send> Send for SpinOnceLock<T> { }
+unsafe impl<T: Send+Sync> Sync for SpinOnceLock<T> { }
+impl<T> SpinOnceLock<T> {
+    ///Build an empty cell
+    #[inline(always)]
+    pub const fn new() -> SpinOnceLock<T> {
+        SpinOnceLock {
+            once: SpinOnce::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit())
+        }
+    }
+    ///The value, if it has been set
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get<'a>(&'a self) -> Option<&'a T> {
+        if self.once.is_completed() {
+            Some(unsafe{ (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+    ///The value, running `lambda` to produce it if the cell is empty
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_or_init<'a,F>(&'a self, lambda: F) -> &'a T
+    where
+        F: FnOnce() -> T
+    {
+        self.once.call_once(|| unsafe {
+            (*self.value.get()).write(lambda());
+        });
+        unsafe{ (*self.value.get()).assume_init_ref() }
+    }
+    ///The value, running `lambda` to produce it if the cell is empty. If
+    ///`lambda` fails the cell stays empty.
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_or_try_init<'a,E,F>(&'a self, lambda: F) -> Result<&'a T,E>
+    where
+        F: FnOnce() -> Result<T,E>
+    {
+        self.once.try_call_once(|| unsafe {
+            (*self.value.get()).write(lambda()?);
+            Ok(())
+        })?;
+        Ok(unsafe{ (*self.value.get()).assume_init_ref() })
+    }
+    ///Fill the cell
+    ///
+    ///Returns Err(value) if the cell was already set
+    #[inline(always)]
+    pub fn set(&self, value: T) -> Result<(),T> {
+        let mut slot = Some(value);
+        self.get_or_init(|| slot.take().unwrap());
+        match slot {
+            Option::None => Ok(()),
+            Option::Some(value) => Err(value)
+        }
+    }
+    ///Mutable access to the value, if it has been set
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> Option<&'a mut T> {
+        if self.once.is_completed() {
+            Some(unsafe{ (*self.value.get()).assume_init_mut() })
+        } else {
+            None
+        }
+    }
+    ///Empty the cell, returning the value if it has been set
+    #[inline(always)]
+    pub fn take(&mut self) -> Option<T> {
+        ::std::mem::take(self).into_inner()
+    }
+    ///Consume the cell, returning the value if it has been set
+    #[inline(always)]
+    pub fn into_inner(mut self) -> Option<T> {
+        if self.once.is_completed() {
+            //the state is reset so Drop does not drop the value again
+            self.once = SpinOnce::new();
+            Some(unsafe{ (*self.value.get()).assume_init_read() })
+        } else {
+            None
+        }
+    }
+}
+impl<T> From<T> for SpinOnceLock<T> {
+    fn from(value: T) -> SpinOnceLock<T> {
+        let cell = SpinOnceLock::new();
+        let _ = cell.set(value);
+        cell
+    }
+}
+impl<T> Default for SpinOnceLock<T> {
+    fn default() -> SpinOnceLock<T> {
+        SpinOnceLock::new()
+    }
+}
+impl<T> Drop for SpinOnceLock<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe{ (*self.value.get()).assume_init_drop() };
+        }
+    }
+}
+
+///The name most code knows a cell written at most once by
+pub type OnceCell<T> = SpinOnceLock<T>;
+
+///A value computed on first access
+///
+///If the initializer panics the Lazy is poisoned and every later access
+///panics as well.
+pub struct Lazy<T, F = fn() -> T> {
+    cell: SpinOnceLock<T>,
+    init: UnsafeCell<Option<F>>
+}
+unsafe impl<T: Send+Sync, F: Send> Sync for Lazy<T,F> { }
+impl<T, F: FnOnce() -> T> Lazy<T,F> {
+    ///Build a Lazy that runs `init` on first access
+    #[inline(always)]
+    pub const fn new(init: F) -> Lazy<T,F> {
+        Lazy {
+            cell: SpinOnceLock::new(),
+            init: UnsafeCell::new(Some(init))
+        }
+    }
+    ///The value, computing it if this is the first access
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn force<'a>(this: &'a Lazy<T,F>) -> &'a T {
+        this.cell.get_or_init(|| {
+            //only the one running initializer reaches this
+            match unsafe{ (*this.init.get()).take() } {
+                Option::Some(init) => init(),
+                Option::None => panic!("Lazy instance has previously been poisoned")
+            }
+        })
+    }
+    ///The value, if it has been computed
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get<'a>(this: &'a Lazy<T,F>) -> Option<&'a T> {
+        this.cell.get()
+    }
+    ///Consume the Lazy, returning the value or the initializer that has not
+    ///run yet
+    pub fn into_value(this: Lazy<T,F>) -> Result<T,F> {
+        let Lazy { cell, init } = this;
+        match cell.into_inner() {
+            Option::Some(value) => Ok(value),
+            Option::None => match init.into_inner() {
+                Option::Some(init) => Err(init),
+                Option::None => panic!("Lazy instance has previously been poisoned")
+            }
+        }
+    }
+}
+impl<T, F: FnOnce() -> T> Deref for Lazy<T,F> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+impl<T: Default> Default for Lazy<T> {
+    fn default() -> Lazy<T> {
+        Lazy::new(T::default)
+    }
+}
+
+#[test]
+fn test_spin_once_runs_once() {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    let once = Arc::new(SpinOnce::new());
+    let runs = Arc::new(AtomicUsize::new(0));
+    let workers = (0..4).map(|_| {
+        let once = once.clone();
+        let runs = runs.clone();
+        thread::spawn(move || once.call_once(|| { runs.fetch_add(1,SEQ); }))
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert!(once.is_completed());
+    assert_eq!(runs.load(SEQ), 1);
+}
+
+#[test]
+fn test_spin_once_static() {
+    static ONCE: SpinOnce = SpinOnce::new();
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    ONCE.call_once(|| { RUNS.fetch_add(1,SEQ); });
+    ONCE.call_once(|| { RUNS.fetch_add(1,SEQ); });
+    assert_eq!(RUNS.load(SEQ), 1);
+}
+
+#[test]
+fn test_spin_once_lock() {
+    static TABLE: SpinOnceLock<Vec<u32>> = SpinOnceLock::new();
+    assert!(TABLE.get().is_none());
+    assert_eq!(TABLE.get_or_init(|| vec![1,2,3]).len(), 3);
+    assert!(TABLE.set(vec![]) == Err(vec![]));
+    let cell = SpinOnceLock::new();
+    assert!(cell.set(String::from("x")).is_ok());
+    assert_eq!(cell.into_inner(), Some(String::from("x")));
+}
+
+#[test]
+fn test_lazy() {
+    use std::collections::HashMap;
+    static NAMES: Lazy<HashMap<u32,&'static str>> = Lazy::new(|| {
+        let mut m = HashMap::new();
+        m.insert(1, "one");
+        m
+    });
+    assert!(Lazy::get(&NAMES).is_none());
+    assert_eq!(NAMES.get(&1), Some(&"one"));
+    assert!(Lazy::get(&NAMES).is_some());
+    let cell = OnceCell::<u32>::new();
+    assert!(cell.get_or_try_init(|| "no".parse::<u32>()).is_err());
+    assert!(cell.get().is_none());
+    assert_eq!(cell.get_or_try_init(|| "7".parse::<u32>()), Ok(&7));
+    let mut cell = OnceCell::from(3);
+    assert_eq!(cell.take(), Some(3));
+    assert!(cell.get().is_none());
+    let lazy = Lazy::new(|| 5);
+    assert!(Lazy::into_value(lazy).is_err());
+}
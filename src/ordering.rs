@@ -0,0 +1,42 @@
+//!Memory ordering strategies.
+//!
+//!The lock types default to the weakest orderings their protocol allows:
+//!Acquire when the lock is taken, Release when it is handed back, and
+//!Relaxed for pure bookkeeping. Passing `Sequential` as the ordering
+//!parameter turns every one of those into SeqCst, for when the extra
+//!fences are worth easier auditing.
+
+
+use core::sync::atomic::Ordering;
+
+///Picks the orderings a lock uses for each role in its protocol
+pub trait MemoryOrdering {
+    ///taking the lock, and loads that must observe a release
+    const ACQUIRE: Ordering;
+    ///handing the lock back, and stores that publish data
+    const RELEASE: Ordering;
+    ///read-modify-writes that both take and publish
+    const ACQ_REL: Ordering;
+    ///counters and failed compare and swaps that order nothing
+    const RELAXED: Ordering;
+}
+
+///Acquire/Release/Relaxed, the default
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct AcquireRelease;
+impl MemoryOrdering for AcquireRelease {
+    const ACQUIRE: Ordering = Ordering::Acquire;
+    const RELEASE: Ordering = Ordering::Release;
+    const ACQ_REL: Ordering = Ordering::AcqRel;
+    const RELAXED: Ordering = Ordering::Relaxed;
+}
+
+///SeqCst everywhere
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct Sequential;
+impl MemoryOrdering for Sequential {
+    const ACQUIRE: Ordering = Ordering::SeqCst;
+    const RELEASE: Ordering = Ordering::SeqCst;
+    const ACQ_REL: Ordering = Ordering::SeqCst;
+    const RELAXED: Ordering = Ordering::SeqCst;
+}
@@ -0,0 +1,170 @@
+//!Token based thread parking.
+//!
+//!A Parker holds at most one token. `unpark` on any of its Unparkers leaves
+//!the token, `park` consumes it, blocking until it shows up. An unpark that
+//!comes first is not lost and several unparks before a park still only
+//!make one token. `park` returns only once it consumed a token or its
+//!timeout passed, spurious wakeups of the underlying `thread::park` are
+//!absorbed here so callers need no loop of their own.
+
+
+use super::spinlock::SpinLock;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread::{self,Thread};
+use std::time::{Duration,Instant};
+const SEQ: Ordering = Ordering::SeqCst;
+
+const EMPTY: usize = 0;
+const PARKED: usize = 1;
+const NOTIFIED: usize = 2;
+
+struct Inner {
+    state: AtomicUsize,
+    ///the thread inside `park`, set before it announces itself
+    thread: SpinLock<Option<Thread>>
+}
+impl Inner {
+    #[inline(always)]
+    fn take_token(&self) -> bool {
+        self.state.compare_exchange(NOTIFIED, EMPTY, SEQ, SEQ).is_ok()
+    }
+    ///Park until a token shows up or `deadline` passes
+    fn park(&self, deadline: Option<Instant>) -> bool {
+        if self.take_token() {
+            return true;
+        }
+        *self.thread.lock() = Some(thread::current());
+        if self.state.compare_exchange(EMPTY, PARKED, SEQ, SEQ).is_err() {
+            //an unpark came in meanwhile
+            self.state.store(EMPTY, SEQ);
+            return true;
+        }
+        loop {
+            match deadline {
+                Option::None => thread::park(),
+                Option::Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        //either withdraw or find the token that raced in
+                        return self.state.swap(EMPTY, SEQ) == NOTIFIED;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            };
+            if self.take_token() {
+                return true;
+            }
+        }
+    }
+    fn unpark(&self) {
+        if self.state.swap(NOTIFIED, SEQ) == PARKED {
+            if let Option::Some(ref t) = *self.thread.lock() {
+                t.unpark();
+            }
+        }
+    }
+}
+
+///Blocks the thread that owns it until an Unparker leaves a token
+///
+///Parker can be moved to another thread but not shared, only one thread
+///parks on it at a time.
+pub struct Parker {
+    inner: Arc<Inner>,
+    unparker: Unparker,
+    marker: PhantomData<*const ()>
+}
+unsafe impl Send for Parker { }
+impl Parker {
+    ///Build a parker without a token
+    pub fn new() -> Parker {
+        let inner = Arc::new(Inner {
+            state: AtomicUsize::new(EMPTY),
+            thread: SpinLock::new(None)
+        });
+        Parker {
+            unparker: Unparker { inner: inner.clone() },
+            inner,
+            marker: PhantomData
+        }
+    }
+    ///Block until a token is available and consume it
+    pub fn park(&self) {
+        self.inner.park(None);
+    }
+    ///Block until a token is available or `timeout` passes
+    ///
+    ///Returns true if a token was consumed. A timeout too long to express as
+    ///an Instant waits for good.
+    pub fn park_timeout(&self, timeout: Duration) -> bool {
+        self.inner.park(Instant::now().checked_add(timeout))
+    }
+    ///Block until a token is available or `deadline` passes
+    ///
+    ///Returns true if a token was consumed
+    pub fn park_deadline(&self, deadline: Instant) -> bool {
+        self.inner.park(Some(deadline))
+    }
+    ///Consume the token if there is one, without blocking
+    pub fn try_park(&self) -> bool {
+        self.inner.take_token()
+    }
+    ///Handle for waking this parker
+    #[inline(always)]
+    pub fn unparker(&self) -> &Unparker {
+        &self.unparker
+    }
+}
+impl Default for Parker {
+    fn default() -> Parker {
+        Parker::new()
+    }
+}
+
+///Leaves a token for a Parker, waking it if it is parked
+#[derive(Clone)]
+pub struct Unparker {
+    inner: Arc<Inner>
+}
+impl Unparker {
+    ///Leave a token, waking the parked thread if there is one
+    pub fn unpark(&self) {
+        self.inner.unpark();
+    }
+}
+
+#[test]
+fn test_parker_token() {
+    let parker = Parker::new();
+    assert!(!parker.try_park());
+    //tokens do not stack
+    parker.unparker().unpark();
+    parker.unparker().unpark();
+    parker.park();
+    assert!(!parker.park_timeout(Duration::from_millis(5)));
+    parker.unparker().unpark();
+    assert!(parker.park_deadline(Instant::now()));
+    parker.unparker().unpark();
+    assert!(parker.park_timeout(Duration::MAX));
+}
+
+#[test]
+fn test_parker_wakes() {
+    let parker = Parker::new();
+    let unparker = parker.unparker().clone();
+    let waker = thread::spawn(move || {
+        for _ in 0..100 {
+            unparker.unpark();
+            thread::yield_now();
+        }
+    });
+    parker.park();
+    let mut woken = 1;
+    while parker.park_timeout(Duration::from_millis(50)) {
+        woken += 1;
+    }
+    waker.join().unwrap();
+    assert!((1..=100).contains(&woken));
+}
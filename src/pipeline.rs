@@ -0,0 +1,248 @@
+//!Pipelines of threads joined by MRMS channels.
+//!
+//!`source` starts a thread feeding an iterator into the first channel,
+//!every stage adds worker threads reading from the previous channel and
+//!writing to a new one, and `sink` drains the last channel on the calling
+//!thread. Each channel only lets `capacity` items queue up, a fast stage
+//!waits for a slow one instead of piling up memory.
+//!
+//!Shutdown follows the data. When a stage's input runs dry and every
+//!upstream thread has finished, its workers drop their senders and the
+//!next stage sees the end in turn. If a downstream end goes away, upstream
+//!sends fail and those threads stop early. Stages with more than one
+//!worker do not keep items in order.
+
+
+use super::mrms::{channel,MRMSReceiver,MRMSSender};
+use super::semaphore::SpinSemaphore;
+use super::backoff::{backoff,Backoff};
+use super::Async;
+use std::sync::Arc;
+use std::thread::{self,JoinHandle};
+
+///Items a channel between stages holds unless `capacity` says otherwise
+pub const DEFAULT_CAPACITY: usize = 64;
+
+///Sending end of a bounded link between stages
+struct Outlet<T: Send+'static> {
+    sender: MRMSSender<T>,
+    room: Arc<SpinSemaphore>
+}
+impl<T: Send+'static> Clone for Outlet<T> {
+    fn clone(&self) -> Outlet<T> {
+        Outlet {
+            sender: self.sender.clone(),
+            room: self.room.clone()
+        }
+    }
+}
+impl<T: Send+'static> Outlet<T> {
+    ///Wait for room and send, Err if the receiving stage is gone
+    fn push(&self, item: T) -> Result<(),T> {
+        let mut step = 0;
+        loop {
+            if let Ok(permit) = self.room.try_acquire() {
+                permit.forget();
+                break;
+            }
+            if self.sender.poll_ready().is_err() {
+                return Err(item);
+            }
+            backoff(&mut step);
+        }
+        let mut item = item;
+        loop {
+            match self.sender.send(item) {
+                Async::Ok(()) => return Ok(()),
+                Async::Block(x) => {
+                    item = x;
+                    backoff(&mut step);
+                }
+                Async::Err(x) => return Err(x)
+            };
+        }
+    }
+}
+
+///Receiving end of a bounded link between stages
+struct Inlet<T: Send+'static> {
+    receiver: MRMSReceiver<T>,
+    room: Arc<SpinSemaphore>
+}
+impl<T: Send+'static> Clone for Inlet<T> {
+    fn clone(&self) -> Inlet<T> {
+        Inlet {
+            receiver: self.receiver.clone(),
+            room: self.room.clone()
+        }
+    }
+}
+impl<T: Send+'static> Inlet<T> {
+    ///Wait for an item, None once the sending stage is done
+    fn pull(&self) -> Option<T> {
+        let backoff = Backoff::new();
+        loop {
+            match self.receiver.recv() {
+                Async::Ok(Option::Some(item)) => {
+                    self.room.release(1);
+                    return Some(item);
+                }
+                Async::Err(()) => return None,
+                Async::Ok(Option::None) |
+                Async::Block(()) => { }
+            };
+            backoff.snooze();
+        }
+    }
+}
+
+fn link<T: Send+'static>(capacity: usize) -> (Outlet<T>,Inlet<T>) {
+    let (sender, receiver) = channel(capacity);
+    let room = Arc::new(SpinSemaphore::new(capacity.max(1)));
+    (Outlet { sender, room: room.clone() }, Inlet { receiver, room })
+}
+
+///A chain of running stages whose output is not consumed yet
+pub struct Pipeline<T: Send+'static> {
+    inlet: Inlet<T>,
+    threads: Vec<JoinHandle<()>>,
+    capacity: usize
+}
+
+///Start a pipeline with a thread feeding it the items of `iter`
+pub fn source<I>(iter: I) -> Pipeline<I::Item>
+where
+    I: IntoIterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    source_with_capacity(iter, DEFAULT_CAPACITY)
+}
+
+///Start a pipeline whose first channel holds `capacity` items
+pub fn source_with_capacity<I>(iter: I, capacity: usize) -> Pipeline<I::Item>
+where
+    I: IntoIterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    let (outlet, inlet) = link(capacity);
+    let feeder = thread::spawn(move || {
+        for item in iter {
+            if outlet.push(item).is_err() {
+                break;
+            }
+        }
+    });
+    Pipeline {
+        inlet,
+        threads: vec![feeder],
+        capacity
+    }
+}
+
+impl<T: Send+'static> Pipeline<T> {
+    ///Channels of the stages added after this hold `capacity` items
+    pub fn capacity(mut self, capacity: usize) -> Pipeline<T> {
+        self.capacity = capacity;
+        self
+    }
+    ///Add a stage of `workers` threads driving every item through `run`
+    fn stage<U,F>(mut self, workers: usize, run: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        F: Fn(T, &Outlet<U>) -> Result<(),()> + Send + Sync + 'static,
+    {
+        let (outlet, inlet) = link(self.capacity);
+        let run = Arc::new(run);
+        for _ in 0..workers.max(1) {
+            let input = self.inlet.clone();
+            let output = outlet.clone();
+            let run = run.clone();
+            self.threads.push(thread::spawn(move || {
+                while let Option::Some(item) = input.pull() {
+                    if run(item, &output).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        Pipeline {
+            inlet,
+            threads: self.threads,
+            capacity: self.capacity
+        }
+    }
+    ///Add a stage of `workers` threads passing on `f` of every item
+    pub fn map_stage<U,F>(self, workers: usize, f: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        F: Fn(T) -> U + Send + Sync + 'static,
+    {
+        self.stage(workers, move |item, out| out.push(f(item)).map_err(|_| ()))
+    }
+    ///Add a stage of `workers` threads passing on the items `f` returns
+    ///true for
+    pub fn filter_stage<F>(self, workers: usize, f: F) -> Pipeline<T>
+    where
+        F: Fn(&T) -> bool + Send + Sync + 'static,
+    {
+        self.stage(workers, move |item, out| {
+            if f(&item) {
+                out.push(item).map_err(|_| ())
+            } else {
+                Ok(())
+            }
+        })
+    }
+    ///Drain the pipeline into `f` on this thread, then wait for every
+    ///stage to finish
+    ///
+    ///Returns the panic of the first stage thread that panicked
+    pub fn sink<F: FnMut(T)>(self, mut f: F) -> thread::Result<()> {
+        while let Option::Some(item) = self.inlet.pull() {
+            f(item);
+        }
+        drop(self.inlet);
+        let mut result = Ok(());
+        for t in self.threads {
+            if let Err(e) = t.join() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+    ///Drain the pipeline into a Vec
+    pub fn collect(self) -> thread::Result<Vec<T>> {
+        let mut items = Vec::new();
+        self.sink(|item| items.push(item))?;
+        Ok(items)
+    }
+}
+
+#[test]
+fn test_pipeline_stages() {
+    let mut out = source(0..250usize)
+        .capacity(4)
+        .map_stage(3, |x| x * 2)
+        .filter_stage(2, |x| x % 3 == 0)
+        .map_stage(1, |x| x + 1)
+        .collect()
+        .unwrap();
+    out.sort();
+    let want = (0..250usize).map(|x| x * 2).filter(|x| x % 3 == 0).map(|x| x + 1).collect::<Vec<_>>();
+    assert_eq!(out, want);
+}
+
+#[test]
+fn test_pipeline_stage_panic() {
+    let result = source_with_capacity(0..10, 2)
+        .map_stage(1, |x: i32| {
+            if x == 5 {
+                panic!("bad item");
+            }
+            x
+        })
+        .sink(|_| ());
+    assert!(result.is_err());
+}
@@ -0,0 +1,114 @@
+//!Opt-in lock poisoning.
+//!
+//!Locks built with poisoning enabled remember when a guard was dropped
+//!while its thread was panicking. The checked acquisition methods then hand
+//!the guard back wrapped in a PoisonError so the caller can decide whether
+//!the data is still usable.
+//!
+//!Without the `std` feature there is no way to tell a panicking thread
+//!apart, so locks are never poisoned.
+
+
+use core::error::Error;
+use core::fmt;
+use core::sync::atomic::{AtomicBool,Ordering};
+#[cfg(feature="std")]
+use std::thread;
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Result of a checked lock, the guard either way
+pub type LockResult<G> = Result<G,PoisonError<G>>;
+
+///The lock was poisoned, the guard is still held inside
+pub struct PoisonError<G> {
+    guard: G
+}
+impl<G> PoisonError<G> {
+    #[inline(always)]
+    pub(crate) fn new(guard: G) -> PoisonError<G> {
+        PoisonError { guard }
+    }
+    ///Ignore the poisoning and take the guard
+    #[inline(always)]
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_ref<'a>(&'a self) -> &'a G {
+        &self.guard
+    }
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut G {
+        &mut self.guard
+    }
+}
+impl<G> fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PoisonError { .. }")
+    }
+}
+impl<G> fmt::Display for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a thread panicked while holding this lock")
+    }
+}
+impl<G> Error for PoisonError<G> { }
+
+#[cfg(feature="std")]
+#[inline(always)]
+fn panicking() -> bool {
+    thread::panicking()
+}
+#[cfg(not(feature="std"))]
+#[inline(always)]
+fn panicking() -> bool {
+    false
+}
+
+///Poison flag embedded in a lock. When poisoning is disabled guards are
+///never armed and the flag is never set.
+pub(crate) struct Poison {
+    enabled: bool,
+    poisoned: AtomicBool
+}
+impl Poison {
+    #[inline(always)]
+    pub(crate) const fn new(enabled: bool) -> Poison {
+        Poison {
+            enabled,
+            poisoned: AtomicBool::new(false)
+        }
+    }
+    ///Called when a guard is created. Returns true if the guard should
+    ///poison the lock should its thread start panicking.
+    #[inline(always)]
+    pub(crate) fn arm(&self) -> bool {
+        self.enabled && !panicking()
+    }
+    ///Called when a guard is dropped
+    #[inline(always)]
+    pub(crate) fn disarm(&self, armed: bool) {
+        if armed && panicking() {
+            self.poisoned.store(true, SEQ);
+        }
+    }
+    #[inline(always)]
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.poisoned.load(SEQ)
+    }
+    #[inline(always)]
+    pub(crate) fn clear(&self) {
+        self.poisoned.store(false, SEQ);
+    }
+    ///Wrap a freshly taken guard according to the flag
+    #[inline(always)]
+    pub(crate) fn check<G>(&self, guard: G) -> Result<G,PoisonError<G>> {
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//!Acquisition policies.
+//!
+//!A policy picks the raw lock a SpinLock or a channel core is built on.
+//!`Unfair` is a single word taken with compare and swap, fastest when
+//!contention is low but a waiter can lose every race. `Ticket` serves
+//!waiters in arrival order at the cost of everybody spinning on one shared
+//!counter. `Queued` is an MCS queue, also in arrival order, where every
+//!waiter spins on its own node.
+
+
+use super::mcs::RawMcsLock;
+use super::ordering::MemoryOrdering;
+use super::spinlock::{Lock,LoanLock,RawSpinLock,TicketLock,LOCK_BIT};
+
+///Picks how contending callers acquire a lock
+pub trait Policy {
+    ///Raw lock backing the policy, using the orderings of `O` where it
+    ///supports them
+    type Raw<O: MemoryOrdering>: Lock;
+    ///True if `Lock::lock` on the raw lock serves waiters in arrival order.
+    ///Fair locks are waited on with `Lock::lock` rather than by polling, a
+    ///poll never jumps the queue.
+    const FAIR: bool;
+    ///Build an unlocked raw lock
+    fn raw<O: MemoryOrdering>() -> Self::Raw<O>;
+    ///Returns true if somebody holds or waits on the raw lock
+    fn is_locked<O: MemoryOrdering>(raw: &Self::Raw<O>) -> bool;
+}
+
+///Compare and swap on one word, whoever retries first wins. The default.
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct Unfair;
+impl Policy for Unfair {
+    type Raw<O: MemoryOrdering> = RawSpinLock<O>;
+    const FAIR: bool = false;
+    fn raw<O: MemoryOrdering>() -> RawSpinLock<O> {
+        RawSpinLock::with_ordering()
+    }
+    fn is_locked<O: MemoryOrdering>(raw: &RawSpinLock<O>) -> bool {
+        raw.loan().load(O::RELAXED) & LOCK_BIT != 0
+    }
+}
+
+///Ticket lock, waiters are served in arrival order
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct Ticket;
+impl Policy for Ticket {
+    type Raw<O: MemoryOrdering> = TicketLock<O>;
+    const FAIR: bool = true;
+    fn raw<O: MemoryOrdering>() -> TicketLock<O> {
+        TicketLock::with_ordering()
+    }
+    fn is_locked<O: MemoryOrdering>(raw: &TicketLock<O>) -> bool {
+        raw.queued() != 0
+    }
+}
+
+///MCS queue lock, waiters are served in arrival order and each spins on
+///its own cache line. Always uses SeqCst.
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct Queued;
+impl Policy for Queued {
+    type Raw<O: MemoryOrdering> = RawMcsLock;
+    const FAIR: bool = true;
+    fn raw<O: MemoryOrdering>() -> RawMcsLock {
+        RawMcsLock::new()
+    }
+    fn is_locked<O: MemoryOrdering>(raw: &RawMcsLock) -> bool {
+        raw.is_locked()
+    }
+}
@@ -0,0 +1,301 @@
+//!Fixed size thread pool.
+//!
+//!Jobs go through an MRMS channel that every worker receives from. Idle
+//!workers back off and then park, `execute` unparks one of them after
+//!queueing a job. Shutting down drops the sender, workers drain whatever
+//!is still queued, see the channel close and exit.
+//!
+//!`ThreadPool::builder` can pin workers to cores or a NUMA node, each
+//!worker applies its `Affinity` as it starts. Pinning is best effort, a
+//!worker the OS refuses to pin runs unpinned.
+//!
+//!On a single threaded target there are no workers to start, `execute`
+//!runs each job on the spot instead.
+//!
+//!With the `debug-registry` feature every pool registers itself under the
+//!name given to the builder.
+
+
+use super::Async;
+use super::mrms::{self,MRMSSender,MRMSReceiver};
+use super::backoff::{backoff,SINGLE_THREADED};
+use super::affinity::{self,Affinity};
+use super::instrument;
+#[cfg(feature="debug-registry")]
+use super::debug;
+use std::panic::{self,AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread::{self,JoinHandle,Thread};
+use std::time::Duration;
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+
+///Empty polls an idle worker makes before parking
+const IDLE_POLLS: u32 = 8;
+///Longest an idle worker parks before checking the channel again, covers
+///an unpark that raced with the worker going to sleep
+const PARK_LIMIT: Duration = Duration::from_millis(1);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+///Counters shared by the pool handle and its workers
+struct Shared {
+    pending: AtomicUsize,
+    panicked: AtomicUsize
+}
+
+///A fixed number of worker threads running submitted jobs
+pub struct ThreadPool {
+    sender: Option<MRMSSender<Job>>,
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    threads: Vec<Thread>,
+    next: AtomicUsize
+}
+impl ThreadPool {
+    ///Start a pool of `n` worker threads, at least one
+    pub fn new(n: usize) -> ThreadPool {
+        ThreadPool::builder().workers(n).build()
+    }
+    ///Configure a pool before starting it
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            workers: 1,
+            affinity: Affinity::Unpinned,
+            name: String::from("pool-worker")
+        }
+    }
+    fn start(builder: ThreadPoolBuilder) -> ThreadPool {
+        let (sender,receiver) = mrms::channel::<Job>(64);
+        let shared = Arc::new(Shared {
+            pending: AtomicUsize::new(0),
+            panicked: AtomicUsize::new(0)
+        });
+        let n = if SINGLE_THREADED { 0 } else { builder.workers.max(1) };
+        let workers = (0..n).map(|i| {
+            let receiver = receiver.clone();
+            let shared = shared.clone();
+            let cores = builder.affinity.resolve(i);
+            thread::Builder::new()
+                .name(format!("{}-{}", builder.name, i))
+                .spawn(move || {
+                    if let Option::Some(cores) = cores {
+                        let _ = affinity::set_current(&cores);
+                    }
+                    work(i, receiver, shared)
+                })
+                .expect("failed to spawn pool worker")
+        }).collect::<Vec<_>>();
+        let threads = workers.iter().map(|w| w.thread().clone()).collect();
+        #[cfg(feature="debug-registry")]
+        {
+            let weak = Arc::downgrade(&shared);
+            debug::register(&builder.name, None, Box::new(move || {
+                weak.upgrade().map(|shared| debug::State::Pool {
+                    workers: n,
+                    pending: shared.pending.load(RELAXED),
+                    panicked: shared.panicked.load(RELAXED)
+                })
+            }));
+        }
+        ThreadPool {
+            sender: Some(sender),
+            shared,
+            workers,
+            threads,
+            next: AtomicUsize::new(0)
+        }
+    }
+    ///Queue `job` to run on one of the workers
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.pending.fetch_add(1, RELAXED);
+        let mut job: Job = Box::new(job);
+        if self.threads.is_empty() {
+            run(0, job, &self.shared);
+            return;
+        }
+        let sender = self.sender.as_ref().unwrap();
+        let mut step = 0;
+        loop {
+            match sender.send(job) {
+                Async::Ok(()) => break,
+                Async::Block(x) => {
+                    job = x;
+                    backoff(&mut step);
+                }
+                //workers only exit once the sender is dropped
+                Async::Err(_) => unreachable!("pool workers exited early")
+            };
+        }
+        let next = self.next.fetch_add(1, RELAXED) % self.threads.len();
+        self.threads[next].unpark();
+    }
+    ///Block until every job submitted so far has finished
+    pub fn join(&self) {
+        let mut step = 0;
+        while self.shared.pending.load(ACQUIRE) != 0 {
+            backoff(&mut step);
+        }
+    }
+    ///Number of worker threads, zero on a single threaded target
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+    ///Number of jobs that panicked. A panicking job does not take its
+    ///worker down.
+    pub fn panicked(&self) -> usize {
+        self.shared.panicked.load(RELAXED)
+    }
+    ///Run every queued job, then stop the workers and wait for them to exit
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+    fn stop(&mut self) {
+        //dropping the only sender closes the channel once it is drained
+        drop(self.sender.take());
+        for t in self.threads.iter() {
+            t.unpark();
+        }
+        for w in self.workers.drain(..) {
+            let _ = w.join();
+        }
+    }
+}
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+///Configures a ThreadPool
+pub struct ThreadPoolBuilder {
+    workers: usize,
+    affinity: Affinity,
+    name: String
+}
+impl ThreadPoolBuilder {
+    ///Start `n` worker threads, at least one
+    pub fn workers(mut self, n: usize) -> ThreadPoolBuilder {
+        self.workers = n;
+        self
+    }
+    ///Restrict where the workers run
+    pub fn affinity(mut self, affinity: Affinity) -> ThreadPoolBuilder {
+        self.affinity = affinity;
+        self
+    }
+    ///Pin worker `i` to `cores[i % cores.len()]`
+    pub fn pin_cores(self, cores: Vec<usize>) -> ThreadPoolBuilder {
+        self.affinity(Affinity::Cores(cores))
+    }
+    ///Keep every worker on the cores of NUMA node `node`
+    pub fn numa_node(self, node: usize) -> ThreadPoolBuilder {
+        self.affinity(Affinity::NumaNode(node))
+    }
+    ///Name workers `{name}-{index}`
+    pub fn name<S: Into<String>>(mut self, name: S) -> ThreadPoolBuilder {
+        self.name = name.into();
+        self
+    }
+    ///Start the pool
+    pub fn build(self) -> ThreadPool {
+        ThreadPool::start(self)
+    }
+}
+
+///Run one job, a panic is counted rather than passed on
+fn run(index: usize, job: Job, shared: &Shared) {
+    if instrument::task(index, || panic::catch_unwind(AssertUnwindSafe(job))).is_err() {
+        shared.panicked.fetch_add(1, RELAXED);
+    }
+    shared.pending.fetch_sub(1, RELEASE);
+}
+
+///Worker loop, runs jobs until the channel is closed and empty
+fn work(index: usize, receiver: MRMSReceiver<Job>, shared: Arc<Shared>) {
+    let mut idle = 0;
+    let mut step = 0;
+    loop {
+        match receiver.recv() {
+            Async::Ok(Option::Some(job)) => {
+                idle = 0;
+                step = 0;
+                run(index, job, &shared);
+            }
+            Async::Ok(Option::None) |
+            Async::Block(()) => {
+                idle += 1;
+                if idle < IDLE_POLLS {
+                    backoff(&mut step);
+                } else {
+                    thread::park_timeout(PARK_LIMIT);
+                }
+            }
+            Async::Err(()) => return
+        };
+    }
+}
+
+#[test]
+fn test_pool_runs_jobs() {
+    let pool = ThreadPool::new(3);
+    let sum = Arc::new(AtomicUsize::new(0));
+    for i in 0..100 {
+        let sum = sum.clone();
+        pool.execute(move || { sum.fetch_add(i, RELAXED); });
+    }
+    pool.join();
+    assert_eq!(sum.load(RELAXED), 4950);
+    pool.execute(|| panic!("job failed"));
+    pool.join();
+    assert_eq!(pool.panicked(), 1);
+    //queued jobs still run during shutdown
+    for _ in 0..10 {
+        let sum = sum.clone();
+        pool.execute(move || { sum.fetch_add(1, RELAXED); });
+    }
+    pool.shutdown();
+    assert_eq!(sum.load(RELAXED), 4960);
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_pool_pinned() {
+    let core = affinity::current().unwrap()[0];
+    let pool = ThreadPool::builder().workers(2).pin_cores(vec![core]).name("pinned").build();
+    let seen = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    for _ in 0..4 {
+        let seen = seen.clone();
+        pool.execute(move || {
+            let name = thread::current().name().unwrap().to_string();
+            seen.lock().unwrap().push((name, affinity::current().unwrap()));
+        });
+    }
+    pool.join();
+    for (name, cores) in seen.lock().unwrap().iter() {
+        assert!(name.starts_with("pinned-"));
+        assert_eq!(*cores, vec![core]);
+    }
+}
+
+#[cfg(single_threaded)]
+#[test]
+fn single_thread_pool() {
+    let pool = ThreadPool::new(4);
+    assert_eq!(pool.workers(), 0);
+    let sum = Arc::new(AtomicUsize::new(0));
+    for i in 0..10 {
+        let sum = sum.clone();
+        pool.execute(move || { sum.fetch_add(i, RELAXED); });
+    }
+    //jobs ran on the spot
+    assert_eq!(sum.load(RELAXED), 45);
+    pool.execute(|| panic!("job failed"));
+    pool.join();
+    assert_eq!(pool.panicked(), 1);
+}
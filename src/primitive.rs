@@ -0,0 +1,107 @@
+//!The atomics and cells the lock and channel core are built on.
+//!
+//!Normally these are the `core` types. Under `--cfg loom` they are loom's,
+//!so the loom tests can check every interleaving of the protocols built on
+//!them. Loom's types have no `const` constructors, `loom_const_fn!` drops
+//!the `const` from a constructor under loom.
+
+
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic::{AtomicBool,AtomicU8,AtomicU16,AtomicU32,AtomicUsize};
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicBool,AtomicU8,AtomicU16,AtomicU32,AtomicUsize};
+
+///Declare a function that is `const` unless building under loom
+macro_rules! loom_const_fn {
+    ($(#[$attr:meta])* $vis:vis fn $($rest:tt)*) => {
+        $(#[$attr])*
+        #[cfg(not(loom))]
+        $vis const fn $($rest)*
+        $(#[$attr])*
+        #[cfg(loom)]
+        $vis fn $($rest)*
+    }
+}
+
+///Body of a busy wait. Under loom it yields to the model's scheduler, a
+///loop that never yields would never let the other side run.
+#[inline(always)]
+pub(crate) fn spin_loop() {
+    #[cfg(not(loom))]
+    core::hint::spin_loop();
+    #[cfg(loom)]
+    loom::hint::spin_loop();
+}
+
+///An UnsafeCell reached through `with` and `with_mut`, which let loom see
+///every access to the data
+#[cfg(not(loom))]
+#[repr(transparent)]
+pub(crate) struct UnsafeCell<T: ?Sized> {
+    data: core::cell::UnsafeCell<T>
+}
+#[cfg(not(loom))]
+impl<T> UnsafeCell<T> {
+    #[inline(always)]
+    pub(crate) const fn new(data: T) -> UnsafeCell<T> {
+        UnsafeCell {
+            data: core::cell::UnsafeCell::new(data)
+        }
+    }
+}
+#[cfg(not(loom))]
+impl<T: ?Sized> UnsafeCell<T> {
+    #[inline(always)]
+    pub(crate) fn with<R,F: FnOnce(*const T) -> R>(&self, f: F) -> R {
+        f(self.data.get())
+    }
+    #[inline(always)]
+    pub(crate) fn with_mut<R,F: FnOnce(*mut T) -> R>(&self, f: F) -> R {
+        f(self.data.get())
+    }
+}
+
+///Loom only tracks accesses to sized cells, so the data sits next to an
+///empty loom cell that every access is recorded on
+#[cfg(loom)]
+#[repr(C)]
+pub(crate) struct UnsafeCell<T: ?Sized> {
+    track: loom::cell::UnsafeCell<()>,
+    data: core::cell::UnsafeCell<T>
+}
+#[cfg(loom)]
+impl<T> UnsafeCell<T> {
+    pub(crate) fn new(data: T) -> UnsafeCell<T> {
+        UnsafeCell {
+            track: loom::cell::UnsafeCell::new(()),
+            data: core::cell::UnsafeCell::new(data)
+        }
+    }
+}
+#[cfg(loom)]
+impl<T: ?Sized> UnsafeCell<T> {
+    pub(crate) fn with<R,F: FnOnce(*const T) -> R>(&self, f: F) -> R {
+        self.track.with(|_| f(self.data.get()))
+    }
+    pub(crate) fn with_mut<R,F: FnOnce(*mut T) -> R>(&self, f: F) -> R {
+        self.track.with_mut(|_| f(self.data.get()))
+    }
+}
+
+impl<T> UnsafeCell<T> {
+    #[inline(always)]
+    pub(crate) fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+impl<T: ?Sized> UnsafeCell<T> {
+    ///Pointer to the data, loom does not see accesses made through it
+    #[inline(always)]
+    pub(crate) fn get(&self) -> *mut T {
+        self.data.get()
+    }
+    #[inline(always)]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
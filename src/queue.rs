@@ -0,0 +1,225 @@
+//!Michael-Scott queue.
+//!
+//!An unbounded lock free FIFO built on a singly linked list with a dummy
+//!node at the head. Producers link new nodes after the tail and swing the
+//!tail forward, any thread that finds the tail lagging helps it along.
+//!Consumers move the head one node forward and take the value from the
+//!node that becomes the new dummy. Unlinked dummies are freed through the
+//!epoch collector.
+
+
+use super::Async;
+use super::epoch;
+use super::backoff::backoff;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr,Ordering};
+use std::time::{Duration,Instant};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+struct Node<T> {
+    ///uninitialized in the dummy node and once taken by a dequeue
+    value: MaybeUninit<T>,
+    next: AtomicPtr<Node<T>>
+}
+impl<T> Node<T> {
+    fn alloc(value: MaybeUninit<T>) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            value,
+            next: AtomicPtr::new(ptr::null_mut())
+        }))
+    }
+}
+
+///Unbounded lock free multi producer multi consumer queue
+pub struct MsQueue<T> {
+    head: AtomicPtr<Node<T>>,
+    tail: AtomicPtr<Node<T>>,
+    marker: PhantomData<T>
+}
+unsafe impl<T: Send> Send for MsQueue<T> { }
+unsafe impl<T: Send> Sync for MsQueue<T> { }
+impl<T> MsQueue<T> {
+    ///Build a new empty queue
+    pub fn new() -> MsQueue<T> {
+        let dummy = Node::alloc(MaybeUninit::uninit());
+        MsQueue {
+            head: AtomicPtr::new(dummy),
+            tail: AtomicPtr::new(dummy),
+            marker: PhantomData
+        }
+    }
+    ///Add an item to the back of the queue
+    pub fn enqueue(&self, item: T) {
+        let node = Node::alloc(MaybeUninit::new(item));
+        let _guard = epoch::pin();
+        loop {
+            let tail = self.tail.load(ACQUIRE);
+            let next = unsafe{ (*tail).next.load(ACQUIRE) };
+            if !next.is_null() {
+                //tail is lagging, help it forward
+                let _ = self.tail.compare_exchange(tail, next, RELEASE, RELAXED);
+                continue;
+            }
+            let linked = unsafe {
+                (*tail).next.compare_exchange(ptr::null_mut(), node, RELEASE, RELAXED).is_ok()
+            };
+            if linked {
+                let _ = self.tail.compare_exchange(tail, node, RELEASE, RELAXED);
+                return;
+            }
+        }
+    }
+    ///Make one attempt to take the item at the front of the queue
+    ///
+    ///Returns Async::Ok(Option<T>) with the item, or None if the queue was
+    ///empty
+    ///Returns Async::Block(()) if another thread changed the queue first,
+    ///retry
+    ///Never returns Async::Err
+    pub fn try_dequeue(&self) -> Async<Option<T>,(),()> {
+        let guard = epoch::pin();
+        let head = self.head.load(ACQUIRE);
+        let next = unsafe{ (*head).next.load(ACQUIRE) };
+        if next.is_null() {
+            return Async::Ok(None);
+        }
+        let tail = self.tail.load(ACQUIRE);
+        if head == tail {
+            //the tail still points at the dummy, move it before the head
+            //passes it
+            let _ = self.tail.compare_exchange(tail, next, RELEASE, RELAXED);
+        }
+        if self.head.compare_exchange(head, next, ACQREL, RELAXED).is_err() {
+            return Async::Block(());
+        }
+        unsafe {
+            //only the thread that moved the head onto next reads its value
+            let value = ptr::read((*next).value.as_ptr());
+            guard.defer_destroy(head);
+            Async::Ok(Some(value))
+        }
+    }
+    ///Take the item at the front of the queue, retrying lost races
+    pub fn dequeue(&self) -> Option<T> {
+        loop {
+            match self.try_dequeue() {
+                Async::Ok(x) => return x,
+                Async::Block(()) |
+                Async::Err(()) => continue
+            };
+        }
+    }
+    ///Wait until an item is available and take it
+    pub fn dequeue_wait(&self) -> T {
+        let mut step = 0;
+        loop {
+            if let Option::Some(x) = self.dequeue() {
+                return x;
+            }
+            backoff(&mut step);
+        }
+    }
+    ///Wait up to `timeout` for an item
+    ///
+    ///A timeout too long to express as an Instant waits for good.
+    pub fn dequeue_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut step = 0;
+        loop {
+            if let Option::Some(x) = self.dequeue() {
+                return Some(x);
+            }
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                return None;
+            }
+            backoff(&mut step);
+        }
+    }
+    ///Returns true if the queue is empty
+    pub fn is_empty(&self) -> bool {
+        let _guard = epoch::pin();
+        let head = self.head.load(ACQUIRE);
+        unsafe{ (*head).next.load(ACQUIRE).is_null() }
+    }
+}
+impl<T> Default for MsQueue<T> {
+    fn default() -> MsQueue<T> {
+        MsQueue::new()
+    }
+}
+impl<T> Drop for MsQueue<T> {
+    fn drop(&mut self) {
+        let dummy = *self.head.get_mut();
+        let mut cursor = unsafe{ Box::from_raw(dummy) }.next.into_inner();
+        while !cursor.is_null() {
+            let mut node = unsafe{ Box::from_raw(cursor) };
+            cursor = *node.next.get_mut();
+            unsafe{ node.value.assume_init_drop() };
+        }
+    }
+}
+
+#[test]
+fn test_queue_fifo() {
+    let queue = MsQueue::new();
+    assert!(queue.try_dequeue().ok() == Some(&None));
+    for i in 0..10 {
+        queue.enqueue(Box::new(i));
+    }
+    assert!(queue.dequeue().map(|x| *x) == Some(0));
+    assert!(queue.dequeue().map(|x| *x) == Some(1));
+    assert!(!queue.is_empty());
+    assert!(*queue.dequeue_wait() == 2);
+    assert!(queue.dequeue_timeout(Duration::MAX).map(|x| *x) == Some(3));
+    //remaining boxes are dropped with the queue
+}
+
+#[test]
+fn test_queue_threads() {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    let queue = Arc::new(MsQueue::new());
+    let taken = Arc::new(AtomicUsize::new(0));
+    let producers = (0..2).map(|t| {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            for i in 0..250 {
+                queue.enqueue((t, i));
+            }
+        })
+    }).collect::<Vec<_>>();
+    let consumers = (0..2).map(|_| {
+        let queue = queue.clone();
+        let taken = taken.clone();
+        thread::spawn(move || {
+            let mut got = Vec::new();
+            while taken.load(RELAXED) < 500 {
+                if let Option::Some(x) = queue.dequeue_timeout(Duration::from_millis(1)) {
+                    taken.fetch_add(1, RELAXED);
+                    got.push(x);
+                }
+            }
+            got
+        })
+    }).collect::<Vec<_>>();
+    for p in producers {
+        p.join().unwrap();
+    }
+    let mut all = Vec::new();
+    for c in consumers {
+        let got = c.join().unwrap();
+        //each producer's items come out in order
+        for t in 0..2 {
+            let mine = got.iter().filter(|x| x.0 == t).map(|x| x.1).collect::<Vec<_>>();
+            assert!(mine.windows(2).all(|w| w[0] < w[1]));
+        }
+        all.extend(got);
+    }
+    assert_eq!(all.len(), 500);
+}
@@ -0,0 +1,118 @@
+//!Token bucket rate limiter.
+//!
+//!The bucket is kept as a single atomic timestamp, the moment it would be
+//!full again, in nanoseconds since the limiter was built. Taking `n` tokens
+//!pushes that moment `n` refill intervals later, and is refused if it would
+//!land more than a full bucket's worth of intervals past now. Refill is
+//!implied by time moving on, nobody has to top the bucket up, and every
+//!acquire is one compare and swap.
+
+
+use super::Async;
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::thread;
+use std::time::{Duration,Instant};
+const RELAXED: Ordering = Ordering::Relaxed;
+
+///Hands out tokens at a steady rate with bursts up to a capacity
+pub struct RateLimiter {
+    start: Instant,
+    ///nanoseconds after `start` at which the bucket is full again
+    full_at: AtomicU64,
+    ///nanoseconds one token takes to refill
+    interval: u64,
+    capacity: u64
+}
+impl RateLimiter {
+    ///Build a full bucket of `capacity` tokens that refills `per_second`
+    ///tokens a second
+    ///
+    ///Panics if `per_second` is zero
+    pub fn new(capacity: u64, per_second: u64) -> RateLimiter {
+        assert!(per_second > 0, "a rate limiter needs a refill rate");
+        RateLimiter::with_interval(capacity, Duration::from_nanos(1_000_000_000 / per_second))
+    }
+    ///Build a full bucket of `capacity` tokens that refills one token
+    ///every `interval`
+    pub fn with_interval(capacity: u64, interval: Duration) -> RateLimiter {
+        RateLimiter {
+            start: Instant::now(),
+            full_at: AtomicU64::new(0),
+            interval: interval.as_nanos().clamp(1, u64::MAX as u128) as u64,
+            capacity: capacity.max(1)
+        }
+    }
+    #[inline(always)]
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+    ///Take `n` tokens without blocking
+    ///
+    ///Returns Async::Ok(()) if the tokens were taken
+    ///Returns Async::Block(Duration) with how long until they are available
+    ///Returns Async::Err(()) if `n` is more than the bucket holds
+    pub fn try_acquire(&self, n: u64) -> Async<(),Duration,()> {
+        if n > self.capacity {
+            return Async::Err(());
+        }
+        //saturates for buckets longer than u64 nanoseconds, ~584 years
+        let limit = self.capacity.saturating_mul(self.interval);
+        let mut full_at = self.full_at.load(RELAXED);
+        loop {
+            let now = self.now();
+            let next = full_at.max(now).saturating_add(n.saturating_mul(self.interval));
+            if next - now > limit {
+                return Async::Block(Duration::from_nanos(next - now - limit));
+            }
+            match self.full_at.compare_exchange_weak(full_at, next, RELAXED, RELAXED) {
+                Ok(_) => return Async::Ok(()),
+                Err(x) => full_at = x
+            };
+        }
+    }
+    ///Take `n` tokens, sleeping until they are available
+    ///
+    ///Returns Err(()) if `n` is more than the bucket holds
+    #[allow(clippy::result_unit_err)]
+    pub fn acquire(&self, n: u64) -> Result<(),()> {
+        loop {
+            match self.try_acquire(n) {
+                Async::Ok(()) => return Ok(()),
+                Async::Block(wait) => thread::sleep(wait),
+                Async::Err(()) => return Err(())
+            };
+        }
+    }
+    ///Tokens in the bucket right now
+    pub fn available(&self) -> u64 {
+        let now = self.now();
+        let owed = self.full_at.load(RELAXED).saturating_sub(now);
+        self.capacity - owed.div_ceil(self.interval).min(self.capacity)
+    }
+    ///Tokens the bucket holds when full
+    #[inline(always)]
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::with_interval(4, Duration::from_millis(20));
+    assert_eq!(limiter.available(), 4);
+    assert!(limiter.try_acquire(3).is_ok());
+    assert!(limiter.try_acquire(1).is_ok());
+    match limiter.try_acquire(2) {
+        Async::Block(wait) => assert!(wait > Duration::from_millis(20) && wait <= Duration::from_millis(40)),
+        _ => panic!("expected an empty bucket")
+    };
+    assert!(limiter.try_acquire(5).is_err());
+    let start = Instant::now();
+    assert!(limiter.acquire(1).is_ok());
+    assert!(start.elapsed() >= Duration::from_millis(15));
+    //buckets past u64 nanoseconds saturate rather than overflow
+    let slow = RateLimiter::with_interval(u64::MAX, Duration::from_secs(u64::MAX));
+    assert!(slow.try_acquire(u64::MAX).is_ok());
+    assert!(slow.try_acquire(1).is_ok());
+    assert_eq!(slow.available(), u64::MAX - 1);
+}
@@ -0,0 +1,177 @@
+//!Read-copy-update cell.
+//!
+//!The current version is an `Arc` whose raw pointer sits in an atomic.
+//!Readers pin the epoch just long enough to load the pointer and take a
+//!strong count, after that their snapshot lives as long as they like and
+//!never blocks a writer. Writers build the next version from the current
+//!one, swap it in, and hand the cell's count on the old version to the
+//!epoch collector, so it is only released once no reader can still be
+//!between loading the pointer and taking its count. The version itself is
+//!freed when its last snapshot goes.
+
+
+use super::epoch;
+use super::spinlock::SpinLock;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicPtr,Ordering};
+const ACQUIRE: Ordering = Ordering::Acquire;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+///Snapshot of an RcuCell, keeps its version alive
+pub struct RcuGuard<T> {
+    version: Arc<T>
+}
+impl<T> RcuGuard<T> {
+    ///Keep the snapshot as a plain Arc
+    #[inline(always)]
+    pub fn into_arc(this: RcuGuard<T>) -> Arc<T> {
+        this.version
+    }
+}
+impl<T> Deref for RcuGuard<T> {
+    type Target = T;
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        &self.version
+    }
+}
+impl<T> Clone for RcuGuard<T> {
+    fn clone(&self) -> RcuGuard<T> {
+        RcuGuard {
+            version: self.version.clone()
+        }
+    }
+}
+
+///Holds a value readers snapshot without locking and writers replace
+pub struct RcuCell<T> {
+    current: AtomicPtr<T>,
+    ///serializes writers so no update is lost
+    writer: SpinLock<()>
+}
+unsafe impl<T: Send+Sync> Send for RcuCell<T> { }
+unsafe impl<T: Send+Sync> Sync for RcuCell<T> { }
+impl<T: Send+Sync+'static> RcuCell<T> {
+    ///Build a cell holding `value`
+    pub fn new(value: T) -> RcuCell<T> {
+        RcuCell {
+            current: AtomicPtr::new(Arc::into_raw(Arc::new(value)) as *mut T),
+            writer: SpinLock::new(())
+        }
+    }
+    ///Snapshot the current version
+    pub fn read(&self) -> RcuGuard<T> {
+        let _guard = epoch::pin();
+        let ptr = self.current.load(ACQUIRE);
+        //the cell's own count cannot be released while we are pinned
+        unsafe {
+            Arc::increment_strong_count(ptr);
+            RcuGuard {
+                version: Arc::from_raw(ptr)
+            }
+        }
+    }
+    ///Install a new version, returning the one it replaced
+    fn install(&self, next: Arc<T>) -> RcuGuard<T> {
+        let old = self.current.swap(Arc::into_raw(next) as *mut T, ACQREL);
+        let guard = epoch::pin();
+        unsafe {
+            //one count for the caller, the cell's count goes to the
+            //collector
+            Arc::increment_strong_count(old);
+            let addr = old as usize;
+            guard.defer(move || drop(Arc::from_raw(addr as *const T)));
+            RcuGuard {
+                version: Arc::from_raw(old)
+            }
+        }
+    }
+    ///Build the next version from the current one and install it
+    ///
+    ///Writers take turns, `f` always sees the latest version. Returns the
+    ///version that was replaced.
+    pub fn update<F>(&self, f: F) -> RcuGuard<T>
+    where
+        F: FnOnce(&T) -> T,
+    {
+        let _writer = self.writer.lock();
+        let next = f(&self.read());
+        self.install(Arc::new(next))
+    }
+    ///Replace the value, returning the version that was replaced
+    pub fn store(&self, value: T) -> RcuGuard<T> {
+        let _writer = self.writer.lock();
+        self.install(Arc::new(value))
+    }
+}
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        unsafe{ drop(Arc::from_raw(*self.current.get_mut())) };
+    }
+}
+impl<T: Send+Sync+Default+'static> Default for RcuCell<T> {
+    fn default() -> RcuCell<T> {
+        RcuCell::new(T::default())
+    }
+}
+
+#[test]
+fn test_rcu_versions() {
+    use std::collections::HashMap;
+    let routes = RcuCell::new(HashMap::new());
+    let before = routes.read();
+    let old = routes.update(|m| {
+        let mut m = m.clone();
+        m.insert("a", 1);
+        m
+    });
+    //snapshots keep seeing their version
+    assert!(before.is_empty());
+    assert!(old.is_empty());
+    assert_eq!(routes.read().get("a"), Some(&1));
+    let arc = RcuGuard::into_arc(before);
+    drop(old);
+    //the cell's count on the old version goes once the epoch moves on,
+    //other tests may hold it back for a moment
+    for _ in 0..10000 {
+        if Arc::strong_count(&arc) == 1 {
+            break;
+        }
+        epoch::pin().flush();
+        ::std::thread::yield_now();
+    }
+    assert_eq!(Arc::strong_count(&arc), 1);
+    routes.store(HashMap::new());
+    assert!(routes.read().is_empty());
+}
+
+#[test]
+fn test_rcu_threads() {
+    use std::thread;
+    let cell = Arc::new(RcuCell::new(0usize));
+    let writers = (0..2).map(|_| {
+        let cell = cell.clone();
+        thread::spawn(move || {
+            for _ in 0..250 {
+                cell.update(|x| x + 1);
+            }
+        })
+    }).collect::<Vec<_>>();
+    let reader = {
+        let cell = cell.clone();
+        thread::spawn(move || {
+            let mut last = 0;
+            for _ in 0..250 {
+                let now = *cell.read();
+                assert!(now >= last);
+                last = now;
+            }
+        })
+    };
+    for w in writers {
+        w.join().unwrap();
+    }
+    reader.join().unwrap();
+    assert_eq!(*cell.read(), 500);
+}
@@ -0,0 +1,151 @@
+//!Reentrant spin lock.
+//!
+//!The lock word holds an id unique to the owning thread, so the owner can
+//!take the lock again without spinning on itself. Since the same thread may
+//!hold several guards at once the guards only hand out shared references.
+
+
+use super::backoff::Backoff;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize,Ordering};
+const SEQ: Ordering = Ordering::SeqCst;
+
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+thread_local!(static THREAD_ID: usize = NEXT_THREAD_ID.fetch_add(1,SEQ));
+
+///Id of the calling thread, never 0
+#[inline(always)]
+fn current_thread() -> usize {
+    THREAD_ID.with(|id| *id)
+}
+
+///Spin lock the holding thread may acquire again
+///
+///Wrap the data in a `Cell` or `RefCell` to mutate it.
+pub struct ReentrantSpinLock<T: ?Sized> {
+    owner: AtomicUsize,
+    depth: UnsafeCell<usize>,
+    data: T
+}
+unsafe impl<T: ?Sized+Send> Send for ReentrantSpinLock<T> { }
+unsafe impl<T: ?Sized+Send> Sync for ReentrantSpinLock<T> { }
+impl<T> ReentrantSpinLock<T> {
+    ///Build a new unlocked ReentrantSpinLock
+    #[inline(always)]
+    pub const fn new(data: T) -> ReentrantSpinLock<T> {
+        ReentrantSpinLock {
+            owner: AtomicUsize::new(0),
+            depth: UnsafeCell::new(0),
+            data
+        }
+    }
+    ///Consume the lock, returning the data
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+}
+impl<T: ?Sized> ReentrantSpinLock<T> {
+    ///Attempt the lock once
+    ///
+    ///Always succeeds if the calling thread already holds the lock.
+    ///Returns Err(()) if another thread holds it.
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_lock<'a>(&'a self) -> Result<ReentrantGuard<'a,T>,()> {
+        let me = current_thread();
+        if self.owner.load(SEQ) != me
+            && self.owner.compare_exchange(0, me, SEQ, SEQ).is_err() {
+            return Err(());
+        }
+        //only the owning thread touches depth
+        unsafe{ *self.depth.get() += 1 };
+        Ok(ReentrantGuard { lock: self, marker: PhantomData })
+    }
+    ///Spin until the lock is held by the calling thread
+    #[inline(always)]
+    pub fn lock<'a>(&'a self) -> ReentrantGuard<'a,T> {
+        let backoff = Backoff::new();
+        loop {
+            if let Ok(guard) = self.try_lock() {
+                return guard;
+            }
+            backoff.snooze();
+        }
+    }
+    ///Returns true if the calling thread holds the lock
+    #[inline(always)]
+    pub fn is_owned(&self) -> bool {
+        self.owner.load(SEQ) == current_thread()
+    }
+    ///How many guards the calling thread holds, 0 if it is not the owner
+    #[inline(always)]
+    pub fn depth(&self) -> usize {
+        if self.is_owned() {
+            unsafe{ *self.depth.get() }
+        } else {
+            0
+        }
+    }
+    ///Mutable access without locking
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        &mut self.data
+    }
+}
+impl<T: Default> Default for ReentrantSpinLock<T> {
+    fn default() -> ReentrantSpinLock<T> {
+        ReentrantSpinLock::new(T::default())
+    }
+}
+
+///Shared access to the data of a ReentrantSpinLock, the lock is released
+///when the outermost guard drops
+///
+///The guard is tied to the thread that took it.
+pub struct ReentrantGuard<'a,T: ?Sized+'a> {
+    lock: &'a ReentrantSpinLock<T>,
+    marker: PhantomData<*const ()>
+}
+impl<'a,T: ?Sized> Deref for ReentrantGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.lock.data
+    }
+}
+impl<'a,T: ?Sized> Drop for ReentrantGuard<'a,T> {
+    fn drop(&mut self) {
+        let depth = unsafe{ &mut *self.lock.depth.get() };
+        *depth -= 1;
+        if *depth == 0 {
+            self.lock.owner.store(0, SEQ);
+        }
+    }
+}
+
+#[test]
+fn test_reentrant_lock() {
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::thread;
+    let lock = Arc::new(ReentrantSpinLock::new(RefCell::new(Vec::new())));
+    {
+        let outer = lock.lock();
+        outer.borrow_mut().push(1);
+        {
+            let inner = lock.lock();
+            inner.borrow_mut().push(2);
+            assert_eq!(lock.depth(), 2);
+        }
+        assert_eq!(lock.depth(), 1);
+        let other = lock.clone();
+        assert!(thread::spawn(move || other.try_lock().is_err()).join().unwrap());
+    }
+    assert!(!lock.is_owned());
+    let other = lock.clone();
+    thread::spawn(move || other.lock().borrow_mut().push(3)).join().unwrap();
+    assert_eq!(*lock.lock().borrow(), vec![1,2,3]);
+}
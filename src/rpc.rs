@@ -0,0 +1,148 @@
+//!Request/response channels built on top of MRMS.
+//!
+//!Every request travels with a `ResponseHandle`. The consumer uses it to
+//!send exactly one reply back to the caller that issued the request.
+
+use super::Async;
+use super::backoff::Backoff;
+use super::mrms::{self,MRMSSender,MRMSReceiver};
+
+///Issues requests and hands back a `Pending` reply for each
+pub struct Client<Req: Sized+'static, Resp: Sized+'static> {
+    tx: MRMSSender<(Req,ResponseHandle<Resp>)>
+}
+impl<Req: Sized+'static, Resp: Sized+'static> Clone for Client<Req,Resp> {
+    fn clone(&self) -> Client<Req,Resp> {
+        Client {
+            tx: self.tx.clone()
+        }
+    }
+}
+impl<Req: Sized+'static, Resp: Sized+'static> Client<Req,Resp> {
+    ///Issue a request
+    ///
+    ///Returns Async::Ok(Pending) which will eventually hold the reply
+    ///Returns Async::Block(Req) if the request channel was blocked
+    ///Returns Async::Err(Req) if there is no server to answer
+    pub fn call(&self, req: Req) -> Async<Pending<Resp>,Req,Req> {
+        let (s,r) = mrms::channel(1);
+        match self.tx.send((req, ResponseHandle { tx: s })) {
+            Async::Ok(()) => Async::Ok(Pending { rx: r }),
+            Async::Block((req,_)) => Async::Block(req),
+            Async::Err((req,_)) => Async::Err(req)
+        }
+    }
+}
+
+///Receives requests along with the handle used to answer them
+pub struct Server<Req: Sized+'static, Resp: Sized+'static> {
+    rx: MRMSReceiver<(Req,ResponseHandle<Resp>)>
+}
+impl<Req: Sized+'static, Resp: Sized+'static> Clone for Server<Req,Resp> {
+    fn clone(&self) -> Server<Req,Resp> {
+        Server {
+            rx: self.rx.clone()
+        }
+    }
+}
+impl<Req: Sized+'static, Resp: Sized+'static> Server<Req,Resp> {
+    ///Receive a request
+    ///
+    ///Has the same return values as `MRMSReceiver::recv`
+    pub fn recv(&self) -> Async<Option<(Req,ResponseHandle<Resp>)>,(),()> {
+        self.rx.recv()
+    }
+}
+
+///The reply slot for a single request
+///
+///Dropping the handle without responding tells the caller no reply is
+///coming.
+pub struct ResponseHandle<Resp: Sized+'static> {
+    tx: MRMSSender<Resp>
+}
+impl<Resp: Sized+'static> ResponseHandle<Resp> {
+    ///Answer the request
+    ///
+    ///Returns Err(Resp) if the caller dropped its `Pending`
+    pub fn respond(self, resp: Resp) -> Result<(),Resp> {
+        //the slot is private to this handle, so it can only block briefly
+        let mut resp = resp;
+        let backoff = Backoff::new();
+        loop {
+            match self.tx.send(resp) {
+                Async::Ok(()) => return Ok(()),
+                Async::Block(x) => resp = x,
+                Async::Err(x) => return Err(x)
+            };
+            backoff.snooze();
+        }
+    }
+}
+
+///A reply that has not been collected yet
+pub struct Pending<Resp: Sized+'static> {
+    rx: MRMSReceiver<Resp>
+}
+impl<Resp: Sized+'static> Pending<Resp> {
+    ///Check for the reply without waiting
+    ///
+    ///Returns Async::Ok(Option<Resp>) the reply may have arrived
+    ///Returns Async::Block(()) the slot is blocked
+    ///Returns Async::Err(()) if the request was dropped unanswered
+    pub fn poll(&self) -> Async<Option<Resp>,(),()> {
+        self.rx.recv()
+    }
+    ///Wait for the reply
+    ///
+    ///Returns None if the request was dropped unanswered
+    pub fn wait(self) -> Option<Resp> {
+        self.rx.into_iter().next()
+    }
+}
+
+///Build a new request/response channel
+///
+///Accepts a sized argument to pre-size the request queue
+pub fn channel<Req: Sized+'static, Resp: Sized+'static>(size: usize) -> (Client<Req,Resp>,Server<Req,Resp>) {
+    let (s,r) = mrms::channel(size);
+    (Client { tx: s }, Server { rx: r })
+}
+
+#[test]
+fn test_rpc_round_trip() {
+    use std::thread;
+    let (client,server) = channel::<usize,String>(4);
+    let worker = thread::spawn(move || {
+        let backoff = Backoff::new();
+        loop {
+            match server.recv() {
+                Async::Ok(Option::Some((req,handle))) => {
+                    if req == 0 {
+                        //dropped without an answer
+                        continue;
+                    }
+                    //the caller may have given up on the reply
+                    let _ = handle.respond(format!("{}", req*2));
+                    backoff.reset();
+                }
+                Async::Ok(Option::None) |
+                Async::Block(()) => backoff.snooze(),
+                Async::Err(()) => break
+            };
+        }
+    });
+    assert!(client.call(21).is_ok());
+    let pending = match client.call(4) {
+        Async::Ok(p) => p,
+        _ => panic!("call failed")
+    };
+    assert_eq!(pending.wait(), Some("8".to_string()));
+    let unanswered = match client.call(0) {
+        Async::Ok(p) => p,
+        _ => panic!("call failed")
+    };
+    assert_eq!(unanswered.wait(), None);
+    drop(client);
+    worker.join().unwrap();
+}
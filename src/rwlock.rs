@@ -0,0 +1,636 @@
+//!Read-write spin lock.
+//!
+//!The whole state lives in one word. `0` is unlocked, `WRITER` is held
+//!exclusively, anything else is the number of readers. The top bit marks a
+//!writer that is spinning, new readers back off while it is set so a steady
+//!stream of readers cannot starve the writer out. The bit below it marks
+//!the single upgradeable reader, which shares the lock with plain readers
+//!but keeps other writers and upgradeable readers out until it upgrades
+//!or releases.
+
+
+use super::poison::{Poison,PoisonError};
+#[cfg(feature="lock-stats")]
+use super::lockstats::LockStats;
+use super::lockstats::{HoldTimer,LockCounters};
+use super::backoff::backoff;
+use std::cell::UnsafeCell;
+use std::mem;
+use std::ops::{Deref,DerefMut};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize,Ordering};
+const SEQ: Ordering = Ordering::SeqCst;
+
+const WRITER: usize = usize::MAX;
+const PENDING: usize = !(usize::MAX >> 1);
+const UPGRADEABLE: usize = PENDING >> 1;
+const READERS: usize = !(PENDING | UPGRADEABLE);
+
+///The bare lock word of a RwSpinLock, for guarding data that lives
+///somewhere else
+pub struct RawRwSpinLock {
+    state: AtomicUsize
+}
+impl RawRwSpinLock {
+    ///Build a new unlocked RawRwSpinLock
+    #[inline(always)]
+    pub const fn new() -> RawRwSpinLock {
+        RawRwSpinLock {
+            state: AtomicUsize::new(0)
+        }
+    }
+    ///Attempt a shared lock
+    ///
+    ///Returns Err(()) if a writer holds the lock or is waiting on it
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_read(&self) -> Result<(),()> {
+        let mut current = self.state.load(SEQ);
+        loop {
+            if current & PENDING != 0 || current & READERS >= READERS-1 {
+                return Err(());
+            }
+            match self.state.compare_exchange(current, current+1, SEQ, SEQ) {
+                Ok(_) => return Ok(()),
+                Err(x) => current = x
+            };
+        }
+    }
+    ///Attempt an exclusive lock
+    ///
+    ///Returns Err(()) if the lock is held at all
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_write(&self) -> Result<(),()> {
+        let mut current = self.state.load(SEQ);
+        loop {
+            if current & !PENDING != 0 {
+                return Err(());
+            }
+            match self.state.compare_exchange(current, WRITER, SEQ, SEQ) {
+                Ok(_) => return Ok(()),
+                Err(x) => current = x
+            };
+        }
+    }
+    ///Attempt the upgradeable shared lock
+    ///
+    ///Returns Err(()) if a writer holds the lock or is waiting on it, or
+    ///another upgradeable reader holds it
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_upgradeable_read(&self) -> Result<(),()> {
+        let mut current = self.state.load(SEQ);
+        loop {
+            if current & (PENDING | UPGRADEABLE) != 0 {
+                return Err(());
+            }
+            match self.state.compare_exchange(current, current | UPGRADEABLE, SEQ, SEQ) {
+                Ok(_) => return Ok(()),
+                Err(x) => current = x
+            };
+        }
+    }
+    ///Attempt to turn the upgradeable shared lock into the exclusive lock
+    ///
+    ///Returns Err(()) if plain readers still hold the lock. Only call this
+    ///while holding the upgradeable lock.
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_upgrade(&self) -> Result<(),()> {
+        let mut current = self.state.load(SEQ);
+        loop {
+            if current & READERS != 0 {
+                return Err(());
+            }
+            match self.state.compare_exchange(current, WRITER, SEQ, SEQ) {
+                Ok(_) => return Ok(()),
+                Err(x) => current = x
+            };
+        }
+    }
+    ///Shared lock, spins while a writer holds or waits on the lock
+    #[inline(always)]
+    pub fn read(&self) {
+        self.read_counted(&LockCounters::new());
+    }
+    ///Exclusive lock, spins until every other holder releases
+    ///
+    ///While spinning the pending bit is held so no new readers get in.
+    #[inline(always)]
+    pub fn write(&self) {
+        self.write_counted(&LockCounters::new());
+    }
+    ///Upgradeable shared lock, spins while a writer or another upgradeable
+    ///reader holds the lock
+    #[inline(always)]
+    pub fn upgradeable_read(&self) {
+        self.upgradeable_counted(&LockCounters::new());
+    }
+    ///Turn the upgradeable shared lock into the exclusive lock without
+    ///letting go of it, spins until the plain readers release
+    ///
+    ///Only call this while holding the upgradeable lock.
+    #[inline(always)]
+    pub fn upgrade(&self) {
+        self.upgrade_counted(&LockCounters::new());
+    }
+    #[inline(always)]
+    fn read_counted(&self, counters: &LockCounters) {
+        let mut spins = 0;
+        let mut step = 0;
+        while self.try_read().is_err() {
+            counters.failed();
+            spins += backoff(&mut step);
+        }
+        counters.spun(spins);
+    }
+    #[inline(always)]
+    fn write_counted(&self, counters: &LockCounters) {
+        let mut spins = 0;
+        let mut step = 0;
+        while self.try_write().is_err() {
+            counters.failed();
+            let current = self.state.load(SEQ);
+            if current != WRITER && current & PENDING == 0 {
+                let _ = self.state.compare_exchange(current, current | PENDING, SEQ, SEQ);
+            }
+            spins += backoff(&mut step);
+        }
+        counters.spun(spins);
+    }
+    #[inline(always)]
+    fn upgradeable_counted(&self, counters: &LockCounters) {
+        let mut spins = 0;
+        let mut step = 0;
+        while self.try_upgradeable_read().is_err() {
+            counters.failed();
+            spins += backoff(&mut step);
+        }
+        counters.spun(spins);
+    }
+    #[inline(always)]
+    fn upgrade_counted(&self, counters: &LockCounters) {
+        let mut spins = 0;
+        let mut step = 0;
+        while self.try_upgrade().is_err() {
+            counters.failed();
+            self.state.fetch_or(PENDING, SEQ);
+            spins += backoff(&mut step);
+        }
+        counters.spun(spins);
+    }
+    ///Drop one shared lock
+    #[inline(always)]
+    pub fn release_read(&self) {
+        self.state.fetch_sub(1, SEQ);
+    }
+    ///Drop the upgradeable shared lock
+    #[inline(always)]
+    pub fn release_upgradeable_read(&self) {
+        self.state.fetch_and(!UPGRADEABLE, SEQ);
+    }
+    ///Drop the exclusive lock
+    #[inline(always)]
+    pub fn release_write(&self) {
+        self.state.store(0, SEQ);
+    }
+    ///Number of readers currently holding the lock
+    #[inline(always)]
+    pub fn readers(&self) -> usize {
+        match self.state.load(SEQ) {
+            WRITER => 0,
+            x => x & READERS
+        }
+    }
+    ///Returns true if a writer holds the lock
+    #[inline(always)]
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(SEQ) == WRITER
+    }
+}
+impl Default for RawRwSpinLock {
+    fn default() -> RawRwSpinLock {
+        RawRwSpinLock::new()
+    }
+}
+
+///`lock_api::RwLock` backed by a RawRwSpinLock
+#[cfg(feature="lock_api")]
+pub type SpinRwLock<T> = lock_api::RwLock<RawRwSpinLock,T>;
+
+#[cfg(feature="lock_api")]
+unsafe impl lock_api::RawRwLock for RawRwSpinLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawRwSpinLock = RawRwSpinLock::new();
+    type GuardMarker = lock_api::GuardSend;
+    fn lock_shared(&self) {
+        self.read();
+    }
+    fn try_lock_shared(&self) -> bool {
+        self.try_read().is_ok()
+    }
+    unsafe fn unlock_shared(&self) {
+        self.release_read();
+    }
+    fn lock_exclusive(&self) {
+        self.write();
+    }
+    fn try_lock_exclusive(&self) -> bool {
+        self.try_write().is_ok()
+    }
+    unsafe fn unlock_exclusive(&self) {
+        self.release_write();
+    }
+    fn is_locked(&self) -> bool {
+        self.state.load(SEQ) & !PENDING != 0
+    }
+    fn is_locked_exclusive(&self) -> bool {
+        self.is_write_locked()
+    }
+}
+
+#[cfg(feature="lock_api")]
+unsafe impl lock_api::RawRwLockUpgrade for RawRwSpinLock {
+    fn lock_upgradable(&self) {
+        self.upgradeable_read();
+    }
+    fn try_lock_upgradable(&self) -> bool {
+        self.try_upgradeable_read().is_ok()
+    }
+    unsafe fn unlock_upgradable(&self) {
+        self.release_upgradeable_read();
+    }
+    unsafe fn upgrade(&self) {
+        RawRwSpinLock::upgrade(self);
+    }
+    unsafe fn try_upgrade(&self) -> bool {
+        RawRwSpinLock::try_upgrade(self).is_ok()
+    }
+}
+
+///Many readers or one writer
+pub struct RwSpinLock<T: ?Sized> {
+    raw: RawRwSpinLock,
+    poison: Poison,
+    counters: LockCounters,
+    data: UnsafeCell<T>
+}
+unsafe impl<T: ?Sized+Send> Send for RwSpinLock<T> { }
+unsafe impl<T: ?Sized+Send+Sync> Sync for RwSpinLock<T> { }
+impl<T> RwSpinLock<T> {
+    ///Build a new unlocked RwSpinLock
+    #[inline(always)]
+    pub const fn new(data: T) -> RwSpinLock<T> {
+        RwSpinLock {
+            raw: RawRwSpinLock::new(),
+            poison: Poison::new(false),
+            counters: LockCounters::new(),
+            data: UnsafeCell::new(data)
+        }
+    }
+    ///Build a new unlocked RwSpinLock that is poisoned when a thread
+    ///panics while holding the write lock
+    #[inline(always)]
+    pub const fn with_poisoning(data: T) -> RwSpinLock<T> {
+        RwSpinLock {
+            raw: RawRwSpinLock::new(),
+            poison: Poison::new(true),
+            counters: LockCounters::new(),
+            data: UnsafeCell::new(data)
+        }
+    }
+    ///Consume the lock, returning the data
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+impl<T: ?Sized> RwSpinLock<T> {
+    ///Attempt a shared lock
+    ///
+    ///Returns Err(()) if a writer holds the lock or is waiting on it
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_read<'a>(&'a self) -> Result<ReadGuard<'a,T>,()> {
+        match self.raw.try_read() {
+            Ok(()) => Ok(self.read_guard()),
+            Err(()) => {
+                self.counters.failed();
+                Err(())
+            }
+        }
+    }
+    ///Attempt an exclusive lock
+    ///
+    ///Returns Err(()) if any guard is alive
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_write<'a>(&'a self) -> Result<WriteGuard<'a,T>,()> {
+        match self.raw.try_write() {
+            Ok(()) => Ok(self.write_guard()),
+            Err(()) => {
+                self.counters.failed();
+                Err(())
+            }
+        }
+    }
+    ///Shared lock, spins while a writer holds or waits on the lock
+    #[inline(always)]
+    pub fn read<'a>(&'a self) -> ReadGuard<'a,T> {
+        self.raw.read_counted(&self.counters);
+        self.read_guard()
+    }
+    ///Exclusive lock, spins until every other guard is dropped
+    ///
+    ///While spinning the pending bit is held so no new readers get in.
+    #[inline(always)]
+    pub fn write<'a>(&'a self) -> WriteGuard<'a,T> {
+        self.raw.write_counted(&self.counters);
+        self.write_guard()
+    }
+    ///Attempt an upgradeable shared lock
+    ///
+    ///Returns Err(()) if a writer holds the lock or is waiting on it, or
+    ///another UpgradeableGuard is alive
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_upgradeable_read<'a>(&'a self) -> Result<UpgradeableGuard<'a,T>,()> {
+        match self.raw.try_upgradeable_read() {
+            Ok(()) => Ok(self.upgradeable_guard()),
+            Err(()) => {
+                self.counters.failed();
+                Err(())
+            }
+        }
+    }
+    ///Shared lock that can later be upgraded to the write lock without
+    ///releasing it in between
+    ///
+    ///Plain readers can share the lock with it, but only one upgradeable
+    ///reader is let in at a time.
+    #[inline(always)]
+    pub fn upgradeable_read<'a>(&'a self) -> UpgradeableGuard<'a,T> {
+        self.raw.upgradeable_counted(&self.counters);
+        self.upgradeable_guard()
+    }
+    #[inline(always)]
+    fn upgradeable_guard<'a>(&'a self) -> UpgradeableGuard<'a,T> {
+        UpgradeableGuard {
+            lock: self,
+            timer: self.counters.acquired()
+        }
+    }
+    #[inline(always)]
+    fn read_guard<'a>(&'a self) -> ReadGuard<'a,T> {
+        ReadGuard {
+            lock: self,
+            timer: self.counters.acquired()
+        }
+    }
+    #[inline(always)]
+    fn write_guard<'a>(&'a self) -> WriteGuard<'a,T> {
+        WriteGuard {
+            lock: self,
+            armed: self.poison.arm(),
+            timer: self.counters.acquired()
+        }
+    }
+    ///Shared lock reporting poisoning
+    ///
+    ///Returns Err(PoisonError) holding the guard if a thread panicked
+    ///while holding the write lock
+    #[inline(always)]
+    pub fn read_checked<'a>(&'a self) -> Result<ReadGuard<'a,T>,PoisonError<ReadGuard<'a,T>>> {
+        let guard = self.read();
+        self.poison.check(guard)
+    }
+    ///Exclusive lock reporting poisoning
+    #[inline(always)]
+    pub fn write_checked<'a>(&'a self) -> Result<WriteGuard<'a,T>,PoisonError<WriteGuard<'a,T>>> {
+        let guard = self.write();
+        self.poison.check(guard)
+    }
+    ///Returns true if a thread panicked while holding the write lock
+    #[inline(always)]
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+    ///Mark the data as consistent again
+    #[inline(always)]
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+    ///Number of readers currently holding the lock
+    #[inline(always)]
+    pub fn readers(&self) -> usize {
+        self.raw.readers()
+    }
+    ///Contention statistics gathered so far, covering both read and write
+    ///acquisitions
+    #[cfg(feature="lock-stats")]
+    pub fn stats(&self) -> LockStats {
+        self.counters.snapshot()
+    }
+    ///Returns true if a writer holds the lock
+    #[inline(always)]
+    pub fn is_write_locked(&self) -> bool {
+        self.raw.is_write_locked()
+    }
+    ///Mutable access without locking
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        self.data.get_mut()
+    }
+}
+impl<T: Default> Default for RwSpinLock<T> {
+    fn default() -> RwSpinLock<T> {
+        RwSpinLock::new(T::default())
+    }
+}
+
+///Shared access to the data of a RwSpinLock
+pub struct ReadGuard<'a,T: ?Sized+'a> {
+    lock: &'a RwSpinLock<T>,
+    timer: HoldTimer
+}
+impl<'a,T: ?Sized> Deref for ReadGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> Drop for ReadGuard<'a,T> {
+    fn drop(&mut self) {
+        self.lock.counters.released(&self.timer);
+        self.lock.raw.release_read();
+    }
+}
+
+///Shared access to the data of a RwSpinLock that can be upgraded to
+///exclusive access
+pub struct UpgradeableGuard<'a,T: ?Sized+'a> {
+    lock: &'a RwSpinLock<T>,
+    timer: HoldTimer
+}
+impl<'a,T: ?Sized> UpgradeableGuard<'a,T> {
+    ///Trade the guard for a WriteGuard, spinning until the plain readers
+    ///release. The lock is never released in between.
+    #[inline(always)]
+    pub fn upgrade(self) -> WriteGuard<'a,T> {
+        self.lock.raw.upgrade_counted(&self.lock.counters);
+        self.into_write()
+    }
+    ///Trade the guard for a WriteGuard if no plain readers hold the lock
+    ///
+    ///Returns Err(self) if readers are still in
+    #[inline(always)]
+    pub fn try_upgrade(self) -> Result<WriteGuard<'a,T>,UpgradeableGuard<'a,T>> {
+        match self.lock.raw.try_upgrade() {
+            Ok(()) => Ok(self.into_write()),
+            Err(()) => Err(self)
+        }
+    }
+    #[inline(always)]
+    fn into_write(self) -> WriteGuard<'a,T> {
+        let lock = self.lock;
+        let timer = unsafe{ ptr::read(&self.timer) };
+        mem::forget(self);
+        WriteGuard {
+            lock,
+            armed: lock.poison.arm(),
+            timer
+        }
+    }
+}
+impl<'a,T: ?Sized> Deref for UpgradeableGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> Drop for UpgradeableGuard<'a,T> {
+    fn drop(&mut self) {
+        self.lock.counters.released(&self.timer);
+        self.lock.raw.release_upgradeable_read();
+    }
+}
+
+///Exclusive access to the data of a RwSpinLock
+pub struct WriteGuard<'a,T: ?Sized+'a> {
+    lock: &'a RwSpinLock<T>,
+    armed: bool,
+    timer: HoldTimer
+}
+impl<'a,T: ?Sized> Deref for WriteGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> DerefMut for WriteGuard<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+impl<'a,T: ?Sized> Drop for WriteGuard<'a,T> {
+    fn drop(&mut self) {
+        self.lock.counters.released(&self.timer);
+        self.lock.poison.disarm(self.armed);
+        self.lock.raw.release_write();
+    }
+}
+
+#[test]
+fn test_rwlock_readers_and_writer() {
+    let lock = RwSpinLock::new(5usize);
+    {
+        let a = lock.read();
+        let b = lock.try_read().unwrap();
+        assert_eq!(*a + *b, 10);
+        assert_eq!(lock.readers(), 2);
+        assert!(lock.try_write().is_err());
+    }
+    {
+        let mut w = lock.write();
+        *w += 1;
+        assert!(lock.is_write_locked());
+        assert!(lock.try_read().is_err());
+    }
+    assert_eq!(*lock.read(), 6);
+}
+
+#[test]
+fn test_rwlock_threads() {
+    use std::sync::Arc;
+    use std::thread;
+    let lock = Arc::new(RwSpinLock::new(Vec::new()));
+    let workers = (0..4).map(|i| {
+        let lock = lock.clone();
+        thread::spawn(move || {
+            for j in 0..100 {
+                if j % 10 == 0 {
+                    lock.write().push(i);
+                } else {
+                    let _ = lock.read().len();
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(lock.read().len(), 40);
+}
+
+#[test]
+fn test_rwlock_upgrade() {
+    use std::sync::Arc;
+    use std::thread;
+    let lock = RwSpinLock::new(Vec::new());
+    {
+        let up = lock.upgradeable_read();
+        let reader = lock.try_read().unwrap();
+        assert!(lock.try_upgradeable_read().is_err());
+        assert!(lock.try_write().is_err());
+        let up = up.try_upgrade().err().unwrap();
+        drop(reader);
+        let mut w = up.try_upgrade().ok().unwrap();
+        assert!(lock.is_write_locked());
+        w.push(1);
+    }
+    assert_eq!(lock.readers(), 0);
+    assert!(lock.try_write().is_ok());
+    //check then insert never duplicates while the guard is held throughout
+    let lock = Arc::new(lock);
+    let workers = (0..4).map(|_| {
+        let lock = lock.clone();
+        thread::spawn(move || {
+            for i in 0..50 {
+                let up = lock.upgradeable_read();
+                if !up.contains(&i) {
+                    up.upgrade().push(i);
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(lock.read().len(), 50);
+}
+
+#[cfg(feature="lock_api")]
+#[test]
+fn test_lock_api_rwlock() {
+    let lock = SpinRwLock::new(3);
+    {
+        let a = lock.read();
+        let b = lock.read();
+        assert_eq!(*a + *b, 6);
+        assert!(lock.try_write().is_none());
+    }
+    *lock.write() = 4;
+    assert_eq!(*lock.read(), 4);
+}
@@ -0,0 +1,159 @@
+//!Scoped threads.
+//!
+//!`scope` hands its closure a `Scope` that spawns threads which may borrow
+//!anything living outside the call. Every thread spawned through the
+//!scope, including threads spawned by those threads, is joined before
+//!`scope` returns, so the borrows never outlive the data.
+
+
+use super::spinlock::SpinLock;
+use std::marker::PhantomData;
+use std::panic::{self,AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread::{self,JoinHandle};
+
+type Handle = Arc<SpinLock<Option<JoinHandle<()>>>>;
+
+struct Inner {
+    ///every thread spawned so far, emptied by whoever joins the thread
+    handles: SpinLock<Vec<Handle>>
+}
+
+///Spawns threads that may borrow from outside the scope
+pub struct Scope<'env> {
+    inner: Arc<Inner>,
+    ///invariant in 'env
+    marker: PhantomData<&'env mut &'env ()>
+}
+impl<'env> Scope<'env> {
+    ///Spawn a thread that is joined before the scope ends
+    ///
+    ///The closure gets the scope again so it can spawn threads of its own
+    pub fn spawn<'scope,F,T>(&'scope self, f: F) -> ScopedJoinHandle<'scope,T>
+    where
+        F: FnOnce(&Scope<'env>) -> T + Send + 'env,
+        T: Send + 'env,
+    {
+        let result = Arc::new(SpinLock::new(None));
+        let scope = Scope {
+            inner: self.inner.clone(),
+            marker: PhantomData
+        };
+        let slot = result.clone();
+        let body = move || {
+            let x = f(&scope);
+            *slot.lock() = Some(x);
+        };
+        //the thread is always joined before 'env ends, either through its
+        //handle or when the scope closes
+        let handle = unsafe {
+            thread::Builder::new()
+                .spawn_unchecked(body)
+                .expect("failed to spawn scoped thread")
+        };
+        let handle = Arc::new(SpinLock::new(Some(handle)));
+        self.inner.handles.lock().push(handle.clone());
+        ScopedJoinHandle {
+            handle,
+            result,
+            marker: PhantomData
+        }
+    }
+    ///Join every thread still running, returns true if one of them
+    ///panicked
+    fn join_all(&self) -> bool {
+        let mut panicked = false;
+        loop {
+            let handles = ::std::mem::take(&mut *self.inner.handles.lock());
+            if handles.is_empty() {
+                return panicked;
+            }
+            for h in handles {
+                let h = h.lock().take();
+                if let Option::Some(h) = h {
+                    panicked |= h.join().is_err();
+                }
+            }
+        }
+    }
+}
+
+///Handle to a thread spawned in a scope
+pub struct ScopedJoinHandle<'scope,T> {
+    handle: Handle,
+    result: Arc<SpinLock<Option<T>>>,
+    marker: PhantomData<&'scope ()>
+}
+impl<'scope,T> ScopedJoinHandle<'scope,T> {
+    ///Wait for the thread to finish
+    ///
+    ///Returns Err with the panic payload if the thread panicked. A panic
+    ///observed here is not raised again when the scope ends.
+    pub fn join(self) -> thread::Result<T> {
+        let handle = self.handle.lock().take();
+        match handle {
+            Option::Some(h) => h.join()?,
+            Option::None => unreachable!("scoped thread joined twice")
+        };
+        Ok(self.result.lock().take().unwrap())
+    }
+    ///Returns true once the thread finished running
+    pub fn is_finished(&self) -> bool {
+        match *self.handle.lock() {
+            Option::Some(ref h) => h.is_finished(),
+            Option::None => true
+        }
+    }
+}
+
+///Run `f` with a scope for spawning borrowing threads
+///
+///Returns once `f` and every thread spawned in the scope finished. Panics
+///if `f` panicked or if a thread panicked and was not joined through its
+///handle.
+pub fn scope<'env,F,R>(f: F) -> R
+where
+    F: FnOnce(&Scope<'env>) -> R,
+{
+    let scope = Scope {
+        inner: Arc::new(Inner {
+            handles: SpinLock::new(Vec::new())
+        }),
+        marker: PhantomData
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    let panicked = scope.join_all();
+    match result {
+        Err(e) => panic::resume_unwind(e),
+        Ok(_) if panicked => panic!("a scoped thread panicked"),
+        Ok(x) => x
+    }
+}
+
+#[test]
+fn test_scope_borrows() {
+    let mut data = vec![1usize,2,3,4];
+    let total = SpinLock::new(0usize);
+    scope(|s| {
+        for chunk in data.chunks_mut(2) {
+            let total = &total;
+            s.spawn(move |s| {
+                for x in chunk.iter_mut() {
+                    *x *= 10;
+                }
+                let sum = chunk.iter().sum::<usize>();
+                //nested spawns are joined too
+                s.spawn(move |_| *total.lock() += sum);
+            });
+        }
+    });
+    assert_eq!(data, vec![10,20,30,40]);
+    assert_eq!(*total.lock(), 100);
+    let n = scope(|s| {
+        let h = s.spawn(|_| data.len());
+        let bad = s.spawn(|_| panic!("scoped thread failed"));
+        assert!(bad.join().is_err());
+        h.join().unwrap()
+    });
+    assert_eq!(n, 4);
+}
@@ -0,0 +1,121 @@
+//!Counting spin semaphore.
+
+
+use super::backoff::backoff;
+use std::mem;
+use std::sync::atomic::{AtomicUsize,Ordering};
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Hands out up to `n` permits at once
+pub struct SpinSemaphore {
+    permits: AtomicUsize
+}
+impl SpinSemaphore {
+    ///Build a semaphore with `permits` available
+    #[inline(always)]
+    pub const fn new(permits: usize) -> SpinSemaphore {
+        SpinSemaphore {
+            permits: AtomicUsize::new(permits)
+        }
+    }
+    ///Take a permit if one is available
+    ///
+    ///Returns Err(()) if every permit is out
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_acquire<'a>(&'a self) -> Result<Permit<'a>,()> {
+        let mut current = self.permits.load(SEQ);
+        loop {
+            if current == 0 {
+                return Err(());
+            }
+            match self.permits.compare_exchange(current, current-1, SEQ, SEQ) {
+                Ok(_) => return Ok(Permit { sem: self }),
+                Err(x) => current = x
+            };
+        }
+    }
+    ///Take a permit, backing off while none are available
+    #[inline(always)]
+    pub fn acquire<'a>(&'a self) -> Permit<'a> {
+        let mut step = 0;
+        loop {
+            if let Ok(permit) = self.try_acquire() {
+                return permit;
+            }
+            backoff(&mut step);
+        }
+    }
+    ///Add `n` permits
+    ///
+    ///Used to return permits given up with `Permit::forget`, or to grow
+    ///the semaphore.
+    #[inline(always)]
+    pub fn release(&self, n: usize) {
+        self.permits.fetch_add(n, SEQ);
+    }
+    ///Number of permits available right now
+    #[inline(always)]
+    pub fn available(&self) -> usize {
+        self.permits.load(SEQ)
+    }
+}
+
+///A permit taken from a SpinSemaphore, returned on drop
+pub struct Permit<'a> {
+    sem: &'a SpinSemaphore
+}
+impl<'a> Permit<'a> {
+    ///Keep the permit consumed without holding the guard, give it back
+    ///later with `SpinSemaphore::release`
+    #[inline(always)]
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        self.sem.release(1);
+    }
+}
+
+#[test]
+fn test_semaphore_bounds_concurrency() {
+    use std::sync::Arc;
+    use std::thread;
+    let sem = Arc::new(SpinSemaphore::new(2));
+    let inside = Arc::new(AtomicUsize::new(0));
+    let workers = (0..6).map(|_| {
+        let sem = sem.clone();
+        let inside = inside.clone();
+        thread::spawn(move || {
+            for _ in 0..50 {
+                let _permit = sem.acquire();
+                assert!(inside.fetch_add(1, SEQ) < 2);
+                thread::yield_now();
+                inside.fetch_sub(1, SEQ);
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(sem.available(), 2);
+    let a = sem.try_acquire().unwrap();
+    sem.try_acquire().unwrap().forget();
+    assert!(sem.try_acquire().is_err());
+    drop(a);
+    assert_eq!(sem.available(), 1);
+    sem.release(1);
+    assert_eq!(sem.available(), 2);
+}
+
+#[test]
+fn test_semaphore_static() {
+    static SEM: SpinSemaphore = SpinSemaphore::new(1);
+    let permit = SEM.try_acquire();
+    assert!(permit.is_ok());
+    assert!(SEM.try_acquire().is_err());
+    drop(permit);
+    assert!(SEM.try_acquire().is_ok());
+}
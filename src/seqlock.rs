@@ -0,0 +1,147 @@
+//!Sequence lock.
+//!
+//!Writers bump the sequence to odd before touching the data and back to even
+//!afterwards. Readers never write to shared memory: they copy the data out
+//!and retry if the sequence was odd or moved while they were copying, so a
+//!reader can never hold up a writer.
+
+
+use super::backoff::Backoff;
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::{fence,AtomicUsize,Ordering};
+const SEQ: Ordering = Ordering::SeqCst;
+
+///Read-mostly cell for `Copy` data
+pub struct SeqLock<T: Copy> {
+    seq: AtomicUsize,
+    data: UnsafeCell<T>
+}
+unsafe impl<T: Copy+Send> Send for SeqLock<T> { }
+unsafe impl<T: Copy+Send> Sync for SeqLock<T> { }
+impl<T: Copy> SeqLock<T> {
+    ///Build a new SeqLock
+    #[inline(always)]
+    pub const fn new(data: T) -> SeqLock<T> {
+        SeqLock {
+            seq: AtomicUsize::new(0),
+            data: UnsafeCell::new(data)
+        }
+    }
+    ///Make one attempt at copying the data
+    ///
+    ///Returns Err(()) if a writer was active during the copy
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_read(&self) -> Result<T,()> {
+        let before = self.seq.load(SEQ);
+        if before & 1 == 1 {
+            return Err(());
+        }
+        //may observe a torn value, it is thrown away if the sequence moved
+        let value = unsafe{ ptr::read_volatile(self.data.get()) };
+        fence(SEQ);
+        if self.seq.load(SEQ) == before {
+            Ok(value)
+        } else {
+            Err(())
+        }
+    }
+    ///Copy the data, retrying until no writer interfered
+    #[inline(always)]
+    pub fn read(&self) -> T {
+        let backoff = Backoff::new();
+        loop {
+            if let Ok(value) = self.try_read() {
+                return value;
+            }
+            backoff.snooze();
+        }
+    }
+    ///Mutate the data in place
+    ///
+    ///Writers are serialized against each other by spinning on the sequence.
+    #[inline(always)]
+    pub fn update<F>(&self, lambda: F)
+    where
+        F: FnOnce(&mut T)
+    {
+        let mut current = self.seq.load(SEQ);
+        let backoff = Backoff::new();
+        loop {
+            if current & 1 == 0 {
+                match self.seq.compare_exchange(current, current.wrapping_add(1), SEQ, SEQ) {
+                    Ok(_) => break,
+                    Err(x) => current = x
+                };
+            } else {
+                backoff.snooze();
+                current = self.seq.load(SEQ);
+            }
+        }
+        fence(SEQ);
+        //closes the write even if lambda panics
+        let _close = Close { seq: &self.seq, next: current.wrapping_add(2) };
+        let mut value = unsafe{ ptr::read_volatile(self.data.get()) };
+        lambda(&mut value);
+        unsafe{ ptr::write_volatile(self.data.get(), value) };
+    }
+    ///Replace the data
+    #[inline(always)]
+    pub fn write(&self, value: T) {
+        self.update(|data| *data = value);
+    }
+    ///Current sequence number, odd while a write is in progress
+    #[inline(always)]
+    pub fn sequence(&self) -> usize {
+        self.seq.load(SEQ)
+    }
+    ///Mutable access without the sequence
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        self.data.get_mut()
+    }
+    ///Consume the lock, returning the data
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+struct Close<'a> {
+    seq: &'a AtomicUsize,
+    next: usize
+}
+impl<'a> Drop for Close<'a> {
+    fn drop(&mut self) {
+        self.seq.store(self.next, SEQ);
+    }
+}
+
+impl<T: Copy+Default> Default for SeqLock<T> {
+    fn default() -> SeqLock<T> {
+        SeqLock::new(T::default())
+    }
+}
+
+#[test]
+fn test_seqlock_consistent_snapshots() {
+    use std::sync::Arc;
+    use std::thread;
+    let lock = Arc::new(SeqLock::new((0u64,0u64)));
+    let writer = {
+        let lock = lock.clone();
+        thread::spawn(move || {
+            for i in 1..1000 {
+                lock.write((i,i*2));
+            }
+        })
+    };
+    for _ in 0..1000 {
+        let (a,b) = lock.read();
+        assert_eq!(a*2, b);
+    }
+    writer.join().unwrap();
+    assert!(lock.read() == (999,1998));
+    assert_eq!(lock.sequence(), 999*2);
+}
@@ -0,0 +1,165 @@
+//!Graceful shutdown coordination.
+//!
+//!Shutdown runs in three phases. Components `register` and get a
+//!`Registration`. The initiator calls `begin`, which cancels a
+//!CancellationToken every registration shares, so each component sees the
+//!signal through `is_shutting_down`, by blocking in `wait`, or by handing
+//!`token()` to a cancellable wait. Each component cleans up and then
+//!acknowledges by calling `complete` or dropping its Registration, while
+//!the initiator waits in `wait_for_completion` for every acknowledgement.
+
+
+use super::backoff::Backoff;
+use super::cancel::CancellationToken;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::{Duration,Instant};
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+struct Inner {
+    token: CancellationToken,
+    ///registrations not yet acknowledged
+    outstanding: AtomicUsize
+}
+
+///Coordinates a shutdown between an initiator and registered components,
+///clones share it
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>
+}
+impl Shutdown {
+    ///Build a coordinator with nothing registered
+    pub fn new() -> Shutdown {
+        Shutdown {
+            inner: Arc::new(Inner {
+                token: CancellationToken::new(),
+                outstanding: AtomicUsize::new(0)
+            })
+        }
+    }
+    ///Register a component whose acknowledgement shutdown waits for
+    ///
+    ///Registering after shutdown began is allowed, the registration sees
+    ///the signal right away.
+    pub fn register(&self) -> Registration {
+        self.inner.outstanding.fetch_add(1, ACQREL);
+        Registration {
+            inner: self.inner.clone()
+        }
+    }
+    ///Signal every registration to shut down
+    pub fn begin(&self) {
+        self.inner.token.cancel();
+    }
+    ///Returns true once shutdown began
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.token.is_cancelled()
+    }
+    ///Registrations that have not acknowledged yet
+    pub fn outstanding(&self) -> usize {
+        self.inner.outstanding.load(ACQUIRE)
+    }
+    ///Wait up to `timeout` for every registration to acknowledge
+    ///
+    ///Returns true if all of them did. Does not begin the shutdown itself.
+    ///A timeout too long to express as an Instant waits for good.
+    pub fn wait_for_completion(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let backoff = Backoff::new();
+        loop {
+            if self.outstanding() == 0 {
+                return true;
+            }
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                return false;
+            }
+            backoff.snooze();
+        }
+    }
+    ///Begin the shutdown and wait up to `timeout` for it to complete
+    ///
+    ///Has the same return value as `wait_for_completion`
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        self.begin();
+        self.wait_for_completion(timeout)
+    }
+}
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown::new()
+    }
+}
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shutdown")
+            .field("shutting_down", &self.is_shutting_down())
+            .field("outstanding", &self.outstanding())
+            .finish()
+    }
+}
+
+///A component's part in a shutdown, acknowledges when dropped
+pub struct Registration {
+    inner: Arc<Inner>
+}
+impl Registration {
+    ///Returns true once shutdown began
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.token.is_cancelled()
+    }
+    ///Token cancelled when shutdown begins, for cancellable waits
+    pub fn token(&self) -> &CancellationToken {
+        &self.inner.token
+    }
+    ///Block until shutdown begins
+    pub fn wait(&self) {
+        self.inner.token.wait();
+    }
+    ///Block until shutdown begins or `timeout` passes
+    ///
+    ///Returns true if shutdown began in time
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.inner.token.wait_timeout(timeout)
+    }
+    ///Acknowledge that this component has shut down
+    pub fn complete(self) {
+        drop(self);
+    }
+}
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.inner.outstanding.fetch_sub(1, RELEASE);
+    }
+}
+
+#[test]
+fn test_shutdown_phases() {
+    use std::thread;
+    let shutdown = Shutdown::new();
+    let workers = (0..3).map(|_| {
+        let reg = shutdown.register();
+        thread::spawn(move || {
+            reg.wait();
+            reg.complete();
+        })
+    }).collect::<Vec<_>>();
+    let stuck = shutdown.register();
+    assert_eq!(shutdown.outstanding(), 4);
+    assert!(!shutdown.wait_for_completion(Duration::from_millis(1)));
+    //the stuck registration never acknowledges
+    assert!(!shutdown.shutdown(Duration::from_millis(20)));
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert!(stuck.is_shutting_down());
+    assert_eq!(shutdown.outstanding(), 1);
+    drop(stuck);
+    assert!(shutdown.wait_for_completion(Duration::from_millis(1)));
+    assert!(shutdown.shutdown(Duration::MAX));
+    //late registrations see the signal at once
+    assert!(shutdown.register().wait_timeout(Duration::from_millis(1)));
+}
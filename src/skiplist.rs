@@ -0,0 +1,435 @@
+//!Concurrent skip list map.
+//!
+//!A lazy skip list: lookups walk the levels without taking any lock,
+//!inserts and removals lock only the predecessors they relink and check
+//!those are still in place before touching them. A node is marked before
+//!it is unlinked and only counts as present once it is linked on every
+//!level. Unlinked nodes are freed through the epoch collector, so values
+//!are handed out as clones rather than references.
+
+
+use super::epoch;
+use super::backoff::backoff;
+use std::cell::Cell;
+use std::ops::{Bound,RangeBounds};
+use std::ptr;
+use std::sync::atomic::{AtomicBool,AtomicPtr,AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+
+///Levels of the tallest tower, enough for ~4^16 entries
+const MAX_HEIGHT: usize = 16;
+
+thread_local!(static SEED: Cell<u32> = const { Cell::new(0) });
+
+///Tower height, each level is kept with probability 1/4
+fn random_height() -> usize {
+    let x = SEED.with(|seed| {
+        let mut x = seed.get();
+        if x == 0 {
+            //any non zero start will do
+            x = (seed as *const Cell<u32> as usize as u32) | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        seed.set(x);
+        x
+    });
+    ((x.trailing_zeros() / 2) as usize + 1).min(MAX_HEIGHT)
+}
+
+struct Node<K,V> {
+    ///None only in the head
+    entry: Option<(K,V)>,
+    next: Box<[AtomicPtr<Node<K,V>>]>,
+    lock: AtomicBool,
+    marked: AtomicBool,
+    fully_linked: AtomicBool
+}
+impl<K,V> Node<K,V> {
+    fn alloc(entry: Option<(K,V)>, height: usize) -> *mut Node<K,V> {
+        Box::into_raw(Box::new(Node {
+            entry,
+            next: (0..height).map(|_| AtomicPtr::new(ptr::null_mut())).collect(),
+            lock: AtomicBool::new(false),
+            marked: AtomicBool::new(false),
+            fully_linked: AtomicBool::new(false)
+        }))
+    }
+    #[inline(always)]
+    fn height(&self) -> usize {
+        self.next.len()
+    }
+    #[inline(always)]
+    fn key(&self) -> &K {
+        &self.entry.as_ref().unwrap().0
+    }
+    fn lock(&self) {
+        let mut step = 0;
+        while self.lock.compare_exchange_weak(false, true, ACQUIRE, RELAXED).is_err() {
+            backoff(&mut step);
+        }
+    }
+    fn unlock(&self) {
+        self.lock.store(false, RELEASE);
+    }
+    ///Linked on every level and not being removed
+    #[inline(always)]
+    fn live(&self) -> bool {
+        self.fully_linked.load(ACQUIRE) && !self.marked.load(ACQUIRE)
+    }
+}
+
+type Links<K,V> = [*mut Node<K,V>; MAX_HEIGHT];
+
+///Unlock the distinct predecessors on levels `0..levels`
+unsafe fn unlock_preds<K,V>(preds: &Links<K,V>, levels: usize) {
+    let mut last = ptr::null_mut();
+    for &pred in preds.iter().take(levels) {
+        if pred != last {
+            (*pred).unlock();
+            last = pred;
+        }
+    }
+}
+
+///Ordered map safe to share between threads
+pub struct SkipListMap<K,V> {
+    head: *mut Node<K,V>,
+    len: AtomicUsize
+}
+unsafe impl<K: Send+Sync,V: Send+Sync> Send for SkipListMap<K,V> { }
+unsafe impl<K: Send+Sync,V: Send+Sync> Sync for SkipListMap<K,V> { }
+impl<K: Ord+Clone,V: Clone> SkipListMap<K,V> {
+    ///Build an empty map
+    pub fn new() -> SkipListMap<K,V> {
+        SkipListMap {
+            head: Node::alloc(None, MAX_HEIGHT),
+            len: AtomicUsize::new(0)
+        }
+    }
+    ///Fill `preds` and `succs` around `key` on every level, returns the
+    ///highest level `key` was found on. The caller is pinned.
+    unsafe fn find(&self, key: &K, preds: &mut Links<K,V>, succs: &mut Links<K,V>) -> Option<usize> {
+        let mut found = None;
+        let mut pred = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            let mut curr = (*pred).next[level].load(ACQUIRE);
+            while !curr.is_null() && (*curr).key() < key {
+                pred = curr;
+                curr = (*pred).next[level].load(ACQUIRE);
+            }
+            if found.is_none() && !curr.is_null() && (*curr).key() == key {
+                found = Some(level);
+            }
+            preds[level] = pred;
+            succs[level] = curr;
+        }
+        found
+    }
+    ///First node with a key not below `bound`. The caller is pinned.
+    unsafe fn seek(&self, bound: Bound<&K>) -> *mut Node<K,V> {
+        let mut pred = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            loop {
+                let curr = (*pred).next[level].load(ACQUIRE);
+                let before = !curr.is_null() && match bound {
+                    Bound::Included(k) => (*curr).key() < k,
+                    Bound::Excluded(k) => (*curr).key() <= k,
+                    Bound::Unbounded => false
+                };
+                if !before {
+                    break;
+                }
+                pred = curr;
+            }
+        }
+        (*pred).next[0].load(ACQUIRE)
+    }
+    ///Insert a key that is not present yet
+    ///
+    ///Returns false and leaves the map alone if the key is already present
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let _guard = epoch::pin();
+        let height = random_height();
+        let mut preds = [ptr::null_mut(); MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        let mut entry = Some((key, value));
+        unsafe {
+            loop {
+                let key = &entry.as_ref().unwrap().0;
+                if let Option::Some(level) = self.find(key, &mut preds, &mut succs) {
+                    let found = &*succs[level];
+                    if !found.marked.load(ACQUIRE) {
+                        //wait for the other insert to finish linking
+                        let mut step = 0;
+                        while !found.fully_linked.load(ACQUIRE) {
+                            backoff(&mut step);
+                        }
+                        return false;
+                    }
+                    //being removed, try again once it is gone
+                    continue;
+                }
+                let mut locked = 0;
+                let mut valid = true;
+                let mut last = ptr::null_mut();
+                for level in 0..height {
+                    let pred = preds[level];
+                    let succ = succs[level];
+                    if pred != last {
+                        (*pred).lock();
+                        last = pred;
+                    }
+                    locked = level + 1;
+                    valid = !(*pred).marked.load(ACQUIRE) &&
+                        (succ.is_null() || !(*succ).marked.load(ACQUIRE)) &&
+                        (*pred).next[level].load(ACQUIRE) == succ;
+                    if !valid {
+                        break;
+                    }
+                }
+                if !valid {
+                    unlock_preds(&preds, locked);
+                    continue;
+                }
+                let node = Node::alloc(entry.take(), height);
+                for (level, &succ) in succs.iter().enumerate().take(height) {
+                    (*node).next[level].store(succ, RELAXED);
+                }
+                for (level, &pred) in preds.iter().enumerate().take(height) {
+                    (*pred).next[level].store(node, RELEASE);
+                }
+                (*node).fully_linked.store(true, RELEASE);
+                unlock_preds(&preds, height);
+                self.len.fetch_add(1, RELAXED);
+                return true;
+            }
+        }
+    }
+    ///Unlink a node the caller marked and holds the lock of. The caller is
+    ///pinned.
+    unsafe fn unlink(&self, victim: *mut Node<K,V>, guard: &epoch::Guard) -> V {
+        let mut preds = [ptr::null_mut(); MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        let height = (*victim).height();
+        loop {
+            self.find((*victim).key(), &mut preds, &mut succs);
+            let mut locked = 0;
+            let mut valid = true;
+            let mut last = ptr::null_mut();
+            for (level, &pred) in preds.iter().enumerate().take(height) {
+                if pred != last {
+                    (*pred).lock();
+                    last = pred;
+                }
+                locked = level + 1;
+                valid = !(*pred).marked.load(ACQUIRE) &&
+                    (*pred).next[level].load(ACQUIRE) == victim;
+                if !valid {
+                    break;
+                }
+            }
+            if !valid {
+                unlock_preds(&preds, locked);
+                continue;
+            }
+            for level in (0..height).rev() {
+                let next = (*victim).next[level].load(ACQUIRE);
+                (*preds[level]).next[level].store(next, RELEASE);
+            }
+            (*victim).unlock();
+            unlock_preds(&preds, height);
+            self.len.fetch_sub(1, RELAXED);
+            //readers may still be cloning the value
+            let value = (*victim).entry.as_ref().unwrap().1.clone();
+            guard.defer_destroy(victim);
+            return value;
+        }
+    }
+    ///Claim `victim` for removal, fails if it is gone or not linked yet
+    unsafe fn mark(victim: *mut Node<K,V>) -> bool {
+        let node = &*victim;
+        node.lock();
+        if node.marked.load(ACQUIRE) || !node.fully_linked.load(ACQUIRE) {
+            node.unlock();
+            return false;
+        }
+        node.marked.store(true, RELEASE);
+        true
+    }
+    ///Remove a key, returning its value
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let guard = epoch::pin();
+        let mut preds = [ptr::null_mut(); MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        unsafe {
+            loop {
+                let level = self.find(key, &mut preds, &mut succs)?;
+                let victim = succs[level];
+                //only the top of the tower is the whole node
+                if (*victim).height() != level + 1 || !(*victim).live() {
+                    if (*victim).marked.load(ACQUIRE) {
+                        return None;
+                    }
+                    //still being linked
+                    continue;
+                }
+                if Self::mark(victim) {
+                    return Some(self.unlink(victim, &guard));
+                }
+            }
+        }
+    }
+    ///Remove the entry with the smallest key
+    pub fn pop_first(&self) -> Option<(K,V)> {
+        let guard = epoch::pin();
+        let mut step = 0;
+        unsafe {
+            loop {
+                let first = self.seek(Bound::Unbounded);
+                if first.is_null() {
+                    return None;
+                }
+                if Self::mark(first) {
+                    let key = (*first).key().clone();
+                    return Some((key, self.unlink(first, &guard)));
+                }
+                backoff(&mut step);
+            }
+        }
+    }
+    ///Clone the value stored for `key`
+    pub fn get(&self, key: &K) -> Option<V> {
+        let _guard = epoch::pin();
+        unsafe {
+            let node = self.seek(Bound::Included(key));
+            if node.is_null() || (*node).key() != key || !(*node).live() {
+                return None;
+            }
+            Some((*node).entry.as_ref().unwrap().1.clone())
+        }
+    }
+    ///Returns true if the map holds `key`
+    pub fn contains_key(&self, key: &K) -> bool {
+        let _guard = epoch::pin();
+        unsafe {
+            let node = self.seek(Bound::Included(key));
+            !node.is_null() && (*node).key() == key && (*node).live()
+        }
+    }
+    ///Clone the entry with the smallest key
+    pub fn first(&self) -> Option<(K,V)> {
+        let _guard = epoch::pin();
+        unsafe {
+            let mut cursor = (*self.head).next[0].load(ACQUIRE);
+            while !cursor.is_null() {
+                let node = &*cursor;
+                if node.live() {
+                    return node.entry.clone();
+                }
+                cursor = node.next[0].load(ACQUIRE);
+            }
+        }
+        None
+    }
+    ///Clone the entries with keys in `range`, in order
+    ///
+    ///Entries inserted or removed while the walk runs may or may not show
+    ///up.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Vec<(K,V)> {
+        let _guard = epoch::pin();
+        let mut out = Vec::new();
+        unsafe {
+            let mut cursor = self.seek(range.start_bound());
+            while !cursor.is_null() {
+                let node = &*cursor;
+                let within = match range.end_bound() {
+                    Bound::Included(k) => node.key() <= k,
+                    Bound::Excluded(k) => node.key() < k,
+                    Bound::Unbounded => true
+                };
+                if !within {
+                    break;
+                }
+                if node.live() {
+                    out.push(node.entry.clone().unwrap());
+                }
+                cursor = node.next[0].load(ACQUIRE);
+            }
+        }
+        out
+    }
+    ///Number of entries
+    pub fn len(&self) -> usize {
+        self.len.load(RELAXED)
+    }
+    ///Returns true if the map is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+impl<K: Ord+Clone,V: Clone> Default for SkipListMap<K,V> {
+    fn default() -> SkipListMap<K,V> {
+        SkipListMap::new()
+    }
+}
+impl<K,V> Drop for SkipListMap<K,V> {
+    fn drop(&mut self) {
+        let mut cursor = self.head;
+        while !cursor.is_null() {
+            let node = unsafe{ Box::from_raw(cursor) };
+            cursor = node.next[0].load(RELAXED);
+        }
+    }
+}
+
+#[test]
+fn test_skiplist_ordered() {
+    let map = SkipListMap::new();
+    for i in [5,1,9,3,7].iter() {
+        assert!(map.insert(*i, i * 10));
+    }
+    assert!(!map.insert(3, 0));
+    assert_eq!(map.len(), 5);
+    assert_eq!(map.get(&7), Some(70));
+    assert_eq!(map.get(&4), None);
+    assert_eq!(map.range(3..9), vec![(3,30),(5,50),(7,70)]);
+    assert_eq!(map.range(..=3), vec![(1,10),(3,30)]);
+    assert_eq!(map.remove(&5), Some(50));
+    assert_eq!(map.remove(&5), None);
+    assert!(!map.contains_key(&5));
+    assert_eq!(map.pop_first(), Some((1,10)));
+    assert_eq!(map.first(), Some((3,30)));
+    assert_eq!(map.len(), 3);
+}
+
+#[test]
+fn test_skiplist_threads() {
+    use std::sync::Arc;
+    use std::thread;
+    let map = Arc::new(SkipListMap::new());
+    let workers = (0..4).map(|t| {
+        let map = map.clone();
+        thread::spawn(move || {
+            for i in 0..250 {
+                assert!(map.insert(i * 4 + t, t));
+            }
+            let mut popped = 0;
+            while popped < 100 {
+                if map.pop_first().is_some() {
+                    popped += 1;
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(map.len(), 600);
+    let rest = map.range(..);
+    assert_eq!(rest.len(), 600);
+    assert!(rest.windows(2).all(|w| w[0].0 < w[1].0));
+}
@@ -1,30 +1,1135 @@
-//!Spin lock abstract traits.
+//!Spin lock abstract traits, and a concrete SpinLock built on them.
 
 
-use std::sync::atomic::{AtomicUsize,Ordering};
-const SEQ: Ordering = Ordering::SeqCst;
+#[cfg(feature="std")]
+use super::Async;
+use super::backoff::backoff;
+use super::backoff::SINGLE_THREADED;
+#[cfg(all(feature="std",not(loom)))]
+use super::cancel::{Cancelled,CancellationToken};
+use super::poison::{LockResult,Poison};
+#[cfg(feature="lock-stats")]
+use super::lockstats::LockStats;
+use super::lockstats::{HoldTimer,LockCounters};
+use super::lockorder;
+use super::instrument;
+use super::ordering::{AcquireRelease,MemoryOrdering};
+use super::policy::{Policy,Unfair};
+use super::primitive::{self,UnsafeCell,AtomicBool,AtomicU8,AtomicU16,AtomicU32,AtomicUsize};
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref,DerefMut};
+use core::ptr;
+use core::sync::atomic::Ordering;
+#[cfg(feature="std")]
+use std::time::{Duration,Instant};
+
+///`Lock::lock` that reports failed polls and spins to `counters`
+#[inline(always)]
+pub(crate) fn lock_counted<L: Lock+?Sized>(lock: &L, counters: &LockCounters) {
+    let id = lockorder::id_of(lock);
+    lockorder::waiting(id);
+    let mut step = 0;
+    let mut spins = 0;
+    let mut failed = 0;
+    while lock.poll().is_err() {
+        if failed == 0 {
+            instrument::lock_waiting(id);
+        }
+        counters.failed();
+        failed += 1;
+        spins += backoff(&mut step);
+    }
+    counters.spun(spins);
+    if failed != 0 {
+        instrument::lock_waited(id);
+    }
+    instrument::lock_acquired(id, failed);
+}
+
+///Low bit of a loaned word, set while the lock is held
+pub const LOCK_BIT: usize = 1;
+///Largest tag that fits next to the lock bit of an AtomicUsize
+pub const MAX_TAG: usize = usize::MAX >> 1;
+
+///An atomic a LoanLock can be built on
+///
+///Values pass through as usize so the lock and tag logic is written once.
+///Tags wider than the word are cut off, `MAX_TAG` is the largest that
+///fits. An AtomicBool has room for the lock bit and nothing else.
+pub trait LockWord {
+    ///The word with the lock clear and a zero tag
+    #[cfg(not(loom))]
+    const UNLOCKED: Self;
+    ///The word with the lock clear and a zero tag, loom's atomics can not
+    ///be constants
+    #[cfg(loom)]
+    fn unlocked() -> Self;
+    ///Largest tag that fits next to the lock bit
+    const MAX_TAG: usize;
+    fn load(&self, order: Ordering) -> usize;
+    fn store(&self, value: usize, order: Ordering);
+    fn compare_exchange_weak(&self, current: usize, new: usize, success: Ordering, failure: Ordering) -> Result<usize,usize>;
+    fn fetch_and(&self, value: usize, order: Ordering) -> usize;
+}
+macro_rules! lock_word {
+    ($atomic: ty, $int: ty) => {
+        impl LockWord for $atomic {
+            #[cfg(not(loom))]
+            #[allow(clippy::declare_interior_mutable_const)]
+            const UNLOCKED: $atomic = <$atomic>::new(0);
+            #[cfg(loom)]
+            fn unlocked() -> $atomic {
+                <$atomic>::new(0)
+            }
+            const MAX_TAG: usize = (<$int>::MAX >> 1) as usize;
+            #[inline(always)]
+            fn load(&self, order: Ordering) -> usize {
+                <$atomic>::load(self, order) as usize
+            }
+            #[inline(always)]
+            fn store(&self, value: usize, order: Ordering) {
+                <$atomic>::store(self, value as $int, order)
+            }
+            #[inline(always)]
+            fn compare_exchange_weak(&self, current: usize, new: usize, success: Ordering, failure: Ordering) -> Result<usize,usize> {
+                <$atomic>::compare_exchange_weak(self, current as $int, new as $int, success, failure)
+                    .map(|x| x as usize)
+                    .map_err(|x| x as usize)
+            }
+            #[inline(always)]
+            fn fetch_and(&self, value: usize, order: Ordering) -> usize {
+                <$atomic>::fetch_and(self, value as $int, order) as usize
+            }
+        }
+    }
+}
+lock_word!(AtomicU8, u8);
+lock_word!(AtomicU16, u16);
+lock_word!(AtomicU32, u32);
+lock_word!(AtomicUsize, usize);
+impl LockWord for AtomicBool {
+    #[cfg(not(loom))]
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: AtomicBool = AtomicBool::new(false);
+    #[cfg(loom)]
+    fn unlocked() -> AtomicBool {
+        AtomicBool::new(false)
+    }
+    const MAX_TAG: usize = 0;
+    #[inline(always)]
+    fn load(&self, order: Ordering) -> usize {
+        AtomicBool::load(self, order) as usize
+    }
+    #[inline(always)]
+    fn store(&self, value: usize, order: Ordering) {
+        AtomicBool::store(self, value & LOCK_BIT != 0, order)
+    }
+    #[inline(always)]
+    fn compare_exchange_weak(&self, current: usize, new: usize, success: Ordering, failure: Ordering) -> Result<usize,usize> {
+        AtomicBool::compare_exchange_weak(self, current & LOCK_BIT != 0, new & LOCK_BIT != 0, success, failure)
+            .map(|x| x as usize)
+            .map_err(|x| x as usize)
+    }
+    #[inline(always)]
+    fn fetch_and(&self, value: usize, order: Ordering) -> usize {
+        AtomicBool::fetch_and(self, value & LOCK_BIT != 0, order) as usize
+    }
+}
 
 ///Trait for when a larger type wants to build up a lock. This loans an
 ///internal atomic 
+///
+///Only the low bit of the word is the lock. The remaining bits are a tag
+///the owner can use for small pieces of state that should live on the same
+///cache line, such as a closed flag or an epoch.
 pub trait LoanLock {
-    fn loan<'a>(&'a self) -> &'a AtomicUsize;
+    ///The atomic being loaned, usually an AtomicUsize
+    type Word: LockWord;
+    #[allow(clippy::needless_lifetimes)]
+    fn loan<'a>(&'a self) -> &'a Self::Word;
+    ///Ordering of a successful `Lock::poll`
+    const ACQUIRE: Ordering = Ordering::Acquire;
+    ///Ordering of `Lock::release`
+    const RELEASE: Ordering = Ordering::Release;
+    ///Ordering of a failed `Lock::poll`
+    const RELAXED: Ordering = Ordering::Relaxed;
+    ///Take the lock once, returning the tag stored next to it
+    ///
+    ///Returns Err(()) if the lock is held
+    #[allow(clippy::result_unit_err)]
+    fn poll_and_read_tag(&self) -> Result<usize,()> {
+        let word = self.loan();
+        let mut current = word.load(Self::RELAXED);
+        loop {
+            if current & LOCK_BIT != 0 {
+                return Err(());
+            }
+            match word.compare_exchange_weak(current, current | LOCK_BIT, Self::ACQUIRE, Self::RELAXED) {
+                Ok(_) => {
+                    lockorder::acquired(lockorder::id_of(self));
+                    return Ok(current >> 1);
+                }
+                Err(x) => current = x
+            };
+        }
+    }
+    ///Release the lock and replace the tag in one store
+    ///
+    ///Must only be called by the holder of the lock.
+    fn release_with_tag(&self, tag: usize) {
+        debug_assert!(tag <= Self::Word::MAX_TAG, "tag {} does not fit next to the lock bit", tag);
+        lockorder::released(lockorder::id_of(self));
+        self.loan().store(tag << 1, Self::RELEASE);
+    }
+    ///Current tag, whether or not the lock is held
+    fn read_tag(&self) -> usize {
+        self.loan().load(Self::ACQUIRE) >> 1
+    }
+    ///Replace the tag without touching the lock bit, returning the old tag
+    fn swap_tag(&self, tag: usize) -> usize {
+        debug_assert!(tag <= Self::Word::MAX_TAG, "tag {} does not fit next to the lock bit", tag);
+        let word = self.loan();
+        let mut current = word.load(Self::RELAXED);
+        loop {
+            let next = (tag << 1) | (current & LOCK_BIT);
+            match word.compare_exchange_weak(current, next, Self::RELEASE, Self::RELAXED) {
+                Ok(_) => return current >> 1,
+                Err(x) => current = x
+            };
+        }
+    }
 }
 
 ///Represents the state of a lock. Poll returns an OK(()) on lock success,
 ///and Err(()) is that the attempt to lock failed
 pub trait Lock {
+    #[allow(clippy::result_unit_err)]
     fn poll(&self) -> Result<(),()>;
     fn release(&self);
+    ///Poll up to `attempts` times, with a spin hint between failures
+    ///
+    ///Returns Err(()) if every attempt found the lock held. On a single
+    ///threaded target nobody can release the lock between polls, it gives
+    ///up after the first.
+    #[allow(clippy::result_unit_err)]
+    fn poll_n(&self, attempts: usize) -> Result<(),()> {
+        for i in 0..attempts {
+            if self.poll().is_ok() {
+                return Ok(());
+            }
+            if SINGLE_THREADED {
+                break;
+            }
+            if i + 1 < attempts {
+                primitive::spin_loop();
+            }
+        }
+        Err(())
+    }
+    ///Block until `poll` succeeds
+    ///
+    ///Failed polls go through `backoff`: they spin exponentially with
+    ///`hint::spin_loop`, then yield, then sleep, so a long wait stops
+    ///hammering the lock word and gives the holder a chance to run.
+    fn lock(&self) {
+        lockorder::waiting(lockorder::id_of(self));
+        let mut step = 0;
+        while self.poll().is_err() {
+            backoff(&mut step);
+        }
+    }
+    #[cfg(feature="std")]
+    ///Block until `poll` succeeds or the deadline passes
+    ///
+    ///Returns Async::Ok(()) when the lock is held
+    ///Returns Async::Block(()) if the deadline passed first
+    fn try_lock_until(&self, deadline: Instant) -> Async<(),(),()> {
+        let mut step = 0;
+        loop {
+            if self.poll().is_ok() {
+                return Async::Ok(());
+            }
+            //nobody else could release it before the deadline
+            if SINGLE_THREADED || Instant::now() >= deadline {
+                return Async::Block(());
+            }
+            backoff(&mut step);
+        }
+    }
+    #[cfg(feature="std")]
+    ///Block for at most `timeout`, a timeout too long to express as an
+    ///Instant waits for good
+    ///
+    ///Has the same return values as `try_lock_until`
+    fn try_lock_for(&self, timeout: Duration) -> Async<(),(),()> {
+        if SINGLE_THREADED {
+            //single threaded wasm has no clock to read
+            return match self.poll() {
+                Ok(()) => Async::Ok(()),
+                Err(()) => Async::Block(())
+            };
+        }
+        match Instant::now().checked_add(timeout) {
+            Option::Some(deadline) => self.try_lock_until(deadline),
+            Option::None => {
+                self.lock();
+                Async::Ok(())
+            }
+        }
+    }
 }
-impl<L: LoanLock> Lock for L {
+impl<L: LoanLock+?Sized> Lock for L {
     fn poll(&self) -> Result<(),()>{
-        if self.loan().compare_and_swap(0,1,SEQ) == 0 {
-            Ok(())
-        } else {
-            Err(())
+        self.poll_and_read_tag().map(|_| ())
+    }
+    ///Clears the lock bit, the tag is left alone
+    fn release(&self) {
+        lockorder::released(lockorder::id_of(self));
+        self.loan().fetch_and(!LOCK_BIT,L::RELEASE);
+    }
+}
+
+///A FIFO spin lock. Every caller of `lock` takes a ticket and waits for
+///`serving` to reach it, so acquisition order matches arrival order.
+///
+///Through the `Lock` trait `poll` only succeeds when nobody holds the lock
+///and nobody is queued, so it never jumps ahead of a waiting ticket.
+pub struct TicketLock<O: MemoryOrdering = AcquireRelease> {
+    next: AtomicUsize,
+    serving: AtomicUsize,
+    order: PhantomData<O>
+}
+impl TicketLock {
+    loom_const_fn! {
+        ///Build a new unlocked TicketLock
+        #[inline(always)]
+        pub fn new() -> TicketLock {
+            TicketLock::with_ordering()
+        }
+    }
+}
+impl<O: MemoryOrdering> TicketLock<O> {
+    loom_const_fn! {
+        ///Build a new unlocked TicketLock using the orderings of `O`
+        #[inline(always)]
+        pub fn with_ordering() -> TicketLock<O> {
+            TicketLock {
+                next: AtomicUsize::new(0),
+                serving: AtomicUsize::new(0),
+                order: PhantomData
+            }
+        }
+    }
+    ///Number of callers holding or waiting on the lock
+    #[inline(always)]
+    pub fn queued(&self) -> usize {
+        self.next.load(O::RELAXED).wrapping_sub(self.serving.load(O::RELAXED))
+    }
+}
+impl<O: MemoryOrdering> Default for TicketLock<O> {
+    fn default() -> TicketLock<O> {
+        TicketLock::with_ordering()
+    }
+}
+impl<O: MemoryOrdering> Lock for TicketLock<O> {
+    fn poll(&self) -> Result<(),()> {
+        //acquire pairs with the release in `release`
+        let serving = self.serving.load(O::ACQUIRE);
+        match self.next.compare_exchange(serving, serving.wrapping_add(1), O::ACQUIRE, O::RELAXED) {
+            Ok(_) => {
+                lockorder::acquired(lockorder::id_of(self));
+                Ok(())
+            }
+            Err(_) => Err(())
         }
     }
     fn release(&self) {
-        self.loan().store(0,SEQ);
+        lockorder::released(lockorder::id_of(self));
+        self.serving.fetch_add(1,O::RELEASE);
+    }
+    ///Take a ticket and spin until it is served
+    fn lock(&self) {
+        lockorder::waiting(lockorder::id_of(self));
+        //tickets only need to be unique, the handover is ordered by serving
+        let ticket = self.next.fetch_add(1,O::RELAXED);
+        let mut step = 0;
+        while self.serving.load(O::ACQUIRE) != ticket {
+            backoff(&mut step);
+        }
+        lockorder::acquired(lockorder::id_of(self));
+    }
+}
+
+///A bare lock word, for guarding data that lives somewhere else
+///
+///`W` is the atomic the word is stored in. Pick a smaller one, such as
+///AtomicU8, when the lock is embedded in many small nodes.
+pub struct RawSpinLock<O: MemoryOrdering = AcquireRelease, W: LockWord = AtomicUsize> {
+    lock: W,
+    order: PhantomData<O>
+}
+impl RawSpinLock {
+    loom_const_fn! {
+        ///Build a new unlocked RawSpinLock
+        #[inline(always)]
+        pub fn new() -> RawSpinLock {
+            RawSpinLock::with_ordering()
+        }
+    }
+}
+impl<O: MemoryOrdering, W: LockWord> RawSpinLock<O,W> {
+    ///Build a new unlocked RawSpinLock using the orderings of `O` and the
+    ///lock word `W`
+    #[cfg(not(loom))]
+    #[inline(always)]
+    pub const fn with_ordering() -> RawSpinLock<O,W> {
+        RawSpinLock {
+            lock: W::UNLOCKED,
+            order: PhantomData
+        }
+    }
+    ///Build a new unlocked RawSpinLock using the orderings of `O` and the
+    ///lock word `W`
+    #[cfg(loom)]
+    pub fn with_ordering() -> RawSpinLock<O,W> {
+        RawSpinLock {
+            lock: W::unlocked(),
+            order: PhantomData
+        }
+    }
+}
+impl<O: MemoryOrdering, W: LockWord> Default for RawSpinLock<O,W> {
+    fn default() -> RawSpinLock<O,W> {
+        RawSpinLock::with_ordering()
+    }
+}
+impl<O: MemoryOrdering, W: LockWord> LoanLock for RawSpinLock<O,W> {
+    type Word = W;
+    #[allow(clippy::needless_lifetimes)]
+    fn loan<'a>(&'a self) -> &'a W {
+        &self.lock
+    }
+    const ACQUIRE: Ordering = O::ACQUIRE;
+    const RELEASE: Ordering = O::RELEASE;
+    const RELAXED: Ordering = O::RELAXED;
+}
+
+///`lock_api::Mutex` backed by a RawSpinLock
+#[cfg(feature="lock_api")]
+pub type SpinMutex<T> = lock_api::Mutex<RawSpinLock,T>;
+///`lock_api::Mutex` backed by a TicketLock
+#[cfg(feature="lock_api")]
+pub type TicketMutex<T> = lock_api::Mutex<TicketLock,T>;
+
+#[cfg(all(feature="lock_api",not(loom)))]
+unsafe impl<O: MemoryOrdering, W: LockWord> lock_api::RawMutex for RawSpinLock<O,W> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawSpinLock<O,W> = RawSpinLock::with_ordering();
+    type GuardMarker = lock_api::GuardSend;
+    fn lock(&self) {
+        Lock::lock(self);
+    }
+    fn try_lock(&self) -> bool {
+        self.poll().is_ok()
+    }
+    unsafe fn unlock(&self) {
+        self.release();
+    }
+    fn is_locked(&self) -> bool {
+        self.lock.load(O::RELAXED) & LOCK_BIT != 0
+    }
+}
+#[cfg(all(feature="lock_api",not(loom)))]
+unsafe impl<O: MemoryOrdering> lock_api::RawMutex for TicketLock<O> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: TicketLock<O> = TicketLock::with_ordering();
+    type GuardMarker = lock_api::GuardSend;
+    fn lock(&self) {
+        Lock::lock(self);
+    }
+    fn try_lock(&self) -> bool {
+        self.poll().is_ok()
+    }
+    unsafe fn unlock(&self) {
+        self.release();
+    }
+    fn is_locked(&self) -> bool {
+        self.queued() != 0
+    }
+}
+
+///An owning spin lock. The lock word lives next to the data and the only
+///way to reach the data is through a SpinGuard, which releases on drop.
+///
+///`O` picks the memory orderings, see the `ordering` module. `P` picks how
+///contending callers are served, see the `policy` module.
+//the raw lock comes first so the SpinLock and its raw lock share an
+//address, and with it an id in the lock order graph
+#[repr(C)]
+pub struct SpinLock<T: ?Sized, O: MemoryOrdering = AcquireRelease, P: Policy = Unfair> {
+    lock: P::Raw<O>,
+    poison: Poison,
+    counters: LockCounters,
+    data: UnsafeCell<T>
+}
+unsafe impl<T: ?Sized+Send, O: MemoryOrdering, P: Policy> Sync for SpinLock<T,O,P> { }
+unsafe impl<T: ?Sized+Send, O: MemoryOrdering, P: Policy> Send for SpinLock<T,O,P> { }
+impl<T> SpinLock<T> {
+    loom_const_fn! {
+        ///Build a new unlocked SpinLock
+        #[inline(always)]
+        pub fn new(data: T) -> SpinLock<T> {
+            SpinLock::unfair(data, false)
+        }
+    }
+    loom_const_fn! {
+        ///Build a new unlocked SpinLock that is poisoned when a thread
+        ///panics while holding it, see `lock_checked`
+        #[inline(always)]
+        pub fn with_poisoning(data: T) -> SpinLock<T> {
+            SpinLock::unfair(data, true)
+        }
+    }
+    loom_const_fn! {
+        ///`build` for the default parameters, which can be done at compile
+        ///time since the raw lock is known
+        #[inline(always)]
+        fn unfair(data: T, poisoning: bool) -> SpinLock<T> {
+            SpinLock {
+                lock: RawSpinLock::new(),
+                poison: Poison::new(poisoning),
+                counters: LockCounters::new(),
+                data: UnsafeCell::new(data)
+            }
+        }
+    }
+}
+impl<T, O: MemoryOrdering, P: Policy> SpinLock<T,O,P> {
+    ///Build a new unlocked SpinLock using the orderings of `O` and the
+    ///acquisition policy `P`
+    #[inline(always)]
+    pub fn with_ordering(data: T) -> SpinLock<T,O,P> {
+        SpinLock::build(data, false)
+    }
+    #[inline(always)]
+    fn build(data: T, poisoning: bool) -> SpinLock<T,O,P> {
+        SpinLock {
+            lock: P::raw(),
+            poison: Poison::new(poisoning),
+            counters: LockCounters::new(),
+            data: UnsafeCell::new(data)
+        }
+    }
+    ///Consume the lock, returning the data
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+impl<T: ?Sized, O: MemoryOrdering, P: Policy> SpinLock<T,O,P> {
+    ///Wrap an acquired lock in a guard
+    #[inline(always)]
+    fn guard<'a>(&'a self) -> SpinGuard<'a,T,O,P> {
+        SpinGuard {
+            lock: self,
+            armed: self.poison.arm(),
+            timer: self.counters.acquired()
+        }
+    }
+    ///Spin until the lock is held
+    ///
+    ///Unfair locks poll with backoff, fair ones wait their turn in the
+    ///queue.
+    #[inline(always)]
+    pub fn lock<'a>(&'a self) -> SpinGuard<'a,T,O,P> {
+        if P::FAIR {
+            let id = lockorder::id_of(&self.lock);
+            //a fair lock waits in its queue rather than polling
+            instrument::lock_waiting(id);
+            self.lock.lock();
+            instrument::lock_waited(id);
+            instrument::lock_acquired(id, 0);
+        } else {
+            lock_counted(&self.lock, &self.counters);
+        }
+        self.guard()
+    }
+    ///Spin until the lock is held, reporting poisoning
+    ///
+    ///Returns Err(PoisonError) holding the guard if a thread panicked
+    ///while holding the lock. Locks built with `new` are never poisoned.
+    #[inline(always)]
+    pub fn lock_checked<'a>(&'a self) -> LockResult<SpinGuard<'a,T,O,P>> {
+        let guard = self.lock();
+        self.poison.check(guard)
     }
+    ///Returns true if a thread panicked while holding the lock
+    #[inline(always)]
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+    ///Mark the data as consistent again
+    #[inline(always)]
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+    ///Attempt the lock once
+    ///
+    ///Returns Err(()) if somebody else holds it
+    #[inline(always)]
+    #[allow(clippy::result_unit_err)]
+    pub fn try_lock<'a>(&'a self) -> Result<SpinGuard<'a,T,O,P>,()> {
+        match self.lock.poll() {
+            Ok(()) => Ok(self.guard()),
+            Err(()) => {
+                self.counters.failed();
+                Err(())
+            }
+        }
+    }
+    #[cfg(feature="std")]
+    ///Spin until the lock is held or the deadline passes
+    ///
+    ///Returns Async::Block(()) if the deadline passed first
+    #[inline(always)]
+    pub fn try_lock_until<'a>(&'a self, deadline: Instant) -> Async<SpinGuard<'a,T,O,P>,(),()> {
+        match self.lock.try_lock_until(deadline) {
+            Async::Ok(()) => Async::Ok(self.guard()),
+            Async::Block(()) => Async::Block(()),
+            Async::Err(()) => Async::Err(())
+        }
+    }
+    #[cfg(all(feature="std",not(loom)))]
+    ///Spin until the lock is held or `token` is cancelled
+    pub fn lock_cancellable<'a>(&'a self, token: &CancellationToken) -> Result<SpinGuard<'a,T,O,P>,Cancelled> {
+        let mut step = 0;
+        loop {
+            if let Ok(guard) = self.try_lock() {
+                return Ok(guard);
+            }
+            token.check()?;
+            backoff(&mut step);
+        }
+    }
+    #[cfg(feature="std")]
+    ///Spin for at most `timeout`
+    #[inline(always)]
+    pub fn try_lock_for<'a>(&'a self, timeout: Duration) -> Async<SpinGuard<'a,T,O,P>,(),()> {
+        match self.lock.try_lock_for(timeout) {
+            Async::Ok(()) => Async::Ok(self.guard()),
+            Async::Block(()) => Async::Block(()),
+            Async::Err(()) => Async::Err(())
+        }
+    }
+    ///Identifies the lock to the debug registry
+    #[cfg(all(feature="debug-registry",not(loom)))]
+    pub(crate) fn raw_id(&self) -> usize {
+        lockorder::id_of(&self.lock)
+    }
+    ///Returns true if somebody holds the lock right now
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        P::is_locked(&self.lock)
+    }
+    ///Contention statistics gathered so far
+    ///
+    ///Fair policies wait in their queue rather than polling, so they only
+    ///count failed `try_lock` calls.
+    #[cfg(feature="lock-stats")]
+    pub fn stats(&self) -> LockStats {
+        self.counters.snapshot()
+    }
+    ///Mutable access without locking, the borrow checker already proves
+    ///nobody else can hold the lock
+    #[inline(always)]
+    #[allow(clippy::needless_lifetimes)]
+    pub fn get_mut<'a>(&'a mut self) -> &'a mut T {
+        self.data.get_mut()
+    }
+}
+impl<T: Default, O: MemoryOrdering, P: Policy> Default for SpinLock<T,O,P> {
+    fn default() -> SpinLock<T,O,P> {
+        SpinLock::with_ordering(T::default())
+    }
+}
+
+///Access to the data of a SpinLock, unlocks on drop
+pub struct SpinGuard<'a,T: ?Sized+'a,O: MemoryOrdering+'a = AcquireRelease,P: Policy+'a = Unfair> {
+    lock: &'a SpinLock<T,O,P>,
+    armed: bool,
+    timer: HoldTimer
+}
+//sharing the guard shares `&T`, the auto impl would only ask for T: Send
+unsafe impl<'a,T: ?Sized+Sync,O: MemoryOrdering,P: Policy> Sync for SpinGuard<'a,T,O,P> { }
+impl<'a,T: ?Sized,O: MemoryOrdering,P: Policy> SpinGuard<'a,T,O,P> {
+    ///The lock this guard came from
+    #[cfg(all(feature="std",not(loom)))]
+    #[inline(always)]
+    pub(crate) fn source(&self) -> &'a SpinLock<T,O,P> {
+        self.lock
+    }
+    ///Narrow the guard down to part of the data
+    ///
+    ///The lock stays held until the returned guard is dropped. This is an
+    ///associated function so it can't shadow a `map` method on `T`.
+    #[inline(always)]
+    pub fn map<U: ?Sized,F>(this: Self, f: F) -> MappedSpinGuard<'a,T,U,O,P>
+        where F: FnOnce(&mut T) -> &mut U
+    {
+        //if `f` panics `this` is still dropped normally and poisons the lock
+        let data = this.lock.data.with_mut(|data| f(unsafe{ &mut *data })) as *mut U;
+        let lock = this.lock;
+        let armed = this.armed;
+        let timer = unsafe{ ptr::read(&this.timer) };
+        mem::forget(this);
+        MappedSpinGuard {
+            lock,
+            data,
+            armed,
+            timer,
+            borrow: PhantomData
+        }
+    }
+}
+impl<'a,T: ?Sized,O: MemoryOrdering,P: Policy> Deref for SpinGuard<'a,T,O,P> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.lock.data.with(|data| unsafe{ &*data })
+    }
+}
+impl<'a,T: ?Sized,O: MemoryOrdering,P: Policy> DerefMut for SpinGuard<'a,T,O,P> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.lock.data.with_mut(|data| unsafe{ &mut *data })
+    }
+}
+impl<'a,T: ?Sized,O: MemoryOrdering,P: Policy> Drop for SpinGuard<'a,T,O,P> {
+    fn drop(&mut self) {
+        self.lock.counters.released(&self.timer);
+        self.lock.poison.disarm(self.armed);
+        self.lock.lock.release();
+    }
+}
+
+///Guard over part of the data of a SpinLock, made by `SpinGuard::map`
+pub struct MappedSpinGuard<'a,T: ?Sized+'a,U: ?Sized+'a,O: MemoryOrdering+'a = AcquireRelease,P: Policy+'a = Unfair> {
+    lock: &'a SpinLock<T,O,P>,
+    data: *mut U,
+    armed: bool,
+    timer: HoldTimer,
+    borrow: PhantomData<&'a mut U>
+}
+unsafe impl<'a,T: ?Sized+Send,U: ?Sized+Sync,O: MemoryOrdering,P: Policy> Sync for MappedSpinGuard<'a,T,U,O,P> { }
+impl<'a,T: ?Sized,U: ?Sized,O: MemoryOrdering,P: Policy> MappedSpinGuard<'a,T,U,O,P> {
+    ///Narrow the guard down further
+    #[inline(always)]
+    pub fn map<V: ?Sized,F>(this: Self, f: F) -> MappedSpinGuard<'a,T,V,O,P>
+        where F: FnOnce(&mut U) -> &mut V
+    {
+        let data = f(unsafe{ &mut *this.data }) as *mut V;
+        let lock = this.lock;
+        let armed = this.armed;
+        let timer = unsafe{ ptr::read(&this.timer) };
+        mem::forget(this);
+        MappedSpinGuard {
+            lock,
+            data,
+            armed,
+            timer,
+            borrow: PhantomData
+        }
+    }
+}
+impl<'a,T: ?Sized,U: ?Sized,O: MemoryOrdering,P: Policy> Deref for MappedSpinGuard<'a,T,U,O,P> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        unsafe{ &*self.data }
+    }
+}
+impl<'a,T: ?Sized,U: ?Sized,O: MemoryOrdering,P: Policy> DerefMut for MappedSpinGuard<'a,T,U,O,P> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe{ &mut *self.data }
+    }
+}
+impl<'a,T: ?Sized,U: ?Sized,O: MemoryOrdering,P: Policy> Drop for MappedSpinGuard<'a,T,U,O,P> {
+    fn drop(&mut self) {
+        self.lock.counters.released(&self.timer);
+        self.lock.poison.disarm(self.armed);
+        self.lock.lock.release();
+    }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_spinlock_guard() {
+    use std::sync::Arc;
+    use std::thread;
+    let lock = Arc::new(SpinLock::new(0usize));
+    {
+        let _held = lock.lock();
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_err());
+    }
+    assert!(!lock.is_locked());
+    let workers = (0..4).map(|_| {
+        let lock = lock.clone();
+        thread::spawn(move || {
+            for _ in 0..1000 {
+                *lock.lock() += 1;
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(*lock.lock(), 4000);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_ticket_lock_order() {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+    let lock = Arc::new(TicketLock::new());
+    let order = Arc::new(Mutex::new(Vec::new()));
+    lock.lock();
+    assert!(lock.poll().is_err());
+    let workers = (0..3).map(|i| {
+        let worker_lock = lock.clone();
+        let order = order.clone();
+        let w = thread::spawn(move || {
+            worker_lock.lock();
+            order.lock().unwrap().push(i);
+            worker_lock.release();
+        });
+        while lock.queued() != i+2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        w
+    }).collect::<Vec<_>>();
+    lock.release();
+    for w in workers {
+        w.join().unwrap();
+    }
+    assert_eq!(*order.lock().unwrap(), vec![0,1,2]);
+    assert!(lock.poll().is_ok());
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_lock_backoff() {
+    use std::thread;
+    use std::sync::Arc;
+    struct Word(AtomicUsize);
+    impl LoanLock for Word {
+        type Word = AtomicUsize;
+        #[allow(clippy::needless_lifetimes)]
+        fn loan<'a>(&'a self) -> &'a AtomicUsize {
+            &self.0
+        }
+    }
+    let word = Arc::new(Word(AtomicUsize::new(0)));
+    word.lock();
+    let waiter = {
+        let word = word.clone();
+        thread::spawn(move || {
+            word.lock();
+            word.release();
+        })
+    };
+    thread::sleep(::std::time::Duration::from_millis(10));
+    word.release();
+    waiter.join().unwrap();
+    assert!(word.poll().is_ok());
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_try_lock_for() {
+    let lock = SpinLock::new(1);
+    {
+        let _held = lock.lock();
+        assert!(lock.try_lock_for(Duration::from_millis(5)).is_blocked());
+        assert!(lock.is_locked());
+    }
+    match lock.try_lock_for(Duration::from_millis(5)) {
+        Async::Ok(guard) => assert_eq!(*guard, 1),
+        _ => panic!("an unheld lock should be taken")
+    };
+    //no deadline rather than an overflow
+    assert!(lock.try_lock_for(Duration::MAX).is_ok());
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_spinlock_poisoning() {
+    use std::thread;
+    use std::sync::Arc;
+    let lock = Arc::new(SpinLock::with_poisoning(0));
+    let worker = {
+        let lock = lock.clone();
+        thread::spawn(move || {
+            let mut guard = lock.lock();
+            *guard = 1;
+            panic!("half way through an update");
+        })
+    };
+    assert!(worker.join().is_err());
+    assert!(lock.is_poisoned());
+    match lock.lock_checked() {
+        Err(poisoned) => assert_eq!(*poisoned.into_inner(), 1),
+        Ok(_) => panic!("the lock should be poisoned")
+    };
+    lock.clear_poison();
+    assert!(lock.lock_checked().is_ok());
+}
+
+#[cfg(feature="lock_api")]
+#[cfg(feature="std")]
+#[test]
+fn test_lock_api_mutex() {
+    let spin = SpinMutex::new(1);
+    *spin.lock() += 1;
+    assert!(spin.try_lock().is_some());
+    let ticket = TicketMutex::new(Vec::new());
+    ticket.lock().push(2);
+    let held = ticket.lock();
+    assert!(ticket.try_lock().is_none());
+    assert_eq!(*held, vec![2]);
+    assert_eq!(*spin.lock(), 2);
+}
+
+#[cfg(feature="lock-stats")]
+#[test]
+fn test_spinlock_stats() {
+    use std::thread;
+    use std::time::Duration;
+    let lock = SpinLock::new(());
+    {
+        let _held = lock.lock();
+        assert!(lock.try_lock().is_err());
+        thread::sleep(Duration::from_millis(2));
+    }
+    drop(lock.lock());
+    let stats = lock.stats();
+    assert_eq!(stats.acquisitions, 2);
+    assert_eq!(stats.failed_polls, 1);
+    assert!(stats.longest_hold >= Duration::from_millis(2));
+}
+
+#[test]
+fn test_sequential_ordering() {
+    use super::ordering::Sequential;
+    let lock: SpinLock<u32,Sequential> = SpinLock::with_ordering(7);
+    *lock.lock() += 1;
+    assert_eq!(lock.into_inner(), 8);
+    let raw = RawSpinLock::<Sequential>::with_ordering();
+    assert!(raw.poll().is_ok());
+    assert!(raw.poll().is_err());
+    raw.release();
+}
+
+#[test]
+fn test_lock_tag_bits() {
+    let lock = RawSpinLock::new();
+    assert_eq!(lock.swap_tag(5), 0);
+    assert!(lock.poll_and_read_tag() == Ok(5));
+    assert!(lock.poll().is_err());
+    lock.release_with_tag(6);
+    assert_eq!(lock.read_tag(), 6);
+    lock.lock();
+    assert_eq!(lock.swap_tag(7), 6);
+    lock.release();
+    assert_eq!(lock.read_tag(), 7);
+    assert!(lock.poll().is_ok());
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_mapped_guard() {
+    use std::collections::VecDeque;
+    struct State {
+        queue: VecDeque<usize>,
+        total: usize
+    }
+    let lock = SpinLock::new(State { queue: VecDeque::new(), total: 0 });
+    {
+        let mut queue = SpinGuard::map(lock.lock(), |s| &mut s.queue);
+        queue.push_back(4);
+        assert!(lock.is_locked());
+    }
+    assert!(!lock.is_locked());
+    {
+        let state = SpinGuard::map(lock.lock(), |s| s);
+        let mut total = MappedSpinGuard::map(state, |s| &mut s.total);
+        *total += 1;
+    }
+    let state = lock.into_inner();
+    assert_eq!(state.queue.front(), Some(&4));
+    assert_eq!(state.total, 1);
+}
+
+#[test]
+fn test_poll_n() {
+    let lock = RawSpinLock::new();
+    assert!(lock.poll_n(0).is_err());
+    assert!(lock.poll_n(3).is_ok());
+    assert!(lock.poll_n(3).is_err());
+    lock.release();
+    assert!(lock.poll_n(1).is_ok());
+    lock.release();
+}
+
+#[cfg(not(loom))]
+#[test]
+fn test_static_spinlock() {
+    static LOCK: SpinLock<usize> = SpinLock::new(0);
+    *LOCK.lock() += 1;
+    assert_eq!(*LOCK.lock(), 1);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_spinlock_policies() {
+    use std::thread;
+    use super::policy::{Queued,Ticket};
+    use std::sync::Arc;
+    fn hammer<P: Policy+'static>(lock: SpinLock<usize,AcquireRelease,P>) {
+        let lock = Arc::new(lock);
+        {
+            let _held = lock.lock();
+            assert!(lock.is_locked());
+            assert!(lock.try_lock().is_err());
+        }
+        assert!(!lock.is_locked());
+        let workers = (0..4).map(|_| {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                for _ in 0..250 {
+                    *lock.lock() += 1;
+                }
+            })
+        }).collect::<Vec<_>>();
+        for w in workers {
+            w.join().unwrap();
+        }
+        assert_eq!(*lock.lock(), 1000);
+    }
+    hammer(SpinLock::<usize,AcquireRelease,Ticket>::with_ordering(0));
+    hammer(SpinLock::<usize,AcquireRelease,Queued>::with_ordering(0));
+}
+
+#[cfg(all(feature="std",not(loom)))]
+#[test]
+fn test_spinlock_cancellable() {
+    let lock = SpinLock::new(0);
+    let token = CancellationToken::new();
+    *lock.lock_cancellable(&token).unwrap() += 1;
+    let guard = lock.lock();
+    token.cancel();
+    assert!(lock.lock_cancellable(&token).is_err());
+    drop(guard);
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_small_lock_words() {
+    use std::mem;
+    //a two byte node with its own lock
+    struct Node {
+        lock: AtomicU8,
+        value: u8
+    }
+    impl LoanLock for Node {
+        type Word = AtomicU8;
+        #[allow(clippy::needless_lifetimes)]
+        fn loan<'a>(&'a self) -> &'a AtomicU8 {
+            &self.lock
+        }
+    }
+    assert_eq!(mem::size_of::<Node>(), 2);
+    let node = Node { lock: AtomicU8::new(0), value: 3 };
+    assert!(node.poll_and_read_tag() == Ok(0));
+    assert!(node.poll().is_err());
+    node.release_with_tag(<AtomicU8 as LockWord>::MAX_TAG);
+    assert_eq!(node.read_tag(), 127);
+    assert_eq!(node.value, 3);
+    let flag = RawSpinLock::<AcquireRelease,AtomicBool>::with_ordering();
+    assert_eq!(mem::size_of_val(&flag), 1);
+    flag.lock();
+    assert!(flag.poll().is_err());
+    flag.release();
+    assert!(flag.poll().is_ok());
+    assert_eq!(flag.read_tag(), 0);
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_spinlock() {
+    use super::policy::Ticket;
+    use loom::sync::Arc;
+    use loom::thread;
+    fn increment<P: Policy+'static>(lock: SpinLock<usize,AcquireRelease,P>) where P::Raw<AcquireRelease>: Send+Sync {
+        let lock = Arc::new(lock);
+        let other = lock.clone();
+        let t = thread::spawn(move || {
+            *other.lock() += 1;
+        });
+        *lock.lock() += 1;
+        t.join().unwrap();
+        assert_eq!(*lock.lock(), 2);
+    }
+    loom::model(|| increment(SpinLock::<usize,AcquireRelease,Unfair>::with_ordering(0)));
+    loom::model(|| increment(SpinLock::<usize,AcquireRelease,Ticket>::with_ordering(0)));
+}
+
+#[cfg(loom)]
+#[test]
+#[should_panic(expected = "Causality violation")]
+fn loom_spinlock_relaxed() {
+    use loom::sync::Arc;
+    use loom::thread;
+    //handing the lock over without release and acquire races on the data
+    struct Relaxed;
+    impl MemoryOrdering for Relaxed {
+        const ACQUIRE: Ordering = Ordering::Relaxed;
+        const RELEASE: Ordering = Ordering::Relaxed;
+        const ACQ_REL: Ordering = Ordering::Relaxed;
+        const RELAXED: Ordering = Ordering::Relaxed;
+    }
+    loom::model(|| {
+        let lock = Arc::new(SpinLock::<usize,Relaxed>::with_ordering(0));
+        let other = lock.clone();
+        let t = thread::spawn(move || {
+            *other.lock() += 1;
+        });
+        *lock.lock() += 1;
+        t.join().unwrap();
+    });
+}
+
+#[cfg(single_threaded)]
+#[test]
+fn single_thread_try_lock() {
+    let lock = SpinLock::new(0usize);
+    let guard = lock.lock();
+    //nothing else could release it, the wait gives up at once
+    assert!(lock.try_lock_for(Duration::from_secs(60)).is_blocked());
+    assert!(lock.try_lock_until(Instant::now() + Duration::from_secs(60)).is_blocked());
+    drop(guard);
+    assert!(lock.try_lock_for(Duration::from_secs(60)).is_ok());
+}
+
+#[cfg(single_threaded)]
+#[test]
+#[should_panic(expected="only has one")]
+fn single_thread_ticket_lock() {
+    let lock = TicketLock::new();
+    lock.lock();
+    //a bounded poll gives up, queueing behind ourselves cannot succeed
+    assert!(lock.poll_n(1000).is_err());
+    lock.lock();
 }
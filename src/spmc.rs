@@ -0,0 +1,313 @@
+//!Bounded single producer multi consumer channel.
+//!
+//!A ring of slots, each with a sequence number that says whose turn it
+//!is. With one producer there is nothing to arbitrate on the sending side:
+//!the sender keeps its position in a plain Cell, writes the slot and
+//!publishes it by bumping the slot's sequence. Receivers claim slots by a
+//!compare and swap on a shared head cursor, then hand the slot back to the
+//!sender by advancing its sequence a lap ahead.
+//!
+//!The sender is Send but not Sync and cannot be cloned, which is what
+//!makes the unsynchronized send side sound.
+
+
+use super::Async;
+use super::cachepadded::CachePadded;
+use super::backoff::Backoff;
+use super::cancel::{Cancelled,CancellationToken};
+use std::cell::{Cell,UnsafeCell};
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+struct Slot<T> {
+    ///`pos + 1` once the item for `pos` is written, `pos + capacity` once
+    ///it has been taken
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>
+}
+
+struct Shared<T> {
+    head: CachePadded<AtomicUsize>,
+    ///sender's position, only published for `len`
+    tail: CachePadded<AtomicUsize>,
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    sender: AtomicBool,
+    receivers: AtomicUsize
+}
+unsafe impl<T: Send> Send for Shared<T> { }
+unsafe impl<T: Send> Sync for Shared<T> { }
+impl<T> Shared<T> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.tail.load(ACQUIRE).wrapping_sub(self.head.load(ACQUIRE)).min(self.slots.len())
+    }
+}
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        for pos in head..tail {
+            let slot = &mut self.slots[pos & self.mask];
+            unsafe{ slot.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+///Sending end, there is only ever one
+pub struct SpmcSender<T: Send+'static> {
+    shared: Arc<Shared<T>>,
+    tail: Cell<usize>
+}
+impl<T: Send+'static> SpmcSender<T> {
+    ///Send an item
+    ///
+    ///Returns Block with the item if the channel is full, Err with the
+    ///item if every receiver is gone
+    pub fn send(&self, data: T) -> Async<(),T,T> {
+        if self.shared.receivers.load(ACQUIRE) == 0 {
+            return Async::Err(data);
+        }
+        let pos = self.tail.get();
+        let slot = &self.shared.slots[pos & self.shared.mask];
+        if slot.seq.load(ACQUIRE) != pos {
+            return Async::Block(data);
+        }
+        unsafe{ (*slot.value.get()).write(data) };
+        slot.seq.store(pos.wrapping_add(1), RELEASE);
+        self.tail.set(pos.wrapping_add(1));
+        self.shared.tail.store(pos.wrapping_add(1), RELEASE);
+        Async::Ok(())
+    }
+    ///Send an item, waiting while the channel is full
+    ///
+    ///Returns Err with the item if every receiver is gone
+    pub fn send_blocking(&self, data: T) -> Result<(),T> {
+        let backoff = Backoff::new();
+        let mut data = data;
+        loop {
+            match self.send(data) {
+                Async::Ok(()) => return Ok(()),
+                Async::Block(x) => data = x,
+                Async::Err(x) => return Err(x)
+            };
+            backoff.snooze();
+        }
+    }
+    ///Send an item, waiting while the channel is full until `token` is
+    ///cancelled
+    ///
+    ///Returns Block with the item if the token was cancelled first, Err
+    ///with the item if every receiver is gone
+    pub fn send_cancellable(&self, data: T, token: &CancellationToken) -> Async<(),T,T> {
+        let backoff = Backoff::new();
+        let mut data = data;
+        loop {
+            match self.send(data) {
+                Async::Block(x) => data = x,
+                x => return x
+            };
+            if token.is_cancelled() {
+                return Async::Block(data);
+            }
+            backoff.snooze();
+        }
+    }
+    ///Items queued right now
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+    ///Returns true if nothing is queued right now
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    ///Items the channel holds when full
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+}
+impl<T: Send+'static> Drop for SpmcSender<T> {
+    fn drop(&mut self) {
+        self.shared.sender.store(false, RELEASE);
+    }
+}
+
+///Receiving end, clones compete for items
+pub struct SpmcReceiver<T: Send+'static> {
+    shared: Arc<Shared<T>>
+}
+impl<T: Send+'static> Clone for SpmcReceiver<T> {
+    fn clone(&self) -> SpmcReceiver<T> {
+        self.shared.receivers.fetch_add(1, RELAXED);
+        SpmcReceiver {
+            shared: self.shared.clone()
+        }
+    }
+}
+impl<T: Send+'static> SpmcReceiver<T> {
+    ///Take the oldest item
+    ///
+    ///Returns Ok(None) if the channel is empty, Block if another receiver
+    ///won the item it tried for, Err once the sender is gone and the
+    ///channel is drained
+    pub fn recv(&self) -> Async<Option<T>,(),()> {
+        let shared = &*self.shared;
+        let pos = shared.head.load(RELAXED);
+        let slot = &shared.slots[pos & shared.mask];
+        if slot.seq.load(ACQUIRE) != pos.wrapping_add(1) {
+            //check the sender before looking again, an item sent right
+            //before it dropped must still be seen
+            let alive = shared.sender.load(ACQUIRE);
+            if slot.seq.load(ACQUIRE) == pos.wrapping_add(1) || shared.head.load(RELAXED) != pos {
+                return Async::Block(());
+            }
+            return if alive {
+                Async::Ok(None)
+            } else {
+                Async::Err(())
+            };
+        }
+        if shared.head.compare_exchange(pos, pos.wrapping_add(1), ACQREL, RELAXED).is_err() {
+            return Async::Block(());
+        }
+        let data = unsafe{ (*slot.value.get()).assume_init_read() };
+        slot.seq.store(pos.wrapping_add(shared.slots.len()), RELEASE);
+        Async::Ok(Some(data))
+    }
+    ///Wait for the next item
+    ///
+    ///Returns None once the sender is gone and the channel is drained
+    pub fn recv_blocking(&self) -> Option<T> {
+        let backoff = Backoff::new();
+        loop {
+            match self.recv() {
+                Async::Ok(Option::Some(data)) => return Some(data),
+                Async::Err(()) => return None,
+                Async::Block(()) => backoff.spin(),
+                Async::Ok(Option::None) => backoff.snooze()
+            };
+        }
+    }
+    ///Wait for the next item until `token` is cancelled
+    ///
+    ///Returns Block if the token was cancelled first, Err once the sender
+    ///is gone and the channel is drained
+    pub fn recv_cancellable(&self, token: &CancellationToken) -> Async<T,Cancelled,()> {
+        let backoff = Backoff::new();
+        loop {
+            match self.recv() {
+                Async::Ok(Option::Some(data)) => return Async::Ok(data),
+                Async::Err(()) => return Async::Err(()),
+                Async::Block(()) => backoff.spin(),
+                Async::Ok(Option::None) => {
+                    if token.is_cancelled() {
+                        return Async::Block(Cancelled);
+                    }
+                    backoff.snooze();
+                }
+            };
+        }
+    }
+    ///Items queued right now
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+    ///Returns true if nothing is queued right now
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+impl<T: Send+'static> Drop for SpmcReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.receivers.fetch_sub(1, RELEASE);
+    }
+}
+
+///Build a channel holding at least `capacity` items, rounded up to a
+///power of two
+///
+///The smallest channel holds 2, with a single slot the sequence of a
+///written slot and of a freed one would be the same.
+pub fn channel<T: Send+'static>(capacity: usize) -> (SpmcSender<T>,SpmcReceiver<T>) {
+    let cap = capacity.max(2).next_power_of_two();
+    let slots = (0..cap).map(|i| Slot {
+        seq: AtomicUsize::new(i),
+        value: UnsafeCell::new(MaybeUninit::uninit())
+    }).collect::<Vec<_>>().into_boxed_slice();
+    let shared = Arc::new(Shared {
+        head: CachePadded::new(AtomicUsize::new(0)),
+        tail: CachePadded::new(AtomicUsize::new(0)),
+        slots,
+        mask: cap - 1,
+        sender: AtomicBool::new(true),
+        receivers: AtomicUsize::new(1)
+    });
+    (SpmcSender { shared: shared.clone(), tail: Cell::new(0) }, SpmcReceiver { shared })
+}
+
+#[test]
+fn test_spmc_bounds() {
+    let (tx, rx) = channel(3);
+    assert_eq!(tx.capacity(), 4);
+    for i in 0..4 {
+        assert!(tx.send(i) == Async::Ok(()));
+    }
+    assert!(tx.send(4) == Async::Block(4));
+    assert_eq!(rx.len(), 4);
+    assert!(rx.recv() == Async::Ok(Some(0)));
+    assert!(tx.send(4) == Async::Ok(()));
+    drop(tx);
+    for i in 1..5 {
+        assert!(rx.recv() == Async::Ok(Some(i)));
+    }
+    assert!(rx.recv().is_err());
+    //leftover items are dropped with the channel
+    let (tx, rx) = channel(2);
+    let item = Arc::new(());
+    assert!(tx.send(item.clone()).is_ok());
+    drop(rx);
+    assert!(tx.send(item.clone()).is_err());
+    drop(tx);
+    assert_eq!(Arc::strong_count(&item), 1);
+}
+
+#[test]
+fn test_spmc_cancellable() {
+    let (tx, rx) = channel(1);
+    let token = CancellationToken::new();
+    assert!(tx.send_cancellable(0, &token) == Async::Ok(()));
+    assert!(tx.send_cancellable(1, &token) == Async::Ok(()));
+    token.cancel();
+    assert!(tx.send_cancellable(2, &token) == Async::Block(2));
+    assert!(rx.recv_cancellable(&token) == Async::Ok(0));
+    assert!(rx.recv_cancellable(&token) == Async::Ok(1));
+    assert!(rx.recv_cancellable(&token) == Async::Block(Cancelled));
+}
+
+#[test]
+fn test_spmc_workers() {
+    use std::thread;
+    let (tx, rx) = channel(8);
+    let workers = (0..4).map(|_| {
+        let rx = rx.clone();
+        thread::spawn(move || {
+            let mut sum = 0;
+            while let Option::Some(x) = rx.recv_blocking() {
+                sum += x;
+            }
+            sum
+        })
+    }).collect::<Vec<_>>();
+    drop(rx);
+    for i in 0..1000usize {
+        tx.send_blocking(i).ok().unwrap();
+    }
+    drop(tx);
+    let total = workers.into_iter().map(|w| w.join().unwrap()).sum::<usize>();
+    assert_eq!(total, 499500);
+}
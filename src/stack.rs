@@ -0,0 +1,172 @@
+//!Treiber stack.
+//!
+//!A singly linked list whose head is swapped with compare and swap. Popped
+//!nodes may still be read by a thread that loaded them as the head, so they
+//!are freed through the epoch collector rather than straight away.
+
+
+use super::Async;
+use super::epoch;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const ACQREL: Ordering = Ordering::AcqRel;
+
+struct Node<T> {
+    ///moved out by the pop that unlinks the node, never dropped in place
+    value: ManuallyDrop<T>,
+    next: *mut Node<T>
+}
+
+///Lock free LIFO stack
+pub struct LockFreeStack<T> {
+    head: AtomicPtr<Node<T>>,
+    marker: PhantomData<T>
+}
+unsafe impl<T: Send> Send for LockFreeStack<T> { }
+unsafe impl<T: Send> Sync for LockFreeStack<T> { }
+impl<T> LockFreeStack<T> {
+    ///Build a new empty stack
+    #[inline(always)]
+    pub const fn new() -> LockFreeStack<T> {
+        LockFreeStack {
+            head: AtomicPtr::new(ptr::null_mut()),
+            marker: PhantomData
+        }
+    }
+    ///Push an item, retrying until it lands
+    pub fn push(&self, item: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(item),
+            next: ptr::null_mut()
+        }));
+        let mut head = self.head.load(RELAXED);
+        loop {
+            unsafe{ (*node).next = head };
+            match self.head.compare_exchange_weak(head, node, RELEASE, RELAXED) {
+                Ok(_) => return,
+                Err(x) => head = x
+            };
+        }
+    }
+    ///Make one attempt to push an item
+    ///
+    ///Returns Async::Ok(()) if the item was pushed
+    ///Returns Async::Block(T) with the item if another thread changed the
+    ///head first, retry
+    ///Never returns Async::Err
+    pub fn try_push(&self, item: T) -> Async<(),T,()> {
+        let head = self.head.load(RELAXED);
+        let node = Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(item),
+            next: head
+        }));
+        match self.head.compare_exchange(head, node, RELEASE, RELAXED) {
+            Ok(_) => Async::Ok(()),
+            Err(_) => {
+                let node = unsafe{ Box::from_raw(node) };
+                Async::Block(ManuallyDrop::into_inner(node.value))
+            }
+        }
+    }
+    ///Make one attempt to pop the most recently pushed item
+    ///
+    ///Returns Async::Ok(Option<T>) with the item, or None if the stack was
+    ///empty
+    ///Returns Async::Block(()) if another thread changed the head first,
+    ///retry
+    ///Never returns Async::Err
+    pub fn try_pop(&self) -> Async<Option<T>,(),()> {
+        let guard = epoch::pin();
+        let head = self.head.load(ACQUIRE);
+        if head.is_null() {
+            return Async::Ok(None);
+        }
+        //head stays allocated while we are pinned
+        let next = unsafe{ (*head).next };
+        if self.head.compare_exchange(head, next, ACQREL, RELAXED).is_err() {
+            return Async::Block(());
+        }
+        unsafe {
+            let value = ptr::read(&*(*head).value);
+            guard.defer_destroy(head);
+            Async::Ok(Some(value))
+        }
+    }
+    ///Pop the most recently pushed item, retrying lost races
+    pub fn pop(&self) -> Option<T> {
+        loop {
+            match self.try_pop() {
+                Async::Ok(x) => return x,
+                Async::Block(()) |
+                Async::Err(()) => continue
+            };
+        }
+    }
+    ///Returns true if the stack is empty
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.head.load(ACQUIRE).is_null()
+    }
+}
+impl<T> Default for LockFreeStack<T> {
+    fn default() -> LockFreeStack<T> {
+        LockFreeStack::new()
+    }
+}
+impl<T> Drop for LockFreeStack<T> {
+    fn drop(&mut self) {
+        let mut cursor = *self.head.get_mut();
+        while !cursor.is_null() {
+            let mut node = unsafe{ Box::from_raw(cursor) };
+            cursor = node.next;
+            unsafe{ ManuallyDrop::drop(&mut node.value) };
+        }
+    }
+}
+
+#[test]
+fn test_stack_lifo() {
+    let stack = LockFreeStack::new();
+    assert!(stack.try_pop().ok() == Some(&None));
+    for i in 0..10 {
+        stack.push(Box::new(i));
+    }
+    assert!(stack.try_push(Box::new(10)).is_ok());
+    assert!(stack.pop().map(|x| *x) == Some(10));
+    assert!(stack.pop().map(|x| *x) == Some(9));
+    assert!(!stack.is_empty());
+    //remaining boxes are dropped with the stack
+}
+
+#[test]
+fn test_stack_threads() {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    let stack = Arc::new(LockFreeStack::new());
+    let total = Arc::new(AtomicUsize::new(0));
+    let workers = (0..4).map(|t| {
+        let stack = stack.clone();
+        let total = total.clone();
+        thread::spawn(move || {
+            for i in 0..250 {
+                stack.push(t * 250 + i + 1);
+                if let Option::Some(x) = stack.pop() {
+                    total.fetch_add(x, RELAXED);
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    while let Option::Some(x) = stack.pop() {
+        total.fetch_add(x, RELAXED);
+    }
+    assert_eq!(total.load(RELAXED), 500500);
+}
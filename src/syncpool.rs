@@ -0,0 +1,238 @@
+//!Object pool shared between threads.
+//!
+//!Where `LocalPool` keeps one free list per thread, a SyncPool's free lists
+//!are shared: an object released on one thread can be picked up on any
+//!other, which suits buffers that travel from producers to consumers. The
+//!free lists are sharded, a thread pushes to and pops from the shard its
+//!id picks and only looks at the others when its own is empty.
+//!
+//!A reset hook runs on every object as it comes back, so whatever is
+//!handed out is clean. At most `max_size` idle objects are kept.
+
+
+use super::spinlock::SpinLock;
+use std::ops::{Deref,DerefMut};
+use std::sync::atomic::{AtomicUsize,Ordering};
+const RELAXED: Ordering = Ordering::Relaxed;
+
+///Idle objects a pool keeps unless its builder says otherwise
+pub const DEFAULT_MAX_SIZE: usize = 1024;
+///Shards a pool has unless its builder says otherwise
+pub const DEFAULT_SHARDS: usize = 8;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+thread_local!(static THREAD: usize = NEXT_THREAD.fetch_add(1, RELAXED));
+
+type Init<T> = Box<dyn Fn() -> T + Send + Sync>;
+type Reset<T> = Box<dyn Fn(&mut T) + Send + Sync>;
+
+///One free list, padded so neighbouring locks never share a cache line
+#[repr(align(128))]
+struct Shard<T>(SpinLock<Vec<T>>);
+
+///Configures a SyncPool
+pub struct SyncPoolBuilder<T> {
+    init: Init<T>,
+    reset: Option<Reset<T>>,
+    max_size: usize,
+    shards: usize
+}
+impl<T> SyncPoolBuilder<T> {
+    ///Keep at most `n` idle objects, more are dropped as they come back
+    pub fn max_size(mut self, n: usize) -> SyncPoolBuilder<T> {
+        self.max_size = n;
+        self
+    }
+    ///Spread the free lists over at least `n` shards, rounded up to a
+    ///power of two
+    pub fn shards(mut self, n: usize) -> SyncPoolBuilder<T> {
+        self.shards = n;
+        self
+    }
+    ///Run `f` on every object returned to the pool
+    pub fn reset<F>(mut self, f: F) -> SyncPoolBuilder<T>
+    where
+        F: Fn(&mut T) + Send + Sync + 'static,
+    {
+        self.reset = Some(Box::new(f));
+        self
+    }
+    ///Build the pool, it starts empty
+    pub fn build(self) -> SyncPool<T> {
+        let shards = self.shards.max(1).next_power_of_two();
+        SyncPool {
+            shards: (0..shards).map(|_| Shard(SpinLock::new(Vec::new()))).collect(),
+            mask: shards - 1,
+            idle: AtomicUsize::new(0),
+            init: self.init,
+            reset: self.reset,
+            max_size: self.max_size
+        }
+    }
+}
+
+///Pool of reusable objects any thread can take from and return to
+pub struct SyncPool<T> {
+    shards: Box<[Shard<T>]>,
+    mask: usize,
+    idle: AtomicUsize,
+    init: Init<T>,
+    reset: Option<Reset<T>>,
+    max_size: usize
+}
+impl<T> SyncPool<T> {
+    ///Build a pool with default settings that makes objects with `init`
+    pub fn new<F>(init: F) -> SyncPool<T>
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        SyncPool::builder(init).build()
+    }
+    ///Configure a pool that makes objects with `init`
+    pub fn builder<F>(init: F) -> SyncPoolBuilder<T>
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        SyncPoolBuilder {
+            init: Box::new(init),
+            reset: None,
+            max_size: DEFAULT_MAX_SIZE,
+            shards: DEFAULT_SHARDS
+        }
+    }
+    #[inline(always)]
+    fn home(&self) -> usize {
+        THREAD.try_with(|t| *t).unwrap_or(0) & self.mask
+    }
+    ///Take an idle object, looking at this thread's shard first, or make a
+    ///new one
+    pub fn acquire<'a>(&'a self) -> Pooled<'a,T> {
+        let home = self.home();
+        let found = (0..self.shards.len()).find_map(|i| {
+            let shard = &self.shards[(home + i) & self.mask].0;
+            //skip shards somebody else is busy with rather than queue
+            let mut list = if i == 0 { shard.lock() } else { shard.try_lock().ok()? };
+            list.pop()
+        });
+        let item = match found {
+            Option::Some(item) => {
+                self.idle.fetch_sub(1, RELAXED);
+                item
+            }
+            Option::None => (self.init)()
+        };
+        Pooled {
+            pool: self,
+            item: Some(item)
+        }
+    }
+    fn give_back(&self, mut item: T) {
+        if self.idle.fetch_add(1, RELAXED) >= self.max_size {
+            self.idle.fetch_sub(1, RELAXED);
+            return;
+        }
+        if let Option::Some(ref reset) = self.reset {
+            reset(&mut item);
+        }
+        self.shards[self.home()].0.lock().push(item);
+    }
+    ///Number of idle objects
+    pub fn idle(&self) -> usize {
+        self.idle.load(RELAXED)
+    }
+    ///Drop every idle object
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            let drained = ::std::mem::take(&mut *shard.0.lock());
+            self.idle.fetch_sub(drained.len(), RELAXED);
+        }
+    }
+}
+
+///Object taken from a SyncPool, goes back to the pool when dropped
+pub struct Pooled<'a,T: 'a> {
+    pool: &'a SyncPool<T>,
+    item: Option<T>
+}
+impl<'a,T> Pooled<'a,T> {
+    ///Keep the object instead of returning it to the pool
+    pub fn into_inner(mut self) -> T {
+        self.item.take().unwrap()
+    }
+}
+impl<'a,T> Deref for Pooled<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.item.as_ref().unwrap()
+    }
+}
+impl<'a,T> DerefMut for Pooled<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().unwrap()
+    }
+}
+impl<'a,T> Drop for Pooled<'a,T> {
+    fn drop(&mut self) {
+        if let Option::Some(item) = self.item.take() {
+            self.pool.give_back(item);
+        }
+    }
+}
+
+#[test]
+fn test_sync_pool_reuse() {
+    let pool = SyncPool::builder(|| Vec::<u8>::with_capacity(64))
+        .max_size(2)
+        .shards(1)
+        .reset(|v| v.clear())
+        .build();
+    let mut a = pool.acquire();
+    a.extend_from_slice(b"hello");
+    let ptr = a.as_ptr();
+    drop(a);
+    assert_eq!(pool.idle(), 1);
+    //the same buffer comes back, cleared
+    let b = pool.acquire();
+    assert_eq!(b.as_ptr(), ptr);
+    assert!(b.is_empty());
+    let c = pool.acquire();
+    let d = pool.acquire();
+    drop((b,c,d));
+    assert_eq!(pool.idle(), 2);
+    let kept = pool.acquire().into_inner();
+    assert_eq!(kept.capacity(), 64);
+    assert_eq!(pool.idle(), 1);
+    pool.clear();
+    assert_eq!(pool.idle(), 0);
+}
+
+#[test]
+fn test_sync_pool_threads() {
+    use std::sync::Arc;
+    use std::thread;
+    let made = Arc::new(AtomicUsize::new(0));
+    let pool = {
+        let made = made.clone();
+        Arc::new(SyncPool::new(move || {
+            made.fetch_add(1, RELAXED);
+            String::new()
+        }))
+    };
+    let workers = (0..4).map(|_| {
+        let pool = pool.clone();
+        thread::spawn(move || {
+            for i in 0..250 {
+                let mut s = pool.acquire();
+                s.clear();
+                s.push_str(&i.to_string());
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    //threads reuse each other's objects, a busy shard is skipped now and
+    //then so allow a few extra
+    assert!(made.load(RELAXED) <= 16);
+    assert_eq!(pool.idle(), made.load(RELAXED));
+}
@@ -0,0 +1,338 @@
+//!Structured concurrency on a thread pool or executor.
+//!
+//!`task_scope` hands its closure a `TaskScope` whose `spawn` runs tasks on
+//!a `ThreadPool` or `Executor`. The call does not return until every task
+//!spawned through the scope, including tasks spawned by those tasks, has
+//!finished, so tasks may borrow from outside the call like `scope` threads
+//!do and none of them is left running behind the caller's back.
+//!
+//!The first task to return an error or panic has that failure recorded
+//!and the scope's CancellationToken cancelled. Tasks still queued are then
+//!skipped, running tasks see the token and can stop early, and the
+//!recorded failure is what `task_scope` returns.
+//!
+//!`task_scope` blocks the calling thread. Calling it from a task running
+//!on the same pool or executor ties up that worker while it waits, with a
+//!single worker that deadlocks.
+
+
+use super::backoff::Backoff;
+use super::cancel::CancellationToken;
+use super::executor::Executor;
+use super::pool::ThreadPool;
+use super::spinlock::SpinLock;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self,AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::task::{Context,Poll};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+///Something that can run jobs on other threads
+pub trait Spawn {
+    ///Run `job` soon, on some other thread
+    fn spawn_job(&self, job: Box<dyn FnOnce() + Send + 'static>);
+}
+impl Spawn for ThreadPool {
+    fn spawn_job(&self, job: Job) {
+        self.execute(job);
+    }
+}
+impl Spawn for Executor {
+    fn spawn_job(&self, job: Job) {
+        drop(self.spawn(RunOnce(Some(job))));
+    }
+}
+
+///Future that runs a job on its first poll
+struct RunOnce(Option<Job>);
+impl Future for RunOnce {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+        if let Option::Some(job) = self.0.take() {
+            job();
+        }
+        Poll::Ready(())
+    }
+}
+
+///Why a task scope failed
+pub enum TaskError<E> {
+    ///A task returned this error
+    Failed(E),
+    ///A task panicked with this payload
+    Panicked(Box<dyn Any + Send + 'static>)
+}
+impl<E> TaskError<E> {
+    ///Returns true if a task panicked
+    pub fn is_panic(&self) -> bool {
+        matches!(*self, TaskError::Panicked(_))
+    }
+}
+impl<E: fmt::Debug> fmt::Debug for TaskError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TaskError::Failed(ref e) => f.debug_tuple("Failed").field(e).finish(),
+            TaskError::Panicked(_) => f.debug_tuple("Panicked").field(&"..").finish()
+        }
+    }
+}
+
+struct Inner<E> {
+    ///tasks spawned and not yet finished
+    pending: AtomicUsize,
+    failure: SpinLock<Option<TaskError<E>>>,
+    token: CancellationToken
+}
+impl<E> Inner<E> {
+    fn fail(&self, err: TaskError<E>) {
+        {
+            let mut failure = self.failure.lock();
+            if failure.is_none() {
+                *failure = Some(err);
+            }
+        }
+        self.token.cancel();
+    }
+}
+
+///A spawned task, fields drop in order so the borrows of `f` are gone
+///before `done` counts the task as finished
+struct Task<'env,F,E: Send+'env> {
+    f: F,
+    scope: TaskScope<'env,E>,
+    done: Done<E>
+}
+
+///Counts a task as finished when dropped
+struct Done<E>(Arc<Inner<E>>);
+impl<E> Drop for Done<E> {
+    fn drop(&mut self) {
+        self.0.pending.fetch_sub(1, RELEASE);
+    }
+}
+
+///Spawns tasks that finish before `task_scope` returns
+///
+///The scope is invariant in `'env`, like `std::thread::Scope`. Were it
+///covariant, a task could be spawned through a scope shortened to borrow
+///a local of the closure, which is gone before the task is waited for:
+///
+///```compile_fail
+///use lib_concurrent::pool::ThreadPool;
+///use lib_concurrent::taskscope::{task_scope,TaskScope};
+///fn shorten<'a,'b:'a>(s: &'a TaskScope<'b,()>) -> &'a TaskScope<'a,()> { s }
+///let pool = ThreadPool::new(1);
+///let _ = task_scope(&pool, |s| {
+///    let local = vec![1usize,2,3];
+///    shorten(s).spawn(|_| { assert_eq!(local.len(), 3); Ok(()) });
+///});
+///```
+pub struct TaskScope<'env,E: Send+'env> {
+    inner: Arc<Inner<E>>,
+    spawner: &'env (dyn Spawn + Sync),
+    env: PhantomData<&'env mut &'env ()>
+}
+impl<'env,E: Send+'env> TaskScope<'env,E> {
+    ///Run `f` as a task of this scope
+    ///
+    ///The task gets the scope again so it can spawn tasks of its own. It
+    ///is skipped if the scope was cancelled before it started.
+    pub fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce(&TaskScope<'env,E>) -> Result<(),E> + Send + 'env,
+    {
+        self.inner.pending.fetch_add(1, RELAXED);
+        let task = Task {
+            f,
+            scope: TaskScope {
+                inner: self.inner.clone(),
+                spawner: self.spawner,
+                env: PhantomData
+            },
+            done: Done(self.inner.clone())
+        };
+        let job: Box<dyn FnOnce() + Send + 'env> = Box::new(move || {
+            //bound one by one, so they drop in reverse: `f` and the scope
+            //before the count goes down
+            let done = task.done;
+            let scope = task.scope;
+            let f = task.f;
+            if scope.inner.token.is_cancelled() {
+                drop(f);
+            } else {
+                match panic::catch_unwind(AssertUnwindSafe(|| f(&scope))) {
+                    Ok(Ok(())) => { }
+                    Ok(Err(e)) => scope.inner.fail(TaskError::Failed(e)),
+                    Err(p) => scope.inner.fail(TaskError::Panicked(p))
+                };
+            }
+            drop(scope);
+            drop(done);
+        });
+        //task_scope waits for pending to reach zero before 'env ends, even
+        //when its closure panics. The count is released by the job itself,
+        //whether it runs or is dropped unrun, including by a spawn_job that
+        //panics.
+        let job = unsafe{ mem::transmute::<Box<dyn FnOnce() + Send + 'env>,Job>(job) };
+        self.spawner.spawn_job(job);
+    }
+    ///Token cancelled when a task fails, pass it to long waits
+    pub fn token(&self) -> &CancellationToken {
+        &self.inner.token
+    }
+    ///Returns true once a task failed or `cancel` was called
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.token.is_cancelled()
+    }
+    ///Cancel the scope without recording a failure, queued tasks are
+    ///skipped
+    pub fn cancel(&self) {
+        self.inner.token.cancel();
+    }
+    fn wait(&self) {
+        let backoff = Backoff::new();
+        while self.inner.pending.load(ACQUIRE) != 0 {
+            backoff.snooze();
+        }
+    }
+}
+
+///Run `f` with a scope spawning tasks on `spawner`
+///
+///Returns once `f` and every task spawned in the scope finished, with the
+///first task failure if there was one. A panic in `f` itself is raised
+///again after the tasks finish.
+pub fn task_scope<'env,S,F,R,E>(spawner: &'env S, f: F) -> Result<R,TaskError<E>>
+where
+    S: Spawn + Sync,
+    F: FnOnce(&TaskScope<'env,E>) -> R,
+    E: Send + 'env,
+{
+    let scope = TaskScope {
+        inner: Arc::new(Inner {
+            pending: AtomicUsize::new(0),
+            failure: SpinLock::new(None),
+            token: CancellationToken::new()
+        }),
+        spawner,
+        env: PhantomData
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    scope.wait();
+    let result = match result {
+        Ok(x) => x,
+        Err(e) => panic::resume_unwind(e)
+    };
+    let failure = scope.inner.failure.lock().take();
+    match failure {
+        Option::Some(e) => Err(e),
+        Option::None => Ok(result)
+    }
+}
+
+#[test]
+fn test_task_scope_borrows() {
+    let pool = ThreadPool::new(2);
+    let mut data = vec![1usize,2,3,4];
+    let total = AtomicUsize::new(0);
+    let out = task_scope::<_,_,_,()>(&pool, |s| {
+        for chunk in data.chunks_mut(2) {
+            let total = &total;
+            s.spawn(move |s| {
+                for x in chunk.iter_mut() {
+                    *x *= 10;
+                }
+                let sum = chunk.iter().sum::<usize>();
+                //nested tasks are waited for too
+                s.spawn(move |_| {
+                    total.fetch_add(sum, RELAXED);
+                    Ok(())
+                });
+                Ok(())
+            });
+        }
+        5
+    });
+    assert_eq!(out.unwrap(), 5);
+    assert_eq!(data, vec![10,20,30,40]);
+    assert_eq!(total.load(RELAXED), 100);
+}
+
+#[test]
+fn test_task_scope_failure() {
+    use std::time::Duration;
+    let exec = Executor::new(2);
+    let out = task_scope(&exec, |s| {
+        s.spawn(|s| {
+            //stops early once its sibling fails
+            s.token().wait_timeout(Duration::from_secs(5));
+            Ok(())
+        });
+        s.spawn(|_| Err("bad input"));
+    });
+    match out {
+        Err(TaskError::Failed(e)) => assert_eq!(e, "bad input"),
+        _ => panic!("expected the task's error")
+    };
+    let pool = ThreadPool::new(1);
+    let out = task_scope::<_,_,_,()>(&pool, |s| {
+        s.spawn(|_| panic!("task panicked"));
+    });
+    assert!(out.unwrap_err().is_panic());
+    assert_eq!(pool.panicked(), 0);
+}
+
+#[test]
+fn test_task_scope_drops_skipped() {
+    use std::thread;
+    use std::time::Duration;
+    //a capture borrowing from outside the scope, dropped slowly
+    struct Slow<'a>(&'a AtomicUsize);
+    impl<'a> Drop for Slow<'a> {
+        fn drop(&mut self) {
+            thread::sleep(Duration::from_millis(5));
+            self.0.fetch_add(1, RELAXED);
+        }
+    }
+    let pool = ThreadPool::new(2);
+    let dropped = AtomicUsize::new(0);
+    let out = task_scope::<_,_,_,()>(&pool, |s| {
+        s.cancel();
+        for _ in 0..4 {
+            let slow = Slow(&dropped);
+            s.spawn(move |_| {
+                let _ = &slow;
+                Ok(())
+            });
+        }
+    });
+    assert!(out.is_ok());
+    //the skipped tasks dropped their captures before the scope returned
+    assert_eq!(dropped.load(RELAXED), 4);
+    //a job dropped unrun still counts as finished
+    struct Discard;
+    impl Spawn for Discard {
+        fn spawn_job(&self, job: Job) {
+            drop(job);
+        }
+    }
+    let out = task_scope::<_,_,_,()>(&Discard, |s| {
+        let slow = Slow(&dropped);
+        s.spawn(move |_| {
+            let _ = &slow;
+            Ok(())
+        });
+    });
+    assert!(out.is_ok());
+    assert_eq!(dropped.load(RELAXED), 5);
+}
@@ -0,0 +1,512 @@
+//!Deterministic scheduler for testing code built on this crate.
+//!
+//!`run` executes a closure as the first of a set of virtual threads.
+//!Virtual threads are real threads, but only one of them runs at a time.
+//!The others wait at a switch point: every operation on the `Mutex` and
+//!channel in this module, `spawn`, `JoinHandle::join` and `yield_now`.
+//!At each switch point the next thread to run is drawn from a generator
+//!seeded by `run`'s seed, so one seed always gives the same interleaving
+//!and a race found with it can be replayed. `run_seeds` runs a test under
+//!a range of seeds and reports the one it failed with.
+//!
+//!A thread that can not make progress, like a receiver on an empty
+//!channel, is blocked until another thread does something that could
+//!unblock it. If every thread is blocked the run panics with a deadlock.
+//!
+//!This is not loom. Loom checks every interleaving of the crate's own
+//!atomics, this samples interleavings of whole operations in your code.
+//!Only switch points are interleaved, so code that shares state through
+//!anything else is not tested for races on it.
+
+
+use super::Async;
+use super::mrms::{self,MRMSReceiver,MRMSSender};
+use super::spinlock::{SpinGuard,SpinLock};
+use std::any::Any;
+use std::cell::RefCell;
+use std::ops::{Deref,DerefMut,Range};
+use std::panic::{self,AssertUnwindSafe};
+use std::sync::{Arc,Condvar};
+use std::sync::Mutex as StdMutex;
+use std::sync::MutexGuard as StdGuard;
+use std::thread;
+pub use std::sync::mpsc::{RecvError,SendError,TryRecvError};
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+enum Status {
+    Runnable,
+    ///waiting for another thread to make progress
+    Blocked,
+    Finished
+}
+
+///Unwinds the virtual threads of a run that failed
+struct Aborted;
+
+struct State {
+    status: Vec<Status>,
+    current: usize,
+    rng: u64,
+    schedule: Vec<usize>,
+    ///why the run failed, the first thread's panic or a deadlock
+    failure: Option<Box<dyn Any + Send>>,
+    threads: Vec<thread::JoinHandle<()>>
+}
+impl State {
+    ///splitmix64
+    fn random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    ///Hand the turn to a random runnable thread, false if there is none
+    fn pick(&mut self) -> bool {
+        let runnable = (0..self.status.len())
+            .filter(|&i| self.status[i] == Status::Runnable)
+            .collect::<Vec<_>>();
+        if runnable.is_empty() {
+            return false;
+        }
+        let next = runnable[(self.random() % runnable.len() as u64) as usize];
+        self.current = next;
+        self.schedule.push(next);
+        true
+    }
+    fn unblock(&mut self) {
+        for status in self.status.iter_mut() {
+            if *status == Status::Blocked {
+                *status = Status::Runnable;
+            }
+        }
+    }
+    fn fail(&mut self, reason: Box<dyn Any + Send>) {
+        if self.failure.is_none() {
+            self.failure = Some(reason);
+        }
+    }
+}
+
+struct Scheduler {
+    state: StdMutex<State>,
+    turn: Condvar
+}
+impl Scheduler {
+    fn lock(&self) -> StdGuard<'_,State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    ///Wait for thread `id`'s turn, unwinds if the run failed meanwhile
+    fn wait_turn(&self, mut state: StdGuard<'_,State>, id: usize) {
+        while state.current != id && state.failure.is_none() {
+            state = self.turn.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        if state.failure.is_some() {
+            drop(state);
+            panic::resume_unwind(Box::new(Aborted));
+        }
+    }
+    ///Give the turn away, leaving thread `id` with `status`
+    fn switch(&self, id: usize, status: Status) {
+        let mut state = self.lock();
+        state.status[id] = status;
+        if !state.pick() {
+            state.fail(Box::new(String::from("deadlock, every virtual thread is blocked")));
+        }
+        self.turn.notify_all();
+        self.wait_turn(state, id);
+    }
+    fn progress(&self) {
+        self.lock().unblock();
+    }
+    fn finish(&self, id: usize) {
+        let mut state = self.lock();
+        state.status[id] = Status::Finished;
+        //whoever joins it may go on
+        state.unblock();
+        if state.failure.is_none() {
+            state.pick();
+        }
+        self.turn.notify_all();
+    }
+}
+
+thread_local!(static CONTEXT: RefCell<Option<(Arc<Scheduler>,usize)>> = const { RefCell::new(None) });
+
+///Scheduler and id of the calling virtual thread
+fn context() -> Option<(Arc<Scheduler>,usize)> {
+    CONTEXT.with(|c| c.borrow().clone())
+}
+
+///Run `f` as virtual thread `id` once it gets its first turn
+fn enter<F: FnOnce()>(scheduler: Arc<Scheduler>, id: usize, f: F) {
+    CONTEXT.with(|c| *c.borrow_mut() = Some((scheduler.clone(), id)));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        scheduler.wait_turn(scheduler.lock(), id);
+        f()
+    }));
+    if let Err(e) = result {
+        if !e.is::<Aborted>() {
+            scheduler.lock().fail(e);
+        }
+    }
+    scheduler.finish(id);
+    CONTEXT.with(|c| *c.borrow_mut() = None);
+}
+
+///Run `f` as the first virtual thread, interleaving it with the threads it
+///spawns as `seed` dictates, until every virtual thread has finished
+///
+///Returns the schedule, the id of the thread that ran after each switch
+///point, threads are numbered in the order they were spawned starting
+///with `f` at 0. Panics if `f` panics or the threads deadlock, a panic in
+///a spawned thread is returned by its JoinHandle instead.
+pub fn run<F: FnOnce() + Send>(seed: u64, f: F) -> Vec<usize> {
+    let scheduler = Arc::new(Scheduler {
+        state: StdMutex::new(State {
+            status: vec![Status::Runnable],
+            current: 0,
+            rng: seed,
+            schedule: vec![0],
+            failure: None,
+            threads: Vec::new()
+        }),
+        turn: Condvar::new()
+    });
+    thread::scope(|s| {
+        let scheduler = scheduler.clone();
+        s.spawn(move || enter(scheduler, 0, f));
+    });
+    let mut state = scheduler.lock();
+    while state.failure.is_none() && state.status.iter().any(|&s| s != Status::Finished) {
+        state = scheduler.turn.wait(state).unwrap_or_else(|e| e.into_inner());
+    }
+    let threads = state.threads.drain(..).collect::<Vec<_>>();
+    drop(state);
+    for t in threads {
+        let _ = t.join();
+    }
+    let mut state = scheduler.lock();
+    match state.failure.take() {
+        Option::Some(e) => match e.downcast::<String>() {
+            Ok(msg) => panic!("{}", msg),
+            Err(e) => {
+                drop(state);
+                panic::resume_unwind(e)
+            }
+        },
+        Option::None => state.schedule.clone()
+    }
+}
+
+///Call `run` with every seed in `seeds`, panicking with the seed that
+///failed if one does
+pub fn run_seeds<F: Fn() + Sync>(seeds: Range<u64>, f: F) {
+    for seed in seeds {
+        if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| run(seed, &f))) {
+            let msg = e.downcast_ref::<String>().map(|s| s.as_str())
+                .or_else(|| e.downcast_ref::<&str>().cloned())
+                .unwrap_or("<non-string panic>");
+            panic!("failed with seed {}: {}", seed, msg);
+        }
+    }
+}
+
+///Start a new virtual thread running `f`
+///
+///Panics if called outside of `run`.
+pub fn spawn<F,T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (scheduler, me) = context().expect("testing::spawn called outside of testing::run");
+    let result = Arc::new(StdMutex::new(None));
+    let id = {
+        let mut state = scheduler.lock();
+        state.status.push(Status::Runnable);
+        state.status.len() - 1
+    };
+    let slot = result.clone();
+    let child = scheduler.clone();
+    let handle = thread::spawn(move || {
+        enter(child, id, move || {
+            let x = panic::catch_unwind(AssertUnwindSafe(f));
+            *slot.lock().unwrap_or_else(|e| e.into_inner()) = Some(x);
+        })
+    });
+    scheduler.lock().threads.push(handle);
+    scheduler.switch(me, Status::Runnable);
+    JoinHandle { result }
+}
+
+///Handle to a virtual thread
+pub struct JoinHandle<T> {
+    result: Arc<StdMutex<Option<thread::Result<T>>>>
+}
+impl<T> JoinHandle<T> {
+    ///Wait for the thread to finish
+    ///
+    ///Returns Err with the panic payload if the thread panicked
+    pub fn join(self) -> thread::Result<T> {
+        loop {
+            if let Option::Some(x) = self.result.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                return x;
+            }
+            block();
+        }
+    }
+}
+
+///Switch point, let the scheduler pick who runs next
+///
+///Outside of `run` this yields the thread.
+pub fn yield_now() {
+    match context() {
+        //unwinding threads just leave
+        Option::Some((scheduler, id)) if !thread::panicking() => scheduler.switch(id, Status::Runnable),
+        Option::Some(_) => { }
+        Option::None => thread::yield_now()
+    };
+}
+
+///Give the turn away until another thread makes progress
+fn block() {
+    match context() {
+        Option::Some((scheduler, id)) => scheduler.switch(id, Status::Blocked),
+        Option::None => thread::yield_now()
+    };
+}
+
+///Something changed that a blocked thread may be waiting for
+fn progress() {
+    if let Option::Some((scheduler, _)) = context() {
+        scheduler.progress();
+    }
+}
+
+///SpinLock whose acquisition and release are switch points
+pub struct Mutex<T> {
+    inner: SpinLock<T>
+}
+impl<T> Mutex<T> {
+    ///Build a new unlocked Mutex
+    pub fn new(data: T) -> Mutex<T> {
+        Mutex {
+            inner: SpinLock::new(data)
+        }
+    }
+    ///Wait for the lock, blocking the virtual thread while it is held
+    pub fn lock<'a>(&'a self) -> MutexGuard<'a,T> {
+        yield_now();
+        loop {
+            if let Ok(guard) = self.inner.try_lock() {
+                return MutexGuard { guard: Some(guard) };
+            }
+            block();
+        }
+    }
+    ///Take the lock if it is free
+    pub fn try_lock<'a>(&'a self) -> Option<MutexGuard<'a,T>> {
+        yield_now();
+        self.inner.try_lock().ok().map(|guard| MutexGuard { guard: Some(guard) })
+    }
+    ///Consume the Mutex, returning the data
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+///Releases the Mutex when dropped
+pub struct MutexGuard<'a,T: 'a> {
+    guard: Option<SpinGuard<'a,T>>
+}
+impl<'a,T: 'a> Deref for MutexGuard<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+impl<'a,T: 'a> DerefMut for MutexGuard<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+impl<'a,T: 'a> Drop for MutexGuard<'a,T> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        progress();
+        yield_now();
+    }
+}
+
+///Build an MRMS channel whose operations are switch points
+pub fn channel<T: 'static>() -> (Sender<T>,Receiver<T>) {
+    let (tx, rx) = mrms::channel(16);
+    (Sender { inner: tx }, Receiver { inner: rx })
+}
+
+///Sending half of a `channel`
+pub struct Sender<T: 'static> {
+    inner: MRMSSender<T>
+}
+impl<T: 'static> Sender<T> {
+    ///Returns Err(SendError(t)) if every receiver is gone
+    pub fn send(&self, mut t: T) -> Result<(),SendError<T>> {
+        yield_now();
+        loop {
+            match self.inner.send(t) {
+                Async::Ok(()) => {
+                    progress();
+                    return Ok(());
+                }
+                Async::Err(x) => return Err(SendError(x)),
+                Async::Block(x) => t = x
+            };
+            block();
+        }
+    }
+}
+impl<T: 'static> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        Sender {
+            inner: self.inner.clone()
+        }
+    }
+}
+impl<T: 'static> Drop for Sender<T> {
+    fn drop(&mut self) {
+        //a blocked receiver may now see the channel closed
+        progress();
+    }
+}
+
+///Receiving half of a `channel`
+pub struct Receiver<T: 'static> {
+    inner: MRMSReceiver<T>
+}
+impl<T: 'static> Receiver<T> {
+    ///Wait for a message, blocking the virtual thread while the channel is
+    ///empty
+    ///
+    ///Returns Err(RecvError) once every sender is gone and the queue is
+    ///empty
+    pub fn recv(&self) -> Result<T,RecvError> {
+        yield_now();
+        loop {
+            match self.inner.recv() {
+                Async::Ok(Option::Some(x)) => {
+                    progress();
+                    return Ok(x);
+                }
+                Async::Err(()) => return Err(RecvError),
+                Async::Ok(Option::None) |
+                Async::Block(()) => block()
+            };
+        }
+    }
+    ///Take a message if one is queued
+    pub fn try_recv(&self) -> Result<T,TryRecvError> {
+        yield_now();
+        match self.inner.recv() {
+            Async::Ok(Option::Some(x)) => {
+                progress();
+                Ok(x)
+            }
+            Async::Err(()) => Err(TryRecvError::Disconnected),
+            Async::Ok(Option::None) |
+            Async::Block(()) => Err(TryRecvError::Empty)
+        }
+    }
+}
+impl<T: 'static> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        progress();
+    }
+}
+
+#[test]
+fn test_sim_replays_seed() {
+    fn scenario() -> Vec<usize> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handles = (0..3).map(|i| {
+            let log = log.clone();
+            spawn(move || {
+                for _ in 0..2 {
+                    log.lock().push(i);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let log = log.lock().clone();
+        log
+    }
+    let observe = |seed| {
+        let mut order = Vec::new();
+        let schedule = run(seed, || order = scenario());
+        (schedule, order)
+    };
+    //the same seed gives the same interleaving
+    assert!(observe(7) == observe(7));
+    let orders = (0..20).map(|seed| observe(seed).1).collect::<Vec<_>>();
+    assert!(orders.iter().any(|o| *o != orders[0]));
+}
+
+#[test]
+fn test_sim_finds_lost_update() {
+    fn scenario() -> usize {
+        let count = Arc::new(Mutex::new(0usize));
+        let handles = (0..2).map(|_| {
+            let count = count.clone();
+            spawn(move || {
+                //read and write under separate locks, an update can be lost
+                let x = *count.lock();
+                *count.lock() = x + 1;
+            })
+        }).collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let x = *count.lock();
+        x
+    }
+    let mut result = 0;
+    let seed = (0..100).find(|&seed| {
+        run(seed, || result = scenario());
+        result == 1
+    }).expect("no seed lost an update");
+    result = 0;
+    run(seed, || result = scenario());
+    assert_eq!(result, 1);
+}
+
+#[test]
+fn test_sim_channel() {
+    run_seeds(0..10, || {
+        let (tx, rx) = channel();
+        let producer = spawn(move || {
+            for i in 0..5 {
+                tx.send(i).unwrap();
+            }
+        });
+        let mut got = Vec::new();
+        while let Ok(x) = rx.recv() {
+            got.push(x);
+        }
+        assert_eq!(got, (0..5).collect::<Vec<_>>());
+        producer.join().unwrap();
+    });
+}
+
+#[test]
+#[should_panic(expected="failed with seed 0: deadlock")]
+fn test_sim_deadlock() {
+    run_seeds(0..1, || {
+        let (tx, rx) = channel::<usize>();
+        //nobody will ever send, and tx keeps the channel open
+        let _ = rx.recv();
+        drop(tx);
+    });
+}
@@ -1,24 +1,656 @@
 
 
-use std::thread::LocalKey;
-use std::cell::RefCell;
+use std::thread::{AccessError,LocalKey};
+use std::cell::{Cell,RefCell,UnsafeCell};
+use super::floater::Floater;
+use super::spinlock::SpinLock;
+use std::mem;
+use std::ops::{Add,Deref,DerefMut};
+use std::panic::Location;
+use std::sync::{Arc,Weak};
+use std::sync::atomic::{AtomicUsize,Ordering};
 
 
+///A cell a thread local can hold for `with_mut` to reach into
+///
+///# Safety
+///
+///`as_mut_ptr` must return a valid pointer to the cell's contents.
+pub unsafe trait CellLike {
+    type Target;
+    fn as_mut_ptr(&self) -> *mut Self::Target;
+}
+unsafe impl<T> CellLike for RefCell<T> {
+    type Target = T;
+    fn as_mut_ptr(&self) -> *mut T {
+        self.as_ptr()
+    }
+}
+unsafe impl<T> CellLike for Cell<T> {
+    type Target = T;
+    fn as_mut_ptr(&self) -> *mut T {
+        self.as_ptr()
+    }
+}
+unsafe impl<T> CellLike for UnsafeCell<T> {
+    type Target = T;
+    fn as_mut_ptr(&self) -> *mut T {
+        self.get()
+    }
+}
+
+//keys this thread is inside `with_mut` for, and where that access began
+#[cfg(feature="debug-reentrancy")]
+thread_local!(static ENTERED: RefCell<Vec<(usize,&'static Location<'static>)>> = const { RefCell::new(Vec::new()) });
+
+///Marks a key as being inside `with_mut` on this thread until dropped.
+///Without the `debug-reentrancy` feature this is zero sized and does
+///nothing.
+#[cfg(feature="debug-reentrancy")]
+struct Entered(usize);
+#[cfg(feature="debug-reentrancy")]
+impl Entered {
+    #[inline(always)]
+    fn enter<C>(key: &'static LocalKey<C>, at: &'static Location<'static>) -> Entered {
+        let id = key as *const LocalKey<C> as usize;
+        //once ENTERED is torn down there is nothing left to check against
+        let _ = ENTERED.try_with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(&(_,outer)) = entered.iter().find(|&&(x,_)| x == id) {
+                drop(entered);
+                panic!("with_mut on a thread local at {} re-enters the access taken at {}", at, outer);
+            }
+            entered.push((id,at));
+        });
+        Entered(id)
+    }
+}
+#[cfg(feature="debug-reentrancy")]
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let _ = ENTERED.try_with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|&(x,_)| x == self.0) {
+                entered.remove(i);
+            }
+        });
+    }
+}
+#[cfg(not(feature="debug-reentrancy"))]
+struct Entered;
+#[cfg(not(feature="debug-reentrancy"))]
+impl Entered {
+    #[inline(always)]
+    fn enter<C>(_key: &'static LocalKey<C>, _at: &'static Location<'static>) -> Entered {
+        Entered
+    }
+}
+
 ///Method of mutating ThreadLocalKeys. It expects the local key to hold a
-///RefCell. Ttherefore it handles getting a mutable pointer to the thread
-///key.
+///RefCell, Cell or UnsafeCell. Ttherefore it handles getting a mutable
+///pointer to the thread key.
 ///
 ///This will not trigger a borrow of the RefCell. So if you are doing
-///co-routines you can have multiple mutable pointers at once.
+///co-routines you can have multiple mutable pointers at once. With the
+///`debug-reentrancy` feature a nested call on the same key panics instead,
+///naming where both accesses came from.
 ///
-pub fn with_mut<T,F,R>(key: &'static LocalKey<RefCell<T>>, lambda: F) -> R
+#[track_caller]
+pub fn with_mut<C,F,R>(key: &'static LocalKey<C>, lambda: F) -> R
 where
-    T: 'static,
+    C: CellLike + 'static,
     R: 'static,
-    F: FnOnce(&mut T) -> R,
+    F: FnOnce(&mut C::Target) -> R,
 {
+    let _entered = Entered::enter(key, Location::caller());
     key.with(|cell| {
-        let mut ptr: &mut T = unsafe{cell.as_ptr().as_mut().unwrap()};
+        let ptr: &mut C::Target = unsafe{cell.as_mut_ptr().as_mut().unwrap()};
+        lambda(ptr)
+    })
+}
+
+///Like `with_mut`, but returns Err(AccessError) instead of panicking if
+///the key was already destroyed, which happens when it is used from a
+///destructor while the thread exits
+#[track_caller]
+pub fn try_with_mut<C,F,R>(key: &'static LocalKey<C>, lambda: F) -> Result<R,AccessError>
+where
+    C: CellLike + 'static,
+    R: 'static,
+    F: FnOnce(&mut C::Target) -> R,
+{
+    let _entered = Entered::enter(key, Location::caller());
+    key.try_with(|cell| {
+        let ptr: &mut C::Target = unsafe{cell.as_mut_ptr().as_mut().unwrap()};
         lambda(ptr)
     })
 }
+
+///Like `with_mut`, but for keys holding an `Option<T>`. On the first access
+///from a thread, or after the value was taken, `init` builds the value
+///before `lambda` runs.
+///
+///Like `with_mut` this does not borrow the RefCell.
+#[track_caller]
+pub fn with_mut_or_init<C,T,I,F,R>(key: &'static LocalKey<C>, init: I, lambda: F) -> R
+where
+    C: CellLike<Target=Option<T>> + 'static,
+    R: 'static,
+    I: FnOnce() -> T,
+    F: FnOnce(&mut T) -> R,
+{
+    with_mut(key, |slot| lambda(slot.get_or_insert_with(init)))
+}
+
+///Move the value out of a thread local, leaving `T::default()` behind
+pub fn take<C>(key: &'static LocalKey<C>) -> C::Target
+where
+    C: CellLike + 'static,
+    C::Target: Default + 'static,
+{
+    with_mut(key, mem::take)
+}
+
+///Put `value` in a thread local, returning what was there
+pub fn replace<C>(key: &'static LocalKey<C>, value: C::Target) -> C::Target
+where
+    C: CellLike + 'static,
+    C::Target: 'static,
+{
+    with_mut(key, |slot| mem::replace(slot, value))
+}
+
+///Put `value` in a thread local, dropping what was there
+pub fn set<C>(key: &'static LocalKey<C>, value: C::Target)
+where
+    C: CellLike + 'static,
+    C::Target: 'static,
+{
+    drop(replace(key, value));
+}
+
+///Swap `temp` into a thread local while `lambda` runs, then put the
+///previous value back. The previous value is restored even if `lambda`
+///panics.
+pub fn with_scoped<C,F,R>(key: &'static LocalKey<C>, temp: C::Target, lambda: F) -> R
+where
+    C: CellLike + 'static,
+    C::Target: 'static,
+    F: FnOnce() -> R,
+{
+    struct Restore<C: CellLike + 'static> where C::Target: 'static {
+        key: &'static LocalKey<C>,
+        old: Option<C::Target>
+    }
+    impl<C: CellLike + 'static> Drop for Restore<C> where C::Target: 'static {
+        fn drop(&mut self) {
+            if let Some(old) = self.old.take() {
+                set(self.key, old);
+            }
+        }
+    }
+    let _restore = Restore {
+        key,
+        old: Some(replace(key, temp))
+    };
+    lambda()
+}
+
+///Per thread free list of reusable objects
+///
+///The free list lives in a thread local the caller declares, so every
+///thread reuses its own objects without synchronizing. `make` builds an
+///object when the list is empty and at most `cap` objects are kept per
+///thread, the rest are dropped.
+pub struct LocalPool<T: 'static> {
+    key: &'static LocalKey<RefCell<Vec<T>>>,
+    cap: usize,
+    make: fn() -> T
+}
+impl<T: 'static> LocalPool<T> {
+    ///Build a pool over `key`
+    pub const fn new(key: &'static LocalKey<RefCell<Vec<T>>>, cap: usize, make: fn() -> T) -> LocalPool<T> {
+        LocalPool { key, cap, make }
+    }
+    ///Take an object from this thread's free list, or build a new one
+    pub fn acquire<'a>(&'a self) -> Pooled<'a,T> {
+        let item = self.key.try_with(|list| list.borrow_mut().pop())
+            .ok()
+            .and_then(|x| x)
+            .unwrap_or_else(self.make);
+        Pooled {
+            pool: self,
+            item: Some(item)
+        }
+    }
+    ///Number of objects in this thread's free list
+    pub fn cached(&self) -> usize {
+        self.key.try_with(|list| list.borrow().len()).unwrap_or(0)
+    }
+    fn give_back(&self, item: T) {
+        //during thread teardown the list may already be gone, then the
+        //item is simply dropped
+        let _ = self.key.try_with(|list| {
+            let mut list = list.borrow_mut();
+            if list.len() < self.cap {
+                list.push(item);
+            }
+        });
+    }
+}
+
+///Object borrowed from a LocalPool, goes back to the free list of the
+///thread that drops it
+pub struct Pooled<'a,T: 'static> {
+    pool: &'a LocalPool<T>,
+    item: Option<T>
+}
+impl<'a,T: 'static> Pooled<'a,T> {
+    ///Keep the object instead of returning it to the pool
+    pub fn into_inner(mut self) -> T {
+        self.item.take().unwrap()
+    }
+}
+impl<'a,T: 'static> Deref for Pooled<'a,T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.item.as_ref().unwrap()
+    }
+}
+impl<'a,T: 'static> DerefMut for Pooled<'a,T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().unwrap()
+    }
+}
+impl<'a,T: 'static> Drop for Pooled<'a,T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.pool.give_back(item);
+        }
+    }
+}
+
+///This thread's shard of a ShardedAccumulator or RegisteredLocal, declare
+///one per accumulator or registry with `thread_local!`
+pub struct LocalShard<T: 'static> {
+    shard: Option<Arc<SpinLock<T>>>
+}
+impl<T: 'static> LocalShard<T> {
+    ///An unregistered shard, registered on the first `add`
+    pub const fn new() -> LocalShard<T> {
+        LocalShard { shard: None }
+    }
+}
+impl<T: 'static> Default for LocalShard<T> {
+    fn default() -> LocalShard<T> {
+        LocalShard::new()
+    }
+}
+
+///Sum spread over per thread shards
+///
+///`add` only touches the calling thread's shard, whose lock nobody else
+///takes outside of `sum` and `collect`, so hot counters don't bounce a
+///shared cache line between cores. Shards of threads that exited are kept
+///so their contributions still count.
+pub struct ShardedAccumulator<T: 'static> {
+    key: &'static LocalKey<RefCell<LocalShard<T>>>,
+    zero: T,
+    shards: SpinLock<Vec<Arc<SpinLock<T>>>>
+}
+impl<T> ShardedAccumulator<T>
+where
+    T: Add<Output=T> + Copy + Send + 'static,
+{
+    ///Build an accumulator keeping its shards in `key`, every shard
+    ///starts out at `zero`
+    pub const fn new(key: &'static LocalKey<RefCell<LocalShard<T>>>, zero: T) -> ShardedAccumulator<T> {
+        ShardedAccumulator {
+            key,
+            zero,
+            shards: SpinLock::new(Vec::new())
+        }
+    }
+    ///Add `value` to this thread's shard
+    #[inline(always)]
+    pub fn add(&self, value: T) {
+        with_mut(self.key, |local| {
+            let shard = local.shard.get_or_insert_with(|| {
+                let shard = Arc::new(SpinLock::new(self.zero));
+                self.shards.lock().push(shard.clone());
+                shard
+            });
+            let mut shard = shard.lock();
+            *shard = *shard + value;
+        })
+    }
+    ///Value of every shard
+    pub fn collect(&self) -> Vec<T> {
+        self.shards.lock().iter().map(|shard| *shard.lock()).collect()
+    }
+    ///Total across all shards
+    pub fn sum(&self) -> T {
+        self.shards.lock().iter().fold(self.zero, |sum,shard| sum + *shard.lock())
+    }
+}
+
+///Thread local value that other threads can visit
+///
+///Each thread's value is built with `init` and registered on first use.
+///A coordinator can then walk every live thread's value with
+///`for_each_thread` or `snapshot`. Values are dropped with their thread
+///and disappear from the registry.
+pub struct RegisteredLocal<T: Send+'static> {
+    key: &'static LocalKey<RefCell<LocalShard<T>>>,
+    init: fn() -> T,
+    threads: SpinLock<Vec<Weak<SpinLock<T>>>>
+}
+impl<T: Send+'static> RegisteredLocal<T> {
+    ///Build a registry keeping each thread's value in `key`
+    pub const fn new(key: &'static LocalKey<RefCell<LocalShard<T>>>, init: fn() -> T) -> RegisteredLocal<T> {
+        RegisteredLocal {
+            key,
+            init,
+            threads: SpinLock::new(Vec::new())
+        }
+    }
+    ///Run `lambda` on this thread's value, registering it first if needed
+    ///
+    ///The value is locked while `lambda` runs, so a visiting coordinator
+    ///never sees it half updated.
+    pub fn with<F,R>(&self, lambda: F) -> R
+    where
+        R: 'static,
+        F: FnOnce(&mut T) -> R,
+    {
+        with_mut(self.key, |local| {
+            let shard = local.shard.get_or_insert_with(|| {
+                let shard = Arc::new(SpinLock::new((self.init)()));
+                self.threads.lock().push(Arc::downgrade(&shard));
+                shard
+            });
+            let mut value = shard.lock();
+            lambda(&mut value)
+        })
+    }
+    ///Visit the value of every live thread that has used the registry
+    pub fn for_each_thread<F>(&self, mut lambda: F)
+    where
+        F: FnMut(&T),
+    {
+        let mut threads = self.threads.lock();
+        threads.retain(|weak| match weak.upgrade() {
+            Option::Some(shard) => {
+                lambda(&shard.lock());
+                true
+            }
+            Option::None => false
+        });
+    }
+    ///Copy of every live thread's value
+    pub fn snapshot(&self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        let mut out = Vec::new();
+        self.for_each_thread(|value| out.push(value.clone()));
+        out
+    }
+}
+
+///This thread's copy of a CachedLocal's value, declare one per
+///CachedLocal with `thread_local!`
+pub struct LocalCopy<T: 'static> {
+    owner: usize,
+    version: usize,
+    value: Option<T>
+}
+impl<T: 'static> LocalCopy<T> {
+    ///An empty copy, filled on the first read
+    pub const fn new() -> LocalCopy<T> {
+        LocalCopy {
+            owner: 0,
+            version: 0,
+            value: None
+        }
+    }
+}
+impl<T: 'static> Default for LocalCopy<T> {
+    fn default() -> LocalCopy<T> {
+        LocalCopy::new()
+    }
+}
+
+//tells apart CachedLocals that are built one after another on the same key
+static NEXT_CACHE: AtomicUsize = AtomicUsize::new(1);
+
+///Shared value that every thread reads through its own cached copy
+///
+///Reads only check a version counter and use the thread local copy, which
+///suits read-mostly data such as configuration. `publish` replaces the
+///shared value and bumps the version, each thread refreshes its copy the
+///next time it reads.
+pub struct CachedLocal<T: Clone+Send+Sync+'static> {
+    key: &'static LocalKey<RefCell<LocalCopy<T>>>,
+    id: usize,
+    version: AtomicUsize,
+    shared: Floater<T>
+}
+impl<T: Clone+Send+Sync+'static> CachedLocal<T> {
+    ///Build a CachedLocal keeping its per thread copies in `key`
+    pub fn new(key: &'static LocalKey<RefCell<LocalCopy<T>>>, value: T) -> CachedLocal<T> {
+        CachedLocal {
+            key,
+            id: NEXT_CACHE.fetch_add(1, Ordering::Relaxed),
+            version: AtomicUsize::new(0),
+            shared: Floater::new(value)
+        }
+    }
+    ///Run `lambda` on this thread's copy, refreshing it first if a newer
+    ///value was published
+    pub fn with<F,R>(&self, lambda: F) -> R
+    where
+        R: 'static,
+        F: FnOnce(&T) -> R,
+    {
+        let version = self.version.load(Ordering::Acquire);
+        with_mut(self.key, |local| {
+            if local.owner != self.id || local.version != version || local.value.is_none() {
+                local.value = Some(self.shared.borrow().clone());
+                local.owner = self.id;
+                local.version = version;
+            }
+            lambda(local.value.as_ref().unwrap())
+        })
+    }
+    ///Clone of this thread's copy
+    pub fn get(&self) -> T {
+        self.with(T::clone)
+    }
+    ///Replace the shared value, returning the old one. Threads pick up the
+    ///new value on their next read.
+    pub fn publish(&self, value: T) -> T {
+        let old = self.shared.replace(value);
+        self.version.fetch_add(1, Ordering::Release);
+        old
+    }
+    ///Number of times a value has been published
+    pub fn version(&self) -> usize {
+        self.version.load(Ordering::Acquire)
+    }
+}
+
+#[test]
+fn test_with_mut_or_init() {
+    thread_local!(static COUNTER: RefCell<Option<Vec<usize>>> = const { RefCell::new(None) });
+    let mut inits = 0;
+    for i in 0..3 {
+        with_mut_or_init(&COUNTER, || { inits += 1; Vec::new() }, |v| v.push(i));
+    }
+    assert_eq!(inits, 1);
+    assert_eq!(with_mut(&COUNTER, |v| v.take()), Some(vec![0,1,2]));
+    //taken out, so the next access builds it again
+    with_mut_or_init(&COUNTER, || vec![9], |v| v.push(3));
+    assert_eq!(with_mut(&COUNTER, |v| v.clone()), Some(vec![9,3]));
+}
+
+#[test]
+fn test_take_replace_set() {
+    thread_local!(static SLOT: RefCell<String> = const { RefCell::new(String::new()) });
+    set(&SLOT, "a".to_string());
+    assert_eq!(replace(&SLOT, "b".to_string()), "a");
+    assert_eq!(take(&SLOT), "b");
+    assert_eq!(take(&SLOT), "");
+}
+
+#[test]
+fn test_local_pool() {
+    thread_local!(static BUFFERS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) });
+    static POOL: LocalPool<Vec<u8>> = LocalPool::new(&BUFFERS, 1, Vec::new);
+    {
+        let mut a = POOL.acquire();
+        a.push(1);
+        let b = POOL.acquire();
+        drop(a);
+        drop(b);
+    }
+    //only one fits under the cap
+    assert_eq!(POOL.cached(), 1);
+    let a = POOL.acquire();
+    assert_eq!(*a, vec![1]);
+    assert_eq!(POOL.cached(), 0);
+    assert_eq!(a.into_inner(), vec![1]);
+    assert_eq!(POOL.cached(), 0);
+    //other threads have their own list
+    ::std::thread::spawn(|| assert_eq!(POOL.cached(), 0)).join().unwrap();
+}
+
+#[test]
+fn test_with_scoped() {
+    use std::panic;
+    thread_local!(static CONTEXT: RefCell<&'static str> = const { RefCell::new("runtime") });
+    let seen = with_scoped(&CONTEXT, "test", || with_mut(&CONTEXT, |c| *c));
+    assert_eq!(seen, "test");
+    assert_eq!(with_mut(&CONTEXT, |c| *c), "runtime");
+    let result = panic::catch_unwind(|| with_scoped(&CONTEXT, "test", || panic!("boom")));
+    assert!(result.is_err());
+    assert_eq!(with_mut(&CONTEXT, |c| *c), "runtime");
+}
+
+#[test]
+fn test_sharded_accumulator() {
+    use std::thread;
+    thread_local!(static SHARD: RefCell<LocalShard<u64>> = const { RefCell::new(LocalShard::new()) });
+    static EVENTS: ShardedAccumulator<u64> = ShardedAccumulator::new(&SHARD, 0);
+    let workers = (0..4).map(|_| {
+        thread::spawn(|| {
+            for _ in 0..100 {
+                EVENTS.add(1);
+            }
+        })
+    }).collect::<Vec<_>>();
+    for w in workers {
+        w.join().unwrap();
+    }
+    EVENTS.add(5);
+    assert_eq!(EVENTS.sum(), 405);
+    let mut shards = EVENTS.collect();
+    shards.sort();
+    assert_eq!(shards, vec![5,100,100,100,100]);
+}
+
+#[test]
+fn test_try_with_mut_teardown() {
+    use std::sync::mpsc;
+    use std::thread;
+    //a value reaching its own key from its destructor always finds the key
+    //destroyed
+    struct Probe(Option<mpsc::Sender<bool>>);
+    impl Drop for Probe {
+        fn drop(&mut self) {
+            if let Some(tx) = self.0.take() {
+                let _ = tx.send(try_with_mut(&PROBE, |_| ()).is_err());
+            }
+        }
+    }
+    thread_local!(static PROBE: RefCell<Probe> = const { RefCell::new(Probe(None)) });
+    let (tx,rx) = mpsc::channel();
+    thread::spawn(move || {
+        assert!(try_with_mut(&PROBE, |p| p.0 = Some(tx)).is_ok());
+    }).join().unwrap();
+    assert!(rx.recv().unwrap());
+}
+
+#[test]
+fn test_cell_like_keys() {
+    thread_local!(static COUNT: Cell<usize> = const { Cell::new(0) });
+    thread_local!(static RAW: UnsafeCell<Vec<usize>> = const { UnsafeCell::new(Vec::new()) });
+    with_mut(&COUNT, |c| *c += 2);
+    assert_eq!(COUNT.with(|c| c.get()), 2);
+    assert_eq!(replace(&COUNT, 5), 2);
+    with_mut(&RAW, |v| v.push(1));
+    assert_eq!(take(&RAW), vec![1]);
+}
+
+#[test]
+fn test_cached_local() {
+    use std::thread;
+    thread_local!(static CONFIG: RefCell<LocalCopy<String>> = const { RefCell::new(LocalCopy::new()) });
+    let config = Arc::new(CachedLocal::new(&CONFIG, "v1".to_string()));
+    assert_eq!(config.get(), "v1");
+    assert_eq!(config.publish("v2".to_string()), "v1");
+    assert_eq!(config.with(|c| c.len()), 2);
+    assert_eq!(config.get(), "v2");
+    let remote = config.clone();
+    assert_eq!(thread::spawn(move || remote.get()).join().unwrap(), "v2");
+    assert_eq!(config.version(), 1);
+    //a new cache on the same key does not see the old copy
+    drop(config);
+    let other = CachedLocal::new(&CONFIG, "other".to_string());
+    assert_eq!(other.get(), "other");
+}
+
+#[cfg(feature="debug-reentrancy")]
+#[test]
+fn test_reentrancy_detected() {
+    use std::panic;
+    thread_local!(static VALUE: RefCell<usize> = const { RefCell::new(0) });
+    let result = panic::catch_unwind(|| {
+        with_mut(&VALUE, |outer| {
+            with_mut(&VALUE, |inner| *inner += 1);
+            *outer += 1;
+        })
+    });
+    assert!(result.is_err());
+    //the outer access was unwound, so the key can be used again
+    with_mut(&VALUE, |v| *v += 1);
+    assert_eq!(with_mut(&VALUE, |v| *v), 1);
+}
+
+#[test]
+fn test_registered_local() {
+    use super::barrier::SpinBarrier;
+    use std::thread;
+    thread_local!(static DEPTH: RefCell<LocalShard<usize>> = const { RefCell::new(LocalShard::new()) });
+    static DEPTHS: RegisteredLocal<usize> = RegisteredLocal::new(&DEPTH, || 0);
+    static READY: SpinBarrier = SpinBarrier::new(3);
+    static DONE: SpinBarrier = SpinBarrier::new(3);
+    let workers = (1..3).map(|i| {
+        thread::spawn(move || {
+            DEPTHS.with(|d| *d = i);
+            READY.wait();
+            DONE.wait();
+        })
+    }).collect::<Vec<_>>();
+    READY.wait();
+    let mut depths = DEPTHS.snapshot();
+    depths.sort();
+    assert_eq!(depths, vec![1,2]);
+    DONE.wait();
+    for w in workers {
+        w.join().unwrap();
+    }
+    //exited threads drop out
+    DEPTHS.with(|d| *d = 7);
+    assert_eq!(DEPTHS.snapshot(), vec![7]);
+}
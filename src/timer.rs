@@ -0,0 +1,329 @@
+//!Hashed timer wheel delivering into MRMS channels.
+//!
+//!Time is cut into ticks and timers hash into one of `SLOTS` slots by the
+//!tick they are due on, so scheduling and cancelling are cheap however many
+//!timers there are. A driver thread wakes every tick, sends the messages
+//!of the timers due in the slot under the hand, and puts periodic timers
+//!back for their next round. Timers fire at most a tick late, never early.
+//!Ticks with nothing due are skipped over rather than stepped through, and
+//!with no timers pending the driver parks until one is scheduled.
+//!
+//!A timer whose channel has no receiver left is dropped when it fires.
+
+
+use super::Async;
+use super::mrms::MRMSSender;
+use super::backoff::backoff;
+use super::spinlock::SpinLock;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,AtomicU64,Ordering};
+use std::thread::{self,JoinHandle};
+use std::time::{Duration,Instant};
+const RELAXED: Ordering = Ordering::Relaxed;
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+
+///Slots on the wheel, a power of two
+const SLOTS: usize = 256;
+///Tick of a wheel built with `new`
+pub const DEFAULT_TICK: Duration = Duration::from_millis(1);
+
+///Identifies a scheduled timer for `cancel`
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash)]
+pub struct TimerId(u64);
+
+///Sends the message, false once nobody can receive it
+type Fire = Box<dyn FnMut() -> bool + Send>;
+
+struct Entry {
+    id: u64,
+    due: u64,
+    period: Option<u64>,
+    fire: Fire
+}
+
+struct Wheel {
+    slots: Vec<Vec<Entry>>,
+    ///ticks handled so far
+    now: u64,
+    pending: usize,
+    ///periodic timers being fired outside the lock
+    firing: Vec<u64>,
+    ///timers in `firing` cancelled meanwhile
+    cancelled: Vec<u64>
+}
+impl Wheel {
+    fn insert(&mut self, mut entry: Entry) {
+        //never behind the hand, it may have skipped ahead since `due`
+        entry.due = entry.due.max(self.now + 1);
+        self.pending += 1;
+        self.slots[entry.due as usize & (SLOTS - 1)].push(entry);
+    }
+}
+
+struct Shared {
+    wheel: SpinLock<Wheel>,
+    start: Instant,
+    tick: Duration,
+    next_id: AtomicU64,
+    shutdown: AtomicBool
+}
+impl Shared {
+    ///Ticks since the wheel started, rounded down
+    fn elapsed(&self) -> u64 {
+        (self.start.elapsed().as_nanos() / self.tick.as_nanos()) as u64
+    }
+    ///Fire everything due up to now, returns true if timers are pending
+    fn advance(&self) -> bool {
+        let target = self.elapsed();
+        loop {
+            let mut due = Vec::new();
+            {
+                let mut wheel = self.wheel.lock();
+                if wheel.now >= target {
+                    return wheel.pending > 0;
+                }
+                if wheel.pending == 0 {
+                    //nothing to fire on the way, skip the idle ticks
+                    wheel.now = target;
+                    return false;
+                }
+                //move the hand to the next tick with something due, or a
+                //rotation on if there is none
+                let window = (target - wheel.now).min(SLOTS as u64);
+                let mut now = wheel.now + window;
+                for tick in wheel.now + 1..wheel.now + window {
+                    if wheel.slots[tick as usize & (SLOTS - 1)].iter().any(|e| e.due <= tick) {
+                        now = tick;
+                        break;
+                    }
+                }
+                wheel.now = now;
+                let slot = &mut wheel.slots[now as usize & (SLOTS - 1)];
+                let mut i = 0;
+                while i < slot.len() {
+                    if slot[i].due <= now {
+                        due.push(slot.swap_remove(i));
+                    } else {
+                        i += 1;
+                    }
+                }
+                wheel.pending -= due.len();
+                let firing = due.iter().filter(|e| e.period.is_some()).map(|e| e.id).collect();
+                wheel.firing = firing;
+            }
+            //send without holding the wheel so schedule never waits on a
+            //channel
+            let mut again = Vec::new();
+            for mut entry in due {
+                if !(entry.fire)() {
+                    continue;
+                }
+                if let Option::Some(period) = entry.period {
+                    entry.due = entry.due.saturating_add(period);
+                    again.push(entry);
+                }
+            }
+            let mut wheel = self.wheel.lock();
+            for entry in again {
+                if wheel.cancelled.contains(&entry.id) {
+                    continue;
+                }
+                //insert makes a late round due right away rather than in
+                //the past
+                wheel.insert(entry);
+            }
+            wheel.firing.clear();
+            wheel.cancelled.clear();
+        }
+    }
+}
+
+///Delivers messages into channels after a delay or periodically
+pub struct Timer {
+    shared: Arc<Shared>,
+    driver: Option<JoinHandle<()>>
+}
+impl Timer {
+    ///Start a wheel ticking every `DEFAULT_TICK`
+    pub fn new() -> Timer {
+        Timer::with_tick(DEFAULT_TICK)
+    }
+    ///Start a wheel ticking every `tick`
+    pub fn with_tick(tick: Duration) -> Timer {
+        let shared = Arc::new(Shared {
+            wheel: SpinLock::new(Wheel {
+                slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+                now: 0,
+                pending: 0,
+                firing: Vec::new(),
+                cancelled: Vec::new()
+            }),
+            start: Instant::now(),
+            tick: tick.max(Duration::from_nanos(1)),
+            next_id: AtomicU64::new(0),
+            shutdown: AtomicBool::new(false)
+        });
+        let driver = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                while !shared.shutdown.load(ACQUIRE) {
+                    if shared.advance() {
+                        thread::park_timeout(shared.tick);
+                    } else {
+                        thread::park();
+                    }
+                }
+            })
+        };
+        Timer {
+            shared,
+            driver: Some(driver)
+        }
+    }
+    ///Ticks from now until `delay` has passed, rounded up. A delay too
+    ///long to count in ticks saturates, the timer then never fires.
+    fn due(&self, delay: Duration) -> u64 {
+        let tick = self.shared.tick.as_nanos();
+        let at = self.shared.start.elapsed().as_nanos() + delay.as_nanos();
+        at.div_ceil(tick).min(u64::MAX as u128) as u64
+    }
+    fn add(&self, delay: Duration, period: Option<Duration>, fire: Fire) -> TimerId {
+        let id = self.shared.next_id.fetch_add(1, RELAXED);
+        let due = self.due(delay);
+        let tick = self.shared.tick.as_nanos();
+        let period = period.map(|p| (p.as_nanos().div_ceil(tick).min(u64::MAX as u128) as u64).max(1));
+        self.shared.wheel.lock().insert(Entry {
+            id,
+            due,
+            period,
+            fire
+        });
+        if let Option::Some(ref driver) = self.driver {
+            driver.thread().unpark();
+        }
+        TimerId(id)
+    }
+    ///Send `msg` on `sender` once `delay` has passed
+    pub fn schedule<T: Send+'static>(&self, delay: Duration, msg: T, sender: &MRMSSender<T>) -> TimerId {
+        let sender = sender.clone();
+        let mut msg = Some(msg);
+        self.add(delay, None, Box::new(move || {
+            deliver(&sender, msg.take().unwrap())
+        }))
+    }
+    ///Send a clone of `msg` on `sender` after `delay` and then every
+    ///`period`, until cancelled or the channel is closed
+    pub fn schedule_periodic<T>(&self, delay: Duration, period: Duration, msg: T, sender: &MRMSSender<T>) -> TimerId
+    where
+        T: Clone + Send + 'static,
+    {
+        let sender = sender.clone();
+        self.add(delay, Some(period), Box::new(move || deliver(&sender, msg.clone())))
+    }
+    ///Cancel a timer that has not fired yet, or a periodic one
+    ///
+    ///Returns true if the timer was still scheduled
+    pub fn cancel(&self, id: TimerId) -> bool {
+        let mut wheel = self.shared.wheel.lock();
+        for i in 0..SLOTS {
+            if let Option::Some(pos) = wheel.slots[i].iter().position(|e| e.id == id.0) {
+                wheel.slots[i].swap_remove(pos);
+                wheel.pending -= 1;
+                return true;
+            }
+        }
+        if wheel.firing.contains(&id.0) && !wheel.cancelled.contains(&id.0) {
+            //it is being fired right now, keep it from coming back
+            wheel.cancelled.push(id.0);
+            return true;
+        }
+        false
+    }
+    ///Number of timers waiting to fire
+    pub fn pending(&self) -> usize {
+        self.shared.wheel.lock().pending
+    }
+}
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, RELEASE);
+        if let Option::Some(driver) = self.driver.take() {
+            driver.thread().unpark();
+            let _ = driver.join();
+        }
+    }
+}
+
+///Send retrying while the channel is contended, false if it is closed
+fn deliver<T: Send+'static>(sender: &MRMSSender<T>, msg: T) -> bool {
+    let mut msg = msg;
+    let mut step = 0;
+    loop {
+        match sender.send(msg) {
+            Async::Ok(()) => return true,
+            Async::Block(x) => {
+                msg = x;
+                backoff(&mut step);
+            }
+            Async::Err(_) => return false
+        };
+    }
+}
+
+#[test]
+fn test_timer_once() {
+    use super::mrms::channel;
+    let timer = Timer::new();
+    let (s,r) = channel::<&'static str>(4);
+    let start = Instant::now();
+    timer.schedule(Duration::from_millis(20), "late", &s);
+    timer.schedule(Duration::from_millis(5), "early", &s);
+    let cancelled = timer.schedule(Duration::from_millis(10), "never", &s);
+    assert!(timer.cancel(cancelled));
+    assert!(!timer.cancel(cancelled));
+    assert!(r.recv_timeout(Duration::from_secs(5)) == Async::Ok("early"));
+    assert!(r.recv_timeout(Duration::from_secs(5)) == Async::Ok("late"));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq!(timer.pending(), 0);
+    //too far out to count in ticks, stays pending until cancelled
+    let never = timer.schedule(Duration::MAX, "never", &s);
+    assert_eq!(timer.pending(), 1);
+    assert!(timer.cancel(never));
+}
+
+#[test]
+fn test_timer_periodic() {
+    use super::mrms::channel;
+    let timer = Timer::new();
+    let (s,r) = channel::<u8>(4);
+    let id = timer.schedule_periodic(Duration::from_millis(1), Duration::from_millis(2), 7, &s);
+    for _ in 0..3 {
+        assert!(r.recv_timeout(Duration::from_secs(5)) == Async::Ok(7));
+    }
+    assert!(timer.cancel(id));
+    //dropping the receiver retires a periodic timer when it next fires
+    timer.schedule_periodic(Duration::from_millis(1), Duration::from_millis(1), 8, &s);
+    drop(r);
+    while timer.pending() > 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_timer_idle() {
+    use super::mrms::channel;
+    //a million ticks go by while nothing is scheduled
+    let timer = Timer::with_tick(Duration::from_nanos(10));
+    let (s,r) = channel::<u8>(4);
+    thread::sleep(Duration::from_millis(10));
+    let start = Instant::now();
+    timer.schedule(Duration::from_millis(1), 1, &s);
+    assert!(r.recv_timeout(Duration::from_secs(5)) == Async::Ok(1));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
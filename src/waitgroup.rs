@@ -0,0 +1,99 @@
+//!Go style wait group.
+//!
+//!Every clone of a WaitGroup is one participant and dropping it signals
+//!that participant is done. `wait` gives up the caller's own membership
+//!and spins, backing off to yields, until the count reaches zero.
+
+
+use super::backoff::backoff;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::{Duration,Instant};
+const ACQUIRE: Ordering = Ordering::Acquire;
+const RELEASE: Ordering = Ordering::Release;
+const RELAXED: Ordering = Ordering::Relaxed;
+
+///Waits for every clone of itself to be dropped
+pub struct WaitGroup {
+    count: Arc<AtomicUsize>
+}
+impl WaitGroup {
+    ///Build a group with one participant, the returned handle
+    pub fn new() -> WaitGroup {
+        WaitGroup {
+            count: Arc::new(AtomicUsize::new(1))
+        }
+    }
+    ///Leave the group and block until every other participant left
+    pub fn wait(self) {
+        let count = self.count.clone();
+        drop(self);
+        let mut step = 0;
+        while count.load(ACQUIRE) != 0 {
+            backoff(&mut step);
+        }
+    }
+    ///Leave the group and block until every other participant left or
+    ///`timeout` passes
+    ///
+    ///Returns true if the group finished in time. A timeout too long to
+    ///express as an Instant waits for good.
+    pub fn wait_timeout(self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let count = self.count.clone();
+        drop(self);
+        let mut step = 0;
+        while count.load(ACQUIRE) != 0 {
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                return false;
+            }
+            backoff(&mut step);
+        }
+        true
+    }
+    ///Number of participants still in the group
+    pub fn count(&self) -> usize {
+        self.count.load(RELAXED)
+    }
+}
+impl Clone for WaitGroup {
+    fn clone(&self) -> WaitGroup {
+        self.count.fetch_add(1, RELAXED);
+        WaitGroup {
+            count: self.count.clone()
+        }
+    }
+}
+impl Default for WaitGroup {
+    fn default() -> WaitGroup {
+        WaitGroup::new()
+    }
+}
+impl Drop for WaitGroup {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, RELEASE);
+    }
+}
+
+#[test]
+fn test_wait_group() {
+    use std::thread;
+    let done = Arc::new(AtomicUsize::new(0));
+    let wg = WaitGroup::new();
+    for _ in 0..4 {
+        let wg = wg.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            done.fetch_add(1, RELAXED);
+            drop(wg);
+        });
+    }
+    wg.wait();
+    assert_eq!(done.load(RELAXED), 4);
+    let wg = WaitGroup::new();
+    let stuck = wg.clone();
+    assert_eq!(wg.count(), 2);
+    assert!(!wg.wait_timeout(Duration::from_millis(5)));
+    assert_eq!(stuck.count(), 1);
+    assert!(stuck.wait_timeout(Duration::MAX));
+}